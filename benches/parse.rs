@@ -0,0 +1,64 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Parsing time for long generated scripts, like those soak tests run
+//!
+//! Run with `cargo bench --bench parse`. Each size is parsed a few times and
+//! the fastest run is reported, with the rate in lines per second
+
+use quipu::parser::{ParseOptions, parse_script};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 5;
+
+// A script of about `lines` lines, mixing typing with the directives a
+// generated soak test is made of
+fn script(lines: usize) -> String {
+    let mut script = String::from("@ speed:0.01\n@ size:120:40\n");
+    for i in 0..lines / 5 {
+        script.push_str(&format!("@ caption:Step {}\n", i));
+        script.push_str(&format!("$ echo \"iteration {}\" | grep -c iter<ret>\n", i));
+        script.push_str("@ expect:^1$\n");
+        script.push_str("$ vim notes.txt<ret>ihello<esc>:wq<ret>\n");
+        script.push_str("@ wait:0.1\n");
+    }
+    script
+}
+
+fn fastest(input: &str, options: &ParseOptions) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            black_box(parse_script(black_box(input), options).unwrap());
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let options = ParseOptions::default();
+    for lines in [1_000, 10_000, 100_000] {
+        let input = script(lines);
+        let time = fastest(&input, &options);
+        println!(
+            "{:>7} lines: {:>9.3}ms, {:>10.0} lines/s",
+            lines,
+            time.as_secs_f64() * 1000.0,
+            lines as f64 / time.as_secs_f64()
+        );
+    }
+}
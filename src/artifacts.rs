@@ -0,0 +1,113 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Artifacts written when an expect fails, for `--artifacts`
+//!
+//! Each failure gets a directory named after the script and the line of
+//! the command that failed, holding the screen as text and as a PNG, and
+//! the last of the raw output, along with anything the session copied with
+//! OSC 52, so a failure in CI can be looked at after the runner has gone
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+use crate::screen::Screen;
+use crate::theme::Theme;
+
+pub struct Artifacts {
+    dir: PathBuf,
+    // The script's file name without its extension
+    name: String,
+    // The line each of the script's commands starts on, when it's known
+    lines: Vec<usize>,
+    theme: Theme,
+}
+
+impl Artifacts {
+    pub fn new(dir: &Path, name: &str, lines: Vec<usize>, theme: Theme) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            name: name.to_string(),
+            lines,
+            theme,
+        }
+    }
+
+    // Where artifacts for a failure at command `index` go; without lines,
+    // as for a script read from stdin, commands are counted instead
+    fn failure_dir(&self, index: usize) -> PathBuf {
+        let place = match self.lines.get(index) {
+            Some(line) => format!("line{}", line),
+            None => format!("command{}", index + 1),
+        };
+        self.dir.join(format!("{}-{}", self.name, place))
+    }
+
+    /// Write `screen.txt`, `screen.png`, `output.raw` and any `clipboard.txt`
+    /// for a failure at command `index`, returning the directory they're in
+    pub fn write(&self, index: usize, screen: &Screen) -> Result<PathBuf> {
+        let dir = self.failure_dir(index);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create directory: {}", dir.display()))?;
+        let copied = screen
+            .copied()
+            .map(|text| ("clipboard.txt", text.into_bytes()));
+        let files = [
+            ("screen.txt", screen.contents().into_bytes()),
+            ("screen.png", screen.draw(&self.theme, None).png()),
+            ("output.raw", screen.recent_output()),
+        ]
+        .into_iter()
+        .chain(copied);
+        for (name, content) in files {
+            let path = dir.join(name);
+            std::fs::write(&path, content)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+        Ok(dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::width::AmbiguousWidth;
+
+    #[test]
+    fn test_write() {
+        let dir = std::env::temp_dir().join(format!("quipu-artifacts-{}", std::process::id()));
+        let artifacts = Artifacts::new(&dir, "demo", vec![1, 4], Theme::default());
+        let screen = Screen::new(20, 4, AmbiguousWidth::Narrow, 0);
+        screen.process(b"\x1b[1mready\x1b[m");
+
+        let written = artifacts.write(1, &screen).unwrap();
+        assert_eq!(written, dir.join("demo-line4"));
+        assert_eq!(
+            std::fs::read_to_string(written.join("screen.txt")).unwrap(),
+            "ready"
+        );
+        assert_eq!(
+            std::fs::read(written.join("output.raw")).unwrap(),
+            b"\x1b[1mready\x1b[m"
+        );
+        assert!(
+            std::fs::read(written.join("screen.png"))
+                .unwrap()
+                .starts_with(b"\x89PNG")
+        );
+        assert_eq!(artifacts.failure_dir(2), dir.join("demo-command3"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
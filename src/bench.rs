@@ -0,0 +1,235 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! `quipu bench`, measuring how closely keys keep to time
+//!
+//! A line of keys is typed into a real session at a steady speed, and the
+//! time each one is written is compared with when it was due. Keys are
+//! timed against deadlines, so a slow timer shows up as every key being a
+//! little late rather than as intervals drifting; that lateness is the
+//! offset `--save` stores for the schedule to take off
+
+use anyhow::Result;
+use std::time::{Duration, Instant};
+
+use crate::cast::Cast;
+use crate::key::Keys;
+use crate::playback::PlaybackEngine;
+use crate::screen::Screen;
+use crate::sink::{KeystrokeSink, Signal};
+use crate::types::{Command, PlaybackConfig, Script};
+
+/// Passes everything on to another sink, noting when each write started and
+/// how long it took
+struct TimingSink<S> {
+    inner: S,
+    writes: Vec<(Instant, Duration)>,
+}
+
+impl<S: KeystrokeSink> KeystrokeSink for TimingSink<S> {
+    async fn send(&mut self, bytes: &[u8]) -> Result<()> {
+        let start = Instant::now();
+        self.inner.send(bytes).await?;
+        self.writes.push((start, start.elapsed()));
+        Ok(())
+    }
+
+    fn resize(&mut self, cols: u16, rows: u16) -> Result<()> {
+        self.inner.resize(cols, rows)
+    }
+
+    fn signal(&mut self, signal: Signal) -> Result<()> {
+        self.inner.signal(signal)
+    }
+
+    fn screen(&self) -> &Screen {
+        self.inner.screen()
+    }
+
+    fn set_visible(&self, visible: bool) -> Result<()> {
+        self.inner.set_visible(visible)
+    }
+
+    fn draw_status(&mut self, line: usize, text: &str) -> Result<()> {
+        self.inner.draw_status(line, text)
+    }
+
+    fn clear(&self, scrollback: bool) -> Result<()> {
+        self.inner.clear(scrollback)
+    }
+
+    fn reset(&self) -> Result<()> {
+        self.inner.reset()
+    }
+
+    async fn section(&mut self, name: &str) -> Result<()> {
+        self.inner.section(name).await
+    }
+
+    fn redact(&self, text: &str) -> String {
+        self.inner.redact(text)
+    }
+
+    fn recording(&self) -> Option<Cast> {
+        self.inner.recording()
+    }
+}
+
+/// How the keys of a benchmark run kept to time
+#[derive(Debug)]
+pub struct BenchReport {
+    /// The time between keys asked for
+    pub speed: Duration,
+    /// When each key was written, from the first
+    pub times: Vec<Duration>,
+    /// How long each write took
+    pub writes: Vec<Duration>,
+}
+
+// Mean, median, 95th percentile and maximum, in milliseconds
+fn distribution(mut values: Vec<f64>) -> [f64; 4] {
+    if values.is_empty() {
+        return [0.0; 4];
+    }
+    values.sort_by(f64::total_cmp);
+    let at = |fraction: f64| values[((values.len() - 1) as f64 * fraction).round() as usize];
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    [mean, at(0.5), at(0.95), values[values.len() - 1]]
+}
+
+fn ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+impl BenchReport {
+    // Each interval less the speed, in milliseconds
+    fn interval_errors(&self) -> Vec<f64> {
+        self.times
+            .windows(2)
+            .map(|pair| ms(pair[1] - pair[0]) - ms(self.speed))
+            .collect()
+    }
+
+    // How late each key after the first was, in milliseconds
+    fn lateness(&self) -> Vec<f64> {
+        (1..self.times.len())
+            .map(|index| ms(self.times[index]) - ms(self.speed) * index as f64)
+            .collect()
+    }
+
+    /// The offset to wake early by: how late the typical key was
+    pub fn offset(&self) -> Duration {
+        let [_, median, _, _] = distribution(self.lateness());
+        Duration::from_secs_f64(median.max(0.0) / 1000.0)
+    }
+
+    pub fn summary(&self) -> String {
+        let mut out = format!(
+            "Timing benchmark: {} keys at {:.0}ms\n\n{:<14} {:>8} {:>8} {:>8} {:>8}\n",
+            self.times.len(),
+            ms(self.speed),
+            "",
+            "mean",
+            "median",
+            "p95",
+            "max"
+        );
+        let rows = [
+            ("Interval error", distribution(self.interval_errors())),
+            ("Lateness", distribution(self.lateness())),
+            (
+                "Write",
+                distribution(self.writes.iter().copied().map(ms).collect()),
+            ),
+        ];
+        for (name, [mean, median, p95, max]) in rows {
+            out.push_str(&format!(
+                "{:<14} {:>6.2}ms {:>6.2}ms {:>6.2}ms {:>6.2}ms\n",
+                name, mean, median, p95, max
+            ));
+        }
+        out.push_str(&format!(
+            "\nSuggested timing_offset: {:.4}s\n",
+            self.offset().as_secs_f64()
+        ));
+        out
+    }
+}
+
+/// Type `keys` keys into `sink`, `speed` apart, and report how they kept to time
+pub async fn run<S: KeystrokeSink>(sink: S, speed: Duration, keys: usize) -> Result<BenchReport> {
+    let sink = TimingSink {
+        inner: sink,
+        writes: Vec::new(),
+    };
+    let mut engine = PlaybackEngine::new(sink, PlaybackConfig::default())?;
+    // Speeds are kept to the millisecond, so it's measured against what's played
+    let speed = Duration::from_millis(speed.as_millis() as u64);
+    let script = Script {
+        commands: vec![
+            Command::SetJitter(0.0),
+            Command::SetSpeed(speed.as_secs_f64()),
+            Command::Type(Keys::text(&"x".repeat(keys))),
+            // Clear the line again with Ctrl-U
+            Command::Type("\x15".into()),
+        ],
+    };
+    engine.execute(script).await?;
+
+    let writes = std::mem::take(&mut engine.sink().writes);
+    let writes = &writes[..keys.min(writes.len())];
+    let first = writes
+        .first()
+        .map_or_else(Instant::now, |(start, _)| *start);
+    Ok(BenchReport {
+        speed,
+        times: writes.iter().map(|(start, _)| *start - first).collect(),
+        writes: writes.iter().map(|(_, took)| *took).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::RecordingSink;
+
+    #[test]
+    fn test_bench_report() {
+        let report = BenchReport {
+            speed: Duration::from_millis(10),
+            times: [0, 11, 21, 32, 41].map(Duration::from_millis).to_vec(),
+            writes: vec![Duration::from_micros(20); 5],
+        };
+        assert_eq!(report.interval_errors(), [1.0, 0.0, 1.0, -1.0]);
+        assert_eq!(report.lateness(), [1.0, 1.0, 2.0, 1.0]);
+        assert_eq!(report.offset(), Duration::from_millis(1));
+        assert!(
+            report
+                .summary()
+                .contains("Suggested timing_offset: 0.0010s")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bench_run() {
+        let report = run(RecordingSink::new(80, 24), Duration::from_millis(2), 20)
+            .await
+            .unwrap();
+        assert_eq!(report.times.len(), 20);
+        assert_eq!(report.times[0], Duration::ZERO);
+        // However slow the machine, keys can't come early
+        assert!(report.times[19] >= Duration::from_millis(38));
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Building scripts in code
+//!
+//! [`ScriptBuilder`] puts a script together command by command, for tests
+//! and other programs that would otherwise have to write out script text
+//! for the parser:
+//!
+//! ```
+//! use quipu::builder::ScriptBuilder;
+//! use quipu::key::Key;
+//!
+//! let script = ScriptBuilder::new()
+//!     .speed(0.05)
+//!     .type_line("ls -la")
+//!     .key(Key::Enter)
+//!     .wait_secs(1.0)
+//!     .build()
+//!     .unwrap();
+//! assert_eq!(script.commands.len(), 3);
+//! ```
+
+use std::time::Duration;
+
+use crate::key::{Key, KeyEvent, Keys, Modifiers};
+use crate::types::{Command, Script};
+
+#[derive(Debug, Default, Clone)]
+pub struct ScriptBuilder {
+    commands: Vec<Command>,
+}
+
+impl ScriptBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add any command, for those without a method of their own
+    pub fn command(mut self, command: Command) -> Self {
+        self.commands.push(command);
+        self
+    }
+
+    /// Seconds per keystroke, as `@ speed:`
+    pub fn speed(self, secs: f64) -> Self {
+        self.command(Command::SetSpeed(secs))
+    }
+
+    /// Random variation in the speed, as `@ jitter:`
+    pub fn jitter(self, secs: f64) -> Self {
+        self.command(Command::SetJitter(secs))
+    }
+
+    /// Terminal size, as `@ size:`
+    pub fn size(self, cols: u16, rows: u16) -> Self {
+        self.command(Command::SetSize(cols, rows))
+    }
+
+    /// Shell to start the session with, as `@ shell:`
+    pub fn shell(self, shell: &str) -> Self {
+        self.command(Command::SetShell(shell.to_string()))
+    }
+
+    /// Start a new `$` line typing `text` as it is, with no keys in angle brackets
+    pub fn type_line(self, text: &str) -> Self {
+        self.command(Command::Type(Keys::from(text)))
+    }
+
+    // Add keys to the current `$` line, or start one
+    fn push_keys(mut self, keys: Keys) -> Self {
+        match self.commands.last_mut() {
+            Some(Command::Type(line)) => line.append(keys),
+            _ => self.commands.push(Command::Type(keys)),
+        }
+        self
+    }
+
+    /// Press `key` at the end of the current `$` line, as `<ret>` and so on do
+    pub fn key(self, key: Key) -> Self {
+        self.press(KeyEvent::new(key))
+    }
+
+    /// Press a key with modifiers held, as `<C-S-right>`; combinations
+    /// terminals have no encoding for are typed as `<...>`, as the parser does
+    pub fn press(self, event: KeyEvent) -> Self {
+        let keys = match event.legacy() {
+            Some(_) => Keys::key(event),
+            None => Keys::text(&format!("<{}>", event)),
+        };
+        self.push_keys(keys)
+    }
+
+    /// Press Ctrl with `ch`, as `<C-c>`
+    pub fn ctrl(self, ch: char) -> Self {
+        let mods = Modifiers {
+            ctrl: true,
+            ..Modifiers::NONE
+        };
+        self.press(KeyEvent::with_mods(Key::Char(ch), mods))
+    }
+
+    /// Pause, as `@ wait:`
+    pub fn wait(self, duration: Duration) -> Self {
+        self.command(Command::Wait(duration))
+    }
+
+    pub fn wait_secs(self, secs: f64) -> Self {
+        self.wait(Duration::from_secs_f64(secs))
+    }
+
+    /// The script, checked as the parser checks directives
+    pub fn build(self) -> Result<Script, String> {
+        for command in &self.commands {
+            command.check()?;
+        }
+        Ok(Script {
+            commands: self.commands,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{ParseOptions, parse_script};
+
+    #[test]
+    fn test_builder_matches_parser() {
+        let built = ScriptBuilder::new()
+            .size(100, 30)
+            .speed(0.05)
+            .type_line("ls -la")
+            .key(Key::Enter)
+            .wait_secs(1.0)
+            .key(Key::Up)
+            .ctrl('c')
+            .build()
+            .unwrap();
+        let parsed = parse_script(
+            "@ size:100:30\n@ speed:0.05\n$ ls -la<ret>\n@ wait:1s\n$ <up><C-c>\n",
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(built.commands, parsed.commands);
+
+        assert!(ScriptBuilder::new().speed(-1.0).build().is_err());
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Captions from `@ caption:` directives
+//!
+//! Shown on a status row during playback, and collected with their timings
+//! so they can be written out as WebVTT subtitles for a recording
+
+use std::time::Duration;
+
+#[derive(Debug, PartialEq)]
+struct Cue {
+    start: Duration,
+    end: Duration,
+    text: String,
+}
+
+#[derive(Debug, Default)]
+pub struct CaptionTrack {
+    cues: Vec<Cue>,
+    // Caption currently on screen and when it appeared
+    current: Option<(Duration, String)>,
+}
+
+impl CaptionTrack {
+    /// Show `text` from `at`, replacing the current caption; empty text clears it
+    pub fn set(&mut self, at: Duration, text: &str) {
+        self.finish(at);
+        if !text.is_empty() {
+            self.current = Some((at, text.to_string()));
+        }
+    }
+
+    /// End the current caption at `at`
+    pub fn finish(&mut self, at: Duration) {
+        if let Some((start, text)) = self.current.take() {
+            self.cues.push(Cue {
+                start,
+                end: at,
+                text,
+            });
+        }
+    }
+
+    /// The caption on screen now, if any
+    pub fn current(&self) -> Option<&str> {
+        self.current.as_ref().map(|(_, text)| text.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cues.is_empty() && self.current.is_none()
+    }
+
+    pub fn to_webvtt(&self) -> String {
+        let mut out = String::from("WEBVTT\n");
+        for cue in &self.cues {
+            out.push_str(&format!(
+                "\n{} --> {}\n{}\n",
+                timestamp(cue.start),
+                timestamp(cue.end),
+                cue.text
+            ));
+        }
+        out
+    }
+}
+
+fn timestamp(time: Duration) -> String {
+    let millis = time.as_millis();
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// A caption centred in `width` columns, in bold
+pub fn styled(text: &str, width: usize) -> String {
+    let text: String = text.chars().take(width).collect();
+    let indent = (width - text.chars().count()) / 2;
+    format!("{}\x1b[1m{}\x1b[22m", " ".repeat(indent), text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_webvtt() {
+        let mut track = CaptionTrack::default();
+        track.set(Duration::from_millis(1500), "Build the release");
+        track.set(Duration::from_secs(65), "Run it");
+        track.set(Duration::from_secs(70), "");
+        track.finish(Duration::from_secs(80));
+
+        assert_eq!(
+            track.to_webvtt(),
+            "WEBVTT\n\n00:00:01.500 --> 00:01:05.000\nBuild the release\n\n00:01:05.000 --> 00:01:10.000\nRun it\n"
+        );
+    }
+
+    #[test]
+    fn test_styled_caption() {
+        assert_eq!(styled("hi", 6), "  \x1b[1mhi\x1b[22m");
+    }
+}
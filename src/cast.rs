@@ -0,0 +1,463 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! asciinema cast support
+//!
+//! Reads v2 and v3 recordings and rebuilds a script from their input and
+//! resize events, and records sessions (or converts other recordings) as
+//! v2 casts, keys and all, so a recording quipu made imports again
+
+use anyhow::{Context, Result, bail};
+use serde_json::{Value, json};
+use std::time::{Duration, Instant};
+
+use crate::redact::Redactor;
+use crate::types::{Command, MetaField, Script};
+
+// Gaps longer than this (or several keystrokes' worth) become waits
+const MIN_WAIT_SECS: f64 = 0.5;
+
+// Keys kept on a line that's still being typed before the older half is
+// masked and let go; the newer half stays to mask a secret that spans them
+const MAX_TYPING: usize = 512;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CastEvent {
+    // Seconds since the start of the recording
+    pub time: f64,
+    pub kind: String,
+    pub data: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cast {
+    pub cols: u16,
+    pub rows: u16,
+    pub title: Option<String>,
+    pub events: Vec<CastEvent>,
+}
+
+pub fn parse_cast(input: &str) -> Result<Cast> {
+    let mut lines = input.lines().filter(|line| !line.trim().is_empty());
+    let header: Value = serde_json::from_str(lines.next().context("Cast file is empty")?)
+        .context("Invalid cast header")?;
+
+    let version = header["version"]
+        .as_u64()
+        .context("Cast header has no version")?;
+    let (cols, rows) = match version {
+        2 => (&header["width"], &header["height"]),
+        3 => (&header["term"]["cols"], &header["term"]["rows"]),
+        _ => bail!("Unsupported cast version: {}", version),
+    };
+    let cols = cols.as_u64().context("Cast header has no terminal width")? as u16;
+    let rows = rows
+        .as_u64()
+        .context("Cast header has no terminal height")? as u16;
+
+    let mut events = Vec::new();
+    let mut elapsed = 0.0;
+    for (line_num, line) in lines.enumerate() {
+        // v3 comment lines
+        if line.starts_with('#') {
+            continue;
+        }
+
+        let (time, kind, data): (f64, String, String) = serde_json::from_str(line)
+            .with_context(|| format!("Invalid cast event on line {}", line_num + 2))?;
+
+        // v2 stores absolute times, v3 stores intervals since the previous event
+        elapsed = if version == 2 { time } else { elapsed + time };
+        events.push(CastEvent {
+            time: elapsed,
+            kind,
+            data,
+        });
+    }
+
+    let title = header["title"].as_str().map(str::to_string);
+    Ok(Cast {
+        cols,
+        rows,
+        title,
+        events,
+    })
+}
+
+/// Write `cast` as an asciinema v2 recording
+pub fn write_cast(cast: &Cast) -> String {
+    let mut header = json!({ "version": 2, "width": cast.cols, "height": cast.rows });
+    if let Some(title) = &cast.title {
+        header["title"] = json!(title);
+    }
+    let mut out = header.to_string() + "\n";
+    for event in &cast.events {
+        out.push_str(&json!([round_time(event.time), event.kind, event.data]).to_string());
+        out.push('\n');
+    }
+    out
+}
+
+// Event times are kept to the microsecond, as asciinema writes them
+fn round_time(secs: f64) -> f64 {
+    (secs * 1_000_000.0).round() / 1_000_000.0
+}
+
+/// Take the text from the start of `bytes`, leaving an incomplete character
+/// at the end for more bytes to finish
+pub fn take_utf8(bytes: &mut Vec<u8>) -> String {
+    let complete = match std::str::from_utf8(bytes) {
+        Ok(_) => bytes.len(),
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => bytes.len(),
+    };
+    let rest = bytes.split_off(complete);
+    String::from_utf8_lossy(&std::mem::replace(bytes, rest)).into_owned()
+}
+
+/// Output of a session, and the keys sent to it, collected as cast events as it plays
+pub struct Recorder {
+    started: Instant,
+    cast: Cast,
+    // The start of a UTF-8 character split across reads
+    partial: Vec<u8>,
+    // Masks keys as `--redact` does output
+    redactor: Redactor,
+    // Input events on the line still being typed, masked once it's finished
+    typing: Vec<usize>,
+}
+
+impl Recorder {
+    pub fn new(cols: u16, rows: u16, title: Option<String>, redactor: Redactor) -> Self {
+        Self {
+            started: Instant::now(),
+            cast: Cast {
+                cols,
+                rows,
+                title,
+                events: Vec::new(),
+            },
+            partial: Vec::new(),
+            redactor,
+            typing: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, kind: &str, data: String) {
+        self.cast.events.push(CastEvent {
+            time: self.started.elapsed().as_secs_f64(),
+            kind: kind.to_string(),
+            data,
+        });
+    }
+
+    pub fn output(&mut self, bytes: &[u8]) {
+        self.partial.extend_from_slice(bytes);
+        let data = take_utf8(&mut self.partial);
+        if !data.is_empty() {
+            self.push("o", data);
+        }
+    }
+
+    pub fn resize(&mut self, cols: u16, rows: u16) {
+        self.push("r", format!("{}x{}", cols, rows));
+    }
+
+    /// Keys sent to the session. A secret is typed one key at a time, so
+    /// they're masked a line at a time rather than as they come
+    pub fn input(&mut self, bytes: &[u8]) {
+        let data = String::from_utf8_lossy(bytes).into_owned();
+        let ends_line = data.contains(['\r', '\n']);
+        self.typing.push(self.cast.events.len());
+        self.push("i", data);
+        if ends_line {
+            mask_typing(&self.redactor, &mut self.cast, &self.typing);
+            self.typing.clear();
+        } else if self.typing.len() > MAX_TYPING {
+            mask_typing(&self.redactor, &mut self.cast, &self.typing);
+            self.typing.drain(..self.typing.len() - MAX_TYPING / 2);
+        }
+    }
+
+    /// The recording so far
+    pub fn snapshot(&self) -> Cast {
+        let mut cast = self.cast.clone();
+        mask_typing(&self.redactor, &mut cast, &self.typing);
+        if !self.partial.is_empty() {
+            cast.events.push(CastEvent {
+                time: self.started.elapsed().as_secs_f64(),
+                kind: "o".to_string(),
+                data: String::from_utf8_lossy(&self.partial).into_owned(),
+            });
+        }
+        cast
+    }
+}
+
+// Mask the input events at `typing` as one run of text, which keeps its
+// length in characters, and share it back out between them
+fn mask_typing(redactor: &Redactor, cast: &mut Cast, typing: &[usize]) {
+    let text: String = typing
+        .iter()
+        .map(|&i| cast.events[i].data.as_str())
+        .collect();
+    let mut masked = redactor
+        .mask_str(&text)
+        .chars()
+        .collect::<Vec<_>>()
+        .into_iter();
+    for &i in typing {
+        let event = &mut cast.events[i];
+        event.data = masked.by_ref().take(event.data.chars().count()).collect();
+    }
+}
+
+fn round(secs: f64) -> f64 {
+    (secs * 100.0).round() / 100.0
+}
+
+// Median gap between keystrokes, ignoring pauses
+fn estimate_speed(inputs: &[&CastEvent]) -> f64 {
+    let mut gaps: Vec<f64> = inputs
+        .windows(2)
+        .map(|pair| pair[1].time - pair[0].time)
+        .filter(|gap| *gap < MIN_WAIT_SECS)
+        .collect();
+
+    if gaps.is_empty() {
+        return 0.1;
+    }
+
+    gaps.sort_by(f64::total_cmp);
+    round(gaps[gaps.len() / 2]).max(0.01)
+}
+
+/// Terminal size from a resize event, written as `COLSxROWS`
+pub fn parse_resize(data: &str) -> Option<(u16, u16)> {
+    let (cols, rows) = data.split_once('x')?;
+    Some((cols.parse().ok()?, rows.parse().ok()?))
+}
+
+/// Rebuild a script from the input and resize events of a recording
+pub fn import_cast(input: &str) -> Result<Script> {
+    script_from_cast(&parse_cast(input)?)
+}
+
+/// Whether `cast` has the input events needed to rebuild a script
+pub fn has_input(cast: &Cast) -> bool {
+    cast.events.iter().any(|e| e.kind == "i")
+}
+
+pub fn script_from_cast(cast: &Cast) -> Result<Script> {
+    let inputs: Vec<&CastEvent> = cast.events.iter().filter(|e| e.kind == "i").collect();
+    if inputs.is_empty() {
+        bail!("Recording has no input events (record with `asciinema rec --stdin`)");
+    }
+
+    let speed = estimate_speed(&inputs);
+    let pause_threshold = MIN_WAIT_SECS.max(speed * 4.0);
+
+    let mut commands = Vec::new();
+    if let Some(title) = &cast.title {
+        commands.push(Command::Meta(MetaField::Title, title.clone()));
+    }
+    commands.extend([
+        Command::SetSize(cast.cols, cast.rows),
+        Command::SetSpeed(speed),
+    ]);
+    let mut line = String::new();
+    let mut last_time = 0.0;
+
+    let events = cast
+        .events
+        .iter()
+        .filter(|e| e.kind == "i" || e.kind == "r");
+    for event in events {
+        let resize = match event.kind.as_str() {
+            "r" => match parse_resize(&event.data) {
+                Some(size) => Some(size),
+                None => continue,
+            },
+            _ => None,
+        };
+
+        let gap = event.time - last_time;
+        if gap > pause_threshold {
+            if !line.is_empty() {
+                commands.push(Command::Type(std::mem::take(&mut line).into()));
+            }
+            // Playback already waits one keystroke's worth after the last key
+            let wait = round(gap - speed);
+            commands.push(Command::Wait(Duration::from_secs_f64(wait)));
+        }
+
+        last_time = event.time;
+        if let Some((cols, rows)) = resize {
+            if !line.is_empty() {
+                commands.push(Command::Type(std::mem::take(&mut line).into()));
+            }
+            commands.push(Command::Resize(cols, rows));
+            continue;
+        }
+
+        line.push_str(&event.data);
+        if event.data.ends_with('\r') {
+            commands.push(Command::Type(std::mem::take(&mut line).into()));
+        }
+    }
+
+    if !line.is_empty() {
+        commands.push(Command::Type(line.into()));
+    }
+
+    Ok(Script { commands })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_import_v2() {
+        let cast = r#"{"version": 2, "width": 100, "height": 30}
+[0.1, "o", "$ "]
+[1.0, "i", "l"]
+[1.1, "i", "s"]
+[1.2, "i", "\r"]
+[1.3, "o", "file.txt\r\n"]
+[3.3, "i", "\u001b[A"]
+[3.4, "i", "\r"]
+"#;
+        let script = import_cast(cast).unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetSize(100, 30),
+                Command::SetSpeed(0.1),
+                Command::Wait(Duration::from_secs_f64(0.9)),
+                Command::Type("ls\r".into()),
+                Command::Wait(Duration::from_secs_f64(2.0)),
+                Command::Type("\x1b[A\r".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_import_v3_intervals() {
+        let cast = r#"{"version": 3, "term": {"cols": 80, "rows": 24}, "title": "Demo"}
+# comment
+[0.2, "i", "a"]
+[0.2, "i", "b"]
+"#;
+        let script = import_cast(cast).unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Meta(MetaField::Title, "Demo".to_string()),
+                Command::SetSize(80, 24),
+                Command::SetSpeed(0.2),
+                Command::Type("ab".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_import_resize_events() {
+        let cast = r#"{"version": 2, "width": 80, "height": 24}
+[0.1, "i", "v"]
+[0.2, "i", "i"]
+[0.25, "r", "120x40"]
+[0.3, "i", "m"]
+[0.4, "r", "bad"]
+"#;
+        let script = import_cast(cast).unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetSize(80, 24),
+                Command::SetSpeed(0.1),
+                Command::Type("vi".into()),
+                Command::Resize(120, 40),
+                Command::Type("m".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recording_round_trip() {
+        let mut recorder = Recorder::new(80, 24, Some("Demo".to_string()), Redactor::default());
+        // "é" split across two reads
+        recorder.output(b"caf\xc3");
+        recorder.output(b"\xa9\r\n");
+        recorder.resize(100, 30);
+        let cast = parse_cast(&write_cast(&recorder.snapshot())).unwrap();
+
+        assert_eq!((cast.cols, cast.rows), (80, 24));
+        assert_eq!(cast.title.as_deref(), Some("Demo"));
+        let events: Vec<(&str, &str)> = cast
+            .events
+            .iter()
+            .map(|e| (e.kind.as_str(), e.data.as_str()))
+            .collect();
+        assert_eq!(events, [("o", "caf"), ("o", "é\r\n"), ("r", "100x30")]);
+    }
+
+    #[test]
+    fn test_recording_masks_input() {
+        let redactor = Redactor::new(&["sk-[0-9a-f]+".to_string()]).unwrap();
+        let mut recorder = Recorder::new(80, 24, None, redactor);
+        for key in ["e", "cho ", "s", "k-1", "2", "\r"] {
+            recorder.input(key.as_bytes());
+            recorder.output(b"x");
+        }
+        recorder.input(b"sk-9");
+        let cast = recorder.snapshot();
+        let typed: String = cast
+            .events
+            .iter()
+            .filter(|e| e.kind == "i")
+            .map(|e| e.data.as_str())
+            .collect();
+        assert_eq!(typed, "echo *****\r****");
+
+        // A long line without Enter is masked as it goes, a secret across the
+        // keys let go included
+        let mut recorder = Recorder::new(80, 24, None, recorder.redactor.clone());
+        for _ in 0..MAX_TYPING - 2 {
+            recorder.input(b"x");
+        }
+        for key in ["s", "k", "-", "1", "2"] {
+            recorder.input(key.as_bytes());
+        }
+        assert!(recorder.typing.len() <= MAX_TYPING);
+        let typed: String = recorder
+            .snapshot()
+            .events
+            .iter()
+            .map(|e| e.data.as_str())
+            .collect();
+        assert!(typed.ends_with("x*****"));
+
+        // And the keys come back as the lines they typed
+        let script = script_from_cast(&cast).unwrap();
+        assert_eq!(script.commands[2], Command::Type("echo *****\r".into()));
+    }
+
+    #[test]
+    fn test_import_requires_input_events() {
+        let cast = "{\"version\": 2, \"width\": 80, \"height\": 24}\n[0.5, \"o\", \"hi\"]\n";
+        assert!(import_cast(cast).is_err());
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Clipboards: the system one for `@ typeclipboard`, and the session's
+//! OSC 52 writes
+//!
+//! The system clipboard is read with whichever of the platform's clipboard
+//! tools is installed, as there's no one API for it everywhere. Programs in
+//! the session copy with OSC 52, which is kept from the real terminal unless
+//! `--forward-clipboard` is given, and can be caught with `@ expect-clipboard`
+
+use anyhow::{Result, bail};
+use std::process::{Command, Stdio};
+
+use crate::screen::{Scan, Sequence};
+
+// Commands that print the clipboard, tried in order
+#[cfg(target_os = "macos")]
+const PASTE: &[&[&str]] = &[&["pbpaste"]];
+#[cfg(windows)]
+const PASTE: &[&[&str]] = &[&["powershell", "-NoProfile", "-Command", "Get-Clipboard -Raw"]];
+#[cfg(all(unix, not(target_os = "macos")))]
+const PASTE: &[&[&str]] = &[
+    &["wl-paste", "--no-newline"],
+    &["xclip", "-selection", "clipboard", "-o"],
+    &["xsel", "--clipboard", "--output"],
+];
+
+/// The text on the clipboard, with Windows line endings made plain
+pub fn read() -> Result<String> {
+    for command in PASTE {
+        let output = Command::new(command[0])
+            .args(&command[1..])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output();
+        if let Ok(output) = output
+            && output.status.success()
+        {
+            return Ok(String::from_utf8_lossy(&output.stdout).replace("\r\n", "\n"));
+        }
+    }
+    let tools: Vec<&str> = PASTE.iter().map(|command| command[0]).collect();
+    bail!("Failed to read the clipboard with {}", tools.join(", "))
+}
+
+/// The text an OSC 52 body such as `52;c;aGk=` copies, or `None` for
+/// anything else, including queries for what's on the clipboard
+pub fn osc52_text(body: &[u8]) -> Option<String> {
+    let rest = body.strip_prefix(b"52;")?;
+    let semicolon = rest.iter().position(|&byte| byte == b';')?;
+    let data = &rest[semicolon + 1..];
+    if data == b"?" {
+        return None;
+    }
+    base64_decode(data).map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn base64_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for &byte in data {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' | b'\r' | b'\n' => continue,
+            _ => return None,
+        };
+        bits = bits << 6 | u32::from(value);
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Drops OSC 52 clipboard writes from output, even when they're split across reads
+#[derive(Default)]
+pub struct Osc52Filter {
+    scan: Scan,
+    // A sequence that may yet turn out to be a clipboard write
+    pending: Vec<u8>,
+    // Set in a clipboard write too long to keep, all of which is dropped
+    // rather than let through from there on, with whether ESC was just seen
+    oversized: Option<bool>,
+}
+
+impl Osc52Filter {
+    pub fn strip(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(bytes.len());
+        for &byte in bytes {
+            if let Some(escaped) = self.oversized {
+                self.oversized = match byte {
+                    0x07 => None,
+                    b'\\' if escaped => None,
+                    0x1b => Some(true),
+                    _ if !escaped => Some(false),
+                    // Any other ESC starts a new sequence, as it would in the scan
+                    _ => {
+                        self.oversized = None;
+                        self.scan = Scan::Escape;
+                        self.pending.push(0x1b);
+                        self.strip_byte(byte, &mut out);
+                        continue;
+                    }
+                };
+                continue;
+            }
+            self.strip_byte(byte, &mut out);
+        }
+        out
+    }
+
+    fn strip_byte(&mut self, byte: u8, out: &mut Vec<u8>) {
+        let copying = self.is_copy();
+        self.pending.push(byte);
+        match self.scan.next(byte) {
+            Some(Sequence::Osc(body, _)) if body.starts_with(b"52;") => self.pending.clear(),
+            // The scan gives up on a write past MAX_COPY_LEN
+            None if copying && self.scan.is_ground() => {
+                self.pending.clear();
+                self.oversized = Some(false);
+            }
+            _ if self.may_be_copy() => {}
+            _ => out.append(&mut self.pending),
+        }
+    }
+
+    fn is_copy(&self) -> bool {
+        matches!(&self.scan, Scan::Str(b']', body, false) if body.starts_with(b"52;"))
+    }
+
+    // Other sequences go straight through, so they're never held back
+    fn may_be_copy(&self) -> bool {
+        match &self.scan {
+            Scan::Escape => true,
+            Scan::Str(b']', body, _) => body.starts_with(b"52;") || b"52;".starts_with(body),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_osc52() {
+        assert_eq!(
+            osc52_text(b"52;c;aGVsbG8sIHdvcmxk"),
+            Some("hello, world".to_string())
+        );
+        assert_eq!(osc52_text(b"52;;w6k="), Some("\u{e9}".to_string()));
+        assert_eq!(osc52_text(b"52;c;?"), None);
+        assert_eq!(osc52_text(b"0;title"), None);
+
+        // Split across reads, with other sequences left alone
+        let mut filter = Osc52Filter::default();
+        assert_eq!(filter.strip(b"a\x1b]52;c;aGk"), b"a");
+        assert_eq!(
+            filter.strip(b"=\x07b\x1b]0;t\x07\x1b[1m"),
+            b"b\x1b]0;t\x07\x1b[1m"
+        );
+        assert_eq!(filter.strip(b"\x1b]52;c;aGk=\x1b\\c"), b"c");
+
+        // One too long to keep is dropped whole, not let through once it's given up on
+        let long = vec![b'A'; crate::screen::MAX_COPY_LEN];
+        assert_eq!(filter.strip(b"\x1b]52;c;"), b"");
+        assert_eq!(filter.strip(&long), b"");
+        assert_eq!(filter.strip(&long), b"");
+        assert_eq!(filter.strip(b"AA\x1b\\d\x1b]52;c;"), b"d");
+        assert_eq!(filter.strip(&long), b"");
+        assert_eq!(filter.strip(b"\x1b[1m"), b"\x1b[1m");
+    }
+}
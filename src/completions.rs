@@ -0,0 +1,259 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Shell completion scripts for `quipu completions`
+//!
+//! The scripts are generated by clap_complete from the command line
+//! definition, so they stay in step with it. Checkpoint names for
+//! `--resume-from`, `--skip` and `--only` are then hooked in, to be looked up
+//! as they're completed by running the hidden `quipu complete checkpoints
+//! SCRIPT` on the script already on the line
+
+use clap::{Command, ValueEnum};
+
+use crate::key::Key;
+use crate::types::{self, Script};
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// Names that can be completed by running `quipu complete`
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Completable {
+    /// Checkpoint names in a script
+    Checkpoints,
+    /// Key names, as written in `<...>`
+    Keys,
+}
+
+// Options whose values are names from the script, with what goes before each
+const CHECKPOINT_OPTIONS: &[(&str, &str)] = &[
+    ("resume-from", ""),
+    ("skip", "markers:"),
+    ("only", "markers:"),
+];
+
+/// The completion script for `shell`
+pub fn generate(command: &mut Command, shell: Shell) -> String {
+    let name = command.get_name().to_string();
+    let generator = match shell {
+        Shell::Bash => clap_complete::Shell::Bash,
+        Shell::Zsh => clap_complete::Shell::Zsh,
+        Shell::Fish => clap_complete::Shell::Fish,
+    };
+    let mut script = Vec::new();
+    clap_complete::generate(generator, command, &name, &mut script);
+    let script = String::from_utf8_lossy(&script);
+    match shell {
+        Shell::Bash => bash(&script, &name),
+        Shell::Zsh => zsh(&script, &name),
+        Shell::Fish => fish(&script, &name),
+    }
+}
+
+/// Names to offer for `what`, one per line
+pub fn candidates(what: Completable, script: Option<&Script>) -> Vec<String> {
+    match what {
+        Completable::Checkpoints => {
+            let mut names: Vec<String> = Vec::new();
+            // Blocks can't hold them, so they're all at the top
+            for command in script.map_or(&[][..], |script| &script.commands) {
+                if let types::Command::Checkpoint(name) = command
+                    && !names.contains(name)
+                {
+                    names.push(name.clone());
+                }
+            }
+            names
+        }
+        Completable::Keys => Key::names(),
+    }
+}
+
+// The checkpoint option, with its prefix, that a generated `line` is about
+fn checkpoint_option(line: &str, spelling: impl Fn(&str) -> String) -> Option<&'static str> {
+    CHECKPOINT_OPTIONS
+        .iter()
+        .find(|(long, _)| line.contains(&spelling(long)))
+        .map(|(_, prefix)| *prefix)
+}
+
+// Checkpoint options are answered first, and everything else is passed on to
+// the generated function, which is registered in its place
+fn bash(script: &str, name: &str) -> String {
+    let mut out = script.to_string();
+    out.push_str(&format!("\n_{}_checkpoints() {{\n", name));
+    out.push_str("    local cur=${COMP_WORDS[COMP_CWORD]} prev=${COMP_WORDS[COMP_CWORD-1]}\n");
+    out.push_str("    local prefix script word\n");
+    out.push_str("    case \"$prev\" in\n");
+    for (long, prefix) in CHECKPOINT_OPTIONS {
+        out.push_str(&format!("        --{}) prefix={} ;;\n", long, prefix));
+    }
+    out.push_str(&format!("        *) _{} \"$@\"; return ;;\n", name));
+    out.push_str("    esac\n");
+    out.push_str("    for word in \"${COMP_WORDS[@]:1:COMP_CWORD-1}\"; do\n");
+    out.push_str("        [[ -f $word ]] && script=$word\n");
+    out.push_str("    done\n");
+    out.push_str(&format!(
+        "    COMPREPLY=($(compgen -W \"$({} complete checkpoints \"$script\" 2>/dev/null | sed \"s/^/$prefix/\")\" -- \"$cur\"))\n}}\n\n",
+        name
+    ));
+    out.push_str(&format!(
+        "complete -F _{}_checkpoints -o bashdefault -o default {}\n",
+        name, name
+    ));
+    out
+}
+
+// The options' `_arguments` specs get a function of their own, defined before
+// the generated script runs its completion
+fn zsh(script: &str, name: &str) -> String {
+    let mut out = String::new();
+    for line in script.split_inclusive('\n') {
+        let prefix = checkpoint_option(line, |long| format!("--{}=[", long));
+        match (prefix, line.rfind("]:")) {
+            (Some(prefix), Some(end)) if line.starts_with('\'') => {
+                let action = match prefix {
+                    "" => format!("_{}_checkpoints", name),
+                    _ => format!("_{}_checkpoints {}", name, prefix.replace(':', "\\:")),
+                };
+                out.push_str(&format!("{}]:checkpoint:{}' \\\n", &line[..end], action));
+            }
+            _ => out.push_str(line),
+        }
+    }
+
+    let function = format!(
+        "_{name}_checkpoints() {{\n    local script word\n    for word in ${{words[2,CURRENT-1]}}; do\n        [[ -f $word ]] && script=$word\n    done\n    compadd -P \"$1\" -- ${{(f)\"$({name} complete checkpoints \"$script\" 2>/dev/null)\"}}\n}}\n\n"
+    );
+    let run = format!("if [ \"$funcstack[1]\" = \"_{}\" ]", name);
+    match out.find(&run) {
+        Some(at) => out.insert_str(at, &function),
+        None => out.push_str(&function),
+    }
+    out
+}
+
+// Quoted for fish, which only escapes backslashes and quotes inside ''
+fn fish_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+// The options' `complete` lines offer the names instead of files
+fn fish(script: &str, name: &str) -> String {
+    let mut out = format!(
+        "function __{name}_script\n    set -l script\n    for word in (commandline -opc)[2..-1]\n        test -f \"$word\"; and set script $word\n    end\n    echo $script\nend\n\n"
+    );
+    for line in script.lines() {
+        out.push_str(line);
+        if line.starts_with("complete ")
+            && let Some(prefix) = checkpoint_option(line, |long| format!(" -l {} ", long))
+        {
+            let prefixed = if prefix.is_empty() {
+                String::new()
+            } else {
+                format!(" | string replace -r '^' '{}'", prefix)
+            };
+            out.push_str(&format!(
+                " -x -a {}",
+                fish_quote(&format!(
+                    "({} complete checkpoints (__{}_script) 2>/dev/null{})",
+                    name, name, prefixed
+                ))
+            ));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{ParseOptions, parse_script};
+    use clap::Arg;
+
+    fn command() -> Command {
+        Command::new("quipu")
+            .arg(Arg::new("script"))
+            .arg(
+                Arg::new("resume-from")
+                    .long("resume-from")
+                    .help("Start from a checkpoint"),
+            )
+            .subcommand(
+                Command::new("convert")
+                    .about("Convert a script")
+                    .arg(Arg::new("to").long("to").value_parser(["json", "yaml"])),
+            )
+    }
+
+    #[test]
+    fn test_bash_completions() {
+        let script = generate(&mut command(), Shell::Bash);
+        assert!(script.starts_with("_quipu() {\n"));
+        assert!(script.contains("        --resume-from) prefix= ;;\n"));
+        assert!(script.contains("        *) _quipu \"$@\"; return ;;\n"));
+        assert!(script.contains("$(quipu complete checkpoints \"$script\" 2>/dev/null"));
+        assert!(
+            script.ends_with("complete -F _quipu_checkpoints -o bashdefault -o default quipu\n")
+        );
+    }
+
+    #[test]
+    fn test_zsh_completions() {
+        let script = generate(&mut command(), Shell::Zsh);
+        assert!(script.contains(
+            "'--resume-from=[Start from a checkpoint]:checkpoint:_quipu_checkpoints' \\\n"
+        ));
+        // Defined before the script completes anything
+        let function = script.find("_quipu_checkpoints() {\n").unwrap();
+        let run = script
+            .find("if [ \"$funcstack[1]\" = \"_quipu\" ]")
+            .unwrap();
+        assert!(function < run);
+    }
+
+    #[test]
+    fn test_fish_completions() {
+        let script = generate(&mut command(), Shell::Fish);
+        assert!(script.starts_with("function __quipu_script\n"));
+        assert!(script.contains("-l to -r -f -a \"json\\t''"));
+        assert!(script.contains(
+            "-l resume-from -d 'Start from a checkpoint' -r -x -a '(quipu complete checkpoints (__quipu_script) 2>/dev/null)'\n"
+        ));
+    }
+
+    #[test]
+    fn test_candidates() {
+        let script = parse_script(
+            "@ checkpoint:setup\n@ checkpoint:deploy\n@ checkpoint:setup\n",
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            candidates(Completable::Checkpoints, Some(&script)),
+            ["setup", "deploy"]
+        );
+        let keys = candidates(Completable::Keys, None);
+        assert!(keys.iter().any(|key| key == "ret"));
+        assert!(keys.iter().any(|key| key == "F12"));
+        assert!(keys.iter().any(|key| key == "kp5"));
+    }
+}
@@ -0,0 +1,79 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Host conditions for `@ waitfile:`, `@ waitport:` and `@ waiturl:`
+//!
+//! Playback polls these until they hold, for scripts that start a server in
+//! the background and need it up before going on. Each check is quick, so a
+//! host that never answers can't hold up the poll beyond its timeout
+
+use std::path::Path;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::types::WaitCondition;
+use crate::webhook;
+
+// How long one connection attempt gets before it counts as not up yet
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Check that `target` is something `condition` can wait for. Targets with
+/// `${NAME}` variables in them are checked once they're expanded
+pub fn check(condition: WaitCondition, target: &str) -> Result<(), String> {
+    match condition {
+        _ if target.is_empty() => Err(format!("{} needs something to wait for", condition.name())),
+        _ if target.contains("${") => Ok(()),
+        WaitCondition::File => Ok(()),
+        WaitCondition::Port => match target.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+            _ => Err(format!("Not a host:port to wait for: '{}'", target)),
+        },
+        WaitCondition::Url => webhook::check_url(target),
+    }
+}
+
+/// Whether `condition` holds for `target` right now
+pub async fn is_met(condition: WaitCondition, target: &str) -> bool {
+    match condition {
+        WaitCondition::File => Path::new(target).exists(),
+        WaitCondition::Port => matches!(
+            timeout(ATTEMPT_TIMEOUT, TcpStream::connect(target)).await,
+            Ok(Ok(_))
+        ),
+        WaitCondition::Url => matches!(
+            timeout(ATTEMPT_TIMEOUT, webhook::get(target)).await,
+            Ok(Ok(()))
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        assert!(check(WaitCondition::Port, "localhost:8080").is_ok());
+        assert!(check(WaitCondition::Port, "localhost").is_err());
+        assert!(check(WaitCondition::Port, ":8080").is_err());
+        assert!(check(WaitCondition::Url, "http://localhost:3000/health").is_ok());
+        assert!(check(WaitCondition::Url, "https://example.com/").is_ok());
+        assert!(check(WaitCondition::Url, "example.com/").is_err());
+        assert!(check(WaitCondition::File, "").is_err());
+        assert!(check(WaitCondition::Port, "localhost:${PORT}").is_ok());
+        assert!(check(WaitCondition::Url, "${BASE}/health").is_ok());
+    }
+}
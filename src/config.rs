@@ -0,0 +1,491 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! User configuration and settings resolution
+//!
+//! Defaults can be set in `~/.config/quipu/config.toml` (or a file given
+//! with `--config`). Settings are resolved with the priority
+//! CLI > script > config file > built-in defaults.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::parser::ParseOptions;
+use crate::profile::ShellProfile;
+use crate::types::{Command, NoHistory, PaneLayout, PlaybackConfig, Script};
+use crate::typing::TypingProfile;
+use crate::width::AmbiguousWidth;
+
+const DEFAULT_COLS: u16 = 80;
+const DEFAULT_ROWS: u16 = 24;
+const DEFAULT_TERM: &str = "xterm-256color";
+const DEFAULT_SCROLLBACK: usize = 10_000;
+
+// Anything more than this is a measurement gone wrong rather than a slow timer
+const MAX_TIMING_OFFSET: f64 = 0.1;
+
+// One layer of settings; every field is optional so layers can be stacked
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub shell: Option<String>,
+    // Arguments for the shell, e.g. `["-l", "-i"]`
+    pub shell_args: Option<Vec<String>>,
+    // Start the shell without its startup files
+    pub no_rc: Option<bool>,
+    // A built-in shell profile, as for `@ shell-profile`
+    pub shell_profile: Option<String>,
+    // TERM and COLORTERM for the session
+    pub term: Option<String>,
+    pub colorterm: Option<String>,
+    pub cols: Option<u16>,
+    pub rows: Option<u16>,
+    // Size of the terminal in pixels, or of each cell, e.g. `cell_size = [10, 20]`
+    pub pixel_size: Option<(u16, u16)>,
+    pub cell_size: Option<(u16, u16)>,
+    // `wide` to draw East Asian ambiguous-width characters in two columns
+    pub ambiguous_width: Option<AmbiguousWidth>,
+    // Lines kept after they scroll off the top, for `@ dump-scrollback`
+    pub scrollback: Option<usize>,
+    pub speed: Option<f64>,
+    pub jitter: Option<f64>,
+    // `histfile` or `space`, as for `@ no-history`
+    pub no_history: Option<NoHistory>,
+    // How several sessions share the terminal, as for `@ layout`
+    pub layout: Option<PaneLayout>,
+    // Patterns masked in the session's output, added to those given with --redact
+    pub redact: Vec<String>,
+    // Key aliases available to every script, e.g. `save = "<esc>:w<ret>"`
+    pub aliases: HashMap<String, String>,
+    // Directives handled by plugins, e.g. `db-reset = "/usr/local/bin/quipu-db-reset"`
+    pub directives: HashMap<String, String>,
+    // Directories `@ include:` and `@ call:` look in after the script's own,
+    // once those given with --lib-path, $QUIPU_PATH and $TYPECAST_PATH have been tried
+    pub lib_path: Vec<PathBuf>,
+    // Fail on unknown keys and directives, as with --strict
+    pub strict: Option<bool>,
+    // Typing profiles for `@ profile:`, e.g. `[profiles.slow]` with `speed = 0.2`
+    pub profiles: HashMap<String, TypingProfile>,
+    // Lua interpreter for `@ lua` blocks, reading the program on stdin, e.g. `lua5.4`
+    pub lua_command: Option<String>,
+    // Theme for the HTML and PNG exports, a built-in name or a theme file
+    pub theme: Option<String>,
+    // Seconds to wake early for each key, as measured by `quipu bench --save`
+    pub timing_offset: Option<f64>,
+    // Shell command `@ say:` text is piped to, e.g. `piper -m en.onnx --output-raw | aplay -q`
+    pub say_command: Option<String>,
+}
+
+// Fully resolved settings for a session
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub shell: String,
+    pub shell_args: Vec<String>,
+    // Whose startup file, if any, is written when the session starts
+    pub shell_profile: Option<&'static ShellProfile>,
+    pub term: String,
+    // Left as inherited from the real terminal when not set
+    pub colorterm: Option<String>,
+    pub cols: u16,
+    pub rows: u16,
+    // Zero when unknown
+    pub pixel_width: u16,
+    pub pixel_height: u16,
+    pub ambiguous_width: AmbiguousWidth,
+    pub scrollback: usize,
+    pub redact: Vec<String>,
+    pub layout: PaneLayout,
+    // Initial playback settings, before any script directives
+    pub playback: PlaybackConfig,
+}
+
+impl Settings {
+    /// The shell's arguments, with `startup`'s, which point it at the profile's
+    /// startup file, after the profile's own and before the rest
+    pub fn shell_argv(&self, startup: &[String]) -> Vec<String> {
+        let (profile, rest) = self
+            .shell_args
+            .split_at(self.shell_profile.map_or(0, |profile| profile.args.len()));
+        profile.iter().chain(startup).chain(rest).cloned().collect()
+    }
+}
+
+/// `$XDG_CONFIG_HOME`, or `~/.config`
+pub fn config_home() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+}
+
+fn default_path() -> Option<PathBuf> {
+    Some(config_home()?.join("quipu").join("config.toml"))
+}
+
+impl Config {
+    /// Load `path`, or the default config file if it exists
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+        let config: Self = toml::from_str(&content)
+            .with_context(|| format!("Invalid config file: {}", path.display()))?;
+        config
+            .check()
+            .map_err(|e| anyhow::anyhow!("Invalid config file: {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    // Same limits as the matching script directives
+    fn check(&self) -> Result<(), String> {
+        if let Some(speed) = self.speed {
+            Command::SetSpeed(speed).check()?;
+        }
+        if let Some(jitter) = self.jitter {
+            Command::SetJitter(jitter).check()?;
+        }
+        if let Some(term) = &self.term {
+            Command::SetTerm(term.clone()).check()?;
+        }
+        if let Some(name) = &self.shell_profile {
+            crate::profile::check_name(name)?;
+        }
+        if let Some(offset) = self.timing_offset
+            && !(0.0..MAX_TIMING_OFFSET).contains(&offset)
+        {
+            return Err(format!(
+                "timing_offset must be at least 0 and under {}s, got {}",
+                MAX_TIMING_OFFSET, offset
+            ));
+        }
+        for name in self.directives.keys() {
+            crate::plugin::check_name(name)?;
+        }
+        for pattern in &self.redact {
+            crate::redact::check_pattern(pattern)?;
+        }
+        for (width, height) in self.pixel_size.iter().chain(&self.cell_size) {
+            Command::SetPixelSize(*width, *height).check()?;
+        }
+        Command::SetSize(self.cols.unwrap_or(1), self.rows.unwrap_or(1)).check()
+    }
+
+    /// Set `key` to `value` in the config file at `path`, or the default one,
+    /// keeping the rest of the file as it is. Returns the file written
+    pub fn save_setting(path: Option<&Path>, key: &str, value: &str) -> Result<PathBuf> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => default_path().context("Can't find the config directory")?,
+        };
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read config file: {}", path.display()));
+            }
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        std::fs::write(&path, set_setting(&content, key, value))
+            .with_context(|| format!("Failed to write config file: {}", path.display()))?;
+        Ok(path)
+    }
+
+    pub fn parse_options(&self) -> ParseOptions {
+        ParseOptions {
+            aliases: self.aliases.clone(),
+            directives: self.directives.keys().cloned().collect(),
+            script_dir: None,
+            lib_path: self.lib_path.clone(),
+            strict: self.strict.unwrap_or(false),
+            profiles: self.profiles.clone(),
+        }
+    }
+
+    fn from_script(script: &Script) -> Self {
+        let preamble = script.preamble();
+        Self {
+            shell: preamble.shell,
+            shell_args: preamble.shell_args,
+            shell_profile: preamble.shell_profile,
+            term: preamble.term,
+            cols: preamble.size.map(|(cols, _)| cols),
+            rows: preamble.size.map(|(_, rows)| rows),
+            pixel_size: preamble.pixel_size,
+            no_history: preamble.no_history,
+            layout: preamble.layout,
+            ..Self::default()
+        }
+    }
+}
+
+// `content` with a top-level `key = value` line replacing any already there.
+// New keys go before the first table, since anything after it belongs to it
+fn set_setting(content: &str, key: &str, value: &str) -> String {
+    let line = format!("{} = {}", key, value);
+    let is_key = |l: &str| {
+        l.trim_start()
+            .strip_prefix(key)
+            .is_some_and(|rest| rest.trim_start().starts_with('='))
+    };
+    let mut lines: Vec<&str> = content.lines().collect();
+    let tables = lines
+        .iter()
+        .position(|l| l.trim_start().starts_with('['))
+        .unwrap_or(lines.len());
+    match lines[..tables].iter().position(|l| is_key(l)) {
+        Some(index) => lines[index] = &line,
+        None => {
+            // After the last top-level line, rather than the blank ones before a table
+            let end = lines[..tables]
+                .iter()
+                .rposition(|l| !l.trim().is_empty())
+                .map_or(0, |last| last + 1);
+            lines.insert(end, &line);
+        }
+    }
+    lines.join("\n") + "\n"
+}
+
+/// Arguments that stop `shell` reading its startup files, for the shells that have them
+pub fn no_rc_args(shell: &str) -> Option<&'static [&'static str]> {
+    let name = Path::new(shell).file_name()?.to_str()?.to_ascii_lowercase();
+    match name.strip_suffix(".exe").unwrap_or(&name) {
+        "bash" => Some(&["--norc", "--noprofile"]),
+        "zsh" => Some(&["-f"]),
+        "fish" => Some(&["--no-config"]),
+        "pwsh" | "powershell" => Some(&["-NoProfile"]),
+        // Skips the AutoRun commands in the registry
+        "cmd" => Some(&["/D"]),
+        _ => None,
+    }
+}
+
+/// The shell used when none is set: `$SHELL` on Unix, and PowerShell on Windows
+pub fn default_shell() -> String {
+    if cfg!(windows) {
+        // SHELL is only set on Windows by Git Bash and MSYS, as a path ConPTY can't run
+        let on_path = |name: &str| {
+            std::env::var_os("PATH").is_some_and(|path| {
+                std::env::split_paths(&path).any(|dir| dir.join(name).is_file())
+            })
+        };
+        ["pwsh.exe", "powershell.exe"]
+            .into_iter()
+            .find(|name| on_path(name))
+            .map(str::to_string)
+            .or_else(|| std::env::var("COMSPEC").ok())
+            .unwrap_or_else(|| "cmd.exe".to_string())
+    } else {
+        std::env::var("SHELL").unwrap_or_else(|_| "bash".to_string())
+    }
+}
+
+fn first<T>(layers: &[&Config], field: impl Fn(&Config) -> Option<T>) -> Option<T> {
+    layers.iter().find_map(|layer| field(layer))
+}
+
+/// Resolve session settings with the priority CLI > script > config file > defaults
+pub fn resolve(cli: &Config, script: &Script, file: &Config) -> Settings {
+    let from_script = Config::from_script(script);
+    let layers = [cli, &from_script, file];
+    let defaults = PlaybackConfig::default();
+
+    // Checked when each layer was read
+    let shell_profile =
+        first(&layers, |c| c.shell_profile.clone()).and_then(|name| crate::profile::find(&name));
+    let shell = first(&layers, |c| c.shell.clone())
+        .or_else(|| shell_profile.map(|profile| profile.shell.to_string()))
+        .unwrap_or_else(default_shell);
+
+    // A profile skips the startup files itself. Its arguments, or --no-rc's,
+    // come first, as bash only takes long options before the rest
+    let mut shell_args: Vec<String> = match shell_profile {
+        Some(profile) => profile.args.iter().map(|arg| arg.to_string()).collect(),
+        None if first(&layers, |c| c.no_rc) == Some(true) => no_rc_args(&shell)
+            .unwrap_or_default()
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect(),
+        None => Vec::new(),
+    };
+    shell_args.extend(first(&layers, |c| c.shell_args.clone()).unwrap_or_default());
+
+    let cols = first(&layers, |c| c.cols).unwrap_or(DEFAULT_COLS);
+    let rows = first(&layers, |c| c.rows).unwrap_or(DEFAULT_ROWS);
+    let (pixel_width, pixel_height) = first(&layers, |c| {
+        c.pixel_size.or(c
+            .cell_size
+            .map(|(width, height)| (width.saturating_mul(cols), height.saturating_mul(rows))))
+    })
+    .unwrap_or((0, 0));
+
+    Settings {
+        shell_args,
+        shell,
+        shell_profile,
+        term: first(&layers, |c| c.term.clone()).unwrap_or_else(|| DEFAULT_TERM.to_string()),
+        colorterm: first(&layers, |c| c.colorterm.clone()),
+        cols,
+        rows,
+        pixel_width,
+        pixel_height,
+        ambiguous_width: first(&layers, |c| c.ambiguous_width).unwrap_or_default(),
+        scrollback: first(&layers, |c| c.scrollback).unwrap_or(DEFAULT_SCROLLBACK),
+        redact: layers.iter().flat_map(|c| c.redact.clone()).collect(),
+        layout: first(&layers, |c| c.layout).unwrap_or_default(),
+        playback: PlaybackConfig {
+            speed: first(&layers, |c| c.speed).unwrap_or(defaults.speed),
+            jitter: first(&layers, |c| c.jitter).unwrap_or(defaults.jitter),
+            no_history: first(&layers, |c| c.no_history),
+            ..defaults
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{ParseOptions, parse_script};
+
+    #[test]
+    fn test_resolve_precedence() {
+        let file: Config = toml::from_str(
+            "shell = \"/bin/fish\"\ncols = 120\nrows = 40\nspeed = 0.05\njitter = 0.1\nambiguous_width = \"wide\"\n",
+        )
+        .unwrap();
+        let script = parse_script(
+            "@ shell:/bin/zsh\n@ size:100:30\n$ ls<ret>\n",
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        let cli = Config {
+            shell: Some("/bin/bash".to_string()),
+            ..Config::default()
+        };
+
+        let settings = resolve(&cli, &script, &file);
+        assert_eq!(settings.shell, "/bin/bash");
+        assert_eq!((settings.cols, settings.rows), (100, 30));
+        assert_eq!(settings.playback.speed, 0.05);
+        assert_eq!(settings.playback.jitter, 0.1);
+        assert_eq!(settings.ambiguous_width, AmbiguousWidth::Wide);
+
+        let settings = resolve(&Config::default(), &script, &Config::default());
+        assert_eq!(settings.shell, "/bin/zsh");
+        assert_eq!(settings.playback.speed, PlaybackConfig::default().speed);
+        assert_eq!(settings.term, "xterm-256color");
+        assert_eq!(settings.colorterm, None);
+        assert_eq!(settings.ambiguous_width, AmbiguousWidth::Narrow);
+    }
+
+    #[test]
+    fn test_resolve_shell_args() {
+        let script = parse_script("@ shellargs:-l -i\n", &ParseOptions::default()).unwrap();
+        let settings = resolve(&Config::default(), &script, &Config::default());
+        assert_eq!(settings.shell_args, vec!["-l", "-i"]);
+
+        let cli = Config {
+            shell: Some("/usr/bin/bash".to_string()),
+            no_rc: Some(true),
+            ..Config::default()
+        };
+        let settings = resolve(&cli, &script, &Config::default());
+        assert_eq!(
+            settings.shell_args,
+            vec!["--norc", "--noprofile", "-l", "-i"]
+        );
+
+        // A profile's own arguments replace --no-rc's and come before the script's
+        let script = parse_script(
+            "@ shell-profile:bash-clean\n@ shellargs:-i\n",
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        let settings = resolve(&cli, &script, &Config::default());
+        assert_eq!(settings.shell, "/usr/bin/bash");
+        assert_eq!(settings.shell_args, vec!["--noprofile", "-i"]);
+        let startup = ["--rcfile".to_string(), "/tmp/bashrc".to_string()];
+        assert_eq!(
+            settings.shell_argv(&startup),
+            vec!["--noprofile", "--rcfile", "/tmp/bashrc", "-i"]
+        );
+        let settings = resolve(&Config::default(), &script, &Config::default());
+        assert_eq!(settings.shell, "bash");
+    }
+
+    #[test]
+    fn test_no_rc_args() {
+        assert_eq!(no_rc_args("/bin/zsh"), Some(&["-f"][..]));
+        assert_eq!(no_rc_args("pwsh.exe"), Some(&["-NoProfile"][..]));
+        assert_eq!(no_rc_args("PowerShell.EXE"), Some(&["-NoProfile"][..]));
+        assert_eq!(no_rc_args("cmd.exe"), Some(&["/D"][..]));
+        assert_eq!(no_rc_args("/bin/dash"), None);
+    }
+
+    #[test]
+    fn test_resolve_pixel_size() {
+        let file: Config = toml::from_str("cols = 100\nrows = 30\ncell_size = [10, 20]\n").unwrap();
+        let script = parse_script("$ ls<ret>\n", &ParseOptions::default()).unwrap();
+        let settings = resolve(&Config::default(), &script, &file);
+        assert_eq!((settings.pixel_width, settings.pixel_height), (1000, 600));
+
+        let script = parse_script("@ pixelsize:800x480\n", &ParseOptions::default()).unwrap();
+        let settings = resolve(&Config::default(), &script, &file);
+        assert_eq!((settings.pixel_width, settings.pixel_height), (800, 480));
+    }
+
+    #[test]
+    fn test_config_rejects_unknown_keys() {
+        assert!(toml::from_str::<Config>("sped = 0.1\n").is_err());
+    }
+
+    #[test]
+    fn test_set_setting() {
+        assert_eq!(
+            set_setting("", "timing_offset", "0.001"),
+            "timing_offset = 0.001\n"
+        );
+        // Replaced in place, and added above tables when it's new
+        assert_eq!(
+            set_setting(
+                "# Mine\ntiming_offset=0.002\nspeed = 0.05\n",
+                "timing_offset",
+                "0.001"
+            ),
+            "# Mine\ntiming_offset = 0.001\nspeed = 0.05\n"
+        );
+        assert_eq!(
+            set_setting(
+                "speed = 0.05\n\n[aliases]\nsave = \"<esc>:w<ret>\"\n",
+                "timing_offset",
+                "0.001"
+            ),
+            "speed = 0.05\ntiming_offset = 0.001\n\n[aliases]\nsave = \"<esc>:w<ret>\"\n"
+        );
+    }
+}
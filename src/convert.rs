@@ -0,0 +1,197 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Conversion between script representations
+//!
+//! Scripts can be read and written as the quipu text format, JSON, YAML,
+//! VHS tapes or literate Markdown
+
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+use crate::format::format_script;
+use crate::markdown::{format_literate, literate_source, parse_literate};
+use crate::parser::{ParseOptions, included_files, parse_script};
+use crate::tape::{format_tape, parse_tape};
+use crate::types::Script;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ScriptFormat {
+    Qp,
+    Json,
+    Yaml,
+    Tape,
+    Md,
+}
+
+impl ScriptFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "qp" => Some(Self::Qp),
+            "json" => Some(Self::Json),
+            "yaml" | "yml" => Some(Self::Yaml),
+            "tape" => Some(Self::Tape),
+            "md" | "markdown" => Some(Self::Md),
+            _ => None,
+        }
+    }
+}
+
+pub fn read_script(input: &str, format: ScriptFormat, options: &ParseOptions) -> Result<Script> {
+    let script =
+        match format {
+            ScriptFormat::Qp => parse_script(input, options)
+                .map_err(|e| Error::Script(format!("Parse error: {}", e))),
+            ScriptFormat::Json => serde_json::from_str(input)
+                .map_err(|e| Error::Script(format!("Invalid JSON script: {}", e))),
+            // Commands are single-key maps, matching the JSON shape rather than YAML tags
+            ScriptFormat::Yaml => serde_yaml::with::singleton_map_recursive::deserialize(
+                serde_yaml::Deserializer::from_str(input),
+            )
+            .map_err(|e| Error::Script(format!("Invalid YAML script: {}", e))),
+            ScriptFormat::Tape => parse_tape(input).map_err(|e| Error::Script(format!("{:#}", e))),
+            ScriptFormat::Md => parse_literate(input, options)
+                .map_err(|e| Error::Script(format!("Parse error: {}", e))),
+        }?;
+
+    // The text parser checks as it goes, but the other formats can hold anything
+    script
+        .check()
+        .map_err(|e| Error::Script(format!("Invalid script: {}", e)))?;
+    Ok(script)
+}
+
+pub fn write_script(script: &Script, format: ScriptFormat) -> Result<String> {
+    match format {
+        ScriptFormat::Qp => Ok(format_script(script)),
+        ScriptFormat::Json => {
+            let mut json = serde_json::to_string_pretty(script)?;
+            json.push('\n');
+            Ok(json)
+        }
+        ScriptFormat::Yaml => {
+            let mut yaml = Vec::new();
+            let mut serializer = serde_yaml::Serializer::new(&mut yaml);
+            serde_yaml::with::singleton_map_recursive::serialize(script, &mut serializer)?;
+            Ok(String::from_utf8(yaml)?)
+        }
+        ScriptFormat::Tape => format_tape(script),
+        ScriptFormat::Md => Ok(format_literate(script)),
+    }
+}
+
+pub fn convert_file(
+    input: &Path,
+    output: Option<&PathBuf>,
+    from: Option<ScriptFormat>,
+    to: Option<ScriptFormat>,
+    options: &ParseOptions,
+) -> Result<()> {
+    let Some(from) = from.or_else(|| ScriptFormat::from_path(input)) else {
+        bail!(
+            "Cannot determine format of {}, pass --from",
+            input.display()
+        );
+    };
+    let Some(to) = to.or_else(|| output.and_then(|p| ScriptFormat::from_path(p))) else {
+        bail!("Cannot determine output format, pass --to");
+    };
+
+    let content = std::fs::read_to_string(input)
+        .with_context(|| format!("Failed to read script file: {}", input.display()))?;
+    let script = read_script(&content, from, &options.for_script(input))?;
+    write_output(&script, output, to)
+}
+
+/// Read a script file, using its extension to pick the format
+pub fn load_script(path: &Path, options: &ParseOptions) -> Result<Script> {
+    let format = ScriptFormat::from_path(path).unwrap_or(ScriptFormat::Qp);
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read script file: {}", path.display()))?;
+    read_script(&content, format, &options.for_script(path))
+}
+
+/// The files the script file at `path` includes, which only the text formats can
+pub fn load_included(path: &Path, options: &ParseOptions) -> Result<Vec<PathBuf>> {
+    let format = ScriptFormat::from_path(path).unwrap_or(ScriptFormat::Qp);
+    let mut content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read script file: {}", path.display()))?;
+    match format {
+        ScriptFormat::Qp => {}
+        ScriptFormat::Md => {
+            content = literate_source(&content)
+                .map_err(|e| Error::Script(format!("Parse error: {}", e)))?;
+        }
+        _ => return Ok(Vec::new()),
+    }
+    included_files(&content, &options.for_script(path))
+        .map_err(|e| Error::Script(format!("Parse error: {}", e)).into())
+}
+
+/// Write a script to `output` (or stdout) in the given format
+pub fn write_output(script: &Script, output: Option<&PathBuf>, format: ScriptFormat) -> Result<()> {
+    let converted = write_script(script, format)?;
+
+    match output {
+        Some(path) => std::fs::write(path, converted)
+            .with_context(|| format!("Failed to write {}", path.display()))?,
+        None => print!("{}", converted),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_yaml_round_trip() {
+        let script = parse_script(
+            "@ speed:0.05\n@ wait:0.5\n$ ls<ret>\n",
+            &ParseOptions::default(),
+        )
+        .unwrap();
+
+        for format in [ScriptFormat::Json, ScriptFormat::Yaml] {
+            let written = write_script(&script, format).unwrap();
+            let read = read_script(&written, format, &ParseOptions::default()).unwrap();
+            assert_eq!(script.commands, read.commands);
+        }
+    }
+
+    #[test]
+    fn test_json_shape() {
+        let script = parse_script(
+            "@ size:100:30\n@ wait:1.5\n$ ls<ret>\n",
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&write_script(&script, ScriptFormat::Json).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "commands": [
+                    {"set_size": [100, 30]},
+                    {"wait": 1.5},
+                    {"type": "ls<ret>"},
+                ]
+            })
+        );
+    }
+}
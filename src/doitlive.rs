@@ -0,0 +1,135 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! doitlive session import
+//!
+//! Turns doitlive shell sessions into scripts. Each command line is typed
+//! and submitted, `#doitlive` directives become settings where quipu has
+//! an equivalent, and ```` ```python ```` blocks are typed into `python3`
+
+use std::time::Duration;
+
+use crate::types::{Command, PlaybackConfig, Script};
+
+fn push_line(commands: &mut Vec<Command>, line: &str, pause: Duration) {
+    commands.push(Command::Type(format!("{}\r", line).into()));
+    if !pause.is_zero() {
+        commands.push(Command::Wait(pause));
+    }
+}
+
+/// Build a script from a doitlive session, pausing `pause` after each command
+pub fn import_doitlive(input: &str, pause: Duration) -> Script {
+    let mut commands = Vec::new();
+    let mut comment_echo = false;
+    let mut in_python = false;
+
+    for (line_num, line) in input.lines().enumerate() {
+        let trimmed = line.trim();
+
+        if in_python {
+            if trimmed == "```" {
+                in_python = false;
+                commands.push(Command::Type("\x04".into()));
+            } else {
+                push_line(&mut commands, line, Duration::ZERO);
+            }
+            continue;
+        }
+
+        if trimmed.is_empty() {
+            continue;
+        }
+
+        if trimmed.starts_with("```python") || trimmed.starts_with("```ipython") {
+            in_python = true;
+            push_line(&mut commands, "python3", pause);
+            continue;
+        }
+
+        if let Some(directive) = trimmed.strip_prefix("#doitlive") {
+            let (name, value) = directive.split_once(':').unwrap_or((directive, ""));
+            let value = value.trim();
+            match name.trim() {
+                "shell" => commands.push(Command::SetShell(value.to_string())),
+                // doitlive speed is characters per keypress
+                "speed" => match value.parse::<f64>() {
+                    Ok(speed) if speed > 0.0 => {
+                        let base = PlaybackConfig::default().speed;
+                        commands.push(Command::SetSpeed(base / speed));
+                    }
+                    _ => tracing::warn!("Line {}: Invalid speed: {}", line_num + 1, value),
+                },
+                "commentecho" => comment_echo = value == "true",
+                other => tracing::warn!(
+                    "Line {}: Skipping unsupported doitlive directive: {}",
+                    line_num + 1,
+                    other
+                ),
+            }
+            continue;
+        }
+
+        if trimmed.starts_with('#') {
+            // Echoed comments are shown by typing them as shell comments
+            if comment_echo {
+                push_line(&mut commands, trimmed, pause);
+            }
+            continue;
+        }
+
+        push_line(&mut commands, line, pause);
+    }
+
+    Script { commands }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_import_doitlive() {
+        let session = r#"#doitlive shell: /bin/zsh
+#doitlive speed: 2
+#doitlive env: SECRET=1
+
+# Hidden comment
+echo "hello"
+#doitlive commentecho: true
+# Shown comment
+```python
+print(1)
+```
+"#;
+        let script = import_doitlive(session, Duration::from_secs(1));
+        let pause = Command::Wait(Duration::from_secs(1));
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetShell("/bin/zsh".to_string()),
+                Command::SetSpeed(0.05),
+                Command::Type("echo \"hello\"\r".into()),
+                pause.clone(),
+                Command::Type("# Shown comment\r".into()),
+                pause.clone(),
+                Command::Type("python3\r".into()),
+                pause,
+                Command::Type("print(1)\r".into()),
+                Command::Type("\x04".into()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,95 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Errors the `quipu` command tells apart by its exit status
+//!
+//! Most errors are plain `anyhow` errors and exit with 1. These are the ones
+//! a CI job may want to treat differently, such as a broken script rather
+//! than a broken program under demo
+
+/// Playback was stopped with Ctrl-C
+pub const EXIT_INTERRUPTED: u8 = 130;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The script doesn't parse or isn't valid, exiting with 2
+    #[error("{0}")]
+    Script(String),
+    /// The session's PTY or shell couldn't be started, exiting with 3
+    #[error("Failed to create PTY")]
+    Pty,
+    /// An `@ expect-screen` or `@ expect-cell` found the screen never matched,
+    /// exiting with 4
+    #[error("{0}")]
+    Assertion(String),
+    /// An `@ expect`, `@ waitprompt` or host wait timed out, exiting with 5
+    #[error("{0}")]
+    Expect(String),
+    /// A command took longer than `@ timeout` allows, exiting with 6
+    #[error("'{}' took longer than @ timeout ({}s)", .1, .0.as_secs_f64())]
+    CommandTimeout(std::time::Duration, String),
+    /// Playback ran past `--max-duration`, exiting with 124 as `timeout` does
+    #[error("Playback took longer than --max-duration ({}s)", .0.as_secs_f64())]
+    Timeout(std::time::Duration),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Script(_) => 2,
+            Error::Pty => 3,
+            Error::Assertion(_) => 4,
+            Error::Expect(_) => 5,
+            Error::CommandTimeout(_, _) => 6,
+            Error::Timeout(_) => 124,
+        }
+    }
+}
+
+/// The exit status for `error`, from the first [`Error`] it was caused by
+pub fn exit_code(error: &anyhow::Error) -> u8 {
+    error.downcast_ref::<Error>().map_or(1, Error::exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_exit_code() {
+        let parse: anyhow::Result<()> = Err(Error::Script("Parse error".to_string()).into());
+        assert_eq!(exit_code(&parse.context("Failed to load").unwrap_err()), 2);
+
+        let pty: anyhow::Result<()> = Err(anyhow::anyhow!("No such file"));
+        let pty = pty.context(Error::Pty).context("Failed to start");
+        assert_eq!(exit_code(&pty.unwrap_err()), 3);
+
+        let assertion = anyhow::Error::from(Error::Assertion("Timed out".to_string()));
+        assert_eq!(exit_code(&assertion), 4);
+
+        let expect = anyhow::Error::from(Error::Expect("Timed out".to_string()));
+        assert_eq!(exit_code(&expect), 5);
+
+        let command =
+            Error::CommandTimeout(std::time::Duration::from_secs(30), "$ make".to_string());
+        assert_eq!(exit_code(&anyhow::Error::from(command)), 6);
+
+        let timeout = anyhow::Error::from(Error::Timeout(std::time::Duration::from_secs(60)));
+        assert_eq!(exit_code(&timeout), 124);
+
+        assert_eq!(exit_code(&anyhow::anyhow!("Failed to write recording")), 1);
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Events from playback, for programs embedding the engine
+//!
+//! [`PlaybackEngine::events`](crate::playback::PlaybackEngine::events) hands
+//! out an [`EventStream`] that receives each event as it happens, as an
+//! async [`Stream`]. Commands
+//! fast-forwarded with `--resume-from` or `--section` aren't reported, since
+//! nothing is seen of them. [`PlaybackHooks`] are called in line instead,
+//! for callers that want to act on each command or key as it happens
+
+use futures_core::Stream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+use crate::types::Command;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlaybackEvent {
+    /// Command `index` of the script is starting, `elapsed` after playback did
+    CommandStarted {
+        index: usize,
+        command: Command,
+        elapsed: Duration,
+    },
+    /// A key was sent, as the script wrote it before it was encoded for the terminal
+    KeystrokeSent {
+        keystroke: String,
+    },
+    /// `@ wait` or a random wait is pausing for `duration`
+    WaitStarted {
+        duration: Duration,
+    },
+    /// An expect found what it looked for, `elapsed` after it started looking:
+    /// `pattern` is its regex, or the text of `@ expect-cell`, and is empty
+    /// for `@ expect-clipboard`, which has neither
+    ExpectMatched {
+        pattern: String,
+        elapsed: Duration,
+    },
+    /// `unread` bytes of input have sat unread by the session for `duration`,
+    /// as when the program in it is stopped or busy
+    InputStalled {
+        unread: usize,
+        duration: Duration,
+    },
+    Finished {
+        elapsed: Duration,
+    },
+}
+
+/// Callbacks registered with
+/// [`PlaybackEngine::add_hooks`](crate::playback::PlaybackEngine::add_hooks),
+/// called as playback reaches each point; playback waits for them to return
+pub trait PlaybackHooks: Send {
+    fn before_command(&mut self, _index: usize, _command: &Command) {}
+
+    /// Command `index` has finished, having taken `duration`
+    fn after_command(&mut self, _index: usize, _command: &Command, _duration: Duration) {}
+
+    /// A key has been sent, as `bytes` once encoded for the terminal
+    fn on_keystroke(&mut self, _keystroke: &str, _bytes: &[u8]) {}
+}
+
+/// Events from one engine, ending when the engine is dropped
+pub struct EventStream {
+    receiver: UnboundedReceiver<PlaybackEvent>,
+}
+
+impl EventStream {
+    /// The next event, or `None` once there will be no more
+    pub async fn next(&mut self) -> Option<PlaybackEvent> {
+        self.receiver.recv().await
+    }
+
+    /// The next event if one has already happened
+    pub fn try_next(&mut self) -> Option<PlaybackEvent> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+impl Stream for EventStream {
+    type Item = PlaybackEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// The engine's side, sending to every stream handed out
+#[derive(Default)]
+pub struct Events {
+    senders: Vec<UnboundedSender<PlaybackEvent>>,
+}
+
+impl Events {
+    pub fn subscribe(&mut self) -> EventStream {
+        let (sender, receiver) = unbounded_channel();
+        self.senders.push(sender);
+        EventStream { receiver }
+    }
+
+    pub fn send(&mut self, event: PlaybackEvent) {
+        // Streams that have been dropped are forgotten
+        self.senders
+            .retain(|sender| sender.send(event.clone()).is_ok());
+    }
+}
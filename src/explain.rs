@@ -0,0 +1,112 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! `quipu explain`, for seeing what a `$` line sends
+//!
+//! Each key and run of text on the line is shown with the bytes it becomes,
+//! in caret notation and in hex, followed by how quipu reads those bytes
+//! back, as it does when writing out a recorded or imported session. Keys
+//! are shown as scripts store them, before any mode the terminal is in,
+//! such as application cursor keys, changes them
+
+use crate::format::format_type_content;
+use crate::key::Keys;
+use crate::parser::{ParseOptions, line_keys};
+
+/// `text` with control characters in caret notation, as in `^[` for ESC
+pub fn caret(text: &str) -> String {
+    let mut out = String::new();
+    for c in text.chars() {
+        match c {
+            '\x00'..='\x1f' => {
+                out.push('^');
+                out.push((c as u8 + 0x40) as char);
+            }
+            '\x7f' => out.push_str("^?"),
+            '^' => out.push_str("\\^"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn hex(text: &str) -> String {
+    let bytes: Vec<String> = text.bytes().map(|b| format!("{:02x}", b)).collect();
+    bytes.join(" ")
+}
+
+/// A table of the keys on `line` and the bytes each is sent as
+pub fn explain(line: &str, options: &ParseOptions) -> Result<String, String> {
+    let keys = line_keys(line, options)?;
+    let rows: Vec<(String, String, String)> = keys
+        .iter()
+        .map(|(written, typed)| {
+            // Quoted so spaces on their own can be seen
+            let written = match written.trim().is_empty() {
+                true => format!("\"{}\"", written),
+                false => written.to_string(),
+            };
+            let text = typed.legacy();
+            (written, caret(&text), hex(&text))
+        })
+        .collect();
+    let written_width = rows
+        .iter()
+        .map(|(written, _, _)| written.chars().count())
+        .max()
+        .unwrap_or(0);
+    let caret_width = rows
+        .iter()
+        .map(|(_, caret, _)| caret.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    let mut out = String::new();
+    for (written, caret, hex) in &rows {
+        out.push_str(&format!(
+            "{:<w$}  {:<c$}  {}\n",
+            written,
+            caret,
+            hex,
+            w = written_width,
+            c = caret_width
+        ));
+    }
+    let mut sent = Keys::new();
+    for (_, typed) in keys {
+        sent.append(typed);
+    }
+    out.push_str(&format!("\nRead back as: {}\n", format_type_content(&sent)));
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_explain() {
+        assert_eq!(caret("\x1b[A\r\x7f^"), "^[[A^M^?\\^");
+        assert_eq!(
+            explain("$ <C-S-up>foo<F5>", &ParseOptions::default()).unwrap(),
+            "<C-S-up>  ^[[1;6A  1b 5b 31 3b 36 41\n\
+             foo       foo      66 6f 6f\n\
+             <F5>      ^[[15~   1b 5b 31 35 7e\n\
+             \n\
+             Read back as: <C-S-up>foo<F5>\n"
+        );
+        assert!(explain("@ wait:1", &ParseOptions::default()).is_err());
+    }
+}
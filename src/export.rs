@@ -0,0 +1,478 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Export scripts as standalone programs
+//!
+//! Generates `expect` or POSIX shell scripts that replay the same keystrokes
+//! with the same timing, for environments where quipu itself can't be installed
+
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+
+use crate::config::Settings;
+use crate::format::format_command;
+use crate::playback::{keystroke_bytes, typed_keystrokes};
+use crate::screen::KeyModes;
+use crate::types::{Command, Script, text_encoding};
+
+// Tcl double-quoted string; \u escapes avoid Tcl's greedy \x parsing
+fn tcl_quote(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '\\' | '"' | '$' | '[' | ']' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Single-quoted shell string
+fn sh_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+// Argument for `printf '%b'`, with control characters and non-UTF-8 bytes as octal escapes
+fn printf_escape(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                c if c.is_ascii_control() => out.push_str(&format!("\\0{:03o}", c as u32)),
+                c => out.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            out.push_str(&format!("\\0{:03o}", byte));
+        }
+    }
+    sh_quote(&out)
+}
+
+pub fn export_expect(script: &Script, settings: &Settings) -> String {
+    let config = &settings.playback;
+    let (shell, cols, rows) = (&settings.shell, settings.cols, settings.rows);
+
+    let mut out = format!(
+        r#"#!/usr/bin/expect -f
+# Generated by quipu
+
+set timeout -1
+set speed {speed}
+set jitter {jitter}
+
+# Echo whatever the shell has written so far
+proc drain {{}} {{
+    expect -timeout 0 -re {{.+}} {{ exp_continue }} eof {{ exit }}
+}}
+
+# Sleep while still showing output
+proc pause {{secs}} {{
+    set until [expr {{[clock milliseconds] + int($secs * 1000)}}]
+    while {{[clock milliseconds] < $until}} {{
+        drain
+        after 10
+    }}
+    drain
+}}
+
+proc type {{keys}} {{
+    global speed jitter
+    foreach key $keys {{
+        send -- $key
+        pause [expr {{max(0.0, $speed * (1.0 + $jitter * (2.0 * rand() - 1.0)))}}]
+    }}
+}}
+
+set stty_init "rows {rows} columns {cols}"
+set env(TERM) {term}
+spawn -noecho {shell}
+"#,
+        speed = config.speed,
+        jitter = config.jitter,
+        rows = rows,
+        cols = cols,
+        term = tcl_quote(&settings.term),
+        shell = std::iter::once(shell)
+            .chain(&settings.shell_args)
+            .map(|arg| tcl_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" "),
+    );
+
+    if let Some(profile) = settings.shell_profile.filter(|p| p.has_startup_file()) {
+        out.push_str(&format!(
+            "# The {} startup file is not written by the expect export\n",
+            profile.name
+        ));
+    }
+    if script.has_choices() {
+        out.push_str("# Only the first branch of each @ choose block is exported\n");
+    }
+    let script = script.resolve_choices(&mut |_| 0);
+    if script.has_retries() {
+        out.push_str("# @ retry blocks are exported to run once\n");
+    }
+    let script = script.without_retries();
+
+    // Without a terminal to follow, cursor keys use their normal mode unless overridden
+    let mut typing = config.clone();
+    for command in &script.commands {
+        match command {
+            Command::SetSpeed(speed) => out.push_str(&format!("set speed {}\n", speed)),
+            Command::SetRamp(start, end) => out.push_str(&format!(
+                "# ramp:{}..{} is typed at its average speed\nset speed {}\n",
+                start,
+                end,
+                (start + end) / 2.0
+            )),
+            Command::SetJitter(jitter) => out.push_str(&format!("set jitter {}\n", jitter)),
+            Command::SetWaitJitter(jitter) => typing.wait_jitter = *jitter,
+            Command::SetWordPause(pause) => out.push_str(&format!(
+                "# word-pause:{} is not reproduced by the expect export\n",
+                pause
+            )),
+            Command::Wait(duration) if typing.wait_jitter > 0.0 => out.push_str(&format!(
+                "pause [expr {{{} * (1.0 + {} * (2.0 * rand() - 1.0))}}]\n",
+                duration.as_secs_f64(),
+                typing.wait_jitter
+            )),
+            Command::Wait(duration) => out.push_str(&format!("pause {}\n", duration.as_secs_f64())),
+            Command::WaitRandom(min, max) => out.push_str(&format!(
+                "pause [expr {{{} + rand() * {}}}]\n",
+                min.as_secs_f64(),
+                (*max - *min).as_secs_f64()
+            )),
+            // Applied before spawning
+            Command::SetShell(_)
+            | Command::SetShellArgs(_)
+            | Command::SetShellProfile(_)
+            | Command::SetTerm(_)
+            | Command::SetSize(_, _)
+            | Command::SetPixelSize(_, _)
+            | Command::SetLayout(_) => {}
+            Command::Resize(cols, rows) => out.push_str(&format!(
+                "stty rows {} columns {} < $spawn_out(slave,name)\n",
+                rows, cols
+            )),
+            Command::Checkpoint(name) => out.push_str(&format!("# checkpoint:{}\n", name)),
+            Command::Caption(text) => out.push_str(&format!("# caption:{}\n", text)),
+            Command::Note(text) => out.push_str(&format!("# {}\n", text)),
+            Command::Meta(field, text) => out.push_str(&format!("# {}:{}\n", field.name(), text)),
+            Command::Requires(requirement) => out.push_str(&format!("# quipu:{}\n", requirement)),
+            Command::Exec(_, _)
+            | Command::ExecAsync(_)
+            | Command::Webhook(_)
+            | Command::DumpScrollback(_)
+            | Command::SetSound(_)
+            | Command::Audio(_)
+            | Command::Say(_, _)
+            | Command::Plugin(_, _)
+            | Command::SetExpectTimeout(_)
+            | Command::SetCommandTimeout(_)
+            | Command::Expect(_, _)
+            | Command::ExpectScreen(_)
+            | Command::ExpectCell(_, _, _)
+            | Command::ExpectClipboard(_)
+            | Command::SetPromptPattern(_)
+            | Command::WaitPrompt
+            | Command::WaitFor(_, _)
+            | Command::Session(_)
+            | Command::TypeClipboard
+            | Command::Clear(_)
+            | Command::Reset => out.push_str(&format!(
+                "# {} is not run by the expect export\n",
+                format_command(command).trim_start_matches("@ ")
+            )),
+            Command::Setup(_) => out.push_str("# setup is not run by the expect export\n"),
+            Command::Lua(_) => out.push_str("# lua is not run by the expect export\n"),
+            Command::Teardown(_) => out.push_str("# teardown is not run by the expect export\n"),
+            Command::OnError(_) => out.push_str("# on-error is not run by the expect export\n"),
+            Command::Finally(_) => out.push_str("# finally is not run by the expect export\n"),
+            // Resolved above
+            Command::Choose(_) | Command::Retry(_, _, _) => {}
+            Command::SetCursorKeys(mode) => typing.cursor_keys = *mode,
+            Command::SetIme(on) => typing.ime = *on,
+            Command::SetNoHistory(mode) => out.push_str(&format!(
+                "# no-history:{} is not applied by the expect export\n",
+                mode.name()
+            )),
+            Command::SetNewline(newline) => typing.newline = *newline,
+            Command::SetMultiline(mode) => typing.multiline = *mode,
+            // Tcl strings are Unicode, and expect converts them on the way out
+            Command::SetEncoding(label) => out.push_str(&format!(
+                "# encoding:{} is not applied by the expect export\n",
+                label
+            )),
+            Command::Type(text) => {
+                let keys: Vec<String> = typed_keystrokes(text, &typing)
+                    .into_iter()
+                    .map(|key| {
+                        let bytes = keystroke_bytes(key, &typing, KeyModes::default());
+                        tcl_quote(&String::from_utf8_lossy(&bytes))
+                    })
+                    .collect();
+                out.push_str(&format!("type [list {}]\n", keys.join(" ")));
+            }
+        }
+    }
+
+    out.push_str("send -- \"\\u0004\"\nexpect eof\n");
+    out
+}
+
+pub fn export_sh(script: &Script, settings: &Settings) -> String {
+    let config = &settings.playback;
+    let (shell, cols, rows) = (&settings.shell, settings.cols, settings.rows);
+
+    let mut out = format!(
+        r#"#!/bin/sh
+# Generated by quipu
+
+SPEED={speed}
+
+k() {{
+    printf '%b' "$1"
+    sleep "$SPEED"
+}}
+
+# script(1) provides the PTY; util-linux and BSD take different arguments
+pty() {{
+    if script --version >/dev/null 2>&1; then
+        script -q -c "$1" /dev/null
+    else
+        script -q /dev/null sh -c "$1"
+    fi
+}}
+
+{{
+"#,
+        speed = config.speed,
+    );
+
+    if let Some(profile) = settings.shell_profile.filter(|p| p.has_startup_file()) {
+        out.push_str(&format!(
+            "    # The {} startup file is not written by the shell export\n",
+            profile.name
+        ));
+    }
+    if script.has_choices() {
+        out.push_str("    # Only the first branch of each @ choose block is exported\n");
+    }
+    let script = script.resolve_choices(&mut |_| 0);
+    if script.has_retries() {
+        out.push_str("    # @ retry blocks are exported to run once\n");
+    }
+    let script = script.without_retries();
+
+    // Without a terminal to follow, cursor keys use their normal mode unless overridden
+    let mut typing = config.clone();
+    for command in &script.commands {
+        match command {
+            Command::SetSpeed(speed) => out.push_str(&format!("    SPEED={}\n", speed)),
+            Command::SetRamp(start, end) => out.push_str(&format!(
+                "    # ramp:{}..{} is typed at its average speed\n    SPEED={}\n",
+                start,
+                end,
+                (start + end) / 2.0
+            )),
+            Command::SetJitter(jitter) => out.push_str(&format!(
+                "    # jitter:{} is not reproduced by the shell export\n",
+                jitter
+            )),
+            Command::SetWaitJitter(jitter) => out.push_str(&format!(
+                "    # waitjitter:{} is not reproduced by the shell export\n",
+                jitter
+            )),
+            Command::SetWordPause(pause) => out.push_str(&format!(
+                "    # word-pause:{} is not reproduced by the shell export\n",
+                pause
+            )),
+            Command::Wait(duration) => {
+                out.push_str(&format!("    sleep {}\n", duration.as_secs_f64()))
+            }
+            Command::WaitRandom(min, max) => out.push_str(&format!(
+                "    sleep \"$(awk 'BEGIN {{ srand(); print {} + rand() * {} }}')\"\n",
+                min.as_secs_f64(),
+                (*max - *min).as_secs_f64()
+            )),
+            // Applied when starting the PTY
+            Command::SetShell(_)
+            | Command::SetShellArgs(_)
+            | Command::SetShellProfile(_)
+            | Command::SetTerm(_)
+            | Command::SetSize(_, _)
+            | Command::SetPixelSize(_, _)
+            | Command::SetLayout(_) => {}
+            Command::Checkpoint(name) => out.push_str(&format!("    # checkpoint:{}\n", name)),
+            Command::Caption(text) => out.push_str(&format!("    # caption:{}\n", text)),
+            Command::Note(text) => out.push_str(&format!("    # {}\n", text)),
+            Command::Meta(field, text) => {
+                out.push_str(&format!("    # {}:{}\n", field.name(), text))
+            }
+            Command::Requires(requirement) => {
+                out.push_str(&format!("    # quipu:{}\n", requirement))
+            }
+            Command::Exec(_, _)
+            | Command::ExecAsync(_)
+            | Command::Webhook(_)
+            | Command::DumpScrollback(_)
+            | Command::SetSound(_)
+            | Command::Audio(_)
+            | Command::Say(_, _)
+            | Command::Plugin(_, _)
+            | Command::SetExpectTimeout(_)
+            | Command::SetCommandTimeout(_)
+            | Command::Expect(_, _)
+            | Command::ExpectScreen(_)
+            | Command::ExpectCell(_, _, _)
+            | Command::ExpectClipboard(_)
+            | Command::SetPromptPattern(_)
+            | Command::WaitPrompt
+            | Command::WaitFor(_, _)
+            | Command::Session(_)
+            | Command::TypeClipboard
+            | Command::Clear(_)
+            | Command::Reset
+            | Command::Resize(_, _) => out.push_str(&format!(
+                "    # {} is not run by the shell export\n",
+                format_command(command).trim_start_matches("@ ")
+            )),
+            Command::Setup(_) => out.push_str("    # setup is not run by the shell export\n"),
+            Command::Lua(_) => out.push_str("    # lua is not run by the shell export\n"),
+            Command::Teardown(_) => out.push_str("    # teardown is not run by the shell export\n"),
+            Command::OnError(_) => out.push_str("    # on-error is not run by the shell export\n"),
+            Command::Finally(_) => out.push_str("    # finally is not run by the shell export\n"),
+            // Resolved above
+            Command::Choose(_) | Command::Retry(_, _, _) => {}
+            Command::SetCursorKeys(mode) => typing.cursor_keys = *mode,
+            Command::SetIme(on) => typing.ime = *on,
+            Command::SetNoHistory(mode) => out.push_str(&format!(
+                "    # no-history:{} is not applied by the shell export\n",
+                mode.name()
+            )),
+            Command::SetNewline(newline) => typing.newline = *newline,
+            Command::SetMultiline(mode) => typing.multiline = *mode,
+            Command::SetEncoding(label) => {
+                if let Some(encoding) = text_encoding(label) {
+                    typing.encoding = encoding;
+                }
+            }
+            Command::Type(text) => {
+                let keys: Vec<String> = typed_keystrokes(text, &typing)
+                    .into_iter()
+                    .map(|key| {
+                        let bytes = keystroke_bytes(key, &typing, KeyModes::default());
+                        format!("k {}", printf_escape(&bytes))
+                    })
+                    .collect();
+                out.push_str(&format!("    {}\n", keys.join("; ")));
+            }
+        }
+    }
+
+    let shell = std::iter::once(shell)
+        .chain(&settings.shell_args)
+        .map(|arg| sh_quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let session = format!(
+        "stty rows {} cols {}; TERM={} exec {}",
+        rows,
+        cols,
+        sh_quote(&settings.term),
+        shell
+    );
+    out.push_str(&format!(
+        "    k '\\0004'\n}} | pty {}\n",
+        sh_quote(&session)
+    ));
+    out
+}
+
+/// Write a generated program to `output` (made executable) or stdout
+pub fn write_program(program: &str, output: Option<&PathBuf>) -> Result<()> {
+    let Some(path) = output else {
+        print!("{}", program);
+        return Ok(());
+    };
+
+    std::fs::write(path, program).with_context(|| format!("Failed to write {}", path.display()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("Failed to make {} executable", path.display()))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, resolve};
+    use crate::parser::{ParseOptions, parse_script};
+
+    #[test]
+    fn test_export_expect() {
+        let script = parse_script(
+            "@ size:100:30\n@ speed:0.05\n$ echo \"$HOME\"<up><ret>\n@ wait:2.0\n@ newline:crlf\n$ <ret>\n",
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        let cli = Config {
+            shell: Some("bash".to_string()),
+            ..Config::default()
+        };
+        let settings = resolve(&cli, &script, &Config::default());
+        let program = export_expect(&script, &settings);
+        assert!(program.contains(
+            "set stty_init \"rows 30 columns 100\"\nset env(TERM) \"xterm-256color\"\nspawn -noecho \"bash\"\n"
+        ));
+        assert!(program.contains("set speed 0.05\n"));
+        assert!(program.contains(
+            r#"type [list "e" "c" "h" "o" " " "\"" "\$" "H" "O" "M" "E" "\"" "\u001b\[A" "\r"]"#
+        ));
+        assert!(program.contains("pause 2\n"));
+        assert!(program.contains("type [list \"\\r\\u000a\"]\n"));
+    }
+
+    #[test]
+    fn test_export_sh() {
+        let script = parse_script(
+            "@ shell:/bin/zsh\n$ it's<C-c>\n@ wait:0.5\n@ encoding:latin1\n$ é\n",
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        let settings = resolve(&Config::default(), &script, &Config::default());
+        let program = export_sh(&script, &settings);
+        assert!(program.contains(r#"    k 'i'; k 't'; k ''\'''; k 's'; k '\0003'"#));
+        assert!(program.contains("    sleep 0.5\n"));
+        assert!(program.contains("    k '\\0351'\n"));
+        assert!(program.ends_with(
+            "} | pty 'stty rows 24 cols 80; TERM='\\''xterm-256color'\\'' exec '\\''/bin/zsh'\\'''\n"
+        ));
+    }
+}
@@ -0,0 +1,520 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Script writer for quipu files
+//!
+//! Turns parsed commands back into the text format, mapping escape
+//! sequences back to their `<key>` names
+
+use crate::key::{Key, KeyEvent, Keys, Modifiers, Typed};
+use crate::parser::{key_sequence, named_key_sequence};
+use crate::types::{Command, CursorKeys, Script};
+
+// Canonical spelling used when writing keys back out
+const KEY_NAMES: &[&str] = &[
+    "esc", "ret", "tab", "bs", "F1", "F2", "F3", "F4", "F5", "F6", "F7", "F8", "F9", "F10", "F11",
+    "F12", "up", "down", "right", "left", "home", "end", "pageup", "pagedown", "insert", "delete",
+    "kp0", "kp1", "kp2", "kp3", "kp4", "kp5", "kp6", "kp7", "kp8", "kp9", "kpenter", "kpstar",
+    "kpplus", "kpminus", "kpdot", "kpslash",
+];
+
+pub fn format_script(script: &Script) -> String {
+    let mut out = String::new();
+    for line in format_commands(&script.commands) {
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+// Each command formatted, with notes put back at the end of their `$` lines
+fn format_commands(commands: &[Command]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut commands = commands.iter().peekable();
+    while let Some(command) = commands.next() {
+        match (command, commands.peek()) {
+            (Command::Note(note), Some(typed @ Command::Type(_))) => {
+                lines.push(format!("{}  #! {}", format_command(typed), note));
+                commands.next();
+            }
+            _ => lines.push(format_command(command)),
+        }
+    }
+    lines
+}
+
+pub fn format_command(command: &Command) -> String {
+    match command {
+        Command::SetSpeed(speed) => format!("@ speed:{}", speed),
+        Command::SetRamp(start, end) => format!("@ ramp:{}..{}", start, end),
+        Command::SetJitter(jitter) => format!("@ jitter:{}", jitter),
+        Command::SetWaitJitter(jitter) => format!("@ waitjitter:{}", jitter),
+        Command::SetWordPause(pause) => format!("@ word-pause:{}", pause),
+        Command::Wait(duration) => format!("@ wait:{}", duration.as_secs_f64()),
+        Command::WaitRandom(min, max) => {
+            format!("@ wait-random:{}:{}", min.as_secs_f64(), max.as_secs_f64())
+        }
+        Command::SetShell(shell) => format!("@ shell:{}", shell),
+        Command::SetShellProfile(name) => format!("@ shell-profile:{}", name),
+        Command::SetShellArgs(args) => format!("@ shellargs:{}", args.join(" ")),
+        Command::SetTerm(term) => format!("@ term:{}", term),
+        Command::SetSize(cols, rows) => format!("@ size:{}:{}", cols, rows),
+        Command::SetPixelSize(width, height) => format!("@ pixelsize:{}x{}", width, height),
+        Command::Resize(cols, rows) => format!("@ resize:{}:{}", cols, rows),
+        Command::SetCursorKeys(mode) => format!("@ cursor-keys:{}", cursor_keys_name(*mode)),
+        Command::SetEncoding(label) => format!("@ encoding:{}", label),
+        Command::SetNewline(newline) => format!("@ newline:{}", newline.name()),
+        Command::SetMultiline(mode) => format!("@ multiline:{}", mode.name()),
+        Command::SetNoHistory(mode) => format!("@ no-history:{}", mode.name()),
+        Command::Checkpoint(name) => format!("@ checkpoint:{}", name),
+        Command::Session(name) => format!("@ session:{}", name),
+        Command::SetLayout(layout) => format!("@ layout:{}", layout.name()),
+        Command::Caption(text) => format!("@ caption:{}", text),
+        Command::Note(text) => format!("#! {}", text),
+        Command::Meta(field, text) => format!("@ {}:{}", field.name(), text),
+        Command::Requires(requirement) => format!("@ quipu:{}", requirement),
+        Command::Exec(command, None) => format!("@ exec:{}", command),
+        Command::Exec(command, Some(name)) => format!("@ exec:{} -> {}", command, name),
+        Command::ExecAsync(command) => format!("@ exec-async:{}", command),
+        Command::Setup(body) => format_fixture("setup", body),
+        Command::Teardown(body) => format_fixture("teardown", body),
+        Command::Lua(body) => format_fixture("lua", body),
+        Command::Webhook(url) => format!("@ webhook:{}", url),
+        Command::DumpScrollback(path) => format!("@ dump-scrollback:{}", path),
+        Command::SetSound(None) => "@ sound:off".to_string(),
+        Command::SetSound(Some(path)) => format!("@ sound:{}", path),
+        Command::Audio(path) => format!("@ audio:{}", path),
+        Command::Say(text, false) => format!("@ say:{}", text),
+        Command::Say(text, true) => format!("@ say-wait:{}", text),
+        Command::Plugin(name, args) if args.is_empty() => format!("@ {}", name),
+        Command::Plugin(name, args) => format!("@ {}:{}", name, args),
+        Command::SetExpectTimeout(duration) => {
+            format!("@ expect-timeout:{}", duration.as_secs_f64())
+        }
+        Command::SetCommandTimeout(duration) => format!("@ timeout:{}", duration.as_secs_f64()),
+        Command::Expect(pattern, names) if names.is_empty() => format!("@ expect:{}", pattern),
+        Command::Expect(pattern, names) => {
+            format!("@ expect:{} -> {}", pattern, names.join(", "))
+        }
+        Command::ExpectScreen(pattern) => format!("@ expect-screen:{}", pattern),
+        Command::ExpectCell(row, col, text) => format!("@ expect-cell:{}:{}:{}", row, col, text),
+        Command::ExpectClipboard(None) => "@ expect-clipboard".to_string(),
+        Command::ExpectClipboard(Some(name)) => format!("@ expect-clipboard -> {}", name),
+        // Quoted, as prompts tend to end in a space
+        Command::SetPromptPattern(pattern) => format!("@ prompt-pattern:'{}'", pattern),
+        Command::WaitPrompt => "@ waitprompt".to_string(),
+        Command::WaitFor(condition, target) => format!("@ {}:{}", condition.name(), target),
+        Command::Clear(false) => "@ clear".to_string(),
+        Command::Clear(true) => "@ clear:scrollback".to_string(),
+        Command::Reset => "@ reset".to_string(),
+        Command::Choose(branches) => {
+            let branches: Vec<String> = branches
+                .iter()
+                .map(|branch| format_branch(branch))
+                .collect();
+            format!("@ choose\n{}@ endchoose", branches.join("@ or\n"))
+        }
+        Command::Retry(retries, backoff, commands) => format!(
+            "@ retry:{} backoff:{}\n{}@ endretry",
+            retries,
+            backoff,
+            format_branch(commands)
+        ),
+        Command::OnError(commands) => {
+            format!("@ on-error\n{}@ endon-error", format_branch(commands))
+        }
+        Command::Finally(commands) => format!("@ finally\n{}@ endfinally", format_branch(commands)),
+        Command::SetIme(on) => format!("@ ime:{}", if *on { "on" } else { "off" }),
+        Command::Type(text) if text.is_empty() => "$".to_string(),
+        Command::Type(text) => format!("$ {}", format_type_content(text)),
+        Command::TypeClipboard => "@ typeclipboard".to_string(),
+    }
+}
+
+// A `@ setup`, `@ teardown` or `@ lua` block, with its script as written
+fn format_fixture(name: &str, body: &str) -> String {
+    if body.is_empty() {
+        return format!("@ {}\n@ end{}", name, name);
+    }
+    format!("@ {}\n{}\n@ end{}", name, body, name)
+}
+
+// Lines of a `@ choose` branch or another block's body, indented so the block stands out
+fn format_branch(commands: &[Command]) -> String {
+    let mut out = String::new();
+    for command in format_commands(commands) {
+        for line in command.lines() {
+            out.push_str(&format!("  {}\n", line));
+        }
+    }
+    out
+}
+
+pub fn cursor_keys_name(mode: CursorKeys) -> &'static str {
+    match mode {
+        CursorKeys::Auto => "auto",
+        CursorKeys::Normal => "normal",
+        CursorKeys::Application => "application",
+    }
+}
+
+// Longest named sequence at the start of `text`, so `ESC [15~` wins over `ESC`
+fn match_named_key(text: &str) -> Option<(&'static str, usize)> {
+    // Every one starts with a control character, so printable text is passed
+    // over without working out the sequences
+    if !text.starts_with(|c: char| c.is_ascii_control()) {
+        return None;
+    }
+    KEY_NAMES
+        .iter()
+        .filter_map(|name| {
+            let seq = named_key_sequence(name)?;
+            text.starts_with(&seq).then_some((*name, seq.len()))
+        })
+        .max_by_key(|(_, len)| *len)
+}
+
+// Shift-modified special key at the start of `text`, e.g. `ESC [1;2H`
+fn match_shifted_key(text: &str) -> Option<(&'static str, usize)> {
+    if !text.starts_with("\x1b[") {
+        return None;
+    }
+    KEY_NAMES.iter().find_map(|name| {
+        let seq = key_sequence(&format!("S-{}", name));
+        (seq.starts_with("\x1b[") && text.starts_with(&seq)).then_some((*name, seq.len()))
+    })
+}
+
+/// A single keystroke recovered from typed text
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyToken {
+    Char(char),
+    // One of KEY_NAMES
+    Named(&'static str),
+    // Shift with one of KEY_NAMES
+    Shift(&'static str),
+    // Ctrl with a letter, space or one of `\\ ] ^ _`
+    Ctrl(char),
+    // ESC followed by an alphanumeric key
+    Alt(char),
+    // Any other key sent as an escape sequence, such as Ctrl+Alt+Delete
+    Key(KeyEvent),
+}
+
+impl KeyToken {
+    /// The key, or `None` for a character that's typed as it is
+    pub fn event(self) -> Option<KeyEvent> {
+        let mods = |ctrl, alt| Modifiers {
+            ctrl,
+            alt,
+            ..Modifiers::NONE
+        };
+        match self {
+            KeyToken::Char(_) => None,
+            KeyToken::Named(name) => KeyEvent::parse(name),
+            KeyToken::Shift(name) => KeyEvent::parse(&format!("S-{}", name)),
+            KeyToken::Ctrl(' ') => Some(KeyEvent::with_mods(Key::Space, mods(true, false))),
+            KeyToken::Ctrl(c) => Some(KeyEvent::with_mods(Key::Char(c), mods(true, false))),
+            KeyToken::Alt(c) => Some(KeyEvent::with_mods(Key::Char(c), mods(false, true))),
+            KeyToken::Key(event) => Some(event),
+        }
+    }
+}
+
+/// How `event` is written: by the name its bytes are read back as, unless
+/// another key sends the same bytes, as Tab does for `<C-i>`
+pub fn key_token(event: KeyEvent) -> KeyToken {
+    if let Some(seq) = event.legacy()
+        && let [token] = key_tokens(&seq)[..]
+        && token.event() == Some(event)
+    {
+        return token;
+    }
+    KeyToken::Key(event)
+}
+
+// Length of the CSI or SS3 sequence at the start of `text`, if it is one
+fn sequence_len(text: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    match bytes.get(..2)? {
+        b"\x1bO" => bytes.get(2).is_some_and(u8::is_ascii_graphic).then_some(3),
+        b"\x1b[" => {
+            let body = bytes[2..].iter().position(|b| !(0x20..0x40).contains(b))?;
+            (0x40..0x7f).contains(&bytes[2 + body]).then_some(3 + body)
+        }
+        _ => None,
+    }
+}
+
+pub fn key_tokens(text: &str) -> Vec<KeyToken> {
+    let mut tokens = Vec::new();
+    let mut remaining = text;
+
+    while let Some(c) = remaining.chars().next() {
+        if let Some((name, len)) = match_shifted_key(remaining) {
+            tokens.push(KeyToken::Shift(name));
+            remaining = &remaining[len..];
+            continue;
+        }
+
+        if let Some((name, len)) = match_named_key(remaining) {
+            if name != "esc" {
+                tokens.push(KeyToken::Named(name));
+                remaining = &remaining[len..];
+                continue;
+            }
+
+            // Modified keys the names above don't cover, as long as they're
+            // written back as the same bytes
+            if let Some(len) = sequence_len(remaining)
+                && let Some(event) = KeyEvent::decode(&remaining[..len])
+                && event.legacy().as_deref() == Some(&remaining[..len])
+            {
+                tokens.push(KeyToken::Key(event));
+                remaining = &remaining[len..];
+                continue;
+            }
+
+            if let Some(next) = remaining[1..].chars().next()
+                && next.is_ascii_alphanumeric()
+            {
+                tokens.push(KeyToken::Alt(next));
+                remaining = &remaining[2..];
+                continue;
+            }
+
+            tokens.push(KeyToken::Named("esc"));
+            remaining = &remaining[1..];
+            continue;
+        }
+
+        let token = match c {
+            '\x00' => KeyToken::Ctrl(' '),
+            '\x01'..='\x1a' => KeyToken::Ctrl((b'a' + c as u8 - 1) as char),
+            '\x1c' => KeyToken::Ctrl('\\'),
+            '\x1d' => KeyToken::Ctrl(']'),
+            '\x1e' => KeyToken::Ctrl('^'),
+            '\x1f' => KeyToken::Ctrl('_'),
+            _ => KeyToken::Char(c),
+        };
+        tokens.push(token);
+        remaining = &remaining[c.len_utf8()..];
+    }
+
+    tokens
+}
+
+/// The keystrokes in `keys`, each key by the token it's written as
+pub fn typed_tokens(keys: &Keys) -> Vec<KeyToken> {
+    keys.iter()
+        .flat_map(|typed| match typed {
+            // Control characters in text can only be written as keys
+            Typed::Text(text) => key_tokens(text),
+            Typed::Key(event) => vec![key_token(*event)],
+        })
+        .collect()
+}
+
+pub fn format_type_content(keys: &Keys) -> String {
+    let tokens = typed_tokens(keys);
+    let last = tokens.len().saturating_sub(1);
+    let mut result = String::new();
+
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            // Leading and trailing spaces would be trimmed away by the parser
+            KeyToken::Char(' ') if i == 0 || i == last => result.push_str("<space>"),
+            // Or read as the start of a note
+            KeyToken::Char(' ')
+                if tokens[i + 1..].starts_with(&[KeyToken::Char('#'), KeyToken::Char('!')]) =>
+            {
+                result.push_str("<space>")
+            }
+            KeyToken::Char('<') => result.push_str("\\<"),
+            // `\#!` would be read as an escaped `#!`
+            KeyToken::Char('#')
+                if i > 0
+                    && tokens[i - 1] == KeyToken::Char('\\')
+                    && tokens.get(i + 1) == Some(&KeyToken::Char('!')) =>
+            {
+                result.push_str("\\#")
+            }
+            // A backslash at the end would continue the line
+            KeyToken::Char('\\') if i == last => result.push_str("\\\\"),
+            KeyToken::Char(c) => result.push(*c),
+            KeyToken::Named(name) => result.push_str(&format!("<{}>", name)),
+            KeyToken::Shift(name) => result.push_str(&format!("<S-{}>", name)),
+            KeyToken::Ctrl(' ') => result.push_str("<C-space>"),
+            KeyToken::Ctrl(c) => result.push_str(&format!("<C-{}>", c)),
+            KeyToken::Alt(c) => result.push_str(&format!("<A-{}>", c)),
+            KeyToken::Key(event) => result.push_str(&format!("<{}>", event)),
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::Key;
+    use crate::parser::{ParseOptions, parse_script};
+
+    #[test]
+    fn test_format_special_keys() {
+        let format = |bytes: &str| format_type_content(&bytes.into());
+        assert_eq!(format("ls -la\r"), "ls -la<ret>");
+        assert_eq!(format("\x1b[15~\x1b[A"), "<F5><up>");
+        assert_eq!(format("\x03\x1bf\x1b"), "<C-c><A-f><esc>");
+        assert_eq!(format("a <b>"), "a \\<b>");
+        assert_eq!(format("\x1b[1;2H\x1b[Z"), "<S-home><S-tab>");
+    }
+
+    #[test]
+    fn test_key_round_trip() {
+        // Every key, with every set of modifiers it can be sent with, is
+        // written back as the same key, even where another sends the same bytes
+        let keys = Key::names()
+            .into_iter()
+            .chain(["a", "z", "5", "/"].map(String::from));
+        let mut failed = Vec::new();
+        for key in keys {
+            for mods in ["", "C-", "A-", "S-", "C-A-", "C-S-", "A-S-", "C-A-S-"] {
+                let spec = format!("{}{}", mods, key);
+                let Some(event) = KeyEvent::parse(&spec).filter(|e| e.legacy().is_some()) else {
+                    continue;
+                };
+                let mut keys = Keys::text("x");
+                keys.push_key(event);
+                keys.push_text("x");
+                let written = format_type_content(&keys);
+                let script =
+                    parse_script(&format!("$ {}", written), &ParseOptions::default()).unwrap();
+                if script.commands != vec![Command::Type(keys)]
+                    || written.contains("<esc>[")
+                    || written.contains("<esc>O")
+                {
+                    failed.push(format!("<{}> as {}", spec, written));
+                }
+            }
+        }
+        assert!(failed.is_empty(), "{}", failed.join("\n"));
+        assert_eq!(format_type_content(&"\x1b[1;6A".into()), "<C-S-up>");
+        assert_eq!(format_type_content(&"\t".into()), "<tab>");
+        let ctrl_i = KeyEvent::parse("C-i").unwrap();
+        assert_eq!(format_type_content(&Keys::key(ctrl_i)), "<C-i>");
+    }
+
+    #[test]
+    fn test_format_edge_spaces() {
+        assert_eq!(format_type_content(&" x ".into()), "<space>x<space>");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let input = r#"@ speed:0.05
+@ jitter:0.2
+@ waitjitter:0.1
+@ ramp:0.15..0.03
+@ size:100:30
+@ pixelsize:800x480
+@ resize:120:40
+@ shell:/bin/zsh
+@ shellargs:-l -i
+@ shell-profile:fish-clean
+@ term:tmux-256color
+@ cursor-keys:application
+@ ime:on
+@ encoding:latin1
+@ newline:crlf
+@ multiline:enter
+$ echo "hello" # not a comment<ret>
+$ ls -la<ret>  #! list files
+$ echo<space>#! not a note
+$ echo trailing\\
+$ echo a\\#!b
+@ wait:1.5
+@ wait-random:0.25:2
+@ title:Deploying with quipu
+@ quipu:>=0.1, <1
+@ word-pause:0.15
+@ author:Tom
+@ description:From clone to running server
+@ checkpoint:search
+@ caption:Search the history
+@ exec:./reset.sh
+@ exec:date +%s -> NOW
+@ exec-async:sleep 10
+@ setup
+mkdir -p /tmp/demo && cd /tmp/demo
+
+git init -q
+@ endsetup
+@ teardown
+@ endteardown
+@ lua
+for i = 1, 3 do print("$ echo " .. i .. "<ret>") end
+@ endlua
+@ webhook:http://localhost:8000/scene/2
+@ dump-scrollback:out/session.txt
+@ sound:sounds/click.wav
+@ audio:narration.mp3
+@ sound:off
+@ say:Now we run the tests
+@ say-wait:That's all
+@ typeclipboard
+@ expect-timeout:30s
+@ timeout:2m
+@ expect:Listening on port (\d+) -> PORT
+@ expect-screen:^\$ $
+@ expect-cell:1:5:Hello, world
+@ expect-clipboard
+@ expect-clipboard -> COPIED
+@ prompt-pattern:'\$ $'
+@ waitprompt
+@ waitport:localhost:8080
+@ session:server
+@ layout:vertical
+@ clear
+@ no-history:space
+@ clear:scrollback
+@ reset
+@ choose
+$ ls<ret>
+@ or
+@ choose
+@ or
+$ pwd<ret>
+@ endchoose
+@ endchoose
+@ retry:3 backoff:1.5
+$ git clone https://example.com/repo.git<ret>
+@ expect:done\.
+@ endretry
+@ on-error
+$ <C-c>
+@ dump-scrollback:failed.txt
+@ endon-error
+@ finally
+$ exit<ret>
+@ endfinally
+$ <C-r>grep<esc>:wq<ret><A-ret><F12><C-6><C-_><kp5><kpenter>
+$ \<literal\> <space>
+"#;
+        let script = parse_script(input, &ParseOptions::default()).unwrap();
+        let reparsed = parse_script(&format_script(&script), &ParseOptions::default()).unwrap();
+        assert_eq!(script.commands, reparsed.commands);
+    }
+}
@@ -0,0 +1,308 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! PNG frame exports
+//!
+//! The recording is replayed through the screen model and the screen drawn
+//! at a fixed interval with the built-in font, writing one numbered PNG for
+//! each, ready to assemble into video or pick stills from
+
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::cast::{Cast, parse_resize};
+use crate::font;
+use crate::png;
+use crate::render::{Rgb, Style, cell_style};
+use crate::theme::Theme;
+use crate::width::{AmbiguousWidth, Layout};
+
+// The font is drawn at twice its size, a pixel in from the left of the cell
+const SCALE: usize = 2;
+pub const CELL_WIDTH: usize = (font::WIDTH + 1) * SCALE;
+pub const CELL_HEIGHT: usize = font::HEIGHT * SCALE;
+const BAR_HEIGHT: usize = 2 * CELL_HEIGHT;
+// Window buttons at the left of the title bar, as on macOS
+const BUTTONS: [Rgb; 3] = [(0xff, 0x5f, 0x56), (0xff, 0xbd, 0x2e), (0x27, 0xc9, 0x3f)];
+const BUTTON_RADIUS: usize = 6;
+
+/// An RGB image
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Image {
+    fn new(width: usize, height: usize, color: Rgb) -> Self {
+        let mut image = Self {
+            width,
+            height,
+            pixels: vec![0; width * height * 3],
+        };
+        image.fill(0, 0, width, height, color);
+        image
+    }
+
+    fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, (r, g, b): Rgb) {
+        for row in y..(y + height).min(self.height) {
+            for col in x..(x + width).min(self.width) {
+                let at = (row * self.width + col) * 3;
+                self.pixels[at..at + 3].copy_from_slice(&[r, g, b]);
+            }
+        }
+    }
+
+    pub fn png(&self) -> Vec<u8> {
+        png::encode(self.width as u32, self.height as u32, &self.pixels)
+    }
+}
+
+// Which of up, down, left and right a box drawing character has lines to
+fn box_arms(ch: char) -> Option<[bool; 4]> {
+    Some(match ch {
+        '─' | '━' => [false, false, true, true],
+        '│' | '┃' => [true, true, false, false],
+        '┌' | '┏' | '╭' => [false, true, false, true],
+        '┐' | '┓' | '╮' => [false, true, true, false],
+        '└' | '┗' | '╰' => [true, false, false, true],
+        '┘' | '┛' | '╯' => [true, false, true, false],
+        '├' | '┣' => [true, true, false, true],
+        '┤' | '┫' => [true, true, true, false],
+        '┬' | '┳' => [false, true, true, true],
+        '┴' | '┻' => [true, false, true, true],
+        '┼' | '╋' => [true, true, true, true],
+        _ => return None,
+    })
+}
+
+// Draw the first character of `text` in the cell at `x`, `y`, `columns` cells wide
+fn draw_text(image: &mut Image, x: usize, y: usize, columns: usize, text: &str, style: &Style) {
+    let color = style.foreground;
+    let width = CELL_WIDTH * columns;
+    let ch = text.chars().next().unwrap_or(' ');
+
+    if let Some(rows) = font::glyph(ch).filter(|_| columns == 1) {
+        for (row, bits) in rows.iter().enumerate() {
+            for col in 0..font::WIDTH {
+                if bits & (0x10 >> col) != 0 {
+                    // Bold draws each pixel a little wider
+                    let left = x + SCALE / 2 + col * SCALE;
+                    let wide = SCALE + usize::from(style.bold);
+                    image.fill(left, y + row * SCALE, wide, SCALE, color);
+                }
+            }
+        }
+    } else if let Some([up, down, left, right]) = box_arms(ch) {
+        let (cx, cy) = (x + width / 2 - SCALE / 2, y + CELL_HEIGHT / 2 - SCALE / 2);
+        if up {
+            image.fill(cx, y, SCALE, cy - y + SCALE, color);
+        }
+        if down {
+            image.fill(cx, cy, SCALE, y + CELL_HEIGHT - cy, color);
+        }
+        if left {
+            image.fill(x, cy, cx - x + SCALE, SCALE, color);
+        }
+        if right {
+            image.fill(cx, cy, x + width - cx, SCALE, color);
+        }
+    } else {
+        match ch {
+            ' ' => {}
+            '█' => image.fill(x, y, width, CELL_HEIGHT, color),
+            '▀' => image.fill(x, y, width, CELL_HEIGHT / 2, color),
+            '▄' => image.fill(x, y + CELL_HEIGHT / 2, width, CELL_HEIGHT / 2, color),
+            // Anything else the font can't draw is an empty box
+            _ => {
+                let (left, top) = (x + SCALE, y + SCALE);
+                let (w, h) = (width - 2 * SCALE, CELL_HEIGHT - 3 * SCALE);
+                image.fill(left, top, w, 1, color);
+                image.fill(left, top + h - 1, w, 1, color);
+                image.fill(left, top, 1, h, color);
+                image.fill(left + w - 1, top, 1, h, color);
+            }
+        }
+    }
+
+    if style.underline {
+        image.fill(x, y + CELL_HEIGHT - 1, width, 1, color);
+    }
+}
+
+// A title bar across the top of `image`, with `title` centred in it
+fn draw_title_bar(image: &mut Image, theme: &Theme, title: &str) {
+    let color = theme.bar_color();
+    image.fill(0, 0, image.width, BAR_HEIGHT, color);
+
+    let cy = BAR_HEIGHT / 2;
+    for (i, button) in BUTTONS.into_iter().enumerate() {
+        let cx = 2 * BUTTON_RADIUS + i * 3 * BUTTON_RADIUS;
+        for y in cy - BUTTON_RADIUS..cy + BUTTON_RADIUS {
+            for x in cx - BUTTON_RADIUS..cx + BUTTON_RADIUS {
+                let (dx, dy) = (x.abs_diff(cx), y.abs_diff(cy));
+                if dx * dx + dy * dy <= BUTTON_RADIUS * BUTTON_RADIUS {
+                    image.fill(x, y, 1, 1, button);
+                }
+            }
+        }
+    }
+
+    let style = Style {
+        foreground: theme.palette.foreground,
+        background: color,
+        bold: false,
+        italic: false,
+        underline: false,
+    };
+    let chars: Vec<char> = title.chars().collect();
+    let left = image.width.saturating_sub(chars.len() * CELL_WIDTH) / 2;
+    for (i, ch) in chars.iter().enumerate() {
+        let x = left + i * CELL_WIDTH;
+        if x + CELL_WIDTH <= image.width {
+            draw_text(
+                image,
+                x,
+                (BAR_HEIGHT - CELL_HEIGHT) / 2,
+                1,
+                &ch.to_string(),
+                &style,
+            );
+        }
+    }
+}
+
+/// Draw `screen` as it would look in a terminal, cursor included, framed by `theme`
+pub fn draw_screen(screen: &vt100::Screen, theme: &Theme, title: Option<&str>) -> Image {
+    let palette = &theme.palette;
+    let (rows, cols) = screen.size();
+    let (cursor_row, cursor_col) = screen.cursor_position();
+    let cursor = (!screen.hide_cursor()).then_some((cursor_row, cursor_col));
+
+    let padding = usize::from(theme.padding);
+    let top = padding + if theme.title_bar { BAR_HEIGHT } else { 0 };
+    let mut image = Image::new(
+        usize::from(cols) * CELL_WIDTH + 2 * padding,
+        usize::from(rows) * CELL_HEIGHT + top + padding,
+        palette.background,
+    );
+    if theme.title_bar {
+        draw_title_bar(&mut image, theme, title.unwrap_or_default());
+    }
+    for row in 0..rows {
+        for col in 0..cols {
+            let Some(cell) = screen.cell(row, col) else {
+                continue;
+            };
+            if cell.is_wide_continuation() {
+                continue;
+            }
+            let columns = if cell.is_wide() { 2 } else { 1 };
+            let style = cell_style(cell, palette, cursor == Some((row, col)));
+            let x = padding + usize::from(col) * CELL_WIDTH;
+            let y = top + usize::from(row) * CELL_HEIGHT;
+            image.fill(x, y, columns * CELL_WIDTH, CELL_HEIGHT, style.background);
+            draw_text(&mut image, x, y, columns, cell.contents(), &style);
+        }
+    }
+    image
+}
+
+/// Write the screen every `every` through `cast` to `dir` as numbered PNGs,
+/// returning how many were written
+pub fn export_frames(
+    cast: &Cast,
+    every: Duration,
+    theme: &Theme,
+    dir: &Path,
+    ambiguous: AmbiguousWidth,
+) -> Result<usize> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create directory: {}", dir.display()))?;
+
+    let duration = cast.events.last().map_or(0.0, |event| event.time);
+    let every = every.as_secs_f64();
+    // Up to the first frame at or after the end, so the last one shows how it finished
+    let count = (duration / every).ceil() as usize + 1;
+
+    let mut parser = vt100::Parser::new(cast.rows, cast.cols, 0);
+    let mut layout = Layout::new(ambiguous);
+    let mut events = cast.events.iter().peekable();
+    for frame in 0..count {
+        let time = frame as f64 * every;
+        while let Some(event) = events.next_if(|event| event.time <= time) {
+            match event.kind.as_str() {
+                "o" => parser.process(&layout.rewrite(event.data.as_bytes())),
+                "r" => {
+                    if let Some((cols, rows)) = parse_resize(&event.data) {
+                        parser.screen_mut().set_size(rows, cols);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let path = dir.join(format!("frame-{:05}.png", frame));
+        let image = draw_screen(parser.screen(), theme, cast.title.as_deref());
+        std::fs::write(&path, image.png())
+            .with_context(|| format!("Failed to write frame: {}", path.display()))?;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(image: &Image, x: usize, y: usize) -> Rgb {
+        let at = (y * image.width + x) * 3;
+        (image.pixels[at], image.pixels[at + 1], image.pixels[at + 2])
+    }
+
+    #[test]
+    fn test_draw_screen() {
+        let mut parser = vt100::Parser::new(2, 3, 0);
+        parser.process(b"\x1b[41m!\x1b[0m\xe2\x94\x80\r\n\x1b[?25l");
+        let theme = Theme {
+            padding: 0,
+            ..Theme::default()
+        };
+        let palette = &theme.palette;
+        let image = draw_screen(parser.screen(), &theme, None);
+        assert_eq!(
+            (image.width, image.height),
+            (3 * CELL_WIDTH, 2 * CELL_HEIGHT)
+        );
+
+        // The stem of `!` on a red background
+        let red = (205, 0, 0);
+        assert_eq!(pixel(&image, 0, 0), red);
+        assert_eq!(pixel(&image, 1 + 2 * SCALE, 0), palette.foreground);
+        assert_eq!(pixel(&image, 1 + 2 * SCALE, 5 * SCALE), red);
+
+        // A line through the middle of the next cell, and nothing below
+        let middle = CELL_HEIGHT / 2;
+        assert_eq!(pixel(&image, CELL_WIDTH, middle), palette.foreground);
+        assert_eq!(
+            pixel(&image, 2 * CELL_WIDTH - 1, middle),
+            palette.foreground
+        );
+        assert_eq!(
+            pixel(&image, CELL_WIDTH, CELL_HEIGHT + middle),
+            palette.background
+        );
+    }
+}
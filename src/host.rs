@@ -0,0 +1,127 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Host-side commands for `@ exec`, `@ exec-async`, `@ setup` and `@ teardown`
+//!
+//! These run next to quipu rather than being typed into the session, for
+//! setup the audience shouldn't see
+
+use anyhow::{Context, Result, bail};
+use std::process::Stdio;
+use tokio::process::Command;
+
+use crate::types::{self, Script};
+
+/// `command` run by the platform's shell, with nothing on stdin
+pub fn shell_command(command: &str) -> Command {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+    // Anything written to the terminal would land in the middle of playback
+    cmd.arg(command).stdin(Stdio::null());
+    cmd
+}
+
+/// Run `command` to completion, returning its output without the trailing newline
+pub async fn run(command: &str) -> Result<String> {
+    // Stopped if playback gives up on it, as when it outlasts `@ timeout`
+    let output = shell_command(command)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .with_context(|| format!("Failed to run '{}'", command))?;
+
+    if !output.status.success() {
+        bail!(
+            "'{}' failed with {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout.trim_end_matches(['\r', '\n']).to_string())
+}
+
+// Run a `@ setup` or `@ teardown` script, which may write to the terminal
+// since no session is showing
+fn run_fixture(name: &str, script: &str) -> Result<()> {
+    let status = shell_command(script)
+        .as_std_mut()
+        .status()
+        .with_context(|| format!("Failed to run @ {}", name))?;
+    if !status.success() {
+        bail!("@ {} failed with {}", name, status);
+    }
+    Ok(())
+}
+
+/// A script's `@ setup` blocks, run when made, and its `@ teardown` blocks,
+/// run when dropped
+///
+/// Teardown runs however playback ends, including on errors and Ctrl-C, and
+/// after a failed setup too, so it can clean up what setup got done
+pub struct Fixtures {
+    teardown: Vec<String>,
+}
+
+impl Fixtures {
+    pub fn setup(script: &Script) -> Result<Self> {
+        let mut setup = Vec::new();
+        let mut teardown = Vec::new();
+        for command in &script.commands {
+            match command {
+                types::Command::Setup(body) => setup.push(body.as_str()),
+                types::Command::Teardown(body) => teardown.push(body.clone()),
+                _ => {}
+            }
+        }
+        let fixtures = Self { teardown };
+        for body in setup {
+            tracing::debug!("Running @ setup");
+            run_fixture("setup", body)?;
+        }
+        Ok(fixtures)
+    }
+}
+
+impl Drop for Fixtures {
+    fn drop(&mut self) {
+        // Last first, undoing setup in reverse
+        for body in self.teardown.iter().rev() {
+            tracing::debug!("Running @ teardown");
+            if let Err(e) = run_fixture("teardown", body) {
+                tracing::warn!("{:#}", e);
+            }
+        }
+    }
+}
+
+/// Start `command` and carry on without waiting for it
+pub fn spawn(command: &str) -> Result<()> {
+    shell_command(command)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Failed to start '{}'", command))?;
+    Ok(())
+}
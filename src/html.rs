@@ -0,0 +1,327 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! HTML export of recordings
+//!
+//! The recording is played through the screen model ahead of time and each
+//! change to the screen stored as the rows it touched, so the page only has
+//! to swap rows in as time passes. Everything is in the one file, with no
+//! scripts or fonts fetched from elsewhere
+
+use serde_json::json;
+
+use crate::cast::{Cast, parse_resize};
+use crate::render::{Palette, Rgb, Style, row_runs};
+use crate::theme::Theme;
+use crate::width::{AmbiguousWidth, Layout};
+
+// Output this close together is shown as one frame
+const FRAME_SECS: f64 = 1.0 / 30.0;
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn css_color((r, g, b): Rgb) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+fn style_css(style: &Style, palette: &Palette) -> String {
+    let mut css = Vec::new();
+    if style.foreground != palette.foreground {
+        css.push(format!("color:{}", css_color(style.foreground)));
+    }
+    if style.background != palette.background {
+        css.push(format!("background:{}", css_color(style.background)));
+    }
+    if style.bold {
+        css.push("font-weight:bold".to_string());
+    }
+    if style.italic {
+        css.push("font-style:italic".to_string());
+    }
+    if style.underline {
+        css.push("text-decoration:underline".to_string());
+    }
+    css.join(";")
+}
+
+fn row_html(screen: &vt100::Screen, row: u16, palette: &Palette) -> String {
+    row_runs(screen, row, palette)
+        .iter()
+        .map(|run| match style_css(&run.style, palette) {
+            css if css.is_empty() => escape(&run.text),
+            css => format!("<span style=\"{}\">{}</span>", css, escape(&run.text)),
+        })
+        .collect()
+}
+
+// Each frame is its time, the rows that changed, and the new size after a resize
+fn frames(cast: &Cast, palette: &Palette, ambiguous: AmbiguousWidth) -> Vec<serde_json::Value> {
+    let mut parser = vt100::Parser::new(cast.rows, cast.cols, 0);
+    let mut layout = Layout::new(ambiguous);
+    let mut shown: Vec<String> = Vec::new();
+    let mut resized = None;
+    let mut frames = Vec::new();
+    // When the last frame was drawn, so a steady stream of output still gets them
+    let mut drawn = 0.0;
+
+    for (i, event) in cast.events.iter().enumerate() {
+        match event.kind.as_str() {
+            "o" => parser.process(&layout.rewrite(event.data.as_bytes())),
+            "r" => {
+                if let Some((cols, rows)) = parse_resize(&event.data) {
+                    parser.screen_mut().set_size(rows, cols);
+                    resized = Some((cols, rows));
+                    shown.clear();
+                }
+            }
+            _ => continue,
+        }
+
+        let next = cast.events[i + 1..]
+            .iter()
+            .find(|e| e.kind == "o" || e.kind == "r");
+        if next.is_some_and(|next| next.time - event.time < FRAME_SECS)
+            && event.time - drawn < FRAME_SECS
+        {
+            continue;
+        }
+        drawn = event.time;
+
+        let screen = parser.screen();
+        let (rows, _) = screen.size();
+        shown.resize(rows as usize, String::new());
+        let mut changed = Vec::new();
+        for row in 0..rows {
+            let html = row_html(screen, row, palette);
+            if html != shown[row as usize] {
+                changed.push(json!([row, html]));
+                shown[row as usize] = html;
+            }
+        }
+        if !changed.is_empty() || resized.is_some() {
+            let mut frame = json!({ "t": event.time, "rows": changed });
+            if let Some((cols, rows)) = resized.take() {
+                frame["size"] = json!([cols, rows]);
+            }
+            frames.push(frame);
+        }
+    }
+    frames
+}
+
+/// A page that plays `cast` in `theme`, with play/pause and seeking
+pub fn export_html(cast: &Cast, theme: &Theme, ambiguous: AmbiguousWidth) -> String {
+    let palette = &theme.palette;
+    let data = json!({
+        "cols": cast.cols,
+        "rows": cast.rows,
+        "duration": cast.events.last().map_or(0.0, |e| e.time),
+        "frames": frames(cast, palette, ambiguous),
+    });
+    // Keep the data from closing the script element early
+    let data = data.to_string().replace("</", "<\\/");
+
+    let bar = match theme.title_bar {
+        true => format!(
+            "<div class=\"bar\"><i></i><i></i><i></i><span>{}</span></div>",
+            escape(cast.title.as_deref().unwrap_or_default())
+        ),
+        false => String::new(),
+    };
+    let title = cast.title.as_deref().unwrap_or("quipu recording");
+    // Recorded text goes in last, so nothing in it is taken for a placeholder
+    PAGE.replace("{foreground}", &css_color(palette.foreground))
+        .replace("{background}", &css_color(palette.background))
+        .replace("{bar_color}", &css_color(theme.bar_color()))
+        .replace("{font}", &theme.font.replace('<', ""))
+        .replace("{font_size}", &theme.font_size.to_string())
+        .replace("{padding}", &theme.padding.to_string())
+        .replace("{title}", &escape(title))
+        .replace("{bar}", &bar)
+        .replace("{data}", &data)
+}
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+  body { margin: 2em; font-family: sans-serif; }
+  .quipu { display: inline-block; background: {background}; border-radius: 6px; overflow: hidden; }
+  .quipu .bar { position: relative; height: 28px; background: {bar_color}; text-align: center; color: {foreground}; font: 13px/28px sans-serif; }
+  .quipu .bar i { position: absolute; top: 9px; width: 10px; height: 10px; border-radius: 50%; background: #ff5f56; }
+  .quipu .bar i:nth-child(1) { left: 10px; }
+  .quipu .bar i:nth-child(2) { left: 26px; background: #ffbd2e; }
+  .quipu .bar i:nth-child(3) { left: 42px; background: #27c93f; }
+  .quipu pre { margin: 0; padding: {padding}px; color: {foreground}; font-family: {font}; font-size: {font_size}px; line-height: 1.2; }
+  .quipu pre div { white-space: pre; height: 1.2em; }
+  .controls { display: flex; gap: 0.5em; align-items: center; padding: 0 {padding}px {padding}px; color: {foreground}; font: 12px monospace; }
+  .controls input { flex: 1; }
+  .controls button { width: 4em; }
+</style>
+</head>
+<body>
+<div class="quipu">
+  {bar}
+  <pre id="screen"></pre>
+  <div class="controls">
+    <button id="play">Play</button>
+    <input id="seek" type="range" min="0" step="0.01" value="0">
+    <span id="time">0.0s</span>
+  </div>
+</div>
+<script>
+const data = {data};
+const screen = document.getElementById("screen");
+const play = document.getElementById("play");
+const seek = document.getElementById("seek");
+const time = document.getElementById("time");
+let rows = [], next = 0, position = 0, started = null;
+
+function resize(count) {
+  screen.replaceChildren();
+  rows = [];
+  for (let i = 0; i < count; i++) {
+    rows.push(screen.appendChild(document.createElement("div")));
+  }
+}
+
+function apply(frame) {
+  if (frame.size) resize(frame.size[1]);
+  for (const [row, html] of frame.rows) rows[row].innerHTML = html;
+}
+
+// Frames only hold changes, so seeking replays them from the start
+function show(at) {
+  if (at < position) {
+    resize(data.rows);
+    next = 0;
+  }
+  while (next < data.frames.length && data.frames[next].t <= at) apply(data.frames[next++]);
+  position = at;
+  seek.value = at;
+  time.textContent = at.toFixed(1) + "s";
+}
+
+function tick(now) {
+  if (started === null) return;
+  const at = (now - started) / 1000;
+  show(Math.min(at, data.duration));
+  if (at >= data.duration) stop(); else requestAnimationFrame(tick);
+}
+
+function start() {
+  if (position >= data.duration) show(0);
+  started = performance.now() - position * 1000;
+  play.textContent = "Pause";
+  requestAnimationFrame(tick);
+}
+
+function stop() {
+  started = null;
+  play.textContent = "Play";
+}
+
+play.onclick = () => (started === null ? start() : stop());
+seek.oninput = () => {
+  show(parseFloat(seek.value));
+  if (started !== null) started = performance.now() - position * 1000;
+};
+seek.max = data.duration;
+resize(data.rows);
+show(0);
+</script>
+</body>
+</html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cast::CastEvent;
+
+    #[test]
+    fn test_frames_hold_changed_rows() {
+        let event = |time: f64, kind: &str, data: &str| CastEvent {
+            time,
+            kind: kind.to_string(),
+            data: data.to_string(),
+        };
+        let cast = Cast {
+            cols: 4,
+            rows: 2,
+            title: Some("<Demo>".to_string()),
+            events: vec![
+                event(0.0, "o", "ab"),
+                // Close enough to the first to be drawn with it
+                event(0.01, "o", "c"),
+                event(1.0, "o", "\r\n\x1b[1m<"),
+                event(2.0, "r", "3x1"),
+            ],
+        };
+        let theme = Theme {
+            title_bar: true,
+            ..Theme::default()
+        };
+        let frames = frames(&cast, &theme.palette, AmbiguousWidth::Narrow);
+
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0]["t"], 0.01);
+        assert_eq!(
+            frames[0]["rows"][0],
+            json!([
+                0,
+                "abc<span style=\"color:#000000;background:#ffffff\"> </span>"
+            ])
+        );
+        assert_eq!(frames[1]["rows"].as_array().unwrap().len(), 2);
+        assert_eq!(
+            frames[1]["rows"][1][1],
+            "<span style=\"font-weight:bold\">&lt;</span><span style=\"color:#000000;background:#ffffff\"> </span>  "
+        );
+        assert_eq!(frames[2]["size"], json!([3, 1]));
+
+        let page = export_html(&cast, &theme, AmbiguousWidth::Narrow);
+        assert!(page.contains("<title>&lt;Demo&gt;</title>"));
+        assert!(page.contains("<span>&lt;Demo&gt;</span></div>"));
+        assert!(page.contains("&lt;<\\/span>"));
+    }
+
+    #[test]
+    fn test_frames_during_steady_output() {
+        // A line every 10ms for a second, never far enough apart on their own
+        let cast = Cast {
+            cols: 10,
+            rows: 2,
+            title: None,
+            events: (0..100)
+                .map(|i| CastEvent {
+                    time: f64::from(i) / 100.0,
+                    kind: "o".to_string(),
+                    data: format!("\r\n{}", i),
+                })
+                .collect(),
+        };
+        let frames = frames(&cast, &Theme::default().palette, AmbiguousWidth::Narrow);
+        assert!(frames.len() >= 25, "{} frames", frames.len());
+        assert!(frames[0]["t"].as_f64().unwrap() < 0.05);
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Script summaries for `quipu info`
+//!
+//! What a script declares and does at a glance, for reviewing one without
+//! playing it
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::config::Settings;
+use crate::format::format_command;
+use crate::key::Typed;
+use crate::parser::is_variable_name;
+use crate::playback::estimate_duration;
+use crate::types::{Command, Script};
+
+// The name a command is counted under: `$` for typed lines, or the directive's name
+fn kind(command: &Command) -> String {
+    match command {
+        Command::Type(_) => "$".to_string(),
+        Command::Choose(_) => "choose".to_string(),
+        Command::Retry(_, _, _) => "retry".to_string(),
+        command => {
+            let line = format_command(command);
+            let directive = line.trim_start_matches("@ ");
+            directive
+                .split([':', ' ', '\n'])
+                .next()
+                .unwrap_or(directive)
+                .to_string()
+        }
+    }
+}
+
+// Every command, including those inside `@ choose` branches and other blocks
+fn walk<'a>(commands: &'a [Command], out: &mut Vec<&'a Command>) {
+    for command in commands {
+        out.push(command);
+        if let Command::Choose(branches) = command {
+            branches.iter().for_each(|branch| walk(branch, out));
+        }
+        if let Command::Retry(_, _, commands)
+        | Command::OnError(commands)
+        | Command::Finally(commands) = command
+        {
+            walk(commands, out);
+        }
+    }
+}
+
+// Names used as `${NAME}` in `text`
+fn variable_uses(text: &str) -> impl Iterator<Item = &str> {
+    text.split("${")
+        .skip(1)
+        .filter_map(|rest| rest.split_once('}'))
+        .map(|(name, _)| name)
+        .filter(|name| is_variable_name(name))
+}
+
+/// The summary of `script`, which read in the files `included`
+pub fn describe(script: &Script, settings: &Settings, included: &[PathBuf]) -> String {
+    let mut out = String::new();
+    let meta = script.meta();
+    for (label, value) in [
+        ("Title", &meta.title),
+        ("Author", &meta.author),
+        ("Description", &meta.description),
+    ] {
+        if let Some(value) = value {
+            out.push_str(&format!("{:<12} {}\n", label, value));
+        }
+    }
+
+    // Only what the script sets itself, since config files differ between machines
+    let preamble = script.preamble();
+    let shell = match (&preamble.shell, &preamble.shell_args) {
+        (Some(shell), Some(args)) => format!("{} {}", shell, args.join(" ")),
+        (Some(shell), None) => shell.clone(),
+        (None, _) => format!("not set ({})", settings.shell),
+    };
+    out.push_str(&format!("{:<12} {}\n", "Shell", shell));
+    let size = match preamble.size {
+        Some((cols, rows)) => format!("{}x{}", cols, rows),
+        None => format!("not set ({}x{})", settings.cols, settings.rows),
+    };
+    out.push_str(&format!("{:<12} {}\n", "Size", size));
+
+    let duration = estimate_duration(&script.commands, &mut settings.playback.clone());
+    out.push_str(&format!(
+        "{:<12} {:.1}s{}\n",
+        "Duration",
+        duration.as_secs_f64(),
+        if script.has_choices() {
+            ", averaging @ choose branches"
+        } else {
+            ""
+        }
+    ));
+
+    let mut commands = Vec::new();
+    walk(&script.commands, &mut commands);
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for command in &commands {
+        *counts.entry(kind(command)).or_default() += 1;
+    }
+    out.push_str(&format!("\nCommands ({})\n", commands.len()));
+    for (kind, count) in &counts {
+        out.push_str(&format!("  {:<14} {}\n", kind, count));
+    }
+
+    let checkpoints: Vec<&str> = commands
+        .iter()
+        .filter_map(|command| match command {
+            Command::Checkpoint(name) => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    if !checkpoints.is_empty() {
+        out.push_str(&format!("\nCheckpoints\n  {}\n", checkpoints.join("\n  ")));
+    }
+
+    // Each once, however many times it's included
+    let mut files: Vec<&PathBuf> = Vec::new();
+    for file in included {
+        if !files.contains(&file) {
+            files.push(file);
+        }
+    }
+    if !files.is_empty() {
+        out.push_str("\nIncludes\n");
+        for file in files {
+            out.push_str(&format!("  {}\n", file.display()));
+        }
+    }
+
+    // Variables set by `@ exec` or `@ expect`, and those typed without being set, which
+    // the shell expands
+    let mut variables: BTreeMap<&str, (Option<&str>, bool)> = BTreeMap::new();
+    for command in &commands {
+        match command {
+            Command::Exec(_, Some(name)) => variables.entry(name).or_default().0 = Some("@ exec"),
+            Command::Expect(_, names) => {
+                for name in names {
+                    variables.entry(name).or_default().0 = Some("@ expect");
+                }
+            }
+            Command::Type(keys) => {
+                for typed in keys.iter() {
+                    if let Typed::Text(text) = typed {
+                        for name in variable_uses(text) {
+                            variables.entry(name).or_default().1 = true;
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    if !variables.is_empty() {
+        out.push_str("\nVariables\n");
+        for (name, (set, used)) in variables {
+            let note = match (set, used) {
+                (Some(by), true) => format!("set by {}", by),
+                (Some(by), false) => format!("set by {}, never typed", by),
+                (None, _) => "not set by the script, left to the shell".to_string(),
+            };
+            out.push_str(&format!("  {:<14} {}\n", name, note));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, resolve};
+    use crate::parser::{ParseOptions, parse_script};
+
+    #[test]
+    fn test_describe() {
+        let script = parse_script(
+            "@ title:Demo\n@ size:100:30\n@ speed:0.1\n@ exec:echo 8080 -> PORT\n$ curl :${PORT}/${HOME}<ret>\n@ checkpoint:done\n@ wait:2\n",
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        let settings = resolve(&Config::default(), &script, &Config::default());
+        let included = [PathBuf::from("lib/login.qp"), PathBuf::from("lib/login.qp")];
+        let info = describe(&script, &settings, &included);
+
+        assert!(info.starts_with("Title        Demo\n"));
+        assert!(info.contains("Size         100x30\n"));
+        assert!(info.contains("Duration     4.2s\n"));
+        assert!(info.contains("  $              1\n  checkpoint     1\n  exec           1\n"));
+        assert!(info.contains("\nCheckpoints\n  done\n\nIncludes\n  lib/login.qp\n\n"));
+        assert!(info.contains("  HOME           not set by the script, left to the shell\n"));
+        assert!(info.contains("  PORT           set by @ exec\n"));
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Input from the real terminal
+//!
+//! While a session is mirrored, the queries programs send for the cursor
+//! position, colours and so on reach the real terminal, which replies on
+//! quipu's stdin. A thread reads stdin and passes those replies on to the
+//! session's PTY, keeping everything else as keypresses for `--advance`.
+//! When the script itself is piped in on stdin, the controlling terminal is
+//! read instead
+
+use std::fs::File;
+use std::io::{IsTerminal, Read};
+use std::sync::{Mutex, Once};
+use std::thread;
+
+use tokio::sync::mpsc::WeakSender;
+
+use crate::screen::{Scan, Sequence};
+
+/// The queue of writes to a session's PTY, which replies join without waiting
+/// for room or keeping it open
+pub type ReplyQueue = WeakSender<Vec<u8>>;
+
+// Keypresses kept for whoever asks next; older ones are dropped
+const MAX_KEYS: usize = 64;
+
+struct Shared {
+    // Where replies go while a session is mirrored
+    session: Option<ReplyQueue>,
+    keys: Vec<u8>,
+}
+
+static SHARED: Mutex<Shared> = Mutex::new(Shared {
+    session: None,
+    keys: Vec::new(),
+});
+
+static START: Once = Once::new();
+
+// Only one thread can own stdin, so it's started once and shared by every session
+fn start() {
+    START.call_once(|| {
+        if let Some(terminal) = open_terminal() {
+            thread::spawn(move || read_input(terminal));
+        }
+    });
+}
+
+// Where keypresses and replies come from, if anywhere
+fn open_terminal() -> Option<Box<dyn Read + Send>> {
+    if std::io::stdin().is_terminal() {
+        return Some(Box::new(std::io::stdin()));
+    }
+    // Windows has no controlling terminal to open
+    if cfg!(windows) {
+        return None;
+    }
+    let terminal = File::open("/dev/tty").ok()?;
+    terminal
+        .is_terminal()
+        .then(|| Box::new(terminal) as Box<dyn Read + Send>)
+}
+
+/// Whether there's a terminal to read keypresses from
+pub fn has_terminal() -> bool {
+    std::io::stdin().is_terminal() || open_terminal().is_some()
+}
+
+fn read_input(mut terminal: Box<dyn Read + Send>) {
+    let mut filter = Filter::default();
+    let mut buffer = [0u8; 1024];
+
+    while let Ok(n) = terminal.read(&mut buffer) {
+        if n == 0 {
+            break;
+        }
+        let (replies, keys) = filter.split(&buffer[..n]);
+
+        let mut shared = SHARED.lock().unwrap();
+        if !replies.is_empty()
+            && let Some(session) = &shared.session
+        {
+            queue_reply(session, replies);
+        }
+        shared.keys.extend(keys);
+        let excess = shared.keys.len().saturating_sub(MAX_KEYS);
+        shared.keys.drain(..excess);
+    }
+}
+
+/// Queue `reply` for a session's PTY, dropping it if the session has ended or
+/// isn't reading its input
+pub fn queue_reply(queue: &ReplyQueue, reply: Vec<u8>) {
+    if let Some(queue) = queue.upgrade() {
+        let _ = queue.try_send(reply);
+    }
+}
+
+/// Send replies from the real terminal to `session`, or drop them with `None`
+pub fn route_replies(session: Option<ReplyQueue>) {
+    start();
+    SHARED.lock().unwrap().session = session;
+}
+
+/// Keys pressed since the last call
+pub fn take_keys() -> Vec<u8> {
+    start();
+    std::mem::take(&mut SHARED.lock().unwrap().keys)
+}
+
+// Whether `sequence` is something a terminal sends back rather than a key
+fn is_reply(sequence: &Sequence) -> bool {
+    match sequence {
+        Sequence::Csi(body) => {
+            let Some((&last, params)) = body.split_last() else {
+                return false;
+            };
+            match last {
+                // Cursor position, which Shift+F3 also looks like in some terminals
+                b'R' => true,
+                // Device attributes
+                b'c' => params.starts_with(b"?") || params.starts_with(b">"),
+                // Status report
+                b'n' => true,
+                // DECRPM mode report
+                b'y' => params.ends_with(b"$"),
+                // Keyboard protocol flags
+                b'u' => params.starts_with(b"?"),
+                _ => false,
+            }
+        }
+        // Keys are never sent as strings
+        Sequence::Osc(_, _) | Sequence::Dcs(_) | Sequence::Apc => true,
+    }
+}
+
+#[derive(Default)]
+struct Filter {
+    scan: Scan,
+    // The escape sequence read so far
+    pending: Vec<u8>,
+}
+
+impl Filter {
+    // Split input into replies to queries and keypresses
+    fn split(&mut self, input: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut replies = Vec::new();
+        let mut keys = Vec::new();
+        for &byte in input {
+            self.pending.push(byte);
+            match self.scan.next(byte) {
+                Some(sequence) if is_reply(&sequence) => replies.append(&mut self.pending),
+                Some(_) => keys.append(&mut self.pending),
+                None if self.scan.is_ground() => keys.append(&mut self.pending),
+                None => {}
+            }
+        }
+
+        // Replies arrive in one piece, so an ESC left at the end is the Escape key
+        if self.scan.is_escape() {
+            self.scan = Scan::default();
+            keys.append(&mut self.pending);
+        }
+        (replies, keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_splits_replies_from_keys() {
+        let mut filter = Filter::default();
+        let (replies, keys) = filter.split(b"a\x1b[12;5R\x1b[A\x1b]11;rgb:0000/0000/0000\x07b");
+        assert_eq!(replies, b"\x1b[12;5R\x1b]11;rgb:0000/0000/0000\x07");
+        assert_eq!(keys, b"a\x1b[Ab");
+
+        // A reply split across reads, then the Escape key on its own
+        assert_eq!(filter.split(b"\x1b[?62;"), (Vec::new(), Vec::new()));
+        assert_eq!(filter.split(b"22c"), (b"\x1b[?62;22c".to_vec(), Vec::new()));
+        assert_eq!(filter.split(b"\x1b"), (Vec::new(), b"\x1b".to_vec()));
+    }
+
+    #[test]
+    fn test_queue_reply_never_waits() {
+        let (queue, mut writes) = tokio::sync::mpsc::channel(1);
+        let session = queue.downgrade();
+        queue_reply(&session, b"a".to_vec());
+        // A full queue drops the reply rather than waiting for room
+        queue_reply(&session, b"b".to_vec());
+        assert_eq!(writes.try_recv().unwrap(), b"a");
+        assert!(writes.try_recv().is_err());
+
+        // As does one that's been closed
+        drop(queue);
+        queue_reply(&session, b"c".to_vec());
+        assert!(writes.try_recv().is_err());
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Throwaway home directories for `--isolate`
+//!
+//! The session's shell is given a fresh `HOME`, with the XDG directories
+//! inside it, so a demo neither depends on your own dotfiles nor leaves
+//! history and caches behind in them. Each run gets a directory of its own,
+//! so runs side by side don't collide, and it's removed afterwards
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// Told apart from other homes made by the same process
+static COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// A home directory for a session, removed when dropped
+pub struct IsolatedHome {
+    path: PathBuf,
+    env: Vec<(&'static str, String)>,
+}
+
+impl IsolatedHome {
+    /// Make a new, empty home directory, seeded with a copy of `dotfiles` if given
+    pub fn new(dotfiles: Option<&Path>) -> Result<Self> {
+        // One left behind by an earlier process with the same ID is passed over
+        let path = loop {
+            let path = std::env::temp_dir().join(format!(
+                "quipu-home-{}-{}",
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::Relaxed)
+            ));
+            match fs::create_dir(&path) {
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                result => result.with_context(|| {
+                    format!("Failed to create home directory: {}", path.display())
+                })?,
+            }
+            break path;
+        };
+        // Removed on drop from here on, even if seeding it fails
+        let mut home = Self {
+            path,
+            env: Vec::new(),
+        };
+
+        if let Some(dotfiles) = dotfiles {
+            copy_dir(dotfiles, &home.path)
+                .with_context(|| format!("Failed to copy dotfiles from {}", dotfiles.display()))?;
+        }
+        let dirs = [
+            ("XDG_CONFIG_HOME", ".config"),
+            ("XDG_DATA_HOME", ".local/share"),
+            ("XDG_STATE_HOME", ".local/state"),
+            ("XDG_CACHE_HOME", ".cache"),
+        ];
+        for (_, dir) in dirs {
+            fs::create_dir_all(home.path.join(dir))?;
+        }
+
+        let path = home.path.display().to_string();
+        home.env = std::iter::once(("HOME", path.clone()))
+            // zsh would otherwise keep reading startup files from a ZDOTDIR we inherited
+            .chain(std::iter::once(("ZDOTDIR", path)))
+            .chain(dirs.map(|(name, dir)| (name, home.path.join(dir).display().to_string())))
+            .collect();
+        Ok(home)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Environment pointing the session's shell here
+    pub fn env(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.env.iter().map(|(name, value)| (*name, value.as_str()))
+    }
+}
+
+impl Drop for IsolatedHome {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// Copy the contents of `from` into `to`, following symlinks
+fn copy_dir(from: &Path, to: &Path) -> std::io::Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if fs::metadata(entry.path())?.is_dir() {
+            fs::create_dir_all(&target)?;
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_isolated_home() {
+        let dotfiles = IsolatedHome::new(None).unwrap();
+        fs::create_dir(dotfiles.path().join(".vim")).unwrap();
+        fs::write(dotfiles.path().join(".bashrc"), "PS1='$ '\n").unwrap();
+        fs::write(dotfiles.path().join(".vim/vimrc"), "set nu\n").unwrap();
+
+        let home = IsolatedHome::new(Some(dotfiles.path())).unwrap();
+        let path = home.path().to_path_buf();
+        assert_ne!(path, dotfiles.path());
+        assert_eq!(
+            fs::read_to_string(path.join(".bashrc")).unwrap(),
+            "PS1='$ '\n"
+        );
+        assert!(path.join(".vim/vimrc").is_file());
+        assert!(path.join(".local/state").is_dir());
+        let env: Vec<_> = home.env().collect();
+        assert_eq!(env[0], ("HOME", path.to_str().unwrap()));
+
+        drop(home);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,855 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Keys by name rather than by escape sequence
+//!
+//! The parser reads each `<...>` key into a [`KeyEvent`], a key and the
+//! modifiers held with it, and a `$` line into [`Keys`], its text and keys in
+//! order. Keys are only encoded as they're sent, in whichever form the
+//! running program has asked for: the legacy escape sequences, in normal or
+//! application mode, or the kitty keyboard protocol
+
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::format::KeyToken;
+use crate::screen::KeyModes;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Enter,
+    Tab,
+    Backspace,
+    Escape,
+    Space,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+    /// F1 to F12
+    F(u8),
+    /// A keypad key, by the character it types in numeric mode
+    Keypad(char),
+}
+
+// Names a key can be given in a script, with the one it's written as first
+const NAMES: &[(&str, Key)] = &[
+    ("esc", Key::Escape),
+    ("space", Key::Space),
+    ("ret", Key::Enter),
+    ("return", Key::Enter),
+    ("enter", Key::Enter),
+    ("tab", Key::Tab),
+    ("backspace", Key::Backspace),
+    ("bs", Key::Backspace),
+    ("up", Key::Up),
+    ("down", Key::Down),
+    ("right", Key::Right),
+    ("left", Key::Left),
+    ("home", Key::Home),
+    ("end", Key::End),
+    ("pageup", Key::PageUp),
+    ("pgup", Key::PageUp),
+    ("pagedown", Key::PageDown),
+    ("pgdn", Key::PageDown),
+    ("insert", Key::Insert),
+    ("ins", Key::Insert),
+    ("delete", Key::Delete),
+    ("del", Key::Delete),
+    ("kpenter", Key::Keypad('\r')),
+    ("kpstar", Key::Keypad('*')),
+    ("kpplus", Key::Keypad('+')),
+    ("kpminus", Key::Keypad('-')),
+    ("kpdot", Key::Keypad('.')),
+    ("kpslash", Key::Keypad('/')),
+];
+
+// Final bytes of the SS3 sequences for keypad keys in application mode
+const KEYPAD: &[(char, char)] = &[
+    ('\r', 'M'),
+    ('*', 'j'),
+    ('+', 'k'),
+    ('-', 'm'),
+    ('.', 'n'),
+    ('/', 'o'),
+];
+
+// Numbers of the function keys sent as `ESC [n~`
+const TILDE_KEYS: &[(u8, Key)] = &[
+    (2, Key::Insert),
+    (3, Key::Delete),
+    (5, Key::PageUp),
+    (6, Key::PageDown),
+    (15, Key::F(5)),
+    (17, Key::F(6)),
+    (18, Key::F(7)),
+    (19, Key::F(8)),
+    (20, Key::F(9)),
+    (21, Key::F(10)),
+    (23, Key::F(11)),
+    (24, Key::F(12)),
+];
+
+// Final bytes of the cursor keys and F1-F4, sent as `ESC [X` or `ESC O X`
+const LETTER_KEYS: &[(char, Key)] = &[
+    ('A', Key::Up),
+    ('B', Key::Down),
+    ('C', Key::Right),
+    ('D', Key::Left),
+    ('H', Key::Home),
+    ('F', Key::End),
+    ('P', Key::F(1)),
+    ('Q', Key::F(2)),
+    ('R', Key::F(3)),
+    ('S', Key::F(4)),
+];
+
+impl Key {
+    /// The key called `name` in a script, as in `<ret>` or `<kp5>`
+    pub fn from_name(name: &str) -> Option<Key> {
+        if let Some((_, key)) = NAMES.iter().find(|(n, _)| *n == name) {
+            return Some(*key);
+        }
+        if let Some(digit) = name.strip_prefix("kp")
+            && let [d @ b'0'..=b'9'] = digit.as_bytes()
+        {
+            return Some(Key::Keypad(*d as char));
+        }
+        match name.strip_prefix('F')?.parse() {
+            Ok(n @ 1..=12) => Some(Key::F(n)),
+            _ => None,
+        }
+    }
+
+    /// Every name a key can be given in a script, such as `ret` and `F1`
+    pub fn names() -> Vec<String> {
+        let keypad = ('0'..='9').map(|digit| format!("kp{}", digit));
+        let function = (1..=12).map(|n| format!("F{}", n));
+        NAMES
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .chain(keypad)
+            .chain(function)
+            .collect()
+    }
+
+    /// The key's name in a script, as in `<ret>`; characters are themselves
+    pub fn name(self) -> String {
+        match self {
+            Key::Char(ch) => ch.to_string(),
+            Key::F(n) => format!("F{}", n),
+            Key::Keypad(d @ '0'..='9') => format!("kp{}", d),
+            key => NAMES
+                .iter()
+                .find(|(_, k)| *k == key)
+                .map(|(name, _)| name.to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The bytes sent for the key, as for `<name>` in a `$` line
+    pub fn sequence(self) -> String {
+        KeyEvent::new(self).legacy().unwrap_or_default()
+    }
+
+    // Escape sequence for a key on its own, as scripts store it
+    fn base(self) -> Option<String> {
+        let seq = match self {
+            Key::Char(_) => return None,
+            Key::Escape => "\x1b",
+            Key::Space => " ",
+            Key::Enter => "\r",
+            Key::Tab => "\t",
+            Key::Backspace => "\x7f",
+            // Keypad keys are stored in their application (DECKPAM) form and
+            // sent as plain characters while the keypad is in numeric mode
+            Key::Keypad(ch) => return Some(format!("\x1bO{}", keypad_final(ch)?)),
+            key => {
+                if let Some((n, _)) = TILDE_KEYS.iter().find(|(_, k)| *k == key) {
+                    return Some(format!("\x1b[{}~", n));
+                }
+                let (letter, _) = LETTER_KEYS.iter().find(|(_, k)| *k == key)?;
+                let intro = if matches!(key, Key::F(_)) { 'O' } else { '[' };
+                return Some(format!("\x1b{}{}", intro, letter));
+            }
+        };
+        Some(seq.to_string())
+    }
+
+    // Cursor keys, which DECCKM switches to SS3
+    fn is_cursor(self) -> bool {
+        matches!(
+            self,
+            Key::Up | Key::Down | Key::Left | Key::Right | Key::Home | Key::End
+        )
+    }
+}
+
+fn keypad_final(ch: char) -> Option<char> {
+    match ch {
+        '0'..='9' => Some((b'p' + (ch as u8 - b'0')) as char),
+        _ => KEYPAD.iter().find(|(c, _)| *c == ch).map(|(_, f)| *f),
+    }
+}
+
+fn keypad_char(last: char) -> Option<char> {
+    match last {
+        'p'..='y' => Some((b'0' + (last as u8 - b'p')) as char),
+        _ => KEYPAD.iter().find(|(_, f)| *f == last).map(|(c, _)| *c),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+}
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers {
+        ctrl: false,
+        alt: false,
+        shift: false,
+    };
+
+    pub fn is_empty(self) -> bool {
+        self == Self::NONE
+    }
+
+    // The modifier parameter of xterm and kitty sequences: 1 plus Shift 1, Alt 2, Ctrl 4
+    fn param(self) -> u8 {
+        1 + u8::from(self.shift) + u8::from(self.alt) * 2 + u8::from(self.ctrl) * 4
+    }
+
+    fn from_param(param: u8) -> Option<Modifiers> {
+        let bits = param.checked_sub(1).filter(|bits| *bits < 8)?;
+        Some(Modifiers {
+            shift: bits & 1 != 0,
+            alt: bits & 2 != 0,
+            ctrl: bits & 4 != 0,
+        })
+    }
+}
+
+/// How the running program wants keys sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyProtocol {
+    /// xterm's escape sequences, which every program understands
+    Legacy,
+    /// The kitty keyboard protocol, with the flags the program pushed
+    Kitty(u8),
+}
+
+// Kitty flags: disambiguate escape codes, and report every key as one
+const KITTY_DISAMBIGUATE: u8 = 1;
+const KITTY_ALL_KEYS: u8 = 8;
+
+/// A key and the modifiers held with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: Key,
+    pub mods: Modifiers,
+}
+
+/// The key spec, as written between angle brackets
+impl fmt::Display for KeyEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (held, prefix) in [
+            (self.mods.ctrl, "C-"),
+            (self.mods.alt, "A-"),
+            (self.mods.shift, "S-"),
+        ] {
+            if held {
+                f.write_str(prefix)?;
+            }
+        }
+        f.write_str(&self.key.name())
+    }
+}
+
+impl KeyEvent {
+    pub fn new(key: Key) -> Self {
+        Self {
+            key,
+            mods: Modifiers::NONE,
+        }
+    }
+
+    pub fn with_mods(key: Key, mods: Modifiers) -> Self {
+        Self { key, mods }
+    }
+
+    /// Read a key spec from between angle brackets, such as `ret` or `C-S-x`
+    pub fn parse(spec: &str) -> Option<KeyEvent> {
+        if let Some(key) = Key::from_name(spec) {
+            return Some(KeyEvent::new(key));
+        }
+
+        // A trailing `--` means the key itself is `-`, as in <C-->
+        let (spec_mods, key) = match spec.strip_suffix("--") {
+            Some(mods) => (mods, "-"),
+            None => spec.rsplit_once('-')?,
+        };
+
+        let mut mods = Modifiers::NONE;
+        for m in spec_mods.split('-') {
+            match m {
+                "C" | "c" | "Ctrl" | "ctrl" => mods.ctrl = true,
+                "A" | "a" | "Alt" | "alt" | "M" | "m" | "Meta" | "meta" => mods.alt = true,
+                "S" | "s" | "Shift" | "shift" => mods.shift = true,
+                _ => {}
+            }
+        }
+        if mods.is_empty() {
+            return None;
+        }
+
+        let key = match Key::from_name(key) {
+            Some(key) => key,
+            // Single ASCII characters only, as control codes are
+            None if key.len() == 1 => Key::Char(key.chars().next()?),
+            None => return None,
+        };
+        Some(KeyEvent { key, mods })
+    }
+
+    /// The bytes an xterm sends for the key in its normal modes, which is
+    /// how scripts store it, or `None` if it has no legacy encoding
+    pub fn legacy(self) -> Option<String> {
+        let KeyEvent { key, mods } = self;
+        let Modifiers { ctrl, alt, shift } = mods;
+
+        let base = match key {
+            Key::Char(ch) => ch.to_string(),
+            key => key.base()?,
+        };
+        let special = !matches!(key, Key::Char(_));
+
+        if mods.is_empty() {
+            return Some(base);
+        }
+
+        // Shift with a special key uses xterm's modifier parameter
+        if shift && special {
+            return match (key, modified_sequence(key, mods)) {
+                (_, Some(seq)) => Some(seq),
+                (Key::Tab, None) => Some("\x1b[Z".to_string()),
+                // Enter, space and friends are unchanged by Shift alone
+                (_, None) if !ctrl && !alt => Some(base),
+                (_, None) => None,
+            };
+        }
+
+        if ctrl && !alt {
+            return match key {
+                Key::Char(ch) => ctrl_code(ch).map(String::from),
+                Key::Space if !shift => Some("\x00".to_string()),
+                // Special keys don't have standard Ctrl combinations
+                _ => None,
+            };
+        }
+
+        // Alt combinations: prepend ESC to the base key
+        if alt && !ctrl {
+            return Some(format!("\x1b{}", base));
+        }
+
+        // Shift: uppercase single character keys
+        if !ctrl && !alt {
+            return Some(base.to_uppercase());
+        }
+
+        // Ctrl-Alt: ESC followed by Ctrl-key
+        match key {
+            Key::Char(ch) => ctrl_code(ch).map(|code| format!("\x1b{}", code)),
+            _ => Some(format!("\x1b{}", base)),
+        }
+    }
+
+    /// The bytes to send for the key given the modes the terminal is in
+    pub fn encode(self, modes: KeyModes) -> Option<String> {
+        match modes.protocol() {
+            KeyProtocol::Kitty(flags) if flags & (KITTY_DISAMBIGUATE | KITTY_ALL_KEYS) != 0 => {
+                self.kitty(flags, modes)
+            }
+            _ => self.legacy_in(modes),
+        }
+    }
+
+    // Legacy bytes in the terminal's cursor and keypad modes; cursor keys are
+    // stored in their normal form and keypad keys in their application form
+    fn legacy_in(self, modes: KeyModes) -> Option<String> {
+        let seq = self.legacy()?;
+        match self.key {
+            key if key.is_cursor() && self.mods.is_empty() && modes.application_cursor => {
+                Some(seq.replacen('[', "O", 1))
+            }
+            Key::Keypad(ch) if self.mods.is_empty() && !modes.application_keypad => {
+                Some(ch.to_string())
+            }
+            _ => Some(seq),
+        }
+    }
+
+    // The kitty keyboard protocol's `CSI code;mods u` for keys legacy
+    // sequences can't tell apart, with the rest sent as they are in xterm
+    fn kitty(self, flags: u8, modes: KeyModes) -> Option<String> {
+        let all_keys = flags & KITTY_ALL_KEYS != 0;
+        let mut mods = self.mods;
+        let code = match self.key {
+            Key::Char(ch) if ch.is_ascii_uppercase() => {
+                mods.shift = true;
+                u32::from(ch.to_ascii_lowercase())
+            }
+            Key::Char(ch) => u32::from(ch),
+            Key::Space => 32,
+            Key::Escape => 27,
+            Key::Enter => 13,
+            Key::Tab => 9,
+            Key::Backspace => 127,
+            Key::Keypad(ch) if all_keys => match ch {
+                '0'..='9' => 57399 + u32::from(ch) - u32::from('0'),
+                '.' => 57409,
+                '/' => 57410,
+                '*' => 57411,
+                '-' => 57412,
+                '+' => 57413,
+                _ => 57414,
+            },
+            // F3 isn't `CSI R`, which looks like a cursor position report
+            Key::F(3) if !mods.is_empty() => return Some(format!("\x1b[13;{}~", mods.param())),
+            key if !mods.is_empty() => return modified_sequence(key, mods),
+            _ => return self.legacy_in(modes),
+        };
+
+        // Text with nothing but Shift held is still sent as text
+        let text = matches!(self.key, Key::Char(_) | Key::Space) && !mods.ctrl && !mods.alt;
+        let plain = matches!(self.key, Key::Enter | Key::Tab | Key::Backspace) && mods.is_empty();
+        if !all_keys && (text || plain) {
+            return self.legacy();
+        }
+        Some(match mods.param() {
+            1 => format!("\x1b[{}u", code),
+            param => format!("\x1b[{};{}u", code, param),
+        })
+    }
+
+    /// The key a legacy sequence is for, taking a whole keystroke
+    pub fn decode(keystroke: &str) -> Option<KeyEvent> {
+        let mut chars = keystroke.chars();
+        let first = chars.next()?;
+        let rest = chars.as_str();
+        if first != '\x1b' || rest.is_empty() {
+            return rest.is_empty().then(|| decode_char(first));
+        }
+
+        let mut chars = rest.chars();
+        let intro = chars.next()?;
+        let body = chars.as_str();
+        match intro {
+            '[' if !body.is_empty() => decode_csi(body),
+            'O' if !body.is_empty() => {
+                let mut chars = body.chars();
+                let last = chars.next()?;
+                if !chars.as_str().is_empty() {
+                    return None;
+                }
+                match keypad_char(last) {
+                    Some(ch) => Some(KeyEvent::new(Key::Keypad(ch))),
+                    None => letter_key(last).map(KeyEvent::new),
+                }
+            }
+            // Alt sends ESC before the key
+            _ if body.is_empty() => {
+                let mut event = decode_char(intro);
+                event.mods.alt = true;
+                Some(event)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Part of what a `$` line types
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Typed {
+    /// Text, sent as it is
+    Text(String),
+    /// A key, sent as the terminal's modes have it sent
+    Key(KeyEvent),
+}
+
+/// What a `$` line types: runs of text, and the keys between them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Keys(Vec<Typed>);
+
+impl Keys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `text` to be sent as it is, control characters and all
+    pub fn text(text: &str) -> Self {
+        let mut keys = Self::new();
+        keys.push_text(text);
+        keys
+    }
+
+    pub fn key(event: KeyEvent) -> Self {
+        Self(vec![Typed::Key(event)])
+    }
+
+    /// The keys the bytes an xterm sends are for, as in a recording, with
+    /// anything that isn't a key left as text
+    pub fn from_legacy(bytes: &str) -> Self {
+        let mut keys = Self::new();
+        for token in crate::format::key_tokens(bytes) {
+            match (token, token.event()) {
+                // Line breaks are left for `@ multiline` to decide how they're sent
+                (KeyToken::Ctrl('j'), _) => keys.push_text("\n"),
+                (_, Some(event)) => keys.push_key(event),
+                (KeyToken::Char(c), None) => keys.push_text(c.encode_utf8(&mut [0; 4])),
+                (_, None) => {}
+            }
+        }
+        keys
+    }
+
+    pub fn push_text(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        match self.0.last_mut() {
+            Some(Typed::Text(last)) => last.push_str(text),
+            _ => self.0.push(Typed::Text(text.to_string())),
+        }
+    }
+
+    pub fn push_key(&mut self, event: KeyEvent) {
+        self.0.push(Typed::Key(event));
+    }
+
+    pub fn append(&mut self, keys: Keys) {
+        for typed in keys.0 {
+            match typed {
+                Typed::Text(text) => self.push_text(&text),
+                Typed::Key(event) => self.push_key(event),
+            }
+        }
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Typed> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The bytes an xterm sends for the keys in its normal modes
+    pub fn legacy(&self) -> String {
+        self.iter()
+            .map(|typed| match typed {
+                Typed::Text(text) => text.clone(),
+                Typed::Key(event) => event.legacy().unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    /// Whether the last key ends a line, as Enter does
+    pub fn ends_line(&self) -> bool {
+        self.legacy().ends_with(['\r', '\n'])
+    }
+
+    /// The keys up to the first that ends a line, which isn't kept, and the
+    /// rest, or `None` if none do
+    pub fn split_line(&self) -> Option<(Keys, Keys)> {
+        let mut line = Keys::new();
+        for (index, typed) in self.iter().enumerate() {
+            let ends = match typed {
+                Typed::Text(text) => text.find(['\r', '\n']),
+                Typed::Key(event) => event.legacy().filter(|seq| seq == "\r").map(|_| 0),
+            };
+            let Some(at) = ends else {
+                line.0.push(typed.clone());
+                continue;
+            };
+            let mut rest = Keys::new();
+            if let Typed::Text(text) = typed {
+                line.push_text(&text[..at]);
+                rest.push_text(&text[at + 1..]);
+            }
+            rest.0.extend(self.0[index + 1..].iter().cloned());
+            return Some((line, rest));
+        }
+        None
+    }
+
+    /// The text up to the last line break, as the bytes it's pasted as,
+    /// and the keys after it, or `None` and all the keys if there's none
+    pub fn split_paste(self) -> (Option<String>, Keys) {
+        let Some(index) = self
+            .0
+            .iter()
+            .rposition(|typed| matches!(typed, Typed::Text(text) if text.contains('\n')))
+        else {
+            return (None, self);
+        };
+        let mut pasted = Keys(self.0[..index].to_vec()).legacy();
+        let mut rest = Keys::new();
+        if let Typed::Text(text) = &self.0[index] {
+            let (line, typed) = text.split_at(text.rfind('\n').unwrap_or_default() + 1);
+            pasted.push_str(line);
+            rest.push_text(typed);
+        }
+        rest.0.extend(self.0[index + 1..].iter().cloned());
+        (Some(pasted), rest)
+    }
+
+    /// The keys with `f` applied to each run of text
+    pub fn map_text(&self, mut f: impl FnMut(&str) -> String) -> Keys {
+        let mut keys = Keys::new();
+        for typed in self.iter() {
+            match typed {
+                Typed::Text(text) => keys.push_text(&f(text)),
+                Typed::Key(event) => keys.push_key(*event),
+            }
+        }
+        keys
+    }
+}
+
+impl From<&str> for Keys {
+    fn from(bytes: &str) -> Self {
+        Keys::from_legacy(bytes)
+    }
+}
+
+impl From<String> for Keys {
+    fn from(bytes: String) -> Self {
+        Keys::from_legacy(&bytes)
+    }
+}
+
+// Written as on a `$` line, so keys keep their names
+impl Serialize for Keys {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&crate::format::format_type_content(self))
+    }
+}
+
+impl<'de> Deserialize<'de> for Keys {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let line = String::deserialize(deserializer)?;
+        Ok(crate::parser::parse_keys(&line))
+    }
+}
+
+fn letter_key(letter: char) -> Option<Key> {
+    LETTER_KEYS
+        .iter()
+        .find(|(l, _)| *l == letter)
+        .map(|(_, key)| *key)
+}
+
+// A single character, which may be a control code
+fn decode_char(ch: char) -> KeyEvent {
+    let key = match ch {
+        '\r' => Key::Enter,
+        '\t' => Key::Tab,
+        '\x7f' => Key::Backspace,
+        '\x1b' => Key::Escape,
+        '\x00' => {
+            return KeyEvent::with_mods(Key::Space, ctrl());
+        }
+        '\x01'..='\x1a' => {
+            let letter = (b'a' + ch as u8 - 1) as char;
+            return KeyEvent::with_mods(Key::Char(letter), ctrl());
+        }
+        '\x1c'..='\x1f' => {
+            let symbol = ['\\', ']', '^', '_'][ch as usize - 0x1c];
+            return KeyEvent::with_mods(Key::Char(symbol), ctrl());
+        }
+        ch => Key::Char(ch),
+    };
+    KeyEvent::new(key)
+}
+
+fn ctrl() -> Modifiers {
+    Modifiers {
+        ctrl: true,
+        ..Modifiers::NONE
+    }
+}
+
+// The parameters and final byte of a CSI key sequence
+fn decode_csi(body: &str) -> Option<KeyEvent> {
+    let last = body.chars().last()?;
+    let params = &body[..body.len() - last.len_utf8()];
+    let (number, mods) = match params.split_once(';') {
+        Some((number, param)) => (number, Modifiers::from_param(param.parse().ok()?)?),
+        None => (params, Modifiers::NONE),
+    };
+    let key = match last {
+        '~' => {
+            let number: u8 = number.parse().ok()?;
+            TILDE_KEYS.iter().find(|(n, _)| *n == number)?.1
+        }
+        'Z' if params.is_empty() => {
+            return Some(KeyEvent::with_mods(
+                Key::Tab,
+                Modifiers {
+                    shift: true,
+                    ..Modifiers::NONE
+                },
+            ));
+        }
+        letter => {
+            // Unmodified keys have no parameters, and modified ones start with 1
+            let expected = if mods.is_empty() { "" } else { "1" };
+            if number != expected {
+                return None;
+            }
+            letter_key(letter)?
+        }
+    };
+    Some(KeyEvent { key, mods })
+}
+
+// ASCII control code for Ctrl plus a character, following xterm's mapping
+fn ctrl_code(ch: char) -> Option<char> {
+    let code = match ch.to_ascii_lowercase() {
+        // Ctrl-letter maps to ASCII 1-26
+        c @ 'a'..='z' => c as u8 - b'a' + 1,
+        '@' | '2' | ' ' => 0x00,
+        // Ctrl-[ maps to ESC
+        '[' | '3' => 0x1b,
+        '\\' | '4' => 0x1c,
+        ']' | '5' => 0x1d,
+        '^' | '6' | '~' => 0x1e,
+        '_' | '7' | '/' | '-' => 0x1f,
+        '?' | '8' => 0x7f,
+        _ => return None,
+    };
+    Some(code as char)
+}
+
+// xterm encoding of a modified cursor or function key, e.g. `ESC [1;2H` for
+// Shift-Home; keys without a modified form, such as the keypad, give `None`
+fn modified_sequence(key: Key, mods: Modifiers) -> Option<String> {
+    let param = mods.param();
+    if let Some((n, _)) = TILDE_KEYS.iter().find(|(_, k)| *k == key) {
+        return Some(format!("\x1b[{};{}~", n, param));
+    }
+    let (letter, _) = LETTER_KEYS.iter().find(|(_, k)| *k == key)?;
+    Some(format!("\x1b[1;{}{}", param, letter))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kitty(flags: u8) -> KeyModes {
+        KeyModes {
+            kitty_flags: flags,
+            ..KeyModes::default()
+        }
+    }
+
+    #[test]
+    fn test_legacy_round_trip() {
+        for seq in [
+            "a",
+            "Z",
+            "\r",
+            "\t",
+            "\x7f",
+            "\x1b",
+            "\x03",
+            "\x00",
+            "\x1f",
+            "\x1bf",
+            "\x1b\x03",
+            "\x1b[A",
+            "\x1b[1;2H",
+            "\x1b[15;6~",
+            "\x1b[Z",
+            "\x1bOP",
+            "\x1b[1;2P",
+            "\x1bOq",
+        ] {
+            let event = KeyEvent::decode(seq).unwrap();
+            assert_eq!(event.legacy().as_deref(), Some(seq), "{:?}", event);
+        }
+        assert_eq!(KeyEvent::decode("ab"), None);
+        assert_eq!(KeyEvent::decode("\x1b[99~"), None);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            KeyEvent::parse("kp7"),
+            Some(KeyEvent::new(Key::Keypad('7')))
+        );
+        assert_eq!(
+            KeyEvent::parse("C-S-right"),
+            Some(KeyEvent::with_mods(
+                Key::Right,
+                Modifiers {
+                    ctrl: true,
+                    shift: true,
+                    ..Modifiers::NONE
+                }
+            ))
+        );
+        assert_eq!(KeyEvent::parse("x-y"), None);
+        assert_eq!(KeyEvent::parse("F13"), None);
+        assert_eq!(Key::F(5).name(), "F5");
+        assert_eq!(Key::Keypad('\r').name(), "kpenter");
+        let event = KeyEvent::parse("Ctrl-Alt-del").unwrap();
+        assert_eq!(event.to_string(), "C-A-delete");
+        assert_eq!(KeyEvent::parse(&event.to_string()), Some(event));
+    }
+
+    #[test]
+    fn test_encode_kitty() {
+        let event = |spec| KeyEvent::parse(spec).unwrap();
+        let disambiguate = kitty(1);
+        assert_eq!(event("C-i").encode(disambiguate).unwrap(), "\x1b[105;5u");
+        assert_eq!(event("esc").encode(disambiguate).unwrap(), "\x1b[27u");
+        assert_eq!(event("A-S-x").encode(disambiguate).unwrap(), "\x1b[120;4u");
+        assert_eq!(event("ret").encode(disambiguate).unwrap(), "\r");
+        assert_eq!(event("S-tab").encode(disambiguate).unwrap(), "\x1b[9;2u");
+        assert_eq!(event("S-F3").encode(disambiguate).unwrap(), "\x1b[13;2~");
+        assert_eq!(event("up").encode(disambiguate).unwrap(), "\x1b[A");
+        assert_eq!(
+            KeyEvent::new(Key::Char('a')).encode(kitty(8)).unwrap(),
+            "\x1b[97u"
+        );
+        assert_eq!(event("kp1").encode(kitty(9)).unwrap(), "\x1b[57400u");
+        // The same keys without the protocol
+        assert_eq!(event("C-i").encode(KeyModes::default()).unwrap(), "\t");
+        assert_eq!(event("kp1").encode(KeyModes::default()).unwrap(), "1");
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Keystroke overlay for `--show-keys`
+//!
+//! Keeps a short history of what was typed, with plain text run together
+//! and special keys shown by name, e.g. `git log  Enter  Ctrl+R`
+
+use std::collections::VecDeque;
+
+use crate::format::{KeyToken, key_token, key_tokens};
+use crate::key::KeyEvent;
+
+// Entries kept, more than can ever fit on one row
+const HISTORY: usize = 32;
+
+#[derive(Debug, PartialEq)]
+enum Entry {
+    Text(String),
+    Key(String),
+}
+
+fn named_label(name: &str) -> String {
+    let label = match name {
+        "ret" => "Enter",
+        "esc" => "Esc",
+        "tab" => "Tab",
+        "bs" => "Backspace",
+        "up" => "Up",
+        "down" => "Down",
+        "left" => "Left",
+        "right" => "Right",
+        "home" => "Home",
+        "end" => "End",
+        "pageup" => "PgUp",
+        "pagedown" => "PgDn",
+        "insert" => "Ins",
+        "delete" => "Del",
+        // Function and keypad keys keep their script names
+        other => other,
+    };
+    label.to_string()
+}
+
+fn key_label(token: KeyToken) -> String {
+    match token {
+        KeyToken::Char(c) => c.to_string(),
+        KeyToken::Named(name) => named_label(name),
+        KeyToken::Shift(name) => format!("Shift+{}", named_label(name)),
+        KeyToken::Ctrl(' ') => "Ctrl+Space".to_string(),
+        KeyToken::Ctrl(c) => format!("Ctrl+{}", c.to_ascii_uppercase()),
+        KeyToken::Alt(c) => format!("Alt+{}", c),
+        KeyToken::Key(event) => {
+            let mut label = String::new();
+            for (held, name) in [
+                (event.mods.ctrl, "Ctrl+"),
+                (event.mods.alt, "Alt+"),
+                (event.mods.shift, "Shift+"),
+            ] {
+                if held {
+                    label.push_str(name);
+                }
+            }
+            label + &named_label(&event.key.name())
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct KeyCast {
+    entries: VecDeque<Entry>,
+}
+
+impl KeyCast {
+    pub fn push(&mut self, keystroke: &str) {
+        for token in key_tokens(keystroke) {
+            match (token, self.entries.back_mut()) {
+                (KeyToken::Char(c), Some(Entry::Text(text))) => text.push(c),
+                (KeyToken::Char(c), _) => self.entries.push_back(Entry::Text(c.to_string())),
+                (token, _) => self.entries.push_back(Entry::Key(key_label(token))),
+            }
+        }
+        self.trim();
+    }
+
+    /// A key typed by name, shown by the name it was given, so `<C-i>`
+    /// isn't shown as Tab
+    pub fn push_key(&mut self, event: KeyEvent) {
+        let seq = event.legacy().unwrap_or_default();
+        match key_token(event) {
+            // Space types itself
+            token @ KeyToken::Key(_) if seq != " " => {
+                self.entries.push_back(Entry::Key(key_label(token)));
+                self.trim();
+            }
+            _ => self.push(&seq),
+        }
+    }
+
+    fn trim(&mut self) {
+        while self.entries.len() > HISTORY {
+            self.entries.pop_front();
+        }
+    }
+
+    /// The most recent entries that fit in `width` columns, with keys in reverse video
+    pub fn render(&self, width: usize) -> String {
+        let mut used = 0;
+        let mut parts = Vec::new();
+
+        for entry in self.entries.iter().rev() {
+            let (text, styled) = match entry {
+                Entry::Text(text) => (text.clone(), text.clone()),
+                Entry::Key(label) => (
+                    format!(" {} ", label),
+                    format!("\x1b[7m {} \x1b[27m", label),
+                ),
+            };
+            let len = text.chars().count() + 1;
+            if used + len > width {
+                break;
+            }
+            used += len;
+            parts.push(styled);
+        }
+
+        parts.reverse();
+        parts.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keycast_history() {
+        let mut keycast = KeyCast::default();
+        for key in ["g", "i", "t", " ", "s", "\r", "\x12", "\x1b[1;2H"] {
+            keycast.push(key);
+        }
+        assert_eq!(
+            keycast.entries,
+            vec![
+                Entry::Text("git s".to_string()),
+                Entry::Key("Enter".to_string()),
+                Entry::Key("Ctrl+R".to_string()),
+                Entry::Key("Shift+Home".to_string()),
+            ]
+        );
+
+        // Oldest entries are dropped first when space runs out
+        assert_eq!(keycast.render(14), "\x1b[7m Shift+Home \x1b[27m");
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! quipu as a library
+//!
+//! The `quipu` command is built on these modules, and they can be used on
+//! their own to parse, build, convert and play scripts from other programs.
+//! Tests in particular can put a scenario together with
+//! [`builder::ScriptBuilder`] rather than writing out script text
+
+pub mod artifacts;
+pub mod bench;
+pub mod builder;
+pub mod captions;
+pub mod cast;
+pub mod clipboard;
+pub mod completions;
+pub mod condition;
+pub mod config;
+pub mod convert;
+pub mod doitlive;
+pub mod error;
+pub mod events;
+pub mod explain;
+pub mod export;
+pub mod font;
+pub mod format;
+pub mod frames;
+pub mod host;
+pub mod html;
+pub mod info;
+pub mod input;
+pub mod isolate;
+pub mod key;
+pub mod keycast;
+pub mod logging;
+pub mod lua;
+pub mod markdown;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod panes;
+pub mod parser;
+pub mod playback;
+pub mod plugin;
+pub mod png;
+pub mod presenter;
+pub mod profile;
+pub mod pty;
+pub mod publish;
+pub mod redact;
+pub mod render;
+pub mod repl;
+pub mod schedule;
+pub mod screen;
+pub mod sink;
+pub mod sound;
+pub mod stats;
+pub mod suite;
+pub mod summary;
+pub mod supervisor;
+pub mod tape;
+pub mod theme;
+pub mod transcript;
+pub mod ttyrec;
+pub mod types;
+pub mod typescript;
+pub mod typing;
+pub mod version;
+pub mod watch;
+pub mod webhook;
+pub mod width;
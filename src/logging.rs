@@ -0,0 +1,137 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Diagnostics through the `tracing` crate
+//!
+//! Warnings go to stderr as they always have. `QUIPU_LOG` turns on more,
+//! with an `EnvFilter` such as `debug` or `warn,quipu::playback=trace`:
+//! commands are logged at debug, each in a `command` span, and each key at
+//! trace. With `--log-file` it all goes to the file, so the session on
+//! stdout isn't disturbed, and stderr still gets the warnings
+
+use anyhow::{Context, Result};
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::fmt::format::{self, FormatEvent, FormatFields};
+use tracing_subscriber::fmt::time::Uptime;
+use tracing_subscriber::fmt::{FmtContext, Layer};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{Layer as _, Registry};
+
+const FILTER_VAR: &str = "QUIPU_LOG";
+
+/// Which events to keep, from a comma-separated list of `LEVEL` and
+/// `TARGET=LEVEL` directives, keeping warnings when it's empty
+pub fn filter(spec: &str) -> Result<EnvFilter, String> {
+    let directives: Vec<&str> = spec
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .collect();
+    EnvFilter::builder()
+        .with_default_directive(LevelFilter::WARN.into())
+        .parse(directives.join(","))
+        .map_err(|e| format!("Invalid {}: {}", FILTER_VAR, e))
+}
+
+// Warnings and errors on stderr read as quipu's own messages, and anything
+// more as it's written to a log file
+struct Stderr(format::Format<format::Full, Uptime>);
+
+impl<S, N> FormatEvent<S, N> for Stderr
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: format::Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        match *event.metadata().level() {
+            Level::ERROR => write!(writer, "Error: ")?,
+            Level::WARN => write!(writer, "Warning: ")?,
+            _ => return self.0.format_event(ctx, writer, event),
+        }
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+/// Start logging, to `log_file` if given and otherwise to stderr
+pub fn init(log_file: Option<&Path>) -> Result<()> {
+    let filter = match std::env::var(FILTER_VAR) {
+        Ok(spec) => self::filter(&spec).map_err(anyhow::Error::msg)?,
+        Err(_) => self::filter("").map_err(anyhow::Error::msg)?,
+    };
+    let file = log_file
+        .map(|path| {
+            File::create(path)
+                .with_context(|| format!("Failed to create log file: {}", path.display()))
+        })
+        .transpose()?;
+
+    let stderr = Layer::new()
+        .with_writer(std::io::stderr)
+        .with_ansi(false)
+        .event_format(Stderr(
+            format::format()
+                .with_ansi(false)
+                .with_timer(Uptime::default()),
+        ))
+        // Only warnings are left on stderr once there's a file for the rest
+        .with_filter(if file.is_some() {
+            LevelFilter::WARN
+        } else {
+            LevelFilter::TRACE
+        });
+    // Written as it goes so the file is complete if playback is killed
+    let file = file.map(|file| {
+        Layer::new()
+            .with_writer(Mutex::new(file))
+            .with_ansi(false)
+            .with_timer(Uptime::default())
+    });
+
+    Registry::default()
+        .with(filter)
+        .with(stderr)
+        .with(file)
+        .try_init()
+        .context("Logging was already started")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let filter = filter("info, quipu::playback=trace,quipu=warn").unwrap();
+        assert_eq!(filter.max_level_hint(), Some(LevelFilter::TRACE));
+        assert_eq!(
+            self::filter("").unwrap().max_level_hint(),
+            Some(LevelFilter::WARN)
+        );
+        assert!(self::filter("quipu=loud").is_err());
+    }
+}
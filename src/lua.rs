@@ -0,0 +1,99 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! `@ lua` blocks, run by the host's Lua interpreter
+//!
+//! A block is a Lua program that prints script lines for quipu to play in
+//! its place, so typed lines can come from a loop or a CSV file and
+//! branches from what an expect captured. It starts with the script's
+//! variables in the `vars` table and the text on screen in `screen`, and
+//! `set(name, value)` stores a variable for the lines after it. There's no
+//! Lua built in; `lua` on PATH runs the block, or the config file's
+//! `lua_command`
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+use crate::types::Command;
+
+const DEFAULT_COMMAND: &str = "lua";
+
+// Each block's program starts with this, after `vars` and `screen`. `set`
+// prints an `@ exec` that gives the variable its value
+const PRELUDE: &str = r#"function set(name, value)
+  local quoted = string.gsub(tostring(value), "'", "'\\''")
+  print("@ exec:printf '%s' '" .. quoted .. "' -> " .. name)
+end
+"#;
+
+// `text` as a Lua string literal
+fn lua_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for byte in text.bytes() {
+        match byte {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b' '..=b'~' => out.push(byte as char),
+            // Escaped by value, which keeps UTF-8 text as it was, in three digits so
+            // a digit after it isn't read as part of it
+            _ => out.push_str(&format!("\\{:03}", byte)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// The program run for a block with `body`
+pub fn program(body: &str, variables: &HashMap<String, String>, screen: &str) -> String {
+    let mut names: Vec<&String> = variables.keys().collect();
+    names.sort();
+    let vars: Vec<String> = names
+        .into_iter()
+        .map(|name| format!("[{}] = {}", lua_string(name), lua_string(&variables[name])))
+        .collect();
+    format!(
+        "vars = {{ {} }}\nscreen = {}\n{}{}\n",
+        vars.join(", "),
+        lua_string(screen),
+        PRELUDE,
+        body
+    )
+}
+
+/// Run `program` with `lua_command`, or `lua`, returning the commands it printed
+pub async fn run(lua_command: Option<&str>, program: &str) -> Result<Vec<Command>> {
+    let generated =
+        crate::plugin::generate(lua_command.unwrap_or(DEFAULT_COMMAND), program, "lua").await;
+    match lua_command {
+        Some(_) => generated,
+        None => generated
+            .context("@ lua runs `lua` on the host, so Lua must be installed or lua_command set"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_program() {
+        let variables = HashMap::from([("NAME".to_string(), "Zoë \"Z\"".to_string())]);
+        let program = program("print(\"$ echo \" .. vars.NAME)", &variables, "$ \n");
+        assert!(program.starts_with(
+            "vars = { [\"NAME\"] = \"Zo\\195\\171 \\\"Z\\\"\" }\nscreen = \"$ \\010\"\nfunction set"
+        ));
+        assert!(program.ends_with("end\nprint(\"$ echo \" .. vars.NAME)\n"));
+    }
+}
@@ -13,90 +13,1548 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-mod parser;
-mod playback;
-mod pty;
-mod types;
-
 use anyhow::{Context, Result};
-use clap::Parser as ClapParser;
-use std::path::PathBuf;
+use clap::{CommandFactory, Parser as ClapParser, Subcommand};
+use std::ffi::OsString;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+
+use quipu::artifacts::Artifacts;
+use quipu::cast::{self, Cast};
+use quipu::config::{self, Config};
+use quipu::convert::{self, ScriptFormat};
+use quipu::error::Error;
+use quipu::host::Fixtures;
+use quipu::isolate::IsolatedHome;
+use quipu::panes::Panes;
+use quipu::playback::{self, MAIN_SESSION, PlaybackEngine, Sections};
+use quipu::presenter::{self, PresenterServer};
+use quipu::profile::StartupFiles;
+use quipu::summary::Summary;
+use quipu::types::{self, NoHistory, PaneLayout, Script};
+use quipu::width::AmbiguousWidth;
+use quipu::{
+    completions, doitlive, error, explain, export, frames, html, info, input, logging, markdown,
+    parser, pty, publish, redact, suite, summary, theme, ttyrec, typescript, watch, webhook,
+};
 
 #[derive(ClapParser, Debug)]
 #[command(name = "quipu")]
 #[command(about = "Script keyboard entry in the terminal", long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
-    /// The script file to execute
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    /// The script file to execute, or - to play one as it is read from stdin,
+    /// which is the default when stdin isn't a terminal
     #[arg(value_name = "SCRIPT")]
-    script: PathBuf,
+    script: Option<PathBuf>,
 
     /// Shell to use for the PTY session (defaults to current shell)
     #[arg(short, long)]
     shell: Option<String>,
+
+    /// Arguments for the shell, e.g. "-l -i" for a login shell as in a new terminal
+    #[arg(long, value_name = "ARGS", allow_hyphen_values = true)]
+    shell_args: Option<String>,
+
+    /// TERM for the session, e.g. tmux-256color or dumb (defaults to xterm-256color)
+    #[arg(long, value_name = "NAME")]
+    term: Option<String>,
+
+    /// COLORTERM for the session, e.g. truecolor (defaults to your terminal's)
+    #[arg(long, value_name = "VALUE")]
+    colorterm: Option<String>,
+
+    /// Size of a character cell in pixels, e.g. 10x20, for programs that draw images
+    #[arg(long, value_name = "WxH", value_parser = parse_cell_size)]
+    cell_size: Option<(u16, u16)>,
+
+    /// How wide East Asian ambiguous-width characters are, as your terminal draws them
+    #[arg(long, value_name = "WIDTH")]
+    ambiguous_width: Option<AmbiguousWidth>,
+
+    /// Lines of scrollback to keep for `@ dump-scrollback` and --final-dump (default 10000)
+    #[arg(long, value_name = "LINES")]
+    scrollback: Option<usize>,
+
+    /// Write the whole session's text, scrollback included, to FILE once playback ends
+    #[arg(long, value_name = "FILE", conflicts_with = "watch")]
+    final_dump: Option<PathBuf>,
+
+    /// When an expect fails, write the screen and the last of the output to a directory in DIR
+    #[arg(long, value_name = "DIR", conflicts_with = "watch")]
+    artifacts: Option<PathBuf>,
+
+    /// Write a JSON summary of the run to FILE: each command's status and time, the expects, the exit code and files written
+    #[arg(long, value_name = "FILE", conflicts_with = "watch")]
+    summary: Option<PathBuf>,
+
+    /// Start bash, zsh or fish without reading their startup files
+    #[arg(long)]
+    no_rc: bool,
+
+    /// Start the shell with a built-in profile: bash-clean, zsh-clean, fish-clean or nu-clean
+    #[arg(long, value_name = "NAME", value_parser = parse_shell_profile)]
+    shell_profile: Option<String>,
+
+    /// Give the shell a throwaway HOME, empty or seeded with a copy of the dotfiles in DIR
+    #[arg(
+        long,
+        value_name = "DIR",
+        num_args = 0..=1,
+        require_equals = true
+    )]
+    isolate: Option<Option<PathBuf>>,
+
+    /// Start from `@ checkpoint:NAME`, replaying everything before it out of sight
+    #[arg(long, value_name = "NAME")]
+    resume_from: Option<String>,
+
+    /// Fast-forward out of sight through the sections starting at these checkpoints
+    #[arg(long, value_name = "markers:NAME,...", value_parser = parse_markers, conflicts_with = "only")]
+    skip: Option<Markers>,
+
+    /// Play only the sections starting at these checkpoints, fast-forwarding the rest
+    #[arg(long, value_name = "markers:NAME,...", value_parser = parse_markers)]
+    only: Option<Markers>,
+
+    /// Show the keys being typed on the bottom row of the terminal
+    #[arg(long)]
+    show_keys: bool,
+
+    /// Type each `$` line automatically, but wait for a keypress before starting the next
+    #[arg(long)]
+    advance: bool,
+
+    /// Write the script's captions to FILE as WebVTT subtitles
+    #[arg(long, value_name = "FILE")]
+    captions: Option<PathBuf>,
+
+    /// Show the `#!` notes at the end of `$` lines as captions
+    #[arg(long)]
+    note_captions: bool,
+
+    /// Let `@ typeclipboard` type what's on the system clipboard
+    #[arg(long)]
+    allow_clipboard: bool,
+
+    /// Pass the session's clipboard writes (OSC 52) on to the real terminal and recording
+    #[arg(long)]
+    forward_clipboard: bool,
+
+    /// Don't play the script's `@ sound:` and `@ audio:` files or speak its `@ say:` lines
+    #[arg(long)]
+    mute: bool,
+
+    /// Record the session to FILE as an asciinema cast
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// Upload the --record file to asciinema.org, or $ASCIINEMA_API_URL, after playback
+    #[arg(long, requires = "record", conflicts_with = "watch")]
+    publish: bool,
+
+    /// Send progress to `quipu presenter` clients connecting to ADDR, e.g. localhost:4747
+    #[arg(long, value_name = "ADDR")]
+    presenter_socket: Option<String>,
+
+    /// Print how long each line took after playback, as text or with --stats=json
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "text"
+    )]
+    stats: Option<StatsFormat>,
+
+    /// Mask text matching REGEX in the session's output, e.g. home directory paths or API keys
+    #[arg(long, value_name = "REGEX", value_parser = parse_redact)]
+    redact: Vec<String>,
+
+    /// POST a JSON event to URL for every line played, e.g. to switch OBS scenes
+    #[arg(long, value_name = "URL", value_parser = parse_webhook_url)]
+    webhook_url: Option<String>,
+
+    /// Send OpenTelemetry spans for the script and each command to an OTLP/HTTP collector at URL
+    #[cfg(feature = "otlp")]
+    #[arg(long, value_name = "URL", value_parser = parse_webhook_url)]
+    otlp_endpoint: Option<String>,
+
+    /// Seed for jitter and `@ choose`, so every run plays the same
+    #[arg(long, value_name = "N")]
+    seed: Option<u64>,
+
+    /// Stop with an error if playback takes longer than TIME, e.g. 10m, so a hung demo can't stall CI
+    #[arg(
+        long,
+        visible_alias = "timeout",
+        value_name = "TIME",
+        value_parser = parse_every,
+        conflicts_with = "watch"
+    )]
+    max_duration: Option<std::time::Duration>,
+
+    /// Restart playback whenever the script file changes
+    #[arg(short, long)]
+    watch: bool,
+
+    /// Config file to use instead of ~/.config/quipu/config.toml
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Write diagnostics to FILE rather than stderr, as filtered by $QUIPU_LOG
+    #[arg(long, global = true, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+
+    /// Fail on unknown keys, unknown directives and directives that have no effect where they are
+    #[arg(long, global = true)]
+    strict: bool,
+
+    /// Look in DIR for `@ include:` and `@ call:` files, before $QUIPU_PATH, $TYPECAST_PATH and the config file's lib_path
+    #[arg(long, global = true, value_name = "DIR")]
+    lib_path: Vec<PathBuf>,
+}
+
+// Checkpoint names given as `markers:setup,cleanup`
+#[derive(Clone, Debug)]
+struct Markers(Vec<String>);
+
+fn parse_markers(value: &str) -> Result<Markers, String> {
+    let names = value
+        .strip_prefix("markers:")
+        .ok_or("expected markers:NAME,...")?;
+    Ok(Markers(names.split(',').map(str::to_string).collect()))
+}
+
+fn parse_cell_size(value: &str) -> Result<(u16, u16), String> {
+    let size = value
+        .split_once('x')
+        .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+        .ok_or("expected WIDTHxHEIGHT")?;
+    types::Command::SetPixelSize(size.0, size.1).check()?;
+    Ok(size)
+}
+
+fn parse_shell_profile(value: &str) -> Result<String, String> {
+    quipu::profile::check_name(value)?;
+    Ok(value.to_string())
+}
+
+fn parse_size(value: &str) -> Result<(u16, u16), String> {
+    let (cols, rows) = cast::parse_resize(value).ok_or("expected COLSxROWS")?;
+    types::Command::SetSize(cols, rows).check()?;
+    Ok((cols, rows))
+}
+
+fn parse_redact(pattern: &str) -> Result<String, String> {
+    redact::check_pattern(pattern)?;
+    Ok(pattern.to_string())
+}
+
+fn parse_every(value: &str) -> Result<std::time::Duration, String> {
+    match parser::parse_interval(value)? {
+        every if every.is_zero() => Err("must be more than zero".to_string()),
+        every => Ok(every),
+    }
+}
+
+fn parse_webhook_url(url: &str) -> Result<String, String> {
+    webhook::check_url(url)?;
+    Ok(url.to_string())
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum StatsFormat {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Print a completion script for bash, zsh or fish
+    Completions {
+        #[arg(value_enum)]
+        shell: completions::Shell,
+    },
+
+    /// Print names for completion scripts to offer, one per line
+    #[command(hide = true)]
+    Complete {
+        #[arg(value_enum)]
+        what: completions::Completable,
+
+        /// The script to take names from
+        #[arg(value_name = "SCRIPT")]
+        script: Option<PathBuf>,
+    },
+
+    /// Convert a script between the text, JSON, YAML and VHS tape formats
+    Convert {
+        /// The script file to convert
+        #[arg(value_name = "INPUT")]
+        input: PathBuf,
+
+        /// Output file (defaults to stdout)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Input format (defaults to the input file extension)
+        #[arg(long, value_enum)]
+        from: Option<ScriptFormat>,
+
+        /// Output format (defaults to the output file extension)
+        #[arg(long, value_enum)]
+        to: Option<ScriptFormat>,
+    },
+
+    /// Show the bytes each key on a $ line is sent as, in caret notation and hex
+    Explain {
+        /// The line to explain, such as '$ <C-S-up>foo<F5>'
+        #[arg(value_name = "LINE")]
+        line: String,
+    },
+
+    /// Export a script as a standalone program
+    Export {
+        #[command(subcommand)]
+        target: ExportTarget,
+    },
+
+    /// Import a recording or script from another tool
+    Import {
+        #[command(subcommand)]
+        source: ImportSource,
+    },
+
+    /// Summarise a script: metadata, settings, commands, duration and variables
+    Info {
+        /// The script file to describe
+        #[arg(value_name = "SCRIPT")]
+        script: PathBuf,
+    },
+
+    /// Upload a recording to asciinema.org or a self-hosted asciinema server
+    Publish {
+        /// The .cast file to upload
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        /// Server to upload to (defaults to $ASCIINEMA_API_URL or https://asciinema.org)
+        #[arg(long, value_name = "URL")]
+        server: Option<String>,
+    },
+
+    /// Play several scripts at once, each in a session of its own, with the
+    /// options given before `test`
+    Test {
+        /// The scripts to play
+        #[arg(value_name = "SCRIPT", required = true)]
+        scripts: Vec<PathBuf>,
+
+        /// Scripts to play at once (defaults to the number of CPUs)
+        #[arg(short, long, value_name = "N")]
+        jobs: Option<usize>,
+
+        /// Stop a script that plays for longer than TIME, as --max-duration does
+        #[arg(long, value_name = "TIME", value_parser = parse_every)]
+        timeout: Option<std::time::Duration>,
+
+        /// Write each script's output to a log file in DIR as well
+        #[arg(long, value_name = "DIR")]
+        log_dir: Option<PathBuf>,
+    },
+
+    /// Type script lines at a prompt and play each one as it's entered
+    Repl {
+        /// Append each line that plays to this script file
+        #[arg(long, value_name = "FILE")]
+        append: Option<PathBuf>,
+    },
+
+    /// Measure how closely keys keep to time in a real session
+    Bench {
+        /// Number of keys to type
+        #[arg(long, value_name = "N", default_value_t = 200)]
+        keys: usize,
+
+        /// Time between keys, in seconds
+        #[arg(long, value_name = "SECONDS", default_value_t = 0.05)]
+        speed: f64,
+
+        /// Save the suggested offset as timing_offset in the config file
+        #[arg(long)]
+        save: bool,
+    },
+
+    /// Follow playback started with --presenter-socket from a second terminal
+    Presenter {
+        /// Address playback is listening on
+        #[arg(value_name = "ADDR")]
+        addr: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ExportTarget {
+    /// Generate an expect(1) script
+    Expect {
+        /// The script file to export
+        #[arg(value_name = "SCRIPT")]
+        script: PathBuf,
+
+        /// Output file (defaults to stdout)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Generate a POSIX shell script that drives script(1)
+    Sh {
+        /// The script file to export
+        #[arg(value_name = "SCRIPT")]
+        script: PathBuf,
+
+        /// Output file (defaults to stdout)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Generate a self-contained HTML page that plays a recording
+    Html {
+        /// A .cast recording, or a script to play out of sight and record
+        #[arg(value_name = "INPUT")]
+        input: PathBuf,
+
+        /// Output file (defaults to stdout)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Built-in theme name or a theme .toml file
+        #[arg(long, value_name = "NAME|FILE")]
+        theme: Option<String>,
+    },
+
+    /// Generate a Markdown tutorial, with `#` comments as the prose between commands
+    Md {
+        /// The script file to export
+        #[arg(value_name = "SCRIPT")]
+        script: PathBuf,
+
+        /// Output file (defaults to stdout)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Leave out the output, rather than playing the script out of sight for it
+        #[arg(long)]
+        no_output: bool,
+    },
+
+    /// Write the screen as numbered PNG frames at a fixed interval
+    Frames {
+        /// A .cast recording, or a script to play out of sight and record
+        #[arg(value_name = "INPUT")]
+        input: PathBuf,
+
+        /// Directory to write frame-00000.png and on into
+        #[arg(value_name = "DIR")]
+        dir: PathBuf,
+
+        /// Time between frames, e.g. 100ms
+        #[arg(long, value_name = "TIME", default_value = "100ms", value_parser = parse_every)]
+        every: std::time::Duration,
+
+        /// Built-in theme name or a theme .toml file
+        #[arg(long, value_name = "NAME|FILE")]
+        theme: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ImportSource {
+    /// Rebuild a script from an asciinema recording made with --stdin
+    Cast {
+        /// The .cast file to import
+        #[arg(value_name = "INPUT")]
+        input: PathBuf,
+
+        /// Output file (defaults to stdout)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Convert a doitlive session file into a script
+    Doitlive {
+        /// The doitlive session to import
+        #[arg(value_name = "INPUT")]
+        input: PathBuf,
+
+        /// Output file (defaults to stdout)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Seconds to pause after each command, in place of doitlive's keypress
+        #[arg(long, default_value_t = 1.0)]
+        pause: f64,
+    },
+
+    /// Convert a ttyrec recording into a cast
+    Ttyrec {
+        /// The ttyrec file to import
+        #[arg(value_name = "INPUT")]
+        input: PathBuf,
+
+        /// Output file (defaults to stdout)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Size of the terminal it was recorded in, which ttyrec doesn't keep
+        #[arg(long, value_name = "COLSxROWS", default_value = "80x24", value_parser = parse_size)]
+        size: (u16, u16),
+    },
+
+    /// Convert a script(1) recording into a cast, or a script if input was logged too
+    Script {
+        /// The typescript file
+        #[arg(value_name = "TYPESCRIPT")]
+        typescript: PathBuf,
+
+        /// The timing file written with --timing or --log-timing
+        #[arg(value_name = "TIMING")]
+        timing: PathBuf,
+
+        /// Output file (defaults to stdout)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
-
-    let script_content = std::fs::read_to_string(&args.script)
-        .with_context(|| format!("Failed to read script file: {}", args.script.display()))?;
-
-    let script =
-        parser::parse_script(&script_content).map_err(|e| anyhow::anyhow!("Parse error: {}", e))?;
-
-    // Determine shell to use (priority: CLI arg > script directive > $SHELL env > bash)
-    let default_shell = args
-        .shell
-        .or_else(|| std::env::var("SHELL").ok())
-        .unwrap_or_else(|| "bash".to_string());
-
-    // Check if script specifies a shell or size (must come before any Type commands)
-    let mut shell = default_shell;
-    let mut cols = 80u16;
-    let mut rows = 24u16;
-
-    for command in &script.commands {
-        match command {
-            types::Command::SetShell(s) => {
-                shell = s.clone();
-            }
-            types::Command::SetSize(c, r) => {
-                cols = *c;
-                rows = *r;
-            }
-            types::Command::Type(_) => {
-                // Stop looking once we hit a Type command
-                break;
+async fn main() -> ExitCode {
+    let result = run(Args::parse()).await;
+    if playback::interrupted() {
+        return ExitCode::from(error::EXIT_INTERRUPTED);
+    }
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(error::exit_code(&e))
+        }
+    }
+}
+
+async fn run(mut args: Args) -> Result<()> {
+    logging::init(args.log_file.as_deref())?;
+    match args.command.take() {
+        Some(Commands::Completions { shell }) => {
+            print!("{}", completions::generate(&mut Args::command(), shell));
+            Ok(())
+        }
+        Some(Commands::Complete { what, script }) => {
+            // Names are looked up as the user types, so a script that doesn't
+            // parse yet just has none
+            let script = script.and_then(|path| load_script(&path, &Config::default()).ok());
+            for name in completions::candidates(what, script.as_ref()) {
+                println!("{}", name);
             }
-            _ => {}
+            Ok(())
+        }
+        Some(Commands::Bench { keys, speed, save }) => bench(args, keys, speed, save).await,
+        Some(Commands::Convert {
+            input,
+            output,
+            from,
+            to,
+        }) => {
+            let config = load_config(&args)?;
+            convert::convert_file(&input, output.as_ref(), from, to, &config.parse_options())
         }
+        Some(Commands::Explain { line }) => {
+            let config = load_config(&args)?;
+            print!(
+                "{}",
+                explain::explain(&line, &config.parse_options()).map_err(anyhow::Error::msg)?
+            );
+            Ok(())
+        }
+        Some(Commands::Export { target }) => {
+            export(target, load_config(&args)?, args.ambiguous_width).await
+        }
+        Some(Commands::Import { source }) => import(source),
+        Some(Commands::Info { script }) => info(&script, load_config(&args)?),
+        Some(Commands::Presenter { addr }) => presenter::run(&addr),
+        Some(Commands::Repl { append }) => repl(args, append).await,
+        Some(Commands::Test {
+            scripts,
+            jobs,
+            timeout,
+            log_dir,
+        }) => test(&scripts, jobs, timeout, log_dir).await,
+        Some(Commands::Publish { file, server }) => {
+            let url = publish::publish(&file, &publish::server_url(server.as_deref())).await?;
+            println!("{}", url);
+            Ok(())
+        }
+        None => play(args).await,
     }
+}
 
-    println!("Parsed {} commands", script.commands.len());
-    println!("Using shell: {}", shell);
-    println!("Terminal size: {}x{}", cols, rows);
-    println!("Starting playback in 1 second...");
-    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+// Everything before the `test` subcommand in `argv`, the options for playing
+// each script. The subcommand is the first `test` that the options before it
+// parse without; any before it is an option's value, as in `--summary test`
+fn play_args(argv: Vec<OsString>) -> Vec<OsString> {
+    let end = (1..argv.len())
+        .filter(|&index| argv[index] == "test")
+        .find(|&index| Args::try_parse_from(&argv[..index]).is_ok())
+        .unwrap_or(argv.len());
+    argv[1.min(end)..end].to_vec()
+}
 
-    let pty = pty::PtyManager::new(&shell, cols, rows).context("Failed to create PTY")?;
+async fn test(
+    scripts: &[PathBuf],
+    jobs: Option<usize>,
+    timeout: Option<std::time::Duration>,
+    log_dir: Option<PathBuf>,
+) -> Result<()> {
+    let jobs = jobs.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, usize::from));
+    let args = play_args(std::env::args_os().collect());
+    let options = suite::SuiteOptions {
+        jobs,
+        timeout,
+        log_dir,
+        args,
+    };
+    let outcomes = suite::run(scripts, options).await?;
+    print!("\n{}", suite::summary(&outcomes));
+    let failed = outcomes.iter().filter(|outcome| !outcome.passed()).count();
+    anyhow::ensure!(
+        failed == 0,
+        "{} of {} scripts failed",
+        failed,
+        outcomes.len()
+    );
+    Ok(())
+}
 
-    let mut engine =
-        playback::PlaybackEngine::new(pty).context("Failed to create playback engine")?;
+async fn export(
+    target: ExportTarget,
+    mut config: Config,
+    ambiguous_width: Option<AmbiguousWidth>,
+) -> Result<()> {
+    config.ambiguous_width = ambiguous_width.or(config.ambiguous_width);
+    let widths = config.ambiguous_width.unwrap_or_default();
+    let (script, output, generate): (_, _, fn(&_, &_) -> String) = match target {
+        ExportTarget::Expect { script, output } => (script, output, export::export_expect),
+        ExportTarget::Sh { script, output } => (script, output, export::export_sh),
+        ExportTarget::Html {
+            input,
+            output,
+            theme,
+        } => {
+            let theme = load_theme(theme, &config)?;
+            let cast = load_recording(&input, config).await?;
+            let page = html::export_html(&cast, &theme, widths);
+            return match output {
+                Some(path) => std::fs::write(&path, page)
+                    .with_context(|| format!("Failed to write {}", path.display())),
+                None => {
+                    print!("{}", page);
+                    Ok(())
+                }
+            };
+        }
+        ExportTarget::Md {
+            script,
+            output,
+            no_output,
+        } => {
+            let source = std::fs::read_to_string(&script)
+                .with_context(|| format!("Failed to read script: {}", script.display()))?;
+            let dumps = std::env::temp_dir().join(format!("quipu-markdown-{}", std::process::id()));
+            let (tutorial, session) = markdown::Tutorial::parse(
+                &source,
+                &config.parse_options().for_script(&script),
+                &dumps,
+            )
+            .map_err(anyhow::Error::msg)?;
+            if !no_output {
+                std::fs::create_dir_all(&dumps)
+                    .with_context(|| format!("Failed to create {}", dumps.display()))?;
+                let played = play_hidden(session, config).await;
+                if played.is_err() {
+                    let _ = std::fs::remove_dir_all(&dumps);
+                }
+                played?;
+            }
+            let page = tutorial.to_markdown((!no_output).then_some(dumps.as_path()));
+            let _ = std::fs::remove_dir_all(&dumps);
+            return match output {
+                Some(path) => std::fs::write(&path, page)
+                    .with_context(|| format!("Failed to write {}", path.display())),
+                None => {
+                    print!("{}", page);
+                    Ok(())
+                }
+            };
+        }
+        ExportTarget::Frames {
+            input,
+            dir,
+            every,
+            theme,
+        } => {
+            let theme = load_theme(theme, &config)?;
+            let cast = load_recording(&input, config).await?;
+            let count = frames::export_frames(&cast, every, &theme, &dir, widths)?;
+            eprintln!("Wrote {} frames to {}", count, dir.display());
+            return Ok(());
+        }
+    };
 
+    let script = convert::load_script(&script, &config.parse_options())?;
+    let settings = config::resolve(&Config::default(), &script, &config);
+    export::write_program(&generate(&script, &settings), output.as_ref())
+}
+
+// The theme given with --theme, or else in the config file
+fn load_theme(theme: Option<String>, config: &Config) -> Result<theme::Theme> {
+    match theme.or_else(|| config.theme.clone()) {
+        Some(spec) => theme::Theme::load(&spec),
+        None => Ok(theme::Theme::default()),
+    }
+}
+
+// A .cast file as it is, or a recording of a script played out of sight
+async fn load_recording(input: &Path, config: Config) -> Result<Cast> {
+    if input.extension().is_some_and(|ext| ext == "cast") {
+        let content = std::fs::read_to_string(input)
+            .with_context(|| format!("Failed to read cast file: {}", input.display()))?;
+        cast::parse_cast(&content)
+    } else {
+        let script = convert::load_script(input, &config.parse_options())?;
+        let engine = play_hidden(script, config).await?;
+        engine.recording().context("Session was not recorded")
+    }
+}
+
+// Play a script out of sight, where only a recording of it is kept
+async fn play_hidden(script: Script, config: Config) -> Result<PlaybackEngine> {
+    let options = SessionOptions {
+        shown: false,
+        ..SessionOptions::new(config)
+    };
+    let _fixtures = Fixtures::setup(&script)?;
+    let mut engine = start_session(&script, &options).await?;
+    // Keys sent before the shell is reading, with no delay to give it time to
+    // start as there is when shown, can be lost, and the end of its input too
+    engine.settle().await;
     engine
         .execute(script)
         .await
         .context("Failed to execute script")?;
+    engine.settle().await;
+    Ok(engine)
+}
+
+fn info(path: &Path, config: Config) -> Result<()> {
+    let script = convert::load_script(path, &config.parse_options())?;
+    let included = convert::load_included(path, &config.parse_options())?;
+    let settings = config::resolve(&Config::default(), &script, &config);
+    print!("{}", info::describe(&script, &settings, &included));
+    Ok(())
+}
+
+fn import(source: ImportSource) -> Result<()> {
+    let (script, output) = match source {
+        ImportSource::Cast { input, output } => {
+            let content = std::fs::read_to_string(&input)
+                .with_context(|| format!("Failed to read cast file: {}", input.display()))?;
+            (cast::import_cast(&content)?, output)
+        }
+        ImportSource::Doitlive {
+            input,
+            output,
+            pause,
+        } => {
+            let content = std::fs::read_to_string(&input)
+                .with_context(|| format!("Failed to read session file: {}", input.display()))?;
+            let pause = std::time::Duration::try_from_secs_f64(pause)
+                .context("Pause must be a non-negative number of seconds")?;
+            (doitlive::import_doitlive(&content, pause), output)
+        }
+        ImportSource::Ttyrec {
+            input,
+            output,
+            size: (cols, rows),
+        } => {
+            let data = std::fs::read(&input)
+                .with_context(|| format!("Failed to read ttyrec file: {}", input.display()))?;
+            return write_imported(ttyrec::parse_ttyrec(&data, cols, rows)?, output);
+        }
+        ImportSource::Script {
+            typescript,
+            timing,
+            output,
+        } => {
+            let data = std::fs::read(&typescript)
+                .with_context(|| format!("Failed to read typescript: {}", typescript.display()))?;
+            let timing = std::fs::read_to_string(&timing)
+                .with_context(|| format!("Failed to read timing file: {}", timing.display()))?;
+            return write_imported(typescript::parse_typescript(&data, &timing)?, output);
+        }
+    };
+
+    let format = output
+        .as_deref()
+        .and_then(ScriptFormat::from_path)
+        .unwrap_or(ScriptFormat::Qp);
+    convert::write_output(&script, output.as_ref(), format)
+}
+
+// A recording with input becomes a script, unless a .cast file is asked for;
+// one with only output stays a recording
+fn write_imported(cast: Cast, output: Option<PathBuf>) -> Result<()> {
+    let format = output.as_deref().and_then(ScriptFormat::from_path);
+    let as_cast = output
+        .as_deref()
+        .is_some_and(|path| path.extension().is_some_and(|ext| ext == "cast"));
+    if format.is_some() {
+        anyhow::ensure!(
+            cast::has_input(&cast),
+            "Recording has no input to rebuild a script from, write a .cast file instead"
+        );
+    }
+
+    if !as_cast && cast::has_input(&cast) {
+        let script = cast::script_from_cast(&cast)?;
+        return convert::write_output(&script, output.as_ref(), format.unwrap_or(ScriptFormat::Qp));
+    }
+    let content = cast::write_cast(&cast);
+    match output {
+        Some(path) => std::fs::write(&path, content)
+            .with_context(|| format!("Failed to write {}", path.display())),
+        None => {
+            print!("{}", content);
+            Ok(())
+        }
+    }
+}
+
+fn load_script(path: &Path, config: &Config) -> Result<Script> {
+    load_script_lines(path, config).map(|(script, _)| script)
+}
+
+// The script and the line each of its commands starts on
+fn load_script_lines(path: &Path, config: &Config) -> Result<(Script, Vec<usize>)> {
+    let mut content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read script file: {}", path.display()))?;
+    if ScriptFormat::from_path(path) == Some(ScriptFormat::Md) {
+        content = markdown::literate_source(&content)
+            .map_err(|e| Error::Script(format!("Parse error: {}", e)))?;
+    }
+    parser::parse_script_lines(&content, &config.parse_options().for_script(path))
+        .map_err(|e| Error::Script(format!("Parse error: {}", e)).into())
+}
+
+// Commands parsed from stdin as they arrive, which can only start running
+// once the commands before the first `$` line have set up the session.
+// Those are returned on their own, and then sent again with the rest
+async fn read_stdin_script(
+    config: &Config,
+) -> Result<(
+    Script,
+    tokio::sync::mpsc::Receiver<Result<types::Command, String>>,
+)> {
+    let commands = parser::parse_reader(
+        std::io::BufReader::new(std::io::stdin()),
+        &config.parse_options(),
+    )
+    .map_err(|e| Error::Script(format!("Parse error: {}", e)))?;
+    let (head_sender, head) = tokio::sync::oneshot::channel();
+    let (sender, receiver) = tokio::sync::mpsc::channel(STDIN_QUEUE);
+
+    std::thread::spawn(move || {
+        let mut commands = commands.peekable();
+        let mut preamble = Vec::new();
+        while let Some(Ok(command)) =
+            commands.next_if(|command| !matches!(command, Ok(types::Command::Type(_)) | Err(_)))
+        {
+            preamble.push(command);
+        }
+        let _ = head_sender.send(preamble.clone());
+        for command in preamble.into_iter().map(Ok).chain(commands) {
+            if sender.blocking_send(command).is_err() {
+                return;
+            }
+        }
+    });
+
+    let commands = head.await.context("Failed to read script from stdin")?;
+    Ok((Script { commands }, receiver))
+}
+
+// Commands read ahead of playback from stdin
+const STDIN_QUEUE: usize = 64;
+
+// Everything needed to start a session besides the script itself
+struct SessionOptions {
+    cli: Config,
+    config: Config,
+    resume_from: Option<String>,
+    sections: Option<Sections>,
+    show_keys: bool,
+    advance: bool,
+    captions: Option<PathBuf>,
+    note_captions: bool,
+    allow_clipboard: bool,
+    forward_clipboard: bool,
+    mute: bool,
+    record: Option<PathBuf>,
+    presenter: Option<Arc<PresenterServer>>,
+    stats: Option<StatsFormat>,
+    seed: Option<u64>,
+    webhook_url: Option<String>,
+    // Whether the session is mirrored to the terminal, rather than only recorded
+    shown: bool,
+    source: Source,
+    // With --isolate, shared by every session of a --watch
+    home: Option<IsolatedHome>,
+}
+
+// Where the commands to play come from
+#[derive(PartialEq)]
+enum Source {
+    File,
+    // Played as it's read from stdin
+    Stdin,
+    // Typed at a prompt on a status line with `quipu repl`
+    Repl,
+}
+
+impl SessionOptions {
+    fn new(config: Config) -> Self {
+        Self {
+            cli: Config::default(),
+            config,
+            resume_from: None,
+            sections: None,
+            show_keys: false,
+            advance: false,
+            captions: None,
+            note_captions: false,
+            allow_clipboard: false,
+            forward_clipboard: false,
+            mute: false,
+            record: None,
+            presenter: None,
+            stats: None,
+            seed: None,
+            webhook_url: None,
+            shown: true,
+            source: Source::File,
+            home: None,
+        }
+    }
+}
+
+async fn start_session(script: &Script, options: &SessionOptions) -> Result<PlaybackEngine> {
+    let settings = config::resolve(&options.cli, script, &options.config);
+    let (shell, cols, rows) = (&settings.shell, settings.cols, settings.rows);
+
+    if options.shown {
+        match options.source {
+            Source::File => println!("Parsed {} commands", script.commands.len()),
+            Source::Stdin => println!("Reading script from stdin"),
+            Source::Repl => println!("Type script lines at the prompt, Ctrl-D to quit"),
+        }
+        if let Some(title) = script.meta().title {
+            println!("Playing: {}", title);
+        }
+        // A profile's arguments are too long to be worth showing
+        match settings.shell_profile {
+            Some(profile) => println!("Using shell: {} with the {} profile", shell, profile.name),
+            None => println!(
+                "Using shell: {}",
+                std::iter::once(shell)
+                    .chain(&settings.shell_args)
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+        }
+    }
+    if options.cli.no_rc.or(options.config.no_rc) == Some(true)
+        && settings.shell_profile.is_none()
+        && config::no_rc_args(shell).is_none()
+    {
+        tracing::warn!(
+            "--no-rc doesn't know how to skip startup files for {}",
+            shell
+        );
+    }
+    if options.shown {
+        println!("Terminal size: {}x{}", cols, rows);
+        println!("Starting playback in 1 second...");
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+    }
+
+    // Status lines take the bottom rows, captions above keys, so the session gets fewer.
+    // They're never part of a recording, so a session out of sight has none
+    let has_captions =
+        options.shown && (script.has_captions() || options.note_captions && script.has_notes());
+    let status_rows = if options.shown && std::io::stdout().is_terminal() {
+        u16::from(has_captions)
+            + u16::from(options.show_keys)
+            + u16::from(options.source == Source::Repl)
+    } else {
+        0
+    };
+    anyhow::ensure!(
+        rows > status_rows,
+        "Terminal is too small for the status lines"
+    );
+    if !pty::has_terminfo(&settings.term) {
+        tracing::warn!(
+            "no terminfo entry for {}, programs may not draw properly",
+            settings.term
+        );
+    }
+    // Status rows take their share of the pixels too
+    let size = pty::PtySize {
+        cols,
+        rows: rows - status_rows,
+        pixel_width: settings.pixel_width,
+        pixel_height: (u32::from(settings.pixel_height) * u32::from(rows - status_rows)
+            / u32::from(rows)) as u16,
+    };
+    let redactor = redact::Redactor::new(&settings.redact).map_err(anyhow::Error::msg)?;
+    // A session out of sight is only ever played to record it
+    let recorder = (options.record.is_some() || !options.shown)
+        .then(|| cast::Recorder::new(cols, size.rows, script.meta().title, redactor.clone()));
+    let recorder = Arc::new(Mutex::new(recorder));
+    let output = pty::Output {
+        name: MAIN_SESSION.to_string(),
+        redactor,
+        shown: options.shown,
+        hidden: false,
+        panes: Arc::new(Panes::new(
+            settings.layout,
+            options.shown,
+            recorder.clone(),
+            size,
+        )),
+        recorder,
+        ambiguous_width: settings.ambiguous_width,
+        scrollback: settings.scrollback,
+        forward_clipboard: options.forward_clipboard,
+    };
+    let startup = match settings.shell_profile {
+        Some(profile) => profile.write_startup()?,
+        None => StartupFiles::default(),
+    };
+    let mut env = session_env(&settings);
+    env.extend(options.home.iter().flat_map(IsolatedHome::env));
+    // After --isolate's, as the profile's ZDOTDIR has to win
+    env.extend(
+        startup
+            .env
+            .iter()
+            .map(|(name, value)| (*name, value.as_str())),
+    );
+    let shell_args = settings.shell_argv(&startup.args);
+    // Sessions named by `$[NAME]` lines start the same way, out of sight until switched to
+    let spawn = {
+        let shell = shell.clone();
+        let shell_args = shell_args.clone();
+        let env: Vec<(String, String)> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let output = pty::Output {
+            hidden: true,
+            ..output.clone()
+        };
+        move |name: &str| {
+            let env: Vec<(&str, &str)> = env
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect();
+            let output = pty::Output {
+                name: name.to_string(),
+                ..output.clone()
+            };
+            let mut pty = pty::PtyManager::new(&shell, &shell_args, size, &env, output)
+                .context(Error::Pty)?;
+            if status_rows > 0 {
+                pty.reserve_status_rows(rows - status_rows + 1, status_rows)?;
+            }
+            Ok(pty)
+        }
+    };
+    let mut pty =
+        pty::PtyManager::new(shell, &shell_args, size, &env, output).context(Error::Pty)?;
+    pty.keep_startup_files(startup);
+    if status_rows > 0 {
+        pty.reserve_status_rows(rows - status_rows + 1, status_rows)?;
+    }
+
+    let mut engine =
+        PlaybackEngine::new(pty, settings.playback).context("Failed to create playback engine")?;
+    engine.start_sessions_with(spawn);
+    if let Some(name) = &options.resume_from {
+        engine.resume_from(name.clone());
+    }
+    if let Some(url) = &options.webhook_url {
+        engine.webhook_url(url.clone());
+    }
+    if let Some(offset) = options.config.timing_offset {
+        engine.timing_offset(std::time::Duration::from_secs_f64(offset));
+    }
+    if let Some(seed) = options.seed {
+        engine.seed(seed);
+    }
+    if let Some(sections) = &options.sections {
+        engine.select_sections(sections.clone());
+    }
+    if has_captions {
+        engine.show_captions(0);
+    }
+    if options.note_captions {
+        engine.show_note_captions();
+    }
+    // Sessions out of sight, as when testing or exporting, stay quiet too
+    if options.shown && !options.mute {
+        engine.play_sounds(options.config.say_command.clone());
+    }
+    engine.directives(options.config.directives.clone());
+    if let Some(command) = &options.config.lua_command {
+        engine.lua_command(command.clone());
+    }
+    if options.allow_clipboard {
+        engine.allow_clipboard();
+    }
+    if options.shown && options.show_keys {
+        engine.show_keys(usize::from(has_captions));
+    }
+    if options.advance {
+        engine.advance_on_keypress();
+    }
+    if let Some(server) = &options.presenter {
+        engine.present_to(server.clone());
+    }
+    Ok(engine)
+}
+
+// Environment for the session's shell, on top of quipu's own
+fn session_env(settings: &config::Settings) -> Vec<(&str, &str)> {
+    let mut env = vec![("TERM", settings.term.as_str())];
+    if let Some(colorterm) = &settings.colorterm {
+        env.push(("COLORTERM", colorterm));
+    }
+    // fish ignores HISTFILE and has its own private mode instead
+    if settings.playback.no_history == Some(NoHistory::Histfile) {
+        env.extend([("HISTFILE", "/dev/null"), ("fish_private_mode", "1")]);
+    }
+    env
+}
+
+fn write_recording(engine: &PlaybackEngine, path: Option<&Path>) -> Result<()> {
+    let (Some(path), Some(cast)) = (path, engine.recording()) else {
+        return Ok(());
+    };
+    std::fs::write(path, cast::write_cast(&cast))
+        .with_context(|| format!("Failed to write recording: {}", path.display()))
+}
+
+fn write_captions(engine: &PlaybackEngine, path: Option<&Path>) -> Result<()> {
+    let Some(path) = path else {
+        return Ok(());
+    };
+    if engine.captions().is_empty() {
+        eprintln!("Script has no captions, not writing {}", path.display());
+        return Ok(());
+    }
+    std::fs::write(path, engine.captions().to_webvtt())
+        .with_context(|| format!("Failed to write captions: {}", path.display()))
+}
+
+// A home for the session with --isolate, seeded from the directory it was given if any
+fn isolated_home(args: &Args) -> Result<Option<IsolatedHome>> {
+    args.isolate
+        .as_ref()
+        .map(|dotfiles| IsolatedHome::new(dotfiles.as_deref()))
+        .transpose()
+}
+
+// Library directories for `@ include:` and `@ call:`, each `:` separated
+// (`;` on Windows), searched after --lib-path in this order
+const LIB_PATH_VARS: [&str; 2] = ["QUIPU_PATH", "TYPECAST_PATH"];
+
+// The config file, with --strict and the library directories from
+// --lib-path, $QUIPU_PATH and $TYPECAST_PATH searched before its own
+fn load_config(args: &Args) -> Result<Config> {
+    let mut config = Config::load(args.config.as_deref())?;
+    if args.strict {
+        config.strict = Some(true);
+    }
+    let env_paths: Vec<OsString> = LIB_PATH_VARS.iter().filter_map(std::env::var_os).collect();
+    config.lib_path = args
+        .lib_path
+        .iter()
+        .cloned()
+        .chain(
+            env_paths
+                .iter()
+                .flat_map(std::env::split_paths)
+                .filter(|dir| !dir.as_os_str().is_empty()),
+        )
+        .chain(config.lib_path)
+        .collect();
+    Ok(config)
+}
+
+// Session settings given on the command line
+fn cli_config(args: &Args) -> Config {
+    Config {
+        shell: args.shell.clone(),
+        shell_args: args.shell_args.as_deref().map(parser::split_args),
+        no_rc: args.no_rc.then_some(true),
+        shell_profile: args.shell_profile.clone(),
+        term: args.term.clone(),
+        colorterm: args.colorterm.clone(),
+        cell_size: args.cell_size,
+        ambiguous_width: args.ambiguous_width,
+        scrollback: args.scrollback,
+        redact: args.redact.clone(),
+        ..Config::default()
+    }
+}
+
+async fn repl(args: Args, append: Option<PathBuf>) -> Result<()> {
+    anyhow::ensure!(
+        input::has_terminal() && std::io::stdout().is_terminal(),
+        "repl needs an interactive terminal"
+    );
+    let config = load_config(&args)?;
+    let options = SessionOptions {
+        cli: cli_config(&args),
+        home: isolated_home(&args)?,
+        show_keys: args.show_keys,
+        record: args.record,
+        seed: args.seed,
+        source: Source::Repl,
+        ..SessionOptions::new(config)
+    };
+    let mut engine = start_session(&Script { commands: vec![] }, &options).await?;
+    // The prompt goes below the keys
+    let prompt_line = usize::from(options.show_keys);
+    quipu::repl::run(
+        &mut engine,
+        prompt_line,
+        &options.config.parse_options(),
+        append.as_deref(),
+    )
+    .await?;
+    write_recording(&engine, options.record.as_deref())?;
+    drop(engine);
+
+    if let Some(path) = &append {
+        println!("\nLines added to {}", path.display());
+    }
+    Ok(())
+}
+
+async fn bench(args: Args, keys: usize, speed: f64, save: bool) -> Result<()> {
+    anyhow::ensure!(keys >= 2, "--keys needs at least 2 keys to time");
+    anyhow::ensure!(
+        speed > 0.0 && speed.is_finite(),
+        "--speed must be a positive number of seconds"
+    );
+    let config = Config::load(args.config.as_deref())?;
+    let settings = config::resolve(&cli_config(&args), &Script { commands: vec![] }, &config);
+    let size = pty::PtySize {
+        cols: settings.cols,
+        rows: settings.rows,
+        pixel_width: settings.pixel_width,
+        pixel_height: settings.pixel_height,
+    };
+    let output = pty::Output {
+        name: MAIN_SESSION.to_string(),
+        redactor: redact::Redactor::new(&[]).map_err(anyhow::Error::msg)?,
+        shown: std::io::stdout().is_terminal(),
+        hidden: false,
+        recorder: Default::default(),
+        panes: Arc::new(Panes::new(
+            PaneLayout::Single,
+            false,
+            Default::default(),
+            size,
+        )),
+        ambiguous_width: settings.ambiguous_width,
+        scrollback: 0,
+        forward_clipboard: false,
+    };
+    let pty = pty::PtyManager::new(
+        &settings.shell,
+        &settings.shell_args,
+        size,
+        &session_env(&settings),
+        output,
+    )
+    .context(Error::Pty)?;
+
+    // Measured without any saved offset, so a new one replaces it rather than adding to it
+    let report = quipu::bench::run(pty, std::time::Duration::from_secs_f64(speed), keys).await?;
+    println!("\n{}", report.summary());
+    if save {
+        let offset = format!("{:.4}", report.offset().as_secs_f64());
+        let path = Config::save_setting(args.config.as_deref(), "timing_offset", &offset)?;
+        println!("Saved timing_offset = {} to {}", offset, path.display());
+    }
+    Ok(())
+}
+
+async fn play(args: Args) -> Result<()> {
+    let script_path = match args.script.clone() {
+        Some(path) => path,
+        None if !std::io::stdin().is_terminal() => PathBuf::from("-"),
+        None => anyhow::bail!("No script file given"),
+    };
+    anyhow::ensure!(
+        script_path != Path::new("-") || !std::io::stdin().is_terminal(),
+        "Can't read a script from stdin while it's a terminal, pipe one in instead"
+    );
+    let options = SessionOptions {
+        cli: cli_config(&args),
+        config: load_config(&args)?,
+        home: isolated_home(&args)?,
+        resume_from: args.resume_from,
+        sections: args
+            .skip
+            .map(|Markers(names)| Sections::Skip(names))
+            .or(args.only.map(|Markers(names)| Sections::Only(names))),
+        show_keys: args.show_keys,
+        advance: args.advance,
+        captions: args.captions,
+        note_captions: args.note_captions,
+        allow_clipboard: args.allow_clipboard,
+        forward_clipboard: args.forward_clipboard,
+        mute: args.mute,
+        record: args.record,
+        presenter: args
+            .presenter_socket
+            .as_deref()
+            .map(PresenterServer::bind)
+            .transpose()?
+            .map(Arc::new),
+        stats: args.stats,
+        seed: args.seed,
+        webhook_url: args.webhook_url,
+        shown: true,
+        source: if script_path == Path::new("-") {
+            Source::Stdin
+        } else {
+            Source::File
+        },
+    };
+
+    anyhow::ensure!(
+        !options.advance || input::has_terminal(),
+        "--advance needs an interactive terminal to read keypresses from"
+    );
+
+    if args.watch {
+        anyhow::ensure!(
+            options.source == Source::File,
+            "--watch needs a script file"
+        );
+        return watch(&script_path, &options).await;
+    }
+
+    anyhow::ensure!(
+        options.source == Source::File
+            || (options.resume_from.is_none() && options.sections.is_none()),
+        "--resume-from, --skip and --only need a script file"
+    );
+    let (script, stream, lines) = if options.source == Source::Stdin {
+        let (head, commands) = read_stdin_script(&options.config).await?;
+        (head, Some(commands), Vec::new())
+    } else {
+        let (script, lines) = load_script_lines(&script_path, &options.config)?;
+        (script, None, lines)
+    };
+    let fixtures = Fixtures::setup(&script)?;
+    let mut engine = start_session(&script, &options).await?;
+    if let Some(dir) = &args.artifacts {
+        let name = match options.source {
+            Source::Stdin => "stdin".to_string(),
+            _ => script_path.file_stem().map_or_else(
+                || "script".to_string(),
+                |s| s.to_string_lossy().into_owned(),
+            ),
+        };
+        let theme = load_theme(None, &options.config)?;
+        engine.save_artifacts(Artifacts::new(dir, &name, lines.clone(), theme));
+    }
+    #[cfg(feature = "otlp")]
+    let tracer = args.otlp_endpoint.as_ref().map(|endpoint| {
+        let tracer = quipu::otlp::Tracer::new(&script_path.display().to_string());
+        engine.add_hooks(tracer.hooks());
+        (tracer, endpoint)
+    });
+    if let Some(limit) = args.max_duration {
+        engine.limit_duration(limit);
+    }
+    let played = match stream {
+        Some(commands) => engine.execute_stream(commands).await,
+        None => engine.execute(script).await,
+    };
+    // Written however playback ended, as it's most use when something went wrong
+    if let Some(path) = &args.final_dump {
+        engine.dump_scrollback(path).await?;
+    }
+    let timed_out = played
+        .as_ref()
+        .is_err_and(|e| matches!(e.downcast_ref::<Error>(), Some(Error::Timeout(_))));
+    let result = if timed_out {
+        // Stopping the shell hangs up on whatever it's running too
+        engine.stop()?;
+        played
+    } else {
+        played.context("Failed to execute script")
+    };
+    #[cfg(feature = "otlp")]
+    if let Some((tracer, endpoint)) = &tracer {
+        let error = result.as_ref().err().map(|e| format!("{:#}", e));
+        if let Err(e) = tracer.export(endpoint, error.as_deref()).await {
+            tracing::warn!("Failed to send spans to {}: {:#}", endpoint, e);
+        }
+    }
+    if result.is_ok() {
+        write_captions(&engine, options.captions.as_deref())?;
+        write_recording(&engine, options.record.as_deref())?;
+    }
+    if let Some(path) = &args.summary {
+        let mut summary = Summary::new(
+            script_path.display().to_string(),
+            engine.stats(),
+            &lines,
+            result.as_ref().err(),
+        );
+        // The shell is only told its input has ended once playback is over
+        summary.shell_exit_code = engine.sink().hang_up();
+        summary.artifacts = summary::Files {
+            recording: options.record.clone().filter(|_| result.is_ok()),
+            captions: options
+                .captions
+                .clone()
+                .filter(|_| result.is_ok() && !engine.captions().is_empty()),
+            final_dump: args.final_dump.clone(),
+            failure: engine.failure_artifacts().map(Path::to_path_buf),
+        };
+        std::fs::write(path, summary.to_json())
+            .with_context(|| format!("Failed to write summary: {}", path.display()))?;
+    }
+    result?;
+    let webhook_errors = engine.webhook_errors().to_vec();
+
+    let stats = options.stats.map(|format| match format {
+        StatsFormat::Text => Ok(engine.stats().summary()),
+        StatsFormat::Json => serde_json::to_string_pretty(engine.stats()).map(|json| json + "\n"),
+    });
 
     // Drop the engine and PTY explicitly to clean up and restore terminal state
     // before printing completion message
     drop(engine);
+    drop(fixtures);
 
     println!("\nPlayback complete!");
+    for error in &webhook_errors {
+        tracing::warn!("Webhook failed: {}", error);
+    }
+    if let Some(stats) = stats {
+        print!("\n{}", stats.context("Failed to write statistics")?);
+    }
+    if let Some(path) = options.record.as_deref().filter(|_| args.publish) {
+        let url = publish::publish(path, &publish::server_url(None)).await?;
+        println!("\nPublished: {}", url);
+    }
 
     // Brief pause so user can see the result
     tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
 
     Ok(())
 }
+
+async fn watch(script_path: &Path, options: &SessionOptions) -> Result<()> {
+    let mut watcher = watch::Watcher::new(script_path);
+
+    loop {
+        let restart = match load_script(script_path, &options.config) {
+            Ok(script) => {
+                let fixtures = Fixtures::setup(&script)?;
+                let mut engine = start_session(&script, options).await?;
+                let restart = tokio::select! {
+                    result = engine.execute(script) => {
+                        result.context("Failed to execute script")?;
+                        write_captions(&engine, options.captions.as_deref())?;
+                        write_recording(&engine, options.record.as_deref())?;
+                        for error in engine.webhook_errors() {
+                            tracing::warn!("Webhook failed: {}", error);
+                        }
+                        false
+                    }
+                    changed = watcher.changed() => changed,
+                };
+                if restart {
+                    engine.stop()?;
+                }
+                drop(engine);
+                drop(fixtures);
+                restart
+            }
+            Err(e) => {
+                eprintln!("{:#}", e);
+                false
+            }
+        };
+
+        if !restart {
+            eprintln!("\nWatching {} for changes...", script_path.display());
+            if !watcher.changed().await {
+                return Ok(());
+            }
+        }
+
+        // Leave whatever screen the last session set up before starting over
+        if std::io::stdout().is_terminal() {
+            print!("\x1b[?1049l\x1b[?25h\x1b[2J\x1b[H");
+        }
+        println!("\nScript changed, restarting playback...");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_play_args() {
+        let argv = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(
+            play_args(argv(&["quipu", "--no-rc", "test", "a.qp"])),
+            argv(&["--no-rc"])
+        );
+        // An option's value can be `test` too
+        assert_eq!(
+            play_args(argv(&["quipu", "--summary", "test", "test", "a.qp"])),
+            argv(&["--summary", "test"])
+        );
+        assert_eq!(play_args(argv(&["quipu", "test", "test"])), argv(&[]));
+    }
+}
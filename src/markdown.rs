@@ -0,0 +1,478 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Markdown tutorials, written from scripts and read as them
+//!
+//! For `quipu export md`, `#` comments become the prose and `$` lines the
+//! commands between them, each run of commands followed by the output it
+//! printed. The output comes from playing the script out of sight with a
+//! scrollback dump after each run, keeping the lines that weren't in the
+//! dump before. A run that ends with Enter is dumped once the prompt is
+//! back, as `@ waitprompt` finds it, so `@ prompt-pattern` may be needed
+//! for shells with other prompts.
+//!
+//! Going the other way, a literate Markdown script keeps its script lines in
+//! fenced `quipu` blocks, or `console` blocks of `$` commands and output.
+//! Headings become checkpoints and paragraphs captions
+
+use std::path::Path;
+
+use crate::format::{format_command, format_type_content};
+use crate::key::Keys;
+use crate::parser::{ParseOptions, ScriptParser, parse_script};
+use crate::types::{Command, Script, ScriptMeta};
+
+#[derive(Debug, PartialEq)]
+enum Section {
+    // A paragraph, as written
+    Prose(String),
+    // Command lines as they'd be typed at a prompt
+    Commands(Vec<String>),
+}
+
+/// A script split into prose and commands
+#[derive(Debug)]
+pub struct Tutorial {
+    meta: ScriptMeta,
+    sections: Vec<Section>,
+}
+
+impl Tutorial {
+    /// Split `source`, along with the script to play for the output, which
+    /// has a `@ dump-scrollback` to `dumps/0.txt` and on after each run of commands
+    pub fn parse(
+        source: &str,
+        options: &ParseOptions,
+        dumps: &Path,
+    ) -> Result<(Self, Script), String> {
+        let mut parser = ScriptParser::new(options)?;
+        let mut sections = Vec::new();
+        let mut commands = Vec::new();
+        let mut paragraph: Vec<&str> = Vec::new();
+        // Typed since the last Enter
+        let mut typed = Keys::new();
+        // Runs of commands so far, and whether the last one has been dumped
+        let mut runs = 0;
+        let mut dumped = true;
+
+        for line in source.lines() {
+            let trimmed = line.trim();
+            // The rest of a `$` line is a command like any other once it's joined up
+            let continued = parser.is_continued();
+            let open = parser.is_open() && !continued;
+            let played = parser.next_line(line)?;
+            if open {
+                commands.extend(played);
+                continue;
+            }
+            // Shebangs and line notes aren't prose
+            if let Some(text) = trimmed
+                .strip_prefix('#')
+                .filter(|_| !continued && !trimmed.starts_with("#!"))
+            {
+                if !dumped {
+                    end_run(&mut commands, dumps, runs - 1, &typed);
+                    dumped = true;
+                }
+                match text.strip_prefix(' ').unwrap_or(text) {
+                    "" => end_paragraph(&mut paragraph, &mut sections),
+                    text => paragraph.push(text),
+                }
+                continue;
+            }
+            // Blocks are played but not shown, as a tutorial has one way through
+            end_paragraph(&mut paragraph, &mut sections);
+            for command in played {
+                if let Command::Type(text) = &command {
+                    if !matches!(sections.last(), Some(Section::Commands(_))) {
+                        sections.push(Section::Commands(Vec::new()));
+                        runs += 1;
+                        dumped = false;
+                    }
+                    let Some(Section::Commands(lines)) = sections.last_mut() else {
+                        unreachable!()
+                    };
+                    typed.append(text.clone());
+                    while let Some((line, rest)) = typed.split_line() {
+                        lines.push(format_type_content(&line));
+                        typed = rest;
+                    }
+                }
+                commands.push(command);
+            }
+        }
+        parser.finish()?;
+        end_paragraph(&mut paragraph, &mut sections);
+        if !dumped {
+            end_run(&mut commands, dumps, runs - 1, &typed);
+        }
+        let script = Script { commands };
+        let tutorial = Self {
+            meta: script.meta(),
+            sections,
+        };
+        Ok((tutorial, script))
+    }
+
+    /// The Markdown, with the output from the scrollback dumps in `dumps`
+    /// after each run of commands, if there are any
+    pub fn to_markdown(&self, dumps: Option<&Path>) -> String {
+        let mut blocks = Vec::new();
+        blocks.extend(self.meta.title.iter().map(|title| format!("# {}", title)));
+        blocks.extend(self.meta.description.clone());
+
+        let mut before: Vec<String> = Vec::new();
+        let mut run = 0;
+        for section in &self.sections {
+            match section {
+                Section::Prose(text) => blocks.push(text.clone()),
+                Section::Commands(lines) => {
+                    // Keys typed without pressing Enter aren't commands
+                    if !lines.is_empty() {
+                        blocks.push(fenced("sh", &lines.join("\n")));
+                    }
+                    let Some(dumps) = dumps else { continue };
+                    let history = std::fs::read_to_string(dumps.join(format!("{}.txt", run)))
+                        .unwrap_or_default();
+                    run += 1;
+                    let after: Vec<String> = history.lines().map(str::to_string).collect();
+                    let output = new_output(&before, &after, lines);
+                    if !output.is_empty() {
+                        blocks.push(fenced("text", &output.join("\n")));
+                    }
+                    before = after;
+                }
+            }
+        }
+
+        let mut out = blocks.join("\n\n");
+        out.push('\n');
+        out
+    }
+}
+
+// Dump the scrollback after run `run`, waiting for its last command to finish
+// first if it was entered, rather than left typed without pressing Enter
+fn end_run(commands: &mut Vec<Command>, dumps: &Path, run: usize, typed: &Keys) {
+    if typed.is_empty() {
+        commands.push(Command::WaitPrompt);
+    }
+    commands.push(Command::DumpScrollback(
+        dumps.join(format!("{}.txt", run)).display().to_string(),
+    ));
+}
+
+fn end_paragraph(paragraph: &mut Vec<&str>, sections: &mut Vec<Section>) {
+    if !paragraph.is_empty() {
+        sections.push(Section::Prose(paragraph.join("\n")));
+        paragraph.clear();
+    }
+}
+
+// Lines in the scrollback `after` since `before`, without the prompt lines
+// the commands were typed at or the prompt left waiting at the end
+fn new_output<'a>(before: &[String], after: &'a [String], commands: &[String]) -> Vec<&'a str> {
+    // The last line before was the prompt, which has been typed on since
+    let kept = before.len().saturating_sub(1);
+    let start = match after.starts_with(&before[..kept]) {
+        true => kept,
+        // The screen was cleared or the scrollback ran out
+        false => 0,
+    };
+    let end = after.len().saturating_sub(1).max(start);
+    // Each command is echoed before its output, on the first line since the
+    // one before's that ends with it
+    let mut echoes = commands.iter().peekable();
+    after[start..end]
+        .iter()
+        .map(|line| line.trim_end())
+        .filter(|line| {
+            let echo = echoes
+                .peek()
+                .is_some_and(|command| line.ends_with(command.as_str()));
+            if echo {
+                echoes.next();
+            }
+            !echo
+        })
+        .collect()
+}
+
+// A fenced code block, with a longer fence if the text has one of its own
+fn fenced(lang: &str, text: &str) -> String {
+    let mut fence = "```".to_string();
+    while text.contains(&fence) {
+        fence.push('`');
+    }
+    format!("{}{}\n{}\n{}", fence, lang, text, fence)
+}
+
+// Fenced blocks a literate script is played from
+#[derive(Clone, Copy, PartialEq)]
+enum Fence {
+    // Script lines as they are
+    Script,
+    // `$` commands to type, between output that's left out
+    Console,
+    // Any other code, which isn't played
+    Other,
+}
+
+/// Read a literate Markdown script
+pub fn parse_literate(input: &str, options: &ParseOptions) -> Result<Script, String> {
+    parse_script(&literate_source(input)?, options)
+}
+
+/// The script lines of a literate Markdown script, one for each line of
+/// Markdown so errors point to the right one
+pub fn literate_source(input: &str) -> Result<String, String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut fence: Option<(&str, Fence)> = None;
+    // The paragraph being read, and the line it started on
+    let mut paragraph: Option<(usize, Vec<&str>)> = None;
+
+    for line in input.lines() {
+        let trimmed = line.trim();
+        if let Some((marker, kind)) = fence {
+            if trimmed.starts_with(marker) && trimmed.trim_start_matches(&marker[..1]).is_empty() {
+                fence = None;
+                lines.push(String::new());
+                continue;
+            }
+            lines.push(match kind {
+                Fence::Script => line.to_string(),
+                Fence::Console => match trimmed.strip_prefix('$') {
+                    Some(command) => format!(
+                        "$ {}",
+                        format_type_content(&Keys::text(&format!("{}\r", command.trim())))
+                    ),
+                    None => String::new(),
+                },
+                Fence::Other => String::new(),
+            });
+            continue;
+        }
+
+        if let Some(opened) = open_fence(trimmed) {
+            end_caption(&mut paragraph, &mut lines);
+            fence = Some(opened);
+            lines.push(String::new());
+        } else if let Some(heading) = heading(trimmed) {
+            end_caption(&mut paragraph, &mut lines);
+            lines.push(match slug(heading) {
+                name if name.is_empty() => String::new(),
+                name => format!("@ checkpoint:{}", name),
+            });
+        } else if trimmed.is_empty() {
+            end_caption(&mut paragraph, &mut lines);
+            lines.push(String::new());
+        } else {
+            paragraph
+                .get_or_insert_with(|| (lines.len(), Vec::new()))
+                .1
+                .push(trimmed);
+            lines.push(String::new());
+        }
+    }
+    if fence.is_some() {
+        return Err(format!(
+            "Line {}: Fenced block without a closing fence",
+            lines.len()
+        ));
+    }
+    end_caption(&mut paragraph, &mut lines);
+    Ok(lines.join("\n"))
+}
+
+// The fence and what kind of block a line opens, if it opens one
+fn open_fence(line: &str) -> Option<(&str, Fence)> {
+    let marker_char = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let length = line.len() - line.trim_start_matches(marker_char).len();
+    if length < 3 {
+        return None;
+    }
+    let (marker, info) = line.split_at(length);
+    let kind = match info.split_whitespace().next() {
+        Some("quipu") => Fence::Script,
+        Some("console") => Fence::Console,
+        _ => Fence::Other,
+    };
+    Some((marker, kind))
+}
+
+// The text of an ATX heading such as `## Setup`
+fn heading(line: &str) -> Option<&str> {
+    let text = line.trim_start_matches('#');
+    let level = line.len() - text.len();
+    ((1..=6).contains(&level) && (text.is_empty() || text.starts_with(' ')))
+        .then(|| text.trim().trim_end_matches('#').trim())
+}
+
+// A checkpoint name for a heading, such as `install-the-cli`
+fn slug(heading: &str) -> String {
+    let words: Vec<String> = heading
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    words.join("-")
+}
+
+// A paragraph as one caption line, on the line it started on
+fn end_caption(paragraph: &mut Option<(usize, Vec<&str>)>, lines: &mut [String]) {
+    if let Some((start, text)) = paragraph.take() {
+        lines[start] = format!("@ caption:{}", plain_text(&text.join(" ")));
+    }
+}
+
+// Markdown's emphasis, code spans and link targets left out, as captions are plain text
+fn plain_text(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        rest = &rest[c.len_utf8()..];
+        match c {
+            '*' | '`' | '[' => {}
+            ']' if rest.starts_with('(') => {
+                rest = rest.find(')').map_or("", |end| &rest[end + 1..]);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Write a script as literate Markdown, with captions as paragraphs and
+/// checkpoints as headings
+pub fn format_literate(script: &Script) -> String {
+    let mut blocks = Vec::new();
+    let mut lines: Vec<String> = Vec::new();
+    for command in &script.commands {
+        let prose = match command {
+            Command::Caption(text) if !text.is_empty() => text.clone(),
+            Command::Checkpoint(name) => format!("## {}", name),
+            command => {
+                lines.push(format_command(command));
+                continue;
+            }
+        };
+        if !lines.is_empty() {
+            blocks.push(fenced("quipu", &lines.join("\n")));
+            lines.clear();
+        }
+        blocks.push(prose);
+    }
+    if !lines.is_empty() {
+        blocks.push(fenced("quipu", &lines.join("\n")));
+    }
+
+    let mut out = blocks.join("\n\n");
+    out.push('\n');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_markdown() {
+        let source = "@ title:Listing files\n# Start with an empty directory.\n\
+            $ mkdir demo && cd demo<ret>\n$ ls<ret>\n\n# Then make a file\n# and list it again:\n\
+            @ wait:1\n$ touch notes.txt<ret>\n$ ls<ret>\n";
+        let dumps = Path::new("/tmp/dumps");
+        let (tutorial, script) = Tutorial::parse(source, &ParseOptions::default(), dumps).unwrap();
+        assert_eq!(script.commands[3], Command::WaitPrompt);
+        assert_eq!(
+            script.commands[4],
+            Command::DumpScrollback("/tmp/dumps/0.txt".to_string())
+        );
+        assert_eq!(script.commands.len(), 10);
+        assert_eq!(
+            tutorial.to_markdown(None),
+            "# Listing files\n\nStart with an empty directory.\n\n\
+            ```sh\nmkdir demo && cd demo\nls\n```\n\n\
+            Then make a file\nand list it again:\n\n```sh\ntouch notes.txt\nls\n```\n"
+        );
+
+        let before = ["$ mkdir demo && cd demo".to_string(), "$ ".to_string()];
+        let after = before[..1]
+            .iter()
+            .cloned()
+            .chain(["$ ls -a".to_string(), ".  ..".to_string(), "$ ".to_string()])
+            .collect::<Vec<_>>();
+        assert_eq!(
+            new_output(&before, &after, &["ls -a".to_string()]),
+            [".  .."]
+        );
+
+        // Only the echo goes, not output that happens to end the same way
+        let after = ["$ ls", "tools", "$ "].map(str::to_string);
+        assert_eq!(new_output(&[], &after, &["ls".to_string()]), ["tools"]);
+    }
+
+    #[test]
+    fn test_markdown_continued_lines() {
+        let source = "# Run it
+$ docker run --rm \\
+    # not prose \\
+    alpine<ret>
+# Done
+";
+        let (tutorial, script) =
+            Tutorial::parse(source, &ParseOptions::default(), Path::new("/tmp")).unwrap();
+        assert_eq!(
+            script.commands[0],
+            Command::Type("docker run --rm # not prose alpine\r".into())
+        );
+        assert_eq!(
+            tutorial.to_markdown(None),
+            "Run it\n\n```sh\ndocker run --rm # not prose alpine\n```\n\nDone\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_literate() {
+        let input = "# Getting started\n\nFirst, **list** the\n[files](https://example.com):\n\n\
+            ```console\n$ ls -a\n.  ..\n```\n\n```sh\nnot played\n```\n\n\
+            ~~~quipu\n@ wait:1\n$ <C-d>\n~~~\n";
+        let script = parse_literate(input, &ParseOptions::default()).unwrap();
+        assert_eq!(
+            script.commands,
+            [
+                Command::Checkpoint("getting-started".to_string()),
+                Command::Caption("First, list the files:".to_string()),
+                Command::Type("ls -a\r".into()),
+                Command::Wait(std::time::Duration::from_secs(1)),
+                Command::Type("\x04".into()),
+            ]
+        );
+
+        // Errors point to the line in the Markdown
+        let error = parse_literate("Text\n\n```quipu\n@ bogus\n```\n", &ParseOptions::default());
+        assert!(error.unwrap_err().starts_with("Line 4:"));
+
+        let written = format_literate(&script);
+        assert!(written.starts_with(
+            "## getting-started\n\nFirst, list the files:\n\n```quipu\n$ ls -a<ret>\n"
+        ));
+        assert_eq!(
+            parse_literate(&written, &ParseOptions::default())
+                .unwrap()
+                .commands,
+            script.commands
+        );
+    }
+}
@@ -0,0 +1,258 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! OpenTelemetry spans for `--otlp-endpoint`, built with the `otlp` feature
+//!
+//! Each run is a trace with a span for the script and one under it for each
+//! command played, expects included, so a step that got slow shows up in the
+//! same tracing backend as the services the script exercises. Spans are kept
+//! until playback ends, then sent in one request as OTLP/HTTP JSON to the
+//! endpoint's `/v1/traces`. As with webhooks only plain `http://` is
+//! supported, which suits a local collector. A W3C `TRACEPARENT` in the
+//! environment, as CI tracing sets, makes the run part of that trace
+
+use anyhow::Result;
+use serde_json::{Value, json};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::events::PlaybackHooks;
+use crate::format::format_command;
+use crate::types::Command;
+
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Span {
+    id: String,
+    name: String,
+    start: SystemTime,
+    end: Option<SystemTime>,
+    attributes: Vec<(&'static str, Value)>,
+}
+
+struct Trace {
+    id: String,
+    // The span this run's is under, from TRACEPARENT
+    parent: Option<String>,
+    script: Span,
+    commands: Vec<Span>,
+}
+
+/// The spans of one run, filled in by the hooks it hands out
+pub struct Tracer {
+    trace: Arc<Mutex<Trace>>,
+}
+
+// The trace and span IDs in a W3C `traceparent` header
+fn parse_traceparent(value: &str) -> Option<(String, String)> {
+    let parts: Vec<&str> = value.trim().split('-').collect();
+    let [_, trace, span, _] = parts[..] else {
+        return None;
+    };
+    let is_id = |id: &str, len| id.len() == len && id.bytes().all(|b| b.is_ascii_hexdigit());
+    (is_id(trace, 32) && is_id(span, 16)).then(|| (trace.to_string(), span.to_string()))
+}
+
+fn span_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+// A command's span is named after its kind, as in the JSON script format
+fn span_name(command: &Command) -> String {
+    match serde_json::to_value(command) {
+        Ok(Value::String(name)) => name,
+        Ok(Value::Object(map)) => map.keys().next().cloned().unwrap_or_default(),
+        _ => "command".to_string(),
+    }
+}
+
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+impl Tracer {
+    pub fn new(script: &str) -> Self {
+        let (id, parent) = match std::env::var("TRACEPARENT")
+            .ok()
+            .and_then(|value| parse_traceparent(&value))
+        {
+            Some((id, parent)) => (id, Some(parent)),
+            None => (format!("{:032x}", rand::random::<u128>()), None),
+        };
+        let script = Span {
+            id: span_id(),
+            name: "script".to_string(),
+            start: SystemTime::now(),
+            end: None,
+            attributes: vec![("quipu.script", json!(script))],
+        };
+        Self {
+            trace: Arc::new(Mutex::new(Trace {
+                id,
+                parent,
+                script,
+                commands: Vec::new(),
+            })),
+        }
+    }
+
+    /// Hooks for the engine that record a span for each command
+    pub fn hooks(&self) -> impl PlaybackHooks + 'static {
+        TraceHooks(self.trace.clone())
+    }
+
+    /// The trace as an OTLP export request, with `error` set on the span
+    /// of the command that failed, or the script's if none was running
+    fn request(&self, error: Option<&str>) -> Value {
+        let trace = self.trace.lock().unwrap();
+        let end = SystemTime::now();
+        let status = |failed: bool| match error {
+            Some(message) if failed => json!({ "code": 2, "message": message }),
+            _ => json!({}),
+        };
+        let failed_command = trace.commands.last().filter(|span| span.end.is_none());
+
+        let span_json = |span: &Span, parent: Option<&str>, failed: bool| {
+            let attributes: Vec<Value> = span
+                .attributes
+                .iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        Value::Number(n) => json!({ "intValue": n.to_string() }),
+                        other => json!({ "stringValue": other.as_str().unwrap_or_default() }),
+                    };
+                    json!({ "key": key, "value": value })
+                })
+                .collect();
+            let mut span_json = json!({
+                "traceId": trace.id,
+                "spanId": span.id,
+                "name": span.name,
+                "kind": 1,
+                "startTimeUnixNano": nanos(span.start),
+                "endTimeUnixNano": nanos(span.end.unwrap_or(end)),
+                "attributes": attributes,
+                "status": status(failed),
+            });
+            if let Some(parent) = parent {
+                span_json["parentSpanId"] = json!(parent);
+            }
+            span_json
+        };
+
+        let mut spans = vec![span_json(
+            &trace.script,
+            trace.parent.as_deref(),
+            error.is_some() && failed_command.is_none(),
+        )];
+        spans.extend(trace.commands.iter().map(|span| {
+            let failed = span.end.is_none();
+            span_json(span, Some(&trace.script.id), failed)
+        }));
+
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [
+                        { "key": "service.name", "value": { "stringValue": "quipu" } },
+                    ],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "quipu", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": spans,
+                }],
+            }],
+        })
+    }
+
+    /// Send the trace to `endpoint`, with `error` if playback failed
+    pub async fn export(&self, endpoint: &str, error: Option<&str>) -> Result<()> {
+        let url = match endpoint.trim_end_matches('/') {
+            url if url.ends_with("/v1/traces") => url.to_string(),
+            url => format!("{}/v1/traces", url),
+        };
+        let body = self.request(error).to_string();
+        match tokio::time::timeout(EXPORT_TIMEOUT, crate::webhook::post(&url, &body)).await {
+            Ok(result) => result,
+            Err(_) => anyhow::bail!("timed out"),
+        }
+    }
+}
+
+struct TraceHooks(Arc<Mutex<Trace>>);
+
+impl PlaybackHooks for TraceHooks {
+    fn before_command(&mut self, index: usize, command: &Command) {
+        self.0.lock().unwrap().commands.push(Span {
+            id: span_id(),
+            name: span_name(command),
+            start: SystemTime::now(),
+            end: None,
+            attributes: vec![
+                ("quipu.command.index", json!(index)),
+                ("quipu.command", json!(format_command(command))),
+            ],
+        });
+    }
+
+    fn after_command(&mut self, _index: usize, _command: &Command, _duration: Duration) {
+        if let Some(span) = self.0.lock().unwrap().commands.last_mut() {
+            span.end = Some(SystemTime::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        let tracer = Tracer::new("demo.qp");
+        let mut hooks = tracer.hooks();
+        let wait = Command::Wait(Duration::from_secs(1));
+        hooks.before_command(0, &wait);
+        hooks.after_command(0, &wait, Duration::from_secs(1));
+        hooks.before_command(1, &Command::Expect("ready".to_string(), vec![]));
+
+        let request = tracer.request(Some("timed out"));
+        let spans = &request["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(spans[0]["name"], "script");
+        assert_eq!(spans[0]["status"], json!({}));
+        assert_eq!(spans[1]["name"], "wait");
+        assert_eq!(spans[1]["parentSpanId"], spans[0]["spanId"]);
+        assert_eq!(spans[2]["name"], "expect");
+        assert_eq!(spans[2]["status"]["message"], "timed out");
+        assert_eq!(
+            spans[2]["attributes"][1]["value"]["stringValue"],
+            "@ expect:ready"
+        );
+    }
+
+    #[test]
+    fn test_parse_traceparent() {
+        assert_eq!(
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            Some((
+                "4bf92f3577b34da6a3ce929d0e0e4736".to_string(),
+                "00f067aa0ba902b7".to_string()
+            ))
+        );
+        assert_eq!(parse_traceparent("00-xyz-00f067aa0ba902b7-01"), None);
+    }
+}
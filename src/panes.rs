@@ -0,0 +1,414 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Split-screen playback of several sessions
+//!
+//! Once a script has more than one session, each gets a pane of the real
+//! terminal instead of taking turns at all of it. Sessions are drawn from
+//! their screen models rather than passed through as they write, as their
+//! output moves the cursor as if it had the whole terminal: each pane is a
+//! PTY of its own size, and only the rows that changed are drawn again. A
+//! recording gets the panes as they're drawn, so a split-screen cast can be
+//! made without a terminal at all
+
+use anyhow::{Result, bail};
+use portable_pty::{MasterPty, PtySize};
+use std::io::{IsTerminal, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::pty::SharedRecorder;
+use crate::screen::Screen;
+use crate::types::PaneLayout;
+
+/// A PTY's master end, shared so its pane can be resized when others come and go
+pub type SharedMaster = Arc<Mutex<Box<dyn MasterPty + Send>>>;
+
+/// Where a pane is in the real terminal, from 0, with its title on the row above
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Area {
+    pub top: u16,
+    pub left: u16,
+    pub cols: u16,
+    pub rows: u16,
+}
+
+/// The panes `count` sessions get in `cols` by `rows`, or `None` if they don't fit
+pub fn areas(layout: PaneLayout, cols: u16, rows: u16, count: u16) -> Option<Vec<Area>> {
+    if count == 0 {
+        return Some(Vec::new());
+    }
+    // Spread what's left over between the first few panes
+    let split = |total: u16| {
+        let size = total / count;
+        (0..count).map(move |i| size + u16::from(i < total % count))
+    };
+    let mut areas = Vec::new();
+    match layout {
+        PaneLayout::Single => {
+            if rows < 2 {
+                return None;
+            }
+            areas.push(Area {
+                top: 1,
+                left: 0,
+                cols,
+                rows: rows - 1,
+            });
+        }
+        // Side by side, under one row of titles, with a column between each
+        PaneLayout::Horizontal => {
+            let width = cols.checked_sub(count - 1)?;
+            if rows < 2 || width < count {
+                return None;
+            }
+            let mut left = 0;
+            for pane_cols in split(width) {
+                areas.push(Area {
+                    top: 1,
+                    left,
+                    cols: pane_cols,
+                    rows: rows - 1,
+                });
+                left += pane_cols + 1;
+            }
+        }
+        // One above another, each under its own title
+        PaneLayout::Vertical => {
+            let height = rows.checked_sub(count)?;
+            if height < count {
+                return None;
+            }
+            let mut top = 0;
+            for pane_rows in split(height) {
+                areas.push(Area {
+                    top: top + 1,
+                    left: 0,
+                    cols,
+                    rows: pane_rows,
+                });
+                top += pane_rows + 1;
+            }
+        }
+    }
+    Some(areas)
+}
+
+struct Pane {
+    name: String,
+    screen: Arc<Screen>,
+    master: SharedMaster,
+    // Whether the pane's drawn, which it isn't while playback is out of sight
+    shown: bool,
+    // The rows as last drawn, to draw only those that change
+    drawn: Vec<Vec<u8>>,
+}
+
+struct State {
+    size: PtySize,
+    panes: Vec<Pane>,
+    areas: Vec<Area>,
+    // The pane keys go to, whose title stands out and which has the cursor
+    current: usize,
+    // Whether everything has to be drawn again, as after the layout changes
+    stale: bool,
+    // Whether playback has ended, after which nothing more is drawn
+    finished: bool,
+}
+
+/// How the sessions of a script share the real terminal, and its recording
+pub struct Panes {
+    layout: PaneLayout,
+    mirrored: bool,
+    recorder: SharedRecorder,
+    state: Mutex<State>,
+}
+
+impl Panes {
+    /// Panes for sessions `size` fits, drawn to stdout if `shown` and to `recorder`.
+    /// Output shown as a transcript only ever has one at a time
+    pub fn new(layout: PaneLayout, shown: bool, recorder: SharedRecorder, size: PtySize) -> Self {
+        let mirrored = shown && std::io::stdout().is_terminal();
+        Self {
+            layout: if shown && !mirrored {
+                PaneLayout::Single
+            } else {
+                layout
+            },
+            mirrored,
+            recorder,
+            state: Mutex::new(State {
+                size,
+                panes: Vec::new(),
+                areas: Vec::new(),
+                current: 0,
+                stale: true,
+                finished: false,
+            }),
+        }
+    }
+
+    /// Add a session's pane, laying out the others again to make room,
+    /// returning which pane it is
+    pub fn add(
+        &self,
+        name: &str,
+        screen: Arc<Screen>,
+        master: SharedMaster,
+        shown: bool,
+    ) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        state.panes.push(Pane {
+            name: name.to_string(),
+            screen,
+            master,
+            shown,
+            drawn: Vec::new(),
+        });
+        if self.is_split_state(&state) {
+            self.lay_out(&mut state)?;
+            self.render(&mut state, None)?;
+        }
+        Ok(state.panes.len() - 1)
+    }
+
+    /// Whether sessions are drawn in panes, rather than one at a time as they write
+    pub fn is_split(&self) -> bool {
+        self.is_split_state(&self.state.lock().unwrap())
+    }
+
+    fn is_split_state(&self, state: &State) -> bool {
+        self.layout != PaneLayout::Single && state.panes.len() > 1
+    }
+
+    /// Draw pane `index` or not, making it the current one if it's drawn
+    pub fn show(&self, index: usize, shown: bool) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let Some(pane) = state.panes.get_mut(index) else {
+            return Ok(());
+        };
+        if !shown {
+            pane.shown = false;
+            return Ok(());
+        }
+        if !pane.shown {
+            pane.shown = true;
+            pane.drawn.clear();
+        }
+        state.current = index;
+        if self.is_split_state(&state) {
+            // Titles show which pane is current
+            state.stale = true;
+            self.render(&mut state, Some(index))?;
+        }
+        Ok(())
+    }
+
+    /// Draw what's changed in pane `index` since it was last drawn
+    pub fn draw(&self, index: usize) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if self.is_split_state(&state) {
+            self.render(&mut state, Some(index))?;
+        }
+        Ok(())
+    }
+
+    /// Lay out the panes again in `size`, returning whether they're split,
+    /// without which each session resizes itself
+    pub fn resize(&self, size: PtySize) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let split = self.is_split_state(&state);
+        if (state.size.cols, state.size.rows) == (size.cols, size.rows) {
+            return Ok(split);
+        }
+        state.size = size;
+        if split {
+            if let Some(recorder) = self.recorder.lock().unwrap().as_mut() {
+                recorder.resize(size.cols, size.rows);
+            }
+            self.lay_out(&mut state)?;
+            self.render(&mut state, None)?;
+        }
+        Ok(split)
+    }
+
+    /// Stop drawing, leaving the cursor below the panes
+    pub fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.finished || !self.is_split_state(&state) {
+            return;
+        }
+        state.finished = true;
+        let _ = self.write(format!("\x1b[{};1H\x1b[?25h\r\n", state.size.rows).as_bytes());
+    }
+
+    // Give each pane its share of the terminal, resizing its PTY to match
+    fn lay_out(&self, state: &mut State) -> Result<()> {
+        let size = state.size;
+        let Some(areas) = areas(self.layout, size.cols, size.rows, state.panes.len() as u16) else {
+            bail!("Terminal is too small for {} panes", state.panes.len());
+        };
+        // Cells keep their size in pixels
+        let scale = |pixels: u16, new: u16, old: u16| {
+            (u32::from(pixels) * u32::from(new) / u32::from(old.max(1))) as u16
+        };
+        for (pane, area) in state.panes.iter_mut().zip(&areas) {
+            let pane_size = PtySize {
+                cols: area.cols,
+                rows: area.rows,
+                pixel_width: scale(size.pixel_width, area.cols, size.cols),
+                pixel_height: scale(size.pixel_height, area.rows, size.rows),
+            };
+            pane.master.lock().unwrap().resize(pane_size)?;
+            pane.screen.set_size(area.cols, area.rows);
+            pane.drawn.clear();
+        }
+        state.areas = areas;
+        state.stale = true;
+        Ok(())
+    }
+
+    // Draw pane `index`, or all of them, and everything else once it's stale,
+    // leaving the cursor in the current pane
+    fn render(&self, state: &mut State, index: Option<usize>) -> Result<()> {
+        if state.finished || !state.panes.iter().any(|pane| pane.shown) {
+            return Ok(());
+        }
+        let mut out = b"\x1b[?25l".to_vec();
+        let all = index.is_none() || state.stale;
+        if state.stale {
+            out.extend(self.frame(state));
+            for pane in &mut state.panes {
+                pane.drawn.clear();
+            }
+            state.stale = false;
+        }
+        for (i, (pane, area)) in state.panes.iter_mut().zip(&state.areas).enumerate() {
+            if !pane.shown || !(all || index == Some(i)) {
+                continue;
+            }
+            let (rows, _) = pane.screen.pane_rows();
+            for (row, line) in rows.into_iter().enumerate() {
+                if pane.drawn.get(row) == Some(&line) {
+                    continue;
+                }
+                out.extend(
+                    format!("\x1b[{};{}H", area.top + row as u16 + 1, area.left + 1).into_bytes(),
+                );
+                out.extend(&line);
+                match pane.drawn.get_mut(row) {
+                    Some(drawn) => *drawn = line,
+                    None => pane.drawn.push(line),
+                }
+            }
+        }
+
+        let current = &state.panes[state.current];
+        let area = state.areas[state.current];
+        if current.shown
+            && let (_, Some((row, col))) = current.screen.pane_rows()
+        {
+            out.extend(
+                format!(
+                    "\x1b[{};{}H\x1b[?25h",
+                    area.top + row + 1,
+                    area.left + col + 1
+                )
+                .into_bytes(),
+            );
+        }
+        self.write(&out)?;
+        Ok(())
+    }
+
+    // The terminal cleared, with each pane's title and the lines between them
+    fn frame(&self, state: &State) -> Vec<u8> {
+        let mut out = b"\x1b[m".to_vec();
+        for row in 1..=state.size.rows {
+            out.extend(format!("\x1b[{};1H\x1b[2K", row).into_bytes());
+        }
+        for (i, (pane, area)) in state.panes.iter().zip(&state.areas).enumerate() {
+            let title: String = format!(" {} ", pane.name)
+                .chars()
+                .take(usize::from(area.cols))
+                .collect();
+            let style = if i == state.current { "1;7" } else { "2" };
+            let fill = usize::from(area.cols).saturating_sub(title.chars().count());
+            out.extend(
+                format!(
+                    "\x1b[{};{}H\x1b[{}m{}\x1b[m\x1b[2m{}",
+                    area.top,
+                    area.left + 1,
+                    style,
+                    title,
+                    "─".repeat(fill)
+                )
+                .into_bytes(),
+            );
+            // The column between side-by-side panes
+            if self.layout == PaneLayout::Horizontal && i + 1 < state.panes.len() {
+                let col = area.left + area.cols + 1;
+                out.extend(format!("\x1b[{};{}H┬", area.top, col).into_bytes());
+                for row in area.top..area.top + area.rows {
+                    out.extend(format!("\x1b[{};{}H│", row + 1, col).into_bytes());
+                }
+            }
+            out.extend(b"\x1b[m");
+        }
+        out
+    }
+
+    // Record and show what's drawn, all at once so panes don't tear
+    fn write(&self, bytes: &[u8]) -> Result<()> {
+        if let Some(recorder) = self.recorder.lock().unwrap().as_mut() {
+            recorder.output(bytes);
+        }
+        if self.mirrored {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(bytes)?;
+            stdout.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_areas() {
+        let area = |top, left, cols, rows| Area {
+            top,
+            left,
+            cols,
+            rows,
+        };
+        // 80 columns less a divider leaves one spare for the first pane
+        assert_eq!(
+            areas(PaneLayout::Horizontal, 80, 24, 2),
+            Some(vec![area(1, 0, 40, 23), area(1, 41, 39, 23)])
+        );
+        assert_eq!(
+            areas(PaneLayout::Vertical, 80, 24, 3),
+            Some(vec![
+                area(1, 0, 80, 7),
+                area(9, 0, 80, 7),
+                area(17, 0, 80, 7)
+            ])
+        );
+        assert_eq!(areas(PaneLayout::Horizontal, 4, 24, 3), None);
+        assert_eq!(areas(PaneLayout::Vertical, 80, 5, 3), None);
+    }
+}
@@ -16,33 +16,123 @@
 //! Script parser for quipu files
 //!
 //! Parses scripts with the format:
-//! - @ directives (speed, jitter, wait)
+//! - @ directives (speed, jitter, wait, ...)
 //! - # comments
 //! - $ typing lines
 
 use nom::{
     IResult, Parser,
     branch::alt,
-    bytes::complete::{tag, take_until},
-    character::complete::{char, not_line_ending, space0},
-    combinator::{map, value},
+    bytes::complete::{tag, take_until, take_while1},
+    character::complete::{char, not_line_ending, space0, space1},
+    combinator::{all_consuming, map, opt, success, value, verify},
+    error::ErrorKind,
+    sequence::{delimited, preceded},
 };
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Lines};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use crate::types::{Command, Script};
+use crate::key::{Key, KeyEvent, Keys};
+use crate::types::{
+    Command, CursorKeys, MetaField, Multiline, Newline, NoHistory, PaneLayout, Script,
+    WaitCondition,
+};
+use crate::typing::{self, TypingProfile};
+
+// Alias name to the bytes it expands to
+type Aliases = HashMap<String, Keys>;
+
+// Parser settings that come from outside the script
+#[derive(Debug, Default, Clone)]
+pub struct ParseOptions {
+    // Key aliases from the config file, written like typing lines e.g. `<esc>:w<ret>`
+    pub aliases: HashMap<String, String>,
+    // Directives handled by plugins, from the config file's `[directives]`
+    pub directives: HashSet<String>,
+    // Where the script is, which `@ include:` and `@ call:` look in first;
+    // the working directory if it's not a file
+    pub script_dir: Option<PathBuf>,
+    // Library directories `@ include:` and `@ call:` look in next, in order
+    pub lib_path: Vec<PathBuf>,
+    // Fail on mistakes that are otherwise let through, as `@ strict` does
+    pub strict: bool,
+    // Typing profiles from the config file, used before the built-in ones
+    pub profiles: HashMap<String, TypingProfile>,
+}
+
+impl ParseOptions {
+    /// These options for the script at `path`
+    pub fn for_script(&self, path: &Path) -> Self {
+        Self {
+            script_dir: path.parent().map(Path::to_path_buf),
+            ..self.clone()
+        }
+    }
+}
+
+// Scripts included inside each other, deeper than any real library would be
+const MAX_INCLUDE_DEPTH: usize = 16;
 
 fn parse_float(input: &str) -> IResult<&str, f64> {
     nom::number::complete::double(input)
 }
 
+// Seconds in a time such as `500ms`, `2s`, `1m` or a bare `2.5`
+fn parse_time(input: &str) -> IResult<&str, f64> {
+    let (rest, number) = parse_float(input)?;
+    let (rest, scale) = alt((
+        value(0.001, tag("ms")),
+        value(1.0, tag("s")),
+        value(60.0, tag("m")),
+        success(1.0),
+    ))
+    .parse(rest)?;
+
+    // A failure rather than an error, so parse_script can say what was wrong
+    if !(number.is_finite() && number >= 0.0) {
+        return Err(nom::Err::Failure(nom::error::Error::new(
+            input,
+            ErrorKind::Verify,
+        )));
+    }
+    Ok((rest, number * scale))
+}
+
+fn parse_duration(input: &str) -> IResult<&str, Duration> {
+    map(parse_time, Duration::from_secs_f64).parse(input)
+}
+
+/// A time given on the command line, such as `100ms` or `2s`
+pub fn parse_interval(value: &str) -> Result<Duration, String> {
+    all_consuming(parse_duration)
+        .parse(value)
+        .map(|(_, duration)| duration)
+        .map_err(|_| format!("expected a time such as 100ms or 2s, not '{}'", value))
+}
+
 fn parse_speed(input: &str) -> IResult<&str, Command> {
     let (input, _) = tag("@")(input)?;
     let (input, _) = space0(input)?;
     let (input, _) = tag("speed:")(input)?;
-    let (input, value) = parse_float(input)?;
+    let (input, value) = parse_time(input)?;
     Ok((input, Command::SetSpeed(value)))
 }
 
+fn parse_ramp(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("ramp:")(input)?;
+    // Split first, since a number such as `1.` would otherwise eat the first dot
+    let (input, start) = take_until("..")(input)?;
+    let (_, start) = all_consuming(parse_time).parse(start)?;
+    let (input, _) = tag("..")(input)?;
+    let (input, end) = parse_time(input)?;
+    Ok((input, Command::SetRamp(start, end)))
+}
+
 fn parse_jitter(input: &str) -> IResult<&str, Command> {
     let (input, _) = tag("@")(input)?;
     let (input, _) = space0(input)?;
@@ -51,12 +141,38 @@ fn parse_jitter(input: &str) -> IResult<&str, Command> {
     Ok((input, Command::SetJitter(value)))
 }
 
+fn parse_wait_jitter(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("waitjitter:")(input)?;
+    let (input, value) = parse_float(input)?;
+    Ok((input, Command::SetWaitJitter(value)))
+}
+
+fn parse_word_pause(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("word-pause:")(input)?;
+    let (input, value) = parse_time(input)?;
+    Ok((input, Command::SetWordPause(value)))
+}
+
 fn parse_wait(input: &str) -> IResult<&str, Command> {
     let (input, _) = tag("@")(input)?;
     let (input, _) = space0(input)?;
     let (input, _) = tag("wait:")(input)?;
-    let (input, value) = parse_float(input)?;
-    Ok((input, Command::Wait(Duration::from_secs_f64(value))))
+    let (input, duration) = parse_duration(input)?;
+    Ok((input, Command::Wait(duration)))
+}
+
+fn parse_wait_random(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("wait-random:")(input)?;
+    let (input, min) = parse_duration(input)?;
+    let (input, _) = char(':')(input)?;
+    let (input, max) = parse_duration(input)?;
+    Ok((input, Command::WaitRandom(min, max)))
 }
 
 fn parse_shell(input: &str) -> IResult<&str, Command> {
@@ -67,6 +183,35 @@ fn parse_shell(input: &str) -> IResult<&str, Command> {
     Ok((input, Command::SetShell(shell.trim().to_string())))
 }
 
+fn parse_shell_args(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("shellargs:")(input)?;
+    let (input, args) = not_line_ending(input)?;
+    Ok((input, Command::SetShellArgs(split_args(args))))
+}
+
+fn parse_shell_profile(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("shell-profile:")(input)?;
+    let (input, name) = not_line_ending(input)?;
+    Ok((input, Command::SetShellProfile(name.trim().to_string())))
+}
+
+/// Split shell arguments on whitespace, as given to `@ shellargs:` and `--shell-args`
+pub fn split_args(args: &str) -> Vec<String> {
+    args.split_whitespace().map(str::to_string).collect()
+}
+
+fn parse_term(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("term:")(input)?;
+    let (input, term) = not_line_ending(input)?;
+    Ok((input, Command::SetTerm(term.trim().to_string())))
+}
+
 fn parse_size(input: &str) -> IResult<&str, Command> {
     let (input, _) = tag("@")(input)?;
     let (input, _) = space0(input)?;
@@ -77,255 +222,1320 @@ fn parse_size(input: &str) -> IResult<&str, Command> {
     Ok((input, Command::SetSize(cols, rows)))
 }
 
+fn parse_resize(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("resize:")(input)?;
+    let (input, cols) = nom::character::complete::u16(input)?;
+    let (input, _) = char(':')(input)?;
+    let (input, rows) = nom::character::complete::u16(input)?;
+    Ok((input, Command::Resize(cols, rows)))
+}
+
+fn parse_pixel_size(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("pixelsize:")(input)?;
+    let (input, width) = nom::character::complete::u16(input)?;
+    let (input, _) = char('x')(input)?;
+    let (input, height) = nom::character::complete::u16(input)?;
+    Ok((input, Command::SetPixelSize(width, height)))
+}
+
+fn parse_cursor_keys(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("cursor-keys:")(input)?;
+    let (input, mode) = alt((
+        value(CursorKeys::Auto, tag("auto")),
+        value(CursorKeys::Normal, tag("normal")),
+        value(CursorKeys::Application, tag("application")),
+    ))
+    .parse(input)?;
+    Ok((input, Command::SetCursorKeys(mode)))
+}
+
+fn parse_ime(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("ime:")(input)?;
+    let (input, on) = alt((value(true, tag("on")), value(false, tag("off")))).parse(input)?;
+    Ok((input, Command::SetIme(on)))
+}
+
+fn parse_encoding(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("encoding:")(input)?;
+    let (input, label) = not_line_ending(input)?;
+    Ok((input, Command::SetEncoding(label.trim().to_string())))
+}
+
+fn parse_newline(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("newline:")(input)?;
+    // `crlf` before `cr` so the longer name wins
+    let (input, newline) = alt((
+        value(Newline::Crlf, tag("crlf")),
+        value(Newline::Cr, tag("cr")),
+        value(Newline::Lf, tag("lf")),
+    ))
+    .parse(input)?;
+    Ok((input, Command::SetNewline(newline)))
+}
+
+fn parse_multiline(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("multiline:")(input)?;
+    let (input, mode) = alt((
+        value(Multiline::Literal, tag("literal")),
+        value(Multiline::Enter, tag("enter")),
+        value(Multiline::Paste, tag("paste")),
+    ))
+    .parse(input)?;
+    Ok((input, Command::SetMultiline(mode)))
+}
+
+fn parse_no_history(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("no-history")(input)?;
+    let (input, mode) = alt((
+        value(NoHistory::Histfile, tag(":histfile")),
+        value(NoHistory::Space, tag(":space")),
+        success(NoHistory::Histfile),
+    ))
+    .parse(input)?;
+    Ok((input, Command::SetNoHistory(mode)))
+}
+
+fn parse_checkpoint(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("checkpoint:")(input)?;
+    let (input, name) = take_while1(|c: char| !c.is_whitespace())(input)?;
+    Ok((input, Command::Checkpoint(name.to_string())))
+}
+
+fn parse_session(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("session:")(input)?;
+    let (input, name) = take_while1(|c: char| !c.is_whitespace())(input)?;
+    Ok((input, Command::Session(name.to_string())))
+}
+
+fn parse_layout(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("layout:")(input)?;
+    let (input, layout) = alt((
+        value(PaneLayout::Single, tag("single")),
+        value(PaneLayout::Horizontal, tag("horizontal")),
+        value(PaneLayout::Vertical, tag("vertical")),
+    ))
+    .parse(input)?;
+    Ok((input, Command::SetLayout(layout)))
+}
+
+// The `[NAME]` after the `$` of a line typed into session NAME
+fn parse_session_prefix(input: &str) -> IResult<&str, &str> {
+    let (input, _) = char('$')(input)?;
+    delimited(
+        char('['),
+        take_while1(|c: char| c != ']' && !c.is_whitespace()),
+        char(']'),
+    )
+    .parse(input)
+}
+
+fn parse_caption(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("caption:")(input)?;
+    let (input, text) = not_line_ending(input)?;
+    Ok((input, Command::Caption(text.trim().to_string())))
+}
+
+fn parse_meta(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, field) = alt((
+        value(MetaField::Title, tag("title:")),
+        value(MetaField::Author, tag("author:")),
+        value(MetaField::Description, tag("description:")),
+    ))
+    .parse(input)?;
+    let (input, text) = not_line_ending(input)?;
+    Ok((input, Command::Meta(field, text.trim().to_string())))
+}
+
+fn parse_requires(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("quipu:")(input)?;
+    let (input, requirement) = not_line_ending(input)?;
+    Ok((input, Command::Requires(requirement.trim().to_string())))
+}
+
+/// Whether `name` can be used as a script variable, as in `${NAME}`
+pub fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_exec(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("exec:")(input)?;
+    let (input, line) = not_line_ending(input)?;
+
+    // `cmd -> NAME` stores the output in a variable
+    let command = match line.rsplit_once(" -> ") {
+        Some((command, name)) if is_variable_name(name.trim()) => {
+            Command::Exec(command.trim().to_string(), Some(name.trim().to_string()))
+        }
+        _ => Command::Exec(line.trim().to_string(), None),
+    };
+    Ok((input, command))
+}
+
+fn parse_exec_async(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("exec-async:")(input)?;
+    let (input, command) = not_line_ending(input)?;
+    Ok((input, Command::ExecAsync(command.trim().to_string())))
+}
+
+fn parse_webhook(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("webhook:")(input)?;
+    let (input, url) = take_while1(|c: char| !c.is_whitespace())(input)?;
+    Ok((input, Command::Webhook(url.to_string())))
+}
+
+fn parse_dump_scrollback(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("dump-scrollback:")(input)?;
+    let (input, path) = take_while1(|c: char| !c.is_whitespace())(input)?;
+    Ok((input, Command::DumpScrollback(path.to_string())))
+}
+
+fn parse_sound(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("sound:")(input)?;
+    let (input, path) = take_while1(|c: char| !c.is_whitespace())(input)?;
+    let sound = (path != "off").then(|| path.to_string());
+    Ok((input, Command::SetSound(sound)))
+}
+
+fn parse_audio(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("audio:")(input)?;
+    let (input, path) = take_while1(|c: char| !c.is_whitespace())(input)?;
+    Ok((input, Command::Audio(path.to_string())))
+}
+
+fn parse_say(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, wait) =
+        alt((value(false, tag("say:")), value(true, tag("say-wait:")))).parse(input)?;
+    let (input, text) = not_line_ending(input)?;
+    Ok((input, Command::Say(text.trim().to_string(), wait)))
+}
+
+fn parse_command_timeout(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("timeout:")(input)?;
+    let (input, duration) = parse_duration(input)?;
+    Ok((input, Command::SetCommandTimeout(duration)))
+}
+
+fn parse_expect_timeout(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("expect-timeout:")(input)?;
+    let (input, duration) = parse_duration(input)?;
+    Ok((input, Command::SetExpectTimeout(duration)))
+}
+
+fn parse_expect(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("expect:")(input)?;
+    let (input, line) = not_line_ending(input)?;
+
+    // `pattern -> A, B` stores the pattern's groups in variables
+    let command = match line.rsplit_once(" -> ") {
+        Some((pattern, names)) if names.split(',').all(|name| is_variable_name(name.trim())) => {
+            Command::Expect(
+                pattern.trim().to_string(),
+                names
+                    .split(',')
+                    .map(|name| name.trim().to_string())
+                    .collect(),
+            )
+        }
+        _ => Command::Expect(line.trim().to_string(), Vec::new()),
+    };
+    Ok((input, command))
+}
+
+fn parse_expect_screen(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("expect-screen:")(input)?;
+    let (input, pattern) = not_line_ending(input)?;
+    Ok((input, Command::ExpectScreen(pattern.trim().to_string())))
+}
+
+fn parse_expect_clipboard(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("expect-clipboard")(input)?;
+    // `-> NAME` stores what was copied in a variable
+    let (input, name) = opt(preceded(
+        (space0, tag("->"), space0),
+        take_while1(|c: char| !c.is_whitespace()),
+    ))
+    .parse(input)?;
+    Ok((input, Command::ExpectClipboard(name.map(str::to_string))))
+}
+
+fn parse_expect_cell(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("expect-cell:")(input)?;
+    let (input, row) = nom::character::complete::u16(input)?;
+    let (input, _) = char(':')(input)?;
+    let (input, col) = nom::character::complete::u16(input)?;
+    let (input, _) = char(':')(input)?;
+    let (input, text) = not_line_ending(input)?;
+    Ok((
+        input,
+        Command::ExpectCell(row, col, text.trim().to_string()),
+    ))
+}
+
+fn parse_prompt_pattern(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("prompt-pattern:")(input)?;
+    let (input, pattern) = not_line_ending(input)?;
+    // Quotes keep the space most prompts end with
+    let pattern = pattern.trim();
+    let pattern = ['\'', '"']
+        .iter()
+        .find_map(|&quote| {
+            pattern
+                .strip_prefix(quote)
+                .and_then(|rest| rest.strip_suffix(quote))
+        })
+        .unwrap_or(pattern);
+    Ok((input, Command::SetPromptPattern(pattern.to_string())))
+}
+
+fn parse_wait_prompt(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("waitprompt")(input)?;
+    Ok((input, Command::WaitPrompt))
+}
+
+fn parse_wait_for(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, condition) = alt((
+        value(WaitCondition::File, tag("waitfile:")),
+        value(WaitCondition::Port, tag("waitport:")),
+        value(WaitCondition::Url, tag("waiturl:")),
+    ))
+    .parse(input)?;
+    let (input, target) = not_line_ending(input)?;
+    Ok((
+        input,
+        Command::WaitFor(condition, target.trim().to_string()),
+    ))
+}
+
+fn parse_type_clipboard(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("typeclipboard")(input)?;
+    Ok((input, Command::TypeClipboard))
+}
+
+fn parse_clear(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("clear")(input)?;
+    let (input, scrollback) = opt(tag(":scrollback")).parse(input)?;
+    Ok((input, Command::Clear(scrollback.is_some())))
+}
+
+fn parse_reset(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("reset")(input)?;
+    Ok((input, Command::Reset))
+}
+
+// `@ name:args` or `@ name` for a directive a plugin handles
+fn parse_plugin<'a>(input: &'a str, directives: &HashSet<String>) -> IResult<&'a str, Command> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, name) = verify(
+        take_while1(|c: char| c.is_ascii_alphanumeric() || c == '-'),
+        |name: &str| directives.contains(name),
+    )
+    .parse(input)?;
+    let (input, args) = opt(preceded(char(':'), not_line_ending)).parse(input)?;
+    Ok((
+        input,
+        Command::Plugin(
+            name.to_string(),
+            args.unwrap_or_default().trim().to_string(),
+        ),
+    ))
+}
+
 fn parse_directive(input: &str) -> IResult<&str, Command> {
     alt((
-        parse_speed,
-        parse_jitter,
-        parse_wait,
-        parse_shell,
+        // Timing, nested as alt takes at most 21 parsers
+        alt((
+            parse_speed,
+            parse_ramp,
+            parse_jitter,
+            parse_wait_jitter,
+            parse_word_pause,
+            parse_wait,
+            parse_wait_random,
+        )),
+        // The session's shell, nested for the same reason
+        alt((parse_shell, parse_shell_args, parse_shell_profile)),
+        parse_term,
         parse_size,
+        parse_pixel_size,
+        parse_cursor_keys,
+        parse_ime,
+        parse_encoding,
+        parse_newline,
+        parse_multiline,
+        parse_no_history,
+        parse_checkpoint,
+        parse_caption,
+        // What the script is and needs, nested for the same reason
+        alt((parse_meta, parse_requires)),
+        // Host commands and files, nested for the same reason
+        alt((
+            parse_exec,
+            parse_exec_async,
+            parse_webhook,
+            parse_dump_scrollback,
+            parse_type_clipboard,
+        )),
+        alt((
+            parse_expect_timeout,
+            parse_command_timeout,
+            parse_expect,
+            parse_expect_screen,
+            parse_expect_cell,
+            parse_expect_clipboard,
+            parse_prompt_pattern,
+            parse_wait_prompt,
+            parse_wait_for,
+        )),
+        // Sounds and speech on the host, nested for the same reason
+        alt((parse_sound, parse_audio, parse_say)),
+        // The session's screen, and which session it is, nested for the same reason
+        alt((
+            parse_clear,
+            parse_reset,
+            parse_resize,
+            parse_session,
+            parse_layout,
+        )),
     ))
     .parse(input)
 }
 
+// How much longer each wait between `@ retry` attempts is than the last
+const DEFAULT_BACKOFF: f64 = 2.0;
+
+// Lines that open, divide and close `@ choose`, `@ retry`, `@ on-error`,
+// `@ finally`, `@ setup`, `@ teardown` and `@ lua` blocks
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Block {
+    Choose,
+    Or,
+    End,
+    Retry(u32, f64),
+    EndRetry,
+    OnError,
+    EndOnError,
+    Finally,
+    EndFinally,
+    Setup,
+    EndSetup,
+    Teardown,
+    EndTeardown,
+    Lua,
+    EndLua,
+}
+
+fn parse_block(input: &str) -> IResult<&str, Block> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    alt((
+        parse_retry,
+        value(Block::EndRetry, tag("endretry")),
+        value(Block::Choose, tag("choose")),
+        value(Block::Or, tag("or")),
+        value(Block::End, tag("endchoose")),
+        value(Block::Setup, tag("setup")),
+        value(Block::EndSetup, tag("endsetup")),
+        value(Block::Teardown, tag("teardown")),
+        value(Block::EndTeardown, tag("endteardown")),
+        value(Block::Lua, tag("lua")),
+        value(Block::EndLua, tag("endlua")),
+        value(Block::OnError, tag("on-error")),
+        value(Block::EndOnError, tag("endon-error")),
+        value(Block::Finally, tag("finally")),
+        value(Block::EndFinally, tag("endfinally")),
+    ))
+    .parse(input)
+}
+
+impl Block {
+    // The directive that opens this kind of block
+    fn name(self) -> &'static str {
+        match self {
+            Block::Choose | Block::Or | Block::End => "choose",
+            Block::Retry(_, _) | Block::EndRetry => "retry",
+            Block::OnError | Block::EndOnError => "on-error",
+            Block::Finally | Block::EndFinally => "finally",
+            Block::Setup | Block::EndSetup => "setup",
+            Block::Teardown | Block::EndTeardown => "teardown",
+            Block::Lua | Block::EndLua => "lua",
+        }
+    }
+}
+
+fn parse_retry(input: &str) -> IResult<&str, Block> {
+    let (input, _) = tag("retry:")(input)?;
+    let (input, retries) = nom::character::complete::u32(input)?;
+    let (input, backoff) = opt(preceded((space1, tag("backoff:")), parse_float)).parse(input)?;
+    Ok((
+        input,
+        Block::Retry(retries, backoff.unwrap_or(DEFAULT_BACKOFF)),
+    ))
+}
+
+fn parse_alias(input: &str) -> IResult<&str, (&str, &str)> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("alias:")(input)?;
+    let (input, name) = take_while1(|c: char| c != '=' && !c.is_whitespace())(input)?;
+    let (input, _) = char('=')(input)?;
+    let (input, keys) = not_line_ending(input)?;
+    Ok((input, (name, keys)))
+}
+
+fn parse_profile(input: &str) -> IResult<&str, &str> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("profile:")(input)?;
+    let (input, name) = take_while1(|c: char| !c.is_whitespace())(input)?;
+    Ok((input, name))
+}
+
+fn parse_strict(input: &str) -> IResult<&str, ()> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("strict")(input)?;
+    Ok((input, ()))
+}
+
+fn parse_include(input: &str) -> IResult<&str, &str> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("include:")(input)?;
+    let (input, file) = not_line_ending(input)?;
+    Ok((input, file.trim()))
+}
+
+fn parse_call(input: &str) -> IResult<&str, &str> {
+    let (input, _) = tag("@")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("call:")(input)?;
+    let (input, name) = not_line_ending(input)?;
+    Ok((input, name.trim()))
+}
+
+fn check_alias_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.contains(['<', '>']) {
+        return Err(format!("Invalid alias name: '{}'", name));
+    }
+    if named_key_sequence(name).is_some() {
+        return Err(format!("Alias '{}' would shadow a built-in key", name));
+    }
+    Ok(())
+}
+
 fn parse_comment(input: &str) -> IResult<&str, ()> {
     let (input, _) = char('#')(input)?;
     let (input, _) = not_line_ending(input)?;
     Ok((input, ()))
 }
 
-fn parse_special_key(input: &str) -> IResult<&str, String> {
+/// Escape sequence for a named key, e.g. `ret` or `F5`
+pub fn named_key_sequence(name: &str) -> Option<String> {
+    Key::from_name(name).map(Key::sequence)
+}
+
+fn parse_special_key<'a>(input: &'a str, aliases: &Aliases) -> IResult<&'a str, Keys> {
     let (input, _) = char('<')(input)?;
     let (input, key_spec) = take_until(">")(input)?;
     let (input, _) = char('>')(input)?;
 
-    let escape_seq = match key_spec {
-        "esc" => "\x1b".to_string(),
-        "space" => " ".to_string(),
-        "ret" | "return" | "enter" => "\r".to_string(),
-        "tab" => "\t".to_string(),
-        "backspace" | "bs" => "\x7f".to_string(),
-        "F1" => "\x1bOP".to_string(),
-        "F2" => "\x1bOQ".to_string(),
-        "F3" => "\x1bOR".to_string(),
-        "F4" => "\x1bOS".to_string(),
-        "F5" => "\x1b[15~".to_string(),
-        "F6" => "\x1b[17~".to_string(),
-        "F7" => "\x1b[18~".to_string(),
-        "F8" => "\x1b[19~".to_string(),
-        "F9" => "\x1b[20~".to_string(),
-        "F10" => "\x1b[21~".to_string(),
-        "F11" => "\x1b[23~".to_string(),
-        "F12" => "\x1b[24~".to_string(),
-        "up" => "\x1b[A".to_string(),
-        "down" => "\x1b[B".to_string(),
-        "right" => "\x1b[C".to_string(),
-        "left" => "\x1b[D".to_string(),
-        "home" => "\x1b[H".to_string(),
-        "end" => "\x1b[F".to_string(),
-        "pageup" | "pgup" => "\x1b[5~".to_string(),
-        "pagedown" | "pgdn" => "\x1b[6~".to_string(),
-        "insert" | "ins" => "\x1b[2~".to_string(),
-        "delete" | "del" => "\x1b[3~".to_string(),
-        spec if spec.contains('-') => parse_modifier_combo(spec),
-        _ => format!("<{}>", key_spec),
-    };
+    if let Some(expansion) = aliases.get(key_spec) {
+        return Ok((input, expansion.clone()));
+    }
 
-    Ok((input, escape_seq))
-}
-
-fn parse_modifier_combo(spec: &str) -> String {
-    let parts: Vec<&str> = spec.split('-').collect();
-
-    if parts.len() < 2 {
-        return format!("<{}>", spec);
-    }
-
-    let (modifiers, key) = parts.split_at(parts.len() - 1);
-    let key = key[0];
-
-    let mut has_ctrl = false;
-    let mut has_alt = false;
-    let mut has_shift = false;
-
-    for m in modifiers {
-        match *m {
-            "C" | "c" | "Ctrl" | "ctrl" => has_ctrl = true,
-            "A" | "a" | "Alt" | "alt" | "M" | "m" | "Meta" | "meta" => has_alt = true,
-            "S" | "s" | "Shift" | "shift" => has_shift = true,
-            _ => {}
-        }
-    }
-
-    // Resolve the base key to its escape sequence first
-    let base_key = match key {
-        "esc" => "\x1b",
-        "space" => " ",
-        "ret" | "return" | "enter" => "\r",
-        "tab" => "\t",
-        "backspace" | "bs" => "\x7f",
-        "F1" => "\x1bOP",
-        "F2" => "\x1bOQ",
-        "F3" => "\x1bOR",
-        "F4" => "\x1bOS",
-        "F5" => "\x1b[15~",
-        "F6" => "\x1b[17~",
-        "F7" => "\x1b[18~",
-        "F8" => "\x1b[19~",
-        "F9" => "\x1b[20~",
-        "F10" => "\x1b[21~",
-        "F11" => "\x1b[23~",
-        "F12" => "\x1b[24~",
-        "up" => "\x1b[A",
-        "down" => "\x1b[B",
-        "right" => "\x1b[C",
-        "left" => "\x1b[D",
-        "home" => "\x1b[H",
-        "end" => "\x1b[F",
-        "pageup" | "pgup" => "\x1b[5~",
-        "pagedown" | "pgdn" => "\x1b[6~",
-        "insert" | "ins" => "\x1b[2~",
-        "delete" | "del" => "\x1b[3~",
-        // Single character - leave as-is for modifier processing below
-        _ if key.len() == 1 => key,
-        _ => return format!("<{}>", spec),
+    // Keys terminals have no encoding for are typed as they're written
+    let keys = match KeyEvent::parse(key_spec).filter(|event| event.legacy().is_some()) {
+        Some(event) => Keys::key(event),
+        None => Keys::text(&format!("<{}>", key_spec)),
     };
+    Ok((input, keys))
+}
 
-    // Apply modifiers to the base key
-    if has_ctrl && !has_alt && !has_shift {
-        if key.len() == 1 {
-            let ch = key.chars().next().unwrap().to_ascii_lowercase();
-            if ch.is_ascii_lowercase() {
-                // Ctrl-letter maps to ASCII 1-26
-                let code = (ch as u8) - b'a' + 1;
-                return std::char::from_u32(code as u32).unwrap().to_string();
-            } else if ch == ' ' {
-                return "\x00".to_string();
-            } else if ch == '[' {
-                return "\x1b".to_string(); // Ctrl-[ maps to ESC
-            } else if ch == ']' {
-                return "\x1d".to_string();
-            } else if ch == '\\' {
-                return "\x1c".to_string();
-            }
-        } else {
-            match key {
-                "space" => return "\x00".to_string(),
-                // Special keys don't have standard Ctrl combinations
-                _ => return format!("<{}>", spec),
-            }
+/// Bytes sent for a key spec such as `ret` or `C-x`, or `<spec>` verbatim if unknown
+pub fn key_sequence(key_spec: &str) -> String {
+    KeyEvent::parse(key_spec)
+        .and_then(KeyEvent::legacy)
+        .unwrap_or_else(|| format!("<{}>", key_spec))
+}
+
+// The first `<key>` in typed text that's neither a key nor an alias, and so
+// would be typed as it is. Brackets with spaces between them, as in
+// `sort < in > out`, were never meant as a key
+fn unknown_key<'a>(input: &'a str, aliases: &Aliases) -> Option<&'a str> {
+    let mut remaining = input;
+    while let Some(at) = remaining.find(['<', '\\']) {
+        let escape = remaining[at..].starts_with('\\');
+        remaining = &remaining[at + 1..];
+        if escape {
+            // `\<` and `\>` are brackets to type
+            remaining = remaining.strip_prefix(['<', '>']).unwrap_or(remaining);
+            continue;
+        }
+        let Some(end) = remaining.find('>') else {
+            break;
+        };
+        let spec = &remaining[..end];
+        if !spec.is_empty()
+            && !spec.contains(char::is_whitespace)
+            && !aliases.contains_key(spec)
+            && KeyEvent::parse(spec).and_then(KeyEvent::legacy).is_none()
+        {
+            return Some(spec);
         }
+        remaining = &remaining[end + 1..];
     }
+    None
+}
 
-    // Alt combinations: prepend ESC to the base key
-    if has_alt && !has_ctrl {
-        return format!("\x1b{}", base_key);
-    }
+// Directives that only matter before the shell starts, at the first `$` line
+fn is_preamble(command: &Command) -> bool {
+    matches!(
+        command,
+        Command::SetShell(_)
+            | Command::SetShellArgs(_)
+            | Command::SetShellProfile(_)
+            | Command::SetTerm(_)
+            | Command::SetSize(_, _)
+            | Command::SetPixelSize(_, _)
+            | Command::SetNoHistory(_)
+            | Command::SetLayout(_)
+    )
+}
 
-    // Shift: uppercase single character keys
-    if has_shift && !has_ctrl && !has_alt && key.len() == 1 {
-        return key.to_uppercase();
+fn parse_type_content(input: &str, aliases: &Aliases) -> Keys {
+    let mut keys = Keys::new();
+    for (_, typed) in type_tokens(input, aliases) {
+        keys.append(typed);
     }
+    keys
+}
 
-    if has_ctrl && has_shift && !has_alt && key.len() == 1 {
-        let ch = key.chars().next().unwrap().to_ascii_uppercase();
-        if ch.is_ascii_uppercase() {
-            let code = (ch as u8) - b'A' + 1;
-            return std::char::from_u32(code as u32).unwrap().to_string();
-        }
-    }
+/// The keys typed by the text of a `$` line, without the `$`
+pub fn parse_keys(input: &str) -> Keys {
+    parse_type_content(input, &Aliases::new())
+}
 
-    // Ctrl-Alt: ESC followed by Ctrl-key
-    if has_ctrl && has_alt {
-        if key.len() == 1 {
-            let ch = key.chars().next().unwrap().to_ascii_lowercase();
-            if ch.is_ascii_lowercase() {
-                let code = (ch as u8) - b'a' + 1;
-                return format!("\x1b{}", std::char::from_u32(code as u32).unwrap());
-            }
+// Typed text split into runs of plain text, escapes and keys, each as
+// written and as the keys it types
+fn type_tokens<'a>(input: &'a str, aliases: &Aliases) -> Vec<(&'a str, Keys)> {
+    let mut tokens = Vec::new();
+    let mut remaining = input;
+
+    while let Some(c) = remaining.chars().next() {
+        // Plain text up to the next key or escape is copied in one go. Any
+        // control characters in it are the keys that send them
+        let plain = remaining.find(['<', '\\']).unwrap_or(remaining.len());
+        let (len, text) = if plain > 0 {
+            (plain, Keys::from_legacy(&remaining[..plain]))
+        } else if remaining.starts_with("\\<") || remaining.starts_with("\\>") {
+            (2, Keys::text(&remaining[1..2]))
+        } else if remaining.starts_with("\\#!") {
+            // Typed rather than starting a note
+            (3, Keys::text("#!"))
+        } else if let Ok((rest, keys)) = parse_special_key(remaining, aliases) {
+            (remaining.len() - rest.len(), keys)
         } else {
-            return format!("\x1b{}", base_key);
-        }
+            (c.len_utf8(), Keys::text(&remaining[..c.len_utf8()]))
+        };
+        tokens.push((&remaining[..len], text));
+        remaining = &remaining[len..];
     }
 
-    format!("<{}>", spec)
+    tokens
 }
 
-fn parse_type_content(input: &str) -> String {
-    let mut result = String::new();
-    let mut remaining = input;
+/// The keys on a `$` line, as written and as the keys they type, with the
+/// config file's aliases; the `$` can be left off
+pub fn line_keys<'a>(
+    line: &'a str,
+    options: &ParseOptions,
+) -> Result<Vec<(&'a str, Keys)>, String> {
+    let line = line.trim();
+    if line.starts_with('@') || line.starts_with('#') {
+        return Err(format!("Not a $ line: '{}'", line));
+    }
+    let line = line.strip_prefix('$').unwrap_or(line);
+    let (keys, _) = split_note(line.trim_start());
+    let parser = ScriptParser::new(options)?;
+    Ok(type_tokens(keys, &parser.aliases))
+}
 
-    while !remaining.is_empty() {
-        if remaining.starts_with("\\<") || remaining.starts_with("\\>") {
-            result.push_str(&remaining[1..2]);
-            remaining = &remaining[2..];
-        } else if remaining.starts_with('<') {
-            match parse_special_key(remaining) {
-                Ok((rest, key_seq)) => {
-                    result.push_str(&key_seq);
-                    remaining = rest;
-                }
-                Err(_) => {
-                    result.push('<');
-                    remaining = &remaining[1..];
-                }
-            }
-        } else {
-            result.push(remaining.chars().next().unwrap());
-            remaining = &remaining[remaining.chars().next().unwrap().len_utf8()..];
+// Split a `$` line's `#!` note, after a space, from the keys to type. `\#!`
+// is never a note, as the backslash comes before it
+fn split_note(line: &str) -> (&str, Option<&str>) {
+    let mut from = 0;
+    while let Some(found) = line[from..].find("#!") {
+        let at = from + found;
+        if line[..at].ends_with(char::is_whitespace) {
+            return (line[..at].trim_end(), Some(line[at + 2..].trim()));
         }
+        from = at + 2;
     }
-
-    result
+    (line, None)
 }
 
-fn parse_type(input: &str) -> IResult<&str, Command> {
+fn parse_type<'a>(input: &'a str, aliases: &Aliases) -> IResult<&'a str, Command> {
     let (input, _) = char('$')(input)?;
     let (input, _) = space0(input)?;
     let (input, text) = not_line_ending(input)?;
 
-    let processed_text = parse_type_content(text);
+    let processed_text = parse_type_content(text, aliases);
     Ok((input, Command::Type(processed_text)))
 }
 
-fn parse_line(input: &str) -> IResult<&str, Option<Command>> {
-    alt((
-        map(parse_directive, Some),
-        value(None, parse_comment),
-        map(parse_type, Some),
-    ))
-    .parse(input)
+fn parse_line<'a>(input: &'a str, aliases: &Aliases) -> IResult<&'a str, Option<Command>> {
+    // Each kind of line has its own first character, so only one parser is tried
+    match input.chars().next() {
+        Some('$') => map(|i| parse_type(i, aliases), Some).parse(input),
+        Some('#') => value(None, parse_comment).parse(input),
+        _ => map(parse_directive, Some).parse(input),
+    }
 }
 
-pub fn parse_script(input: &str) -> Result<Script, String> {
+pub fn parse_script(input: &str, options: &ParseOptions) -> Result<Script, String> {
+    parse_script_lines(input, options).map(|(script, _)| script)
+}
+
+/// Parse a complete script, along with the line each command starts on
+pub fn parse_script_lines(
+    input: &str,
+    options: &ParseOptions,
+) -> Result<(Script, Vec<usize>), String> {
+    let mut parser = ScriptParser::new(options)?;
     let mut commands = Vec::new();
+    let mut lines = Vec::new();
+    for line in input.lines() {
+        for command in parser.next_line(line)? {
+            commands.push(command);
+            lines.push(parser.started);
+        }
+    }
+    parser.finish()?;
+    Ok((Script { commands }, lines))
+}
+
+/// The files a complete script includes with `@ include:` and `@ call:`, in
+/// the order they're read
+pub fn included_files(input: &str, options: &ParseOptions) -> Result<Vec<PathBuf>, String> {
+    let mut parser = ScriptParser::new(options)?;
+    for line in input.lines() {
+        parser.next_line(line)?;
+    }
+    parser.finish()?;
+    Ok(parser.included)
+}
+
+/// Parse a script as it's read, yielding each command once its line arrives
+pub fn parse_reader<R: BufRead>(reader: R, options: &ParseOptions) -> Result<Commands<R>, String> {
+    Ok(Commands {
+        lines: reader.lines(),
+        ready: Vec::new().into_iter(),
+        parser: Some(ScriptParser::new(options)?),
+    })
+}
+
+/// Parses a script a line at a time, for scripts that arrive bit by bit
+pub struct ScriptParser {
+    // Config aliases are expanded on their own; script aliases can build on any defined earlier
+    aliases: Aliases,
+    directives: HashSet<String>,
+    profiles: HashMap<String, TypingProfile>,
+    script_dir: Option<PathBuf>,
+    lib_path: Vec<PathBuf>,
+    // Files being included, outermost first
+    including: Vec<PathBuf>,
+    // Every file included so far, nested ones after the file that included them
+    included: Vec<PathBuf>,
+    // From --strict, or `@ strict` on an earlier line
+    strict: bool,
+    // Whether there's been a `$` line yet
+    typed: bool,
+    // Open `@ choose` and `@ retry` blocks, innermost last, with the
+    // branches read so far; a retry block has just the one
+    blocks: Vec<(usize, Block, Vec<Vec<Command>>)>,
+    // An open `@ setup`, `@ teardown` or `@ lua` block, whose lines are kept as they are
+    fixture: Option<(usize, Block, Vec<String>)>,
+    // Lines read so far
+    line_num: usize,
+    // The line the command being read started on
+    started: usize,
+    // A `$` line so far, without the `\` it ended in
+    continued: Option<String>,
+}
+
+impl ScriptParser {
+    pub fn new(options: &ParseOptions) -> Result<Self, String> {
+        let mut aliases = Aliases::new();
+        for (name, keys) in &options.aliases {
+            check_alias_name(name).map_err(|e| format!("Config: {}", e))?;
+            aliases.insert(name.clone(), parse_type_content(keys, &Aliases::new()));
+        }
+        for name in &options.directives {
+            crate::plugin::check_name(name).map_err(|e| format!("Config: {}", e))?;
+        }
+        for (name, profile) in &options.profiles {
+            profile
+                .commands()
+                .iter()
+                .try_for_each(Command::check)
+                .map_err(|e| format!("Config: Profile '{}': {}", name, e))?;
+        }
+        Ok(Self {
+            aliases,
+            directives: options.directives.clone(),
+            profiles: options.profiles.clone(),
+            script_dir: options.script_dir.clone(),
+            lib_path: options.lib_path.clone(),
+            including: Vec::new(),
+            included: Vec::new(),
+            strict: options.strict,
+            typed: false,
+            blocks: Vec::new(),
+            fixture: None,
+            line_num: 0,
+            started: 0,
+            continued: None,
+        })
+    }
 
-    for (line_num, line) in input.lines().enumerate() {
+    /// Parse the next line, returning the command it completes, if any
+    ///
+    /// Commands inside `@ choose` come out together at the `@ endchoose`, and
+    /// likewise for `@ retry`
+    pub fn next_line(&mut self, line: &str) -> Result<Vec<Command>, String> {
+        self.line_num += 1;
+        let line_num = self.line_num;
         let trimmed = line.trim();
 
-        if trimmed.is_empty() {
-            continue;
+        if let Some((_, kind, lines)) = &mut self.fixture {
+            let end = match kind {
+                Block::Setup => Block::EndSetup,
+                Block::Lua => Block::EndLua,
+                _ => Block::EndTeardown,
+            };
+            if parse_block(trimmed) != Ok(("", end)) {
+                lines.push(line.trim_end().to_string());
+                return Ok(Vec::new());
+            }
+            let (_, kind, lines) = self.fixture.take().unwrap();
+            let body = lines.join("\n");
+            let command = match kind {
+                Block::Setup => Command::Setup(body),
+                Block::Lua => Command::Lua(body),
+                _ => Command::Teardown(body),
+            };
+            return Ok(self.complete(command).into_iter().collect());
+        }
+
+        let joined;
+        let trimmed = match self.continued.take() {
+            // The rest of a `$` line, without its indent
+            Some(start) => {
+                joined = start + trimmed;
+                joined.as_str()
+            }
+            None if trimmed.is_empty() => return Ok(Vec::new()),
+            None => {
+                if self.blocks.is_empty() {
+                    self.started = line_num;
+                }
+                trimmed
+            }
+        };
+        // A `\` at the end of a `$` line continues it, and `\\` types a backslash there
+        // instead. Elsewhere backslashes are typed as written, `\\` as two, unless
+        // they escape `<`, `>` or `#!`
+        let trimmed = match trimmed.strip_suffix('\\') {
+            Some(start) if trimmed.starts_with('$') && start.ends_with('\\') => start,
+            Some(start) if trimmed.starts_with('$') => {
+                self.continued = Some(start.to_string());
+                return Ok(Vec::new());
+            }
+            _ => trimmed,
+        };
+
+        if let Ok(("", block)) = parse_block(trimmed) {
+            let open = self.blocks.last().map(|(_, open, _)| *open);
+            let command = match (block, open) {
+                // Run where it is, so it can go inside other blocks
+                (Block::Lua, _) => {
+                    self.fixture = Some((line_num, block, Vec::new()));
+                    return Ok(Vec::new());
+                }
+                (Block::Setup | Block::Teardown, None) => {
+                    self.fixture = Some((line_num, block, Vec::new()));
+                    return Ok(Vec::new());
+                }
+                (Block::Setup | Block::Teardown, Some(open)) => {
+                    return Err(format!(
+                        "Line {}: @ setup and @ teardown can't be inside @ {}",
+                        line_num,
+                        open.name()
+                    ));
+                }
+                (Block::OnError | Block::Finally, Some(open)) => {
+                    return Err(format!(
+                        "Line {}: @ on-error and @ finally can't be inside @ {}",
+                        line_num,
+                        open.name()
+                    ));
+                }
+                (Block::Choose | Block::Retry(_, _) | Block::OnError | Block::Finally, _) => {
+                    self.blocks.push((line_num, block, vec![Vec::new()]));
+                    return Ok(Vec::new());
+                }
+                (Block::Or, Some(Block::Choose)) => {
+                    self.blocks.last_mut().unwrap().2.push(Vec::new());
+                    return Ok(Vec::new());
+                }
+                (Block::End, Some(Block::Choose))
+                | (Block::EndRetry, Some(Block::Retry(_, _)))
+                | (Block::EndOnError, Some(Block::OnError))
+                | (Block::EndFinally, Some(Block::Finally)) => {
+                    let (start, open, mut body) = self.blocks.pop().unwrap();
+                    let command = match open {
+                        Block::Retry(retries, backoff) => {
+                            Command::Retry(retries, backoff, body.remove(0))
+                        }
+                        Block::OnError => Command::OnError(body.remove(0)),
+                        Block::Finally => Command::Finally(body.remove(0)),
+                        _ => Command::Choose(body),
+                    };
+                    // What can go inside is checked once the block is whole
+                    command
+                        .check()
+                        .map_err(|e| format!("Line {}: {}", start, e))?;
+                    command
+                }
+                (_, _) => {
+                    return Err(format!(
+                        "Line {}: '{}' without a matching @ {}",
+                        line_num,
+                        trimmed,
+                        block.name()
+                    ));
+                }
+            };
+            return Ok(self.complete(command).into_iter().collect());
+        }
+
+        // `@ call:NAME` is the library's snippet NAME, as `@ include:NAME.qp`
+        let include = match parse_call(trimmed) {
+            Ok(("", "")) => return Err(format!("Line {}: @ call needs a name", line_num)),
+            Ok(("", name)) => Some(format!("{}.qp", name)),
+            _ => parse_include(trimmed)
+                .ok()
+                .map(|(_, file)| file.to_string()),
+        };
+        if let Some(file) = include {
+            let commands = self
+                .include(&file)
+                .map_err(|e| format!("Line {}: {}", line_num, e))?;
+            return Ok(commands
+                .into_iter()
+                .filter_map(|command| self.complete(command))
+                .collect());
+        }
+
+        if let Ok(("", name)) = parse_profile(trimmed) {
+            let profile = typing::find(name, &self.profiles).ok_or_else(|| {
+                format!(
+                    "Line {}: Unknown profile '{}', add it to the config file's [profiles]",
+                    line_num, name
+                )
+            })?;
+            return Ok(profile
+                .commands()
+                .into_iter()
+                .filter_map(|command| self.complete(command))
+                .collect());
+        }
+
+        if let Ok(("", ())) = parse_strict(trimmed) {
+            self.strict = true;
+            return Ok(Vec::new());
+        }
+
+        if self.strict {
+            self.check_strict(trimmed)
+                .map_err(|e| format!("Line {}: {}", line_num, e))?;
         }
 
-        match parse_line(trimmed) {
+        if let Ok((_, (name, keys))) = parse_alias(trimmed) {
+            check_alias_name(name).map_err(|e| format!("Line {}: {}", line_num, e))?;
+            let expanded = parse_type_content(keys, &self.aliases);
+            self.aliases.insert(name.to_string(), expanded);
+            return Ok(Vec::new());
+        }
+
+        let (trimmed, note) = match trimmed.starts_with('$') {
+            true => split_note(trimmed),
+            false => (trimmed, None),
+        };
+        // `$[NAME] ...` switches to session NAME, then is typed as a `$` line
+        let (session, trimmed) = match parse_session_prefix(trimmed) {
+            Ok((rest, name)) => {
+                let session = Command::Session(name.to_string());
+                session
+                    .check()
+                    .map_err(|e| format!("Line {}: {}", line_num, e))?;
+                (Some(session), Cow::Owned(format!("${}", rest)))
+            }
+            Err(_) => (None, Cow::Borrowed(trimmed)),
+        };
+        let trimmed = trimmed.as_ref();
+        // Built-in directives come first, so a plugin can't change what they
+        // do, but `@ clear-cache` is a plugin's rather than `@ clear` and more
+        let parsed = match parse_line(trimmed, &self.aliases) {
+            Ok((remaining, cmd)) if remaining.trim().is_empty() => Ok((remaining, cmd)),
+            parsed => match parse_plugin(trimmed, &self.directives) {
+                Ok((remaining, cmd)) => Ok((remaining, Some(cmd))),
+                Err(_) => parsed,
+            },
+        };
+        match parsed {
             Ok((remaining, Some(cmd))) => {
                 if !remaining.trim().is_empty() {
                     return Err(format!(
                         "Line {}: Unexpected text after command: '{}'",
-                        line_num + 1,
-                        remaining
+                        line_num, remaining
+                    ));
+                }
+                cmd.check()
+                    .map_err(|e| format!("Line {}: {}", line_num, e))?;
+                let cmd = self.beside_script(cmd);
+                if self.strict && self.typed && is_preamble(&cmd) {
+                    return Err(format!(
+                        "Line {}: '{}' has no effect after the first $ line",
+                        line_num, trimmed
                     ));
                 }
-                commands.push(cmd);
+                self.typed |= matches!(cmd, Command::Type(_));
+                // A line's note comes first, so it's there while the line is typed
+                let note = note.map(|note| Command::Note(note.to_string()));
+                Ok(session
+                    .into_iter()
+                    .chain(note)
+                    .chain([cmd])
+                    .filter_map(|command| self.complete(command))
+                    .collect())
             }
-            Ok((_, None)) => {}
-            Err(e) => {
-                return Err(format!("Line {}: Parse error: {}", line_num + 1, e));
+            Ok((_, None)) => Ok(Vec::new()),
+            Err(nom::Err::Failure(e)) if e.code == ErrorKind::Verify => Err(format!(
+                "Line {}: Times must be non-negative numbers of seconds, optionally with an ms, s or m suffix: '{}'",
+                line_num, trimmed
+            )),
+            Err(e) => Err(format!("Line {}: Parse error: {}", line_num, e)),
+        }
+    }
+
+    // Mistakes in `line` that only `@ strict` catches
+    fn check_strict(&self, line: &str) -> Result<(), String> {
+        let keys = match parse_alias(line) {
+            Ok((_, (_, keys))) => keys,
+            _ if line.starts_with('$') => split_note(line).0,
+            _ => "",
+        };
+        if let Some(spec) = unknown_key(keys, &self.aliases) {
+            return Err(format!(
+                "Unknown key <{}>, write \\<{}> to type it as it is",
+                spec, spec
+            ));
+        }
+        if line.starts_with('@')
+            && parse_directive(line).is_err()
+            && parse_plugin(line, &self.directives).is_err()
+            && parse_alias(line).is_err()
+        {
+            let name = line[1..]
+                .trim_start()
+                .split([':', ' '])
+                .next()
+                .unwrap_or_default();
+            return Err(format!("Unknown directive '@ {}'", name));
+        }
+        Ok(())
+    }
+
+    // Sound files named relative to the script are found beside it, wherever
+    // quipu is run from, as included scripts are
+    fn beside_script(&self, command: Command) -> Command {
+        let Some(dir) = &self.script_dir else {
+            return command;
+        };
+        let resolve = |path: String| match Path::new(&path).is_relative() {
+            true => dir.join(path).to_string_lossy().into_owned(),
+            false => path,
+        };
+        match command {
+            Command::SetSound(Some(path)) => Command::SetSound(Some(resolve(path))),
+            Command::Audio(path) => Command::Audio(resolve(path)),
+            command => command,
+        }
+    }
+
+    // Where `file` is, looking beside the script and then in each library directory
+    fn find_include(&self, file: &str) -> Result<PathBuf, String> {
+        let path = Path::new(file);
+        if path.is_absolute() {
+            return Ok(path.to_path_buf());
+        }
+        let here = self.script_dir.clone().unwrap_or_default();
+        let dirs: Vec<&PathBuf> = std::iter::once(&here).chain(&self.lib_path).collect();
+        dirs.iter()
+            .map(|dir| dir.join(path))
+            .find(|candidate| candidate.is_file())
+            .ok_or_else(|| {
+                let searched: Vec<String> = dirs
+                    .iter()
+                    .map(|dir| match dir.as_os_str().is_empty() {
+                        true => ".".to_string(),
+                        false => dir.display().to_string(),
+                    })
+                    .collect();
+                format!(
+                    "Can't find '{}' to include in {}",
+                    file,
+                    searched.join(", ")
+                )
+            })
+    }
+
+    // The commands in the script `file`, which can use this script's aliases
+    // and leaves its own for the lines after it
+    fn include(&mut self, file: &str) -> Result<Vec<Command>, String> {
+        if file.is_empty() {
+            return Err("@ include needs a file".to_string());
+        }
+        let path = self.find_include(file)?;
+        let real = path.canonicalize().unwrap_or_else(|_| path.clone());
+        if self.including.contains(&real) {
+            return Err(format!("'{}' includes itself", file));
+        }
+        if self.including.len() >= MAX_INCLUDE_DEPTH {
+            return Err(format!("Too many nested @ include lines at '{}'", file));
+        }
+        let content = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+
+        let mut parser = ScriptParser {
+            aliases: std::mem::take(&mut self.aliases),
+            directives: self.directives.clone(),
+            profiles: self.profiles.clone(),
+            script_dir: path.parent().map(Path::to_path_buf),
+            lib_path: self.lib_path.clone(),
+            including: self.including.iter().cloned().chain([real]).collect(),
+            included: Vec::new(),
+            strict: self.strict,
+            typed: self.typed,
+            blocks: Vec::new(),
+            fixture: None,
+            line_num: 0,
+            started: 0,
+            continued: None,
+        };
+        let mut commands = Vec::new();
+        let parsed = content
+            .lines()
+            .try_for_each(|line| {
+                commands.extend(parser.next_line(line)?);
+                Ok(())
+            })
+            .and_then(|_| parser.finish());
+        self.aliases = parser.aliases;
+        self.typed = parser.typed;
+        parsed.map_err(|e| format!("In {}: {}", path.display(), e))?;
+        self.included.push(path);
+        self.included.extend(parser.included);
+
+        if let Some((_, open, _)) = self.blocks.last()
+            && commands.iter().any(|command| {
+                matches!(
+                    command,
+                    Command::Setup(_)
+                        | Command::Teardown(_)
+                        | Command::OnError(_)
+                        | Command::Finally(_)
+                )
+            })
+        {
+            return Err(format!(
+                "'{}' has blocks that can't be inside @ {}",
+                file,
+                open.name()
+            ));
+        }
+        Ok(commands)
+    }
+
+    // Add `command` to the innermost open block, or hand it back if there's none
+    fn complete(&mut self, command: Command) -> Option<Command> {
+        match self.blocks.last_mut() {
+            Some((_, _, branches)) => {
+                branches.last_mut().unwrap().push(command);
+                None
             }
+            None => Some(command),
         }
     }
 
-    Ok(Script { commands })
+    /// Whether the next line belongs to an open block or a continued `$` line
+    pub fn is_open(&self) -> bool {
+        !self.blocks.is_empty() || self.fixture.is_some() || self.continued.is_some()
+    }
+
+    /// Whether the next line continues a `$` line ended with `\`
+    pub fn is_continued(&self) -> bool {
+        self.continued.is_some()
+    }
+
+    /// Check that the script didn't end inside a block or a continued `$` line
+    pub fn finish(&self) -> Result<(), String> {
+        if self.continued.is_some() {
+            return Err(format!(
+                "Line {}: $ line continued with \\ at the end of the script",
+                self.line_num
+            ));
+        }
+        let open = match &self.fixture {
+            Some((line_num, kind, _)) => Some((line_num, kind)),
+            None => self
+                .blocks
+                .last()
+                .map(|(line_num, kind, _)| (line_num, kind)),
+        };
+        match open {
+            Some((line_num, kind)) => Err(format!(
+                "Line {}: @ {} without a matching @ end{}",
+                line_num,
+                kind.name(),
+                kind.name()
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Commands parsed from a reader, from [`parse_reader`]
+///
+/// Stops after the first error, which includes failing to read
+pub struct Commands<R> {
+    lines: Lines<R>,
+    // Commands from the last line not handed out yet
+    ready: std::vec::IntoIter<Command>,
+    // Taken once the script ends or fails
+    parser: Option<ScriptParser>,
+}
+
+impl<R: BufRead> Iterator for Commands<R> {
+    type Item = Result<Command, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(command) = self.ready.next() {
+            return Some(Ok(command));
+        }
+        let parser = self.parser.as_mut()?;
+        let result = loop {
+            match self.lines.next() {
+                Some(Ok(line)) => match parser.next_line(&line) {
+                    Ok(commands) => {
+                        self.ready = commands.into_iter();
+                        if let Some(command) = self.ready.next() {
+                            return Some(Ok(command));
+                        }
+                    }
+                    Err(e) => break Err(e),
+                },
+                Some(Err(e)) => {
+                    break Err(format!(
+                        "Line {}: Failed to read: {}",
+                        parser.line_num + 1,
+                        e
+                    ));
+                }
+                None => match parser.finish() {
+                    Ok(()) => {
+                        self.parser = None;
+                        return None;
+                    }
+                    Err(e) => break Err(e),
+                },
+            }
+        };
+        self.parser = None;
+        Some(result)
+    }
 }
 
 #[cfg(test)]
@@ -341,6 +1551,19 @@ mod tests {
         assert_eq!(cmd, Command::SetSpeed(0.2));
     }
 
+    #[test]
+    fn test_parse_ramp() {
+        assert_eq!(
+            parse_ramp("@ ramp:0.15..0.03"),
+            Ok(("", Command::SetRamp(0.15, 0.03)))
+        );
+        assert_eq!(
+            parse_ramp("@ ramp:1..150ms"),
+            Ok(("", Command::SetRamp(1.0, 0.15)))
+        );
+        assert!(parse_ramp("@ ramp:0.15").is_err());
+    }
+
     #[test]
     fn test_parse_jitter() {
         let input = "@ jitter:0.02";
@@ -350,6 +1573,14 @@ mod tests {
         assert_eq!(cmd, Command::SetJitter(0.02));
     }
 
+    #[test]
+    fn test_parse_wait_jitter() {
+        assert_eq!(
+            parse_directive("@ waitjitter:0.2"),
+            Ok(("", Command::SetWaitJitter(0.2)))
+        );
+    }
+
     #[test]
     fn test_parse_wait() {
         let input = "@ wait:2.0";
@@ -359,6 +1590,114 @@ mod tests {
         assert_eq!(cmd, Command::Wait(Duration::from_secs_f64(2.0)));
     }
 
+    #[test]
+    fn test_parse_wait_random() {
+        let (_, cmd) = parse_wait_random("@ wait-random:0.5:2").unwrap();
+        assert_eq!(
+            cmd,
+            Command::WaitRandom(Duration::from_millis(500), Duration::from_secs(2))
+        );
+
+        let options = ParseOptions::default();
+        assert!(parse_script("@ wait-random:3:1", &options).is_err());
+        assert!(parse_script("@ wait-random:-1:1", &options).is_err());
+    }
+
+    #[test]
+    fn test_parse_time_units() {
+        let options = ParseOptions::default();
+        let script = parse_script(
+            "@ wait:500ms\n@ wait:2s\n@ wait:1m\n@ wait:1.5\n@ speed:50ms\n",
+            &options,
+        )
+        .unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Wait(Duration::from_millis(500)),
+                Command::Wait(Duration::from_secs(2)),
+                Command::Wait(Duration::from_secs(60)),
+                Command::Wait(Duration::from_millis(1500)),
+                Command::SetSpeed(0.05),
+            ]
+        );
+
+        assert!(parse_script("@ wait:2h", &options).is_err());
+        let error = parse_script("$ ls\n@ wait:-1", &options).unwrap_err();
+        assert!(
+            error.starts_with("Line 2: Times must be non-negative"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_values() {
+        let options = ParseOptions::default();
+        for input in ["@ jitter:1.5", "@ jitter:-0.1", "@ size:0:24", "@ speed:-1"] {
+            assert!(parse_script(input, &options).is_err(), "{}", input);
+        }
+        assert_eq!(
+            parse_script("@ jitter:2", &options).unwrap_err(),
+            "Line 1: Jitter must be between 0.0 and 1.0: 2"
+        );
+    }
+
+    #[test]
+    fn test_parse_exec() {
+        let script = parse_script(
+            "@ exec:./reset-db.sh --force\n@ exec:curl -s localhost -> BODY\n@ exec-async:make serve\n",
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Exec("./reset-db.sh --force".to_string(), None),
+                Command::Exec("curl -s localhost".to_string(), Some("BODY".to_string())),
+                Command::ExecAsync("make serve".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_expect() {
+        let script = parse_script(
+            "@ expect:PID is (\\d+) -> PID\n@ expect:(\\w+):(\\d+) -> HOST, PORT\n@ expect:a -> b\n",
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Expect(r"PID is (\d+)".to_string(), vec!["PID".to_string()]),
+                Command::Expect(
+                    r"(\w+):(\d+)".to_string(),
+                    vec!["HOST".to_string(), "PORT".to_string()]
+                ),
+                Command::Expect("a".to_string(), vec!["b".to_string()]),
+            ]
+        );
+        assert!(parse_script("@ expect:(a) -> A, B\n", &ParseOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_parse_webhook() {
+        let script = parse_script(
+            "@ webhook:http://localhost:8000/scene/2\n",
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            script.commands,
+            vec![Command::Webhook(
+                "http://localhost:8000/scene/2".to_string()
+            )]
+        );
+        assert!(parse_script("@ webhook:https://example.com/\n", &ParseOptions::default()).is_ok());
+        assert!(parse_script("@ webhook:ftp://example.com/\n", &ParseOptions::default()).is_err());
+    }
+
     #[test]
     fn test_parse_shell() {
         let input = "@ shell:/bin/zsh";
@@ -371,20 +1710,20 @@ mod tests {
     #[test]
     fn test_parse_type() {
         let input = "$ echo hello";
-        let result = parse_type(input);
+        let result = parse_type(input, &Aliases::new());
         assert!(result.is_ok());
         let (_, cmd) = result.unwrap();
-        assert_eq!(cmd, Command::Type("echo hello".to_string()));
+        assert_eq!(cmd, Command::Type("echo hello".into()));
     }
 
     #[test]
     fn test_parse_type_with_special_keys() {
         let input = "$ echo hello<ret>";
-        let result = parse_type(input);
+        let result = parse_type(input, &Aliases::new());
         assert!(result.is_ok());
         let (_, cmd) = result.unwrap();
         if let Command::Type(text) = cmd {
-            assert_eq!(text, "echo hello\r");
+            assert_eq!(text.legacy(), "echo hello\r");
         } else {
             panic!("Expected Type command");
         }
@@ -393,11 +1732,11 @@ mod tests {
     #[test]
     fn test_parse_type_with_ctrl() {
         let input = "$ <C-c>";
-        let result = parse_type(input);
+        let result = parse_type(input, &Aliases::new());
         assert!(result.is_ok());
         let (_, cmd) = result.unwrap();
         if let Command::Type(text) = cmd {
-            assert_eq!(text, "\x03"); // Ctrl-C
+            assert_eq!(text.legacy(), "\x03"); // Ctrl-C
         } else {
             panic!("Expected Type command");
         }
@@ -406,14 +1745,58 @@ mod tests {
     #[test]
     fn test_parse_type_with_escaped() {
         let input = r"$ \<not a key\>";
-        let result = parse_type(input);
+        let result = parse_type(input, &Aliases::new());
         assert!(result.is_ok());
         let (_, cmd) = result.unwrap();
         if let Command::Type(text) = cmd {
-            assert_eq!(text, "<not a key>");
+            assert_eq!(text.legacy(), "<not a key>");
         } else {
             panic!("Expected Type command");
         }
+
+        // Backslashes and brackets that aren't keys are typed as they are
+        let (_, cmd) = parse_type(r"$ a\b > c<ret>\\<", &Aliases::new()).unwrap();
+        assert_eq!(cmd, Command::Type("a\\b > c\r\\<".into()));
+    }
+
+    #[test]
+    fn test_parse_continued_lines() {
+        let input = "$ docker run --rm \\\n    -it alpine<ret>\n$ echo \\\\\n$ echo a\\\\b\n";
+        let script = parse_script(input, &ParseOptions::default()).unwrap();
+        assert_eq!(
+            script.commands,
+            [
+                Command::Type("docker run --rm -it alpine\r".into()),
+                Command::Type("echo \\".into()),
+                Command::Type("echo a\\\\b".into()),
+            ]
+        );
+        let (_, lines) = parse_script_lines(input, &ParseOptions::default()).unwrap();
+        assert_eq!(lines, [1, 3, 4]);
+
+        let unfinished = parse_script("$ ls \\\n", &ParseOptions::default());
+        assert!(unfinished.unwrap_err().starts_with("Line 1:"));
+    }
+
+    #[test]
+    fn test_parse_notes() {
+        let input = "$ ls -la<ret>  #! list files\n$ echo '#!/bin/sh' # comment<ret>\n\
+                     $ echo \\#!/bin/sh<ret> #! shebang\n$ printf ' \\#!x'<ret>\n";
+        let commands = parse_reader(input.as_bytes(), &ParseOptions::default())
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            commands,
+            [
+                Command::Note("list files".to_string()),
+                Command::Type("ls -la\r".into()),
+                Command::Type("echo '#!/bin/sh' # comment\r".into()),
+                Command::Note("shebang".to_string()),
+                Command::Type("echo #!/bin/sh\r".into()),
+                Command::Type("printf ' #!x'\r".into()),
+            ]
+        );
     }
 
     #[test]
@@ -425,7 +1808,7 @@ $ echo hello
 @ wait:1.0
 $ ls -la
 "#;
-        let result = parse_script(input);
+        let result = parse_script(input, &ParseOptions::default());
         if let Err(e) = &result {
             eprintln!("Parse error: {}", e);
         }
@@ -434,26 +1817,40 @@ $ ls -la
         assert_eq!(script.commands.len(), 5);
     }
 
+    #[test]
+    fn test_parse_script_with_crlf() {
+        let input = "@ speed:0.2\n@ choose\n$ dir<ret>\n@ endchoose\n$ <up><C-c>\n";
+        let options = ParseOptions::default();
+        let script = parse_script(input, &options).unwrap();
+        assert_eq!(
+            parse_script(&input.replace('\n', "\r\n"), &options)
+                .unwrap()
+                .commands,
+            script.commands
+        );
+        assert_eq!(script.commands[2], Command::Type("\x1b[A\x03".into()));
+    }
+
     #[test]
     fn test_parse_alt_with_special_keys() {
         // Test Alt-Enter
         let input = "$ <A-ret>";
-        let result = parse_type(input);
+        let result = parse_type(input, &Aliases::new());
         assert!(result.is_ok());
         let (_, cmd) = result.unwrap();
         if let Command::Type(text) = cmd {
-            assert_eq!(text, "\x1b\r"); // ESC + carriage return
+            assert_eq!(text.legacy(), "\x1b\r"); // ESC + carriage return
         } else {
             panic!("Expected Type command");
         }
 
         // Test Alt-space
         let input = "$ <A-space>";
-        let result = parse_type(input);
+        let result = parse_type(input, &Aliases::new());
         assert!(result.is_ok());
         let (_, cmd) = result.unwrap();
         if let Command::Type(text) = cmd {
-            assert_eq!(text, "\x1b "); // ESC + space
+            assert_eq!(text.legacy(), "\x1b "); // ESC + space
         } else {
             panic!("Expected Type command");
         }
@@ -463,13 +1860,471 @@ $ ls -la
     fn test_parse_ctrl_with_special_keys() {
         // Test Ctrl-space
         let input = "$ <C-space>";
-        let result = parse_type(input);
+        let result = parse_type(input, &Aliases::new());
         assert!(result.is_ok());
         let (_, cmd) = result.unwrap();
         if let Command::Type(text) = cmd {
-            assert_eq!(text, "\x00"); // Ctrl-space
+            assert_eq!(text.legacy(), "\x00"); // Ctrl-space
         } else {
             panic!("Expected Type command");
         }
     }
+
+    #[test]
+    fn test_parse_aliases() {
+        let input = r#"@ alias:save=<esc>:w<ret>
+@ alias:quit=<save>:q<ret>
+$ ihello<save>
+$ <quit><unknown>
+"#;
+        let script = parse_script(input, &ParseOptions::default()).unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Type("ihello\x1b:w\r".into()),
+                Command::Type("\x1b:w\r:q\r<unknown>".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_config_aliases() {
+        let options = ParseOptions {
+            aliases: HashMap::from([("top".to_string(), "gg<C-l>".to_string())]),
+            ..ParseOptions::default()
+        };
+        let script = parse_script("$ <top>", &options).unwrap();
+        assert_eq!(script.commands, vec![Command::Type("gg\x0c".into())]);
+
+        // Script aliases replace config ones
+        let script = parse_script("@ alias:top=G\n$ <top>", &options).unwrap();
+        assert_eq!(script.commands, vec![Command::Type("G".into())]);
+    }
+
+    #[test]
+    fn test_parse_plugin_directives() {
+        let options = ParseOptions {
+            directives: HashSet::from(["db-reset", "clear-cache", "wait"].map(String::from)),
+            ..ParseOptions::default()
+        };
+        let script = parse_script(
+            "@ db-reset:fixtures/shop.sql\n@ db-reset\n@ clear-cache\n@ wait:1",
+            &options,
+        )
+        .unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Plugin("db-reset".to_string(), "fixtures/shop.sql".to_string()),
+                Command::Plugin("db-reset".to_string(), String::new()),
+                Command::Plugin("clear-cache".to_string(), String::new()),
+                Command::Wait(Duration::from_secs(1)),
+            ]
+        );
+        assert!(parse_script("@ db-reset", &ParseOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_alias_cannot_shadow_keys() {
+        let result = parse_script("@ alias:ret=x", &ParseOptions::default());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_ctrl_punctuation() {
+        let cases = [
+            ("<C-@>", "\x00"),
+            ("<C-2>", "\x00"),
+            ("<C-3>", "\x1b"),
+            ("<C-4>", "\x1c"),
+            ("<C-5>", "\x1d"),
+            ("<C-6>", "\x1e"),
+            ("<C-^>", "\x1e"),
+            ("<C-_>", "\x1f"),
+            ("<C-/>", "\x1f"),
+            ("<C-->", "\x1f"),
+            ("<C-7>", "\x1f"),
+            ("<C-8>", "\x7f"),
+            ("<C-A-6>", "\x1b\x1e"),
+            ("<C-1>", "<C-1>"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                parse_type_content(input, &Aliases::new()).legacy(),
+                expected,
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_shift_special_keys() {
+        let cases = [
+            ("<S-F5>", "\x1b[15;2~"),
+            ("<S-F1>", "\x1b[1;2P"),
+            ("<S-home>", "\x1b[1;2H"),
+            ("<S-del>", "\x1b[3;2~"),
+            ("<S-up>", "\x1b[1;2A"),
+            ("<C-S-right>", "\x1b[1;6C"),
+            ("<A-S-left>", "\x1b[1;4D"),
+            ("<S-tab>", "\x1b[Z"),
+            ("<S-ret>", "\r"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                parse_type_content(input, &Aliases::new()).legacy(),
+                expected,
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_cursor_keys() {
+        let script = parse_script(
+            "@ cursor-keys:application\n@ cursor-keys:auto\n",
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetCursorKeys(CursorKeys::Application),
+                Command::SetCursorKeys(CursorKeys::Auto),
+            ]
+        );
+        assert!(parse_script("@ cursor-keys:fast\n", &ParseOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_parse_reader() {
+        let input = "@ speed:0.1\n@ choose\n$ ls\n@ endchoose\n$ pwd\n@ bogus\n$ exit\n";
+        let commands: Vec<_> = parse_reader(input.as_bytes(), &ParseOptions::default())
+            .unwrap()
+            .collect();
+        assert_eq!(
+            commands[..3],
+            [
+                Ok(Command::SetSpeed(0.1)),
+                Ok(Command::Choose(vec![vec![Command::Type("ls".into())]])),
+                Ok(Command::Type("pwd".into())),
+            ]
+        );
+        // Nothing after the first error
+        assert!(commands[3].as_ref().unwrap_err().starts_with("Line 6:"));
+        assert_eq!(commands.len(), 4);
+
+        let mut unclosed =
+            parse_reader(&b"@ choose\n$ ls\n"[..], &ParseOptions::default()).unwrap();
+        assert!(unclosed.next().unwrap().is_err());
+        assert!(unclosed.next().is_none());
+    }
+
+    #[test]
+    fn test_parse_choose() {
+        let input = r#"@ choose
+$ ls
+@ or
+  @ choose
+  $ pwd
+  @ endchoose
+@ endchoose
+$ exit
+"#;
+        let script = parse_script(input, &ParseOptions::default()).unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Choose(vec![
+                    vec![Command::Type("ls".into())],
+                    vec![Command::Choose(vec![vec![Command::Type("pwd".into())]])],
+                ]),
+                Command::Type("exit".into()),
+            ]
+        );
+
+        let options = ParseOptions::default();
+        assert!(
+            parse_script(
+                "@ choose
+$ ls
+",
+                &options
+            )
+            .is_err()
+        );
+        assert!(
+            parse_script(
+                "@ or
+", &options
+            )
+            .is_err()
+        );
+        // Playback can only resume from a checkpoint it's sure to reach
+        assert_eq!(
+            parse_script(
+                "$ ls\n@ choose\n@ or\n@ checkpoint:demo\n@ endchoose\n",
+                &options
+            )
+            .unwrap_err(),
+            "Line 2: @ setup, @ teardown, @ checkpoint, @ on-error and @ finally can't be inside @ choose"
+        );
+    }
+
+    #[test]
+    fn test_parse_fixtures() {
+        let input = "@ setup\nmkdir -p /tmp/demo\n\n  cd /tmp/demo && git init -q\n@ endsetup\n$ ls<ret>\n@ teardown\n# left to the shell\nrm -rf /tmp/demo\n@ endteardown\n";
+        let script = parse_script(input, &ParseOptions::default()).unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Setup("mkdir -p /tmp/demo\n\n  cd /tmp/demo && git init -q".to_string()),
+                Command::Type("ls\r".into()),
+                Command::Teardown("# left to the shell\nrm -rf /tmp/demo".to_string()),
+            ]
+        );
+
+        let options = ParseOptions::default();
+        assert!(parse_script("@ setup\ntrue\n", &options).is_err());
+        assert!(parse_script("@ endteardown\n", &options).is_err());
+        assert!(parse_script("@ choose\n@ setup\n@ endsetup\n@ endchoose\n", &options).is_err());
+        assert!(
+            parse_script(
+                "@ on-error\n@ finally\n@ endfinally\n@ endon-error\n",
+                &options
+            )
+            .is_err()
+        );
+        assert!(parse_script("@ finally\n$ exit\n", &options).is_err());
+        assert!(parse_script("@ retry:2\n@ checkpoint:a\n@ endretry\n", &options).is_err());
+    }
+
+    #[test]
+    fn test_parse_lua() {
+        let input = "@ retry:2\n@ lua\nfor i = 1, 3 do\n  print(\"$ echo \" .. i .. \"<ret>\")\nend\n@ endlua\n@ endretry\n";
+        let script = parse_script(input, &ParseOptions::default()).unwrap();
+        assert_eq!(
+            script.commands,
+            vec![Command::Retry(
+                2,
+                2.0,
+                vec![Command::Lua(
+                    "for i = 1, 3 do\n  print(\"$ echo \" .. i .. \"<ret>\")\nend".to_string()
+                )]
+            )]
+        );
+        assert!(parse_script("@ lua\nprint(1)\n", &ParseOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_parse_sounds() {
+        let input = "@ sound:click.wav\n@ audio:/tmp/intro.mp3\n@ sound:off\n";
+        let options = ParseOptions::default().for_script(Path::new("demos/git.qp"));
+        let script = parse_script(input, &options).unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetSound(Some(
+                    Path::new("demos").join("click.wav").display().to_string()
+                )),
+                Command::Audio("/tmp/intro.mp3".to_string()),
+                Command::SetSound(None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_include() {
+        let dir = std::env::temp_dir().join(format!("quipu-include-{}", std::process::id()));
+        let lib = dir.join("lib");
+        std::fs::create_dir_all(&lib).unwrap();
+        std::fs::write(lib.join("login.qp"), "@ alias:pw=hunter2\n$ login<ret>\n").unwrap();
+        std::fs::write(dir.join("loop.qp"), "@ include:loop.qp\n").unwrap();
+
+        let options = ParseOptions {
+            lib_path: vec![lib.clone()],
+            ..ParseOptions::default()
+        }
+        .for_script(&dir.join("demo.qp"));
+        let script = parse_script(
+            "@ retry:2\n@ include:login.qp\n@ endretry\n$ <pw><ret>\n",
+            &options,
+        )
+        .unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Retry(2, 2.0, vec![Command::Type("login\r".into())]),
+                Command::Type("hunter2\r".into()),
+            ]
+        );
+
+        // A snippet by name, read in like any other included file
+        let input = "@ call:login\n$ <pw><ret>\n";
+        let script = parse_script(input, &options).unwrap();
+        assert_eq!(script.commands[0], Command::Type("login\r".into()));
+        assert_eq!(
+            included_files(input, &options).unwrap(),
+            [lib.join("login.qp")]
+        );
+        assert!(parse_script("@ call:\n", &options).is_err());
+
+        assert!(parse_script("@ include:loop.qp\n", &options).is_err());
+        let missing = parse_script("@ include:logout.qp\n", &options).unwrap_err();
+        assert!(missing.contains(&lib.display().to_string()), "{}", missing);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_profile() {
+        let options = ParseOptions {
+            profiles: HashMap::from([(
+                "prose".to_string(),
+                TypingProfile {
+                    ramp: Some((0.2, 0.05)),
+                    word_pause: Some(0.3),
+                    ..TypingProfile::default()
+                },
+            )]),
+            ..ParseOptions::default()
+        };
+        let script = parse_script("@ profile:code\n@ profile:prose\n", &options).unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetSpeed(0.05),
+                Command::SetJitter(0.2),
+                Command::SetWordPause(0.0),
+                Command::SetRamp(0.2, 0.05),
+                Command::SetWordPause(0.3),
+            ]
+        );
+        assert!(parse_script("@ profile:poetry\n", &options).is_err());
+    }
+
+    #[test]
+    fn test_parse_session() {
+        let options = ParseOptions::default();
+        let script = parse_script(
+            "$[client] curl :${PORT}<ret> #! fetch\n@ session:main\n$ ls\n",
+            &options,
+        )
+        .unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Session("client".to_string()),
+                Command::Note("fetch".to_string()),
+                Command::Type("curl :${PORT}\r".into()),
+                Command::Session("main".to_string()),
+                Command::Type("ls".into()),
+            ]
+        );
+        assert!(parse_script("$[1+2] ls\n", &options).is_err());
+    }
+
+    #[test]
+    fn test_parse_strict() {
+        let options = ParseOptions::default();
+        let typo = "@ alias:save=<esc>:w<ret>\n$ sort < in > out<save>\\<b><rett>\n";
+        assert!(parse_script(typo, &options).is_ok());
+        let strict = format!("@ strict\n{}", typo);
+        assert_eq!(
+            parse_script(&strict, &options).unwrap_err(),
+            "Line 3: Unknown key <rett>, write \\<rett> to type it as it is".to_string()
+        );
+        assert_eq!(
+            parse_script("@ strict\n@ sped:0.1\n", &options).unwrap_err(),
+            "Line 2: Unknown directive '@ sped'".to_string()
+        );
+        assert_eq!(
+            parse_script("@  strict\n@ sped:0.1\n", &options).unwrap_err(),
+            "Line 2: Unknown directive '@ sped'".to_string()
+        );
+        assert!(parse_script("@ size:80:24\n$ ls<ret>\n@ size:100:30\n", &options).is_ok());
+        let options = ParseOptions {
+            strict: true,
+            ..ParseOptions::default()
+        };
+        assert_eq!(
+            parse_script("@ size:80:24\n$ ls<ret>\n@ size:100:30\n", &options).unwrap_err(),
+            "Line 3: '@ size:100:30' has no effect after the first $ line".to_string()
+        );
+    }
+
+    #[test]
+    fn test_parse_retry() {
+        let input =
+            "@ retry:3\n$ ls<ret>\n@ expect:done\n@ endretry\n@retry:1 backoff:1.5\n@ endretry\n";
+        let script = parse_script(input, &ParseOptions::default()).unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::Retry(
+                    3,
+                    2.0,
+                    vec![
+                        Command::Type("ls\r".into()),
+                        Command::Expect("done".to_string(), vec![]),
+                    ]
+                ),
+                Command::Retry(1, 1.5, vec![]),
+            ]
+        );
+
+        let options = ParseOptions::default();
+        assert!(parse_script("@ retry:0\n@ endretry\n", &options).is_err());
+        assert!(parse_script("@ retry:2 backoff:0.5\n@ endretry\n", &options).is_err());
+        assert!(parse_script("@ retry:2\n$ ls\n", &options).is_err());
+        assert!(parse_script("@ retry:2\n@ or\n@ endretry\n", &options).is_err());
+        assert!(parse_script("@ choose\n@ endretry\n", &options).is_err());
+    }
+
+    #[test]
+    fn test_parse_newline() {
+        let script =
+            parse_script("@ newline:crlf\n@ newline:cr\n", &ParseOptions::default()).unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetNewline(Newline::Crlf),
+                Command::SetNewline(Newline::Cr),
+            ]
+        );
+
+        let script = parse_script("@ multiline:paste\n", &ParseOptions::default()).unwrap();
+        assert_eq!(
+            script.commands,
+            vec![Command::SetMultiline(Multiline::Paste)]
+        );
+    }
+
+    #[test]
+    fn test_parse_no_history() {
+        let script = parse_script(
+            "@ no-history\n@ no-history:space\n",
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetNoHistory(NoHistory::Histfile),
+                Command::SetNoHistory(NoHistory::Space),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_encoding() {
+        let script = parse_script("@ encoding:Shift_JIS\n", &ParseOptions::default()).unwrap();
+        assert_eq!(
+            script.commands,
+            vec![Command::SetEncoding("Shift_JIS".to_string())]
+        );
+        assert!(parse_script("@ encoding:klingon\n", &ParseOptions::default()).is_err());
+        assert!(parse_script("@ encoding:utf-16le\n", &ParseOptions::default()).is_err());
+    }
 }
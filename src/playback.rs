@@ -17,52 +17,897 @@
 //!
 //! Executes parsed commands with proper timing and jitter
 
-use anyhow::Result;
-use rand::Rng;
+use anyhow::{Context, Result};
+use encoding_rs::{EncoderResult, Encoding, UTF_8};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use regex::bytes::RegexBuilder;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::{
-    Arc,
+    Arc, OnceLock,
     atomic::{AtomicBool, Ordering},
 };
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Receiver;
 use tokio::time::sleep;
+use tracing::Instrument;
 
+use crate::artifacts::Artifacts;
+use crate::captions::{self, CaptionTrack};
+use crate::cast::Cast;
+use crate::clipboard;
+use crate::condition;
+use crate::error::{self, Error};
+use crate::events::{EventStream, Events, PlaybackEvent, PlaybackHooks};
+use crate::explain;
+use crate::format::{format_command, format_type_content};
+use crate::host;
+use crate::input;
+use crate::key::{Key, KeyEvent, Keys, Modifiers, Typed};
+use crate::keycast::KeyCast;
+use crate::lua;
+use crate::parser::is_variable_name;
+use crate::plugin;
+use crate::presenter::{Event, PresenterServer};
 use crate::pty::PtyManager;
-use crate::types::{Command, PlaybackConfig, Script};
+use crate::schedule::Schedule;
+use crate::screen::{KeyModes, Screen};
+use crate::sink::{KeystrokeSink, Signal};
+use crate::sound::{self, Sounds};
+use crate::stats::PlaybackStats;
+use crate::supervisor;
+use crate::types::{
+    Command, CursorKeys, Multiline, NoHistory, PlaybackConfig, Script, Timing, WaitCondition,
+    text_encoding,
+};
+use crate::webhook::Webhooks;
+
+// Length of the escape sequence at the start of `bytes`, sent whole so the
+// program never sees part of one; unfinished sequences run to the end
+fn escape_sequence_length(bytes: &[u8]) -> usize {
+    let Some(&next) = bytes.get(1) else {
+        return bytes.len();
+    };
+    let end = |i: Option<usize>| i.unwrap_or(bytes.len());
+
+    match next {
+        // CSI: parameters and intermediates, then a final byte; anything else
+        // ends a malformed sequence where it is
+        b'[' => {
+            let rest = bytes[2..].iter().position(|&b| !(0x20..=0x3f).contains(&b));
+            match rest.map(|i| (i + 2, bytes[i + 2])) {
+                Some((i, 0x40..=0x7e)) => i + 1,
+                other => end(other.map(|(i, _)| i)),
+            }
+        }
+        // SS3, as the keypad and F1-F4 send
+        b'O' => bytes.len().min(3),
+        // OSC, DCS, APC, PM and SOS strings, ended by BEL or ST; any other
+        // ESC ends the string and starts a sequence of its own
+        b']' | b'P' | b'_' | b'^' | b'X' => {
+            let mut i = 2;
+            while i < bytes.len() {
+                match bytes[i] {
+                    0x07 => return i + 1,
+                    0x1b if bytes.get(i + 1) == Some(&b'\\') => return i + 2,
+                    0x1b => return i,
+                    _ => i += 1,
+                }
+            }
+            i
+        }
+        // Alt with a key that's a sequence itself, as `ESC ESC [A` for Alt-Up
+        0x1b if matches!(bytes.get(2), Some(b'[' | b'O')) => {
+            1 + escape_sequence_length(&bytes[1..])
+        }
+        // Intermediates then a final byte, as in `ESC ( B`
+        0x20..=0x2f => {
+            let rest = bytes[2..].iter().position(|&b| !(0x20..=0x2f).contains(&b));
+            end(rest.map(|i| i + 3)).min(bytes.len())
+        }
+        // Alt with a character, which may take several bytes
+        _ => 1 + utf8_len(next),
+    }
+}
+
+// Bytes in the UTF-8 character starting with `first`
+fn utf8_len(first: u8) -> usize {
+    match first {
+        0xf0.. => 4,
+        0xe0.. => 3,
+        0xc0.. => 2,
+        _ => 1,
+    }
+}
+
+// CJK runs longer than this are committed in several chunks
+const MAX_COMPOSE_CHARS: usize = 4;
+
+// Rough number of romanized keystrokes per composed character
+const KEYS_PER_COMPOSED_CHAR: u32 = 2;
+
+// Kana, Hangul and CJK ideographs, which are normally entered through an IME
+fn is_composed(c: char) -> bool {
+    matches!(c,
+        '\u{1100}'..='\u{11ff}'
+        | '\u{3040}'..='\u{30ff}'
+        | '\u{3130}'..='\u{318f}'
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}'
+        | '\u{ac00}'..='\u{d7af}'
+        | '\u{f900}'..='\u{faff}'
+        | '\u{ff66}'..='\u{ff9f}'
+    )
+}
+
+// How many characters `keystroke` commits at once through the IME, if any
+fn composed_chars(keystroke: Keystroke, config: &PlaybackConfig) -> Option<u32> {
+    match keystroke {
+        Keystroke::Text(text) if config.ime && text.chars().all(is_composed) => {
+            Some(text.chars().count() as u32)
+        }
+        _ => None,
+    }
+}
+
+/// Split typed text into keystrokes, grouping runs of CJK text into
+/// chunks that are committed at once like IME output
+pub fn split_composed(text: &str) -> Vec<&str> {
+    let mut chunks: Vec<&str> = Vec::new();
+    let mut chunk_start: Option<(usize, usize)> = None;
+
+    for keystroke in split_keystrokes(text) {
+        let offset = keystroke.as_ptr() as usize - text.as_ptr() as usize;
+        let composed = keystroke.chars().count() == 1 && keystroke.chars().all(is_composed);
+
+        match chunk_start {
+            Some((start, count)) if composed && count < MAX_COMPOSE_CHARS => {
+                chunk_start = Some((start, count + 1));
+                continue;
+            }
+            Some((start, _)) => chunks.push(&text[start..offset]),
+            None => {}
+        }
+
+        if composed {
+            chunk_start = Some((offset, 1));
+        } else {
+            chunk_start = None;
+            chunks.push(keystroke);
+        }
+    }
+
+    if let Some((start, _)) = chunk_start {
+        chunks.push(&text[start..]);
+    }
+    chunks
+}
+
+/// Split typed text into keystrokes, keeping escape sequences whole
+pub fn split_keystrokes(text: &str) -> Vec<&str> {
+    let bytes = text.as_bytes();
+    let mut keystrokes = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let len = if bytes[i] == 0x1b {
+            escape_sequence_length(&bytes[i..])
+        } else {
+            text[i..].chars().next().unwrap().len_utf8()
+        };
+        keystrokes.push(&text[i..i + len]);
+        i += len;
+    }
+
+    keystrokes
+}
+
+/// One unit sent to the PTY: a run of text, or a key encoded as it's sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keystroke<'a> {
+    Text(&'a str),
+    Key(KeyEvent),
+}
+
+impl Keystroke<'_> {
+    /// The keystroke as written on a `$` line
+    pub fn written(self) -> String {
+        let keys = match self {
+            Keystroke::Text(text) => Keys::text(text),
+            Keystroke::Key(event) => Keys::key(event),
+        };
+        format_type_content(&keys)
+    }
+}
+
+/// Convert typed text to the target encoding, sending `?` for characters it can't represent
+pub fn encode_text<'a>(text: &'a str, encoding: &'static Encoding) -> Cow<'a, [u8]> {
+    if encoding == UTF_8 {
+        return Cow::Borrowed(text.as_bytes());
+    }
+
+    let mut encoder = encoding.new_encoder();
+    let mut out = Vec::new();
+    let mut remaining = text;
+    loop {
+        out.reserve(remaining.len() * 4 + 8);
+        let (result, read) =
+            encoder.encode_from_utf8_to_vec_without_replacement(remaining, &mut out, true);
+        remaining = &remaining[read..];
+        match result {
+            EncoderResult::InputEmpty => break,
+            EncoderResult::OutputFull => {}
+            EncoderResult::Unmappable(_) => out.push(b'?'),
+        }
+    }
+    Cow::Owned(out)
+}
+
+// Apply a manual cursor key override on top of the terminal's own modes
+fn override_cursor_keys(mut modes: KeyModes, cursor_keys: CursorKeys) -> KeyModes {
+    match cursor_keys {
+        CursorKeys::Auto => {}
+        CursorKeys::Normal => modes.application_cursor = false,
+        CursorKeys::Application => modes.application_cursor = true,
+    }
+    modes
+}
+
+/// Split typed keys into the units sent to the PTY with the current settings
+pub fn typed_keystrokes<'a>(keys: &'a Keys, config: &PlaybackConfig) -> Vec<Keystroke<'a>> {
+    let mut keystrokes = Vec::new();
+    for typed in keys.iter() {
+        match typed {
+            Typed::Text(text) if config.ime => {
+                keystrokes.extend(split_composed(text).into_iter().map(Keystroke::Text))
+            }
+            Typed::Text(text) => {
+                keystrokes.extend(split_keystrokes(text).into_iter().map(Keystroke::Text))
+            }
+            Typed::Key(event) => keystrokes.push(Keystroke::Key(*event)),
+        }
+    }
+    keystrokes
+}
+
+/// Bytes sent for one keystroke, given the input modes the terminal has set
+pub fn keystroke_bytes(
+    keystroke: Keystroke,
+    config: &PlaybackConfig,
+    terminal: KeyModes,
+) -> Vec<u8> {
+    let newline = || encode_text(config.newline.sequence(), config.encoding).into_owned();
+    match keystroke {
+        Keystroke::Text("\r") => newline(),
+        Keystroke::Text("\n") if config.multiline != Multiline::Literal => newline(),
+        Keystroke::Text(text) => encode_text(text, config.encoding).into_owned(),
+        Keystroke::Key(KeyEvent {
+            key: Key::Enter,
+            mods: Modifiers::NONE,
+        }) => newline(),
+        Keystroke::Key(event) => {
+            let modes = override_cursor_keys(terminal, config.cursor_keys);
+            let seq = event.encode(modes).or_else(|| event.legacy());
+            encode_text(&seq.unwrap_or_default(), config.encoding).into_owned()
+        }
+    }
+}
+
+/// Bytes for `text` sent as a bracketed paste, with line breaks as a
+/// terminal pastes them
+pub fn paste_bytes(text: &str, config: &PlaybackConfig) -> Vec<u8> {
+    // The end marker can't appear inside, or the rest would be run as typed
+    let text = text.replace("\x1b[201~", "").replace('\n', "\r");
+    let mut bytes = b"\x1b[200~".to_vec();
+    bytes.extend_from_slice(&encode_text(&text, config.encoding));
+    bytes.extend_from_slice(b"\x1b[201~");
+    bytes
+}
+
+/// Roughly how long `commands` take to play, without jitter, `@ exec` or
+/// waiting for --advance; `@ choose` blocks count as their average branch
+pub fn estimate_duration(commands: &[Command], config: &mut PlaybackConfig) -> Duration {
+    let mut total = Duration::ZERO;
+    for command in commands {
+        total += match command {
+            Command::Wait(duration) => *duration,
+            Command::WaitRandom(min, max) => (*min + *max) / 2,
+            Command::Choose(branches) if !branches.is_empty() => {
+                let sum: Duration = branches
+                    .iter()
+                    .map(|branch| estimate_duration(branch, &mut config.clone()))
+                    .sum();
+                sum / branches.len() as u32
+            }
+            // Assuming it works first time
+            Command::Retry(_, _, commands) | Command::Finally(commands) => {
+                estimate_duration(commands, config)
+            }
+            Command::Type(text) => {
+                let keystrokes = typed_keystrokes(text, config);
+                let count = keystrokes.len();
+                let keys: f64 = keystrokes
+                    .iter()
+                    .enumerate()
+                    .map(|(index, keystroke)| {
+                        let mut delay = config.timing.delay(config.speed, index, count);
+                        if *keystroke == Keystroke::Text(" ") {
+                            delay += config.word_pause;
+                        }
+                        if let Some(chars) = composed_chars(*keystroke, config) {
+                            let keys = chars * KEYS_PER_COMPOSED_CHAR;
+                            delay * f64::from(keys + 1)
+                        } else {
+                            delay
+                        }
+                    })
+                    .sum();
+                Duration::from_secs_f64(keys)
+            }
+            Command::SetSpeed(speed) => {
+                config.speed = *speed;
+                config.timing = Timing::Constant;
+                Duration::ZERO
+            }
+            Command::SetRamp(start, end) => {
+                config.timing = Timing::Ramp(*start, *end);
+                Duration::ZERO
+            }
+            Command::SetIme(on) => {
+                config.ime = *on;
+                Duration::ZERO
+            }
+            Command::SetWordPause(pause) => {
+                config.word_pause = *pause;
+                Duration::ZERO
+            }
+            _ => Duration::ZERO,
+        };
+    }
+    total
+}
+
+/// Replace `${NAME}` with the value of script variable NAME, leaving
+/// anything else, such as the shell's own variables, as it is
+pub fn expand_variables<'a>(text: &'a str, variables: &HashMap<String, String>) -> Cow<'a, str> {
+    if variables.is_empty() || !text.contains("${") {
+        return Cow::Borrowed(text);
+    }
+
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.split_once('}') {
+            Some((name, tail)) if is_variable_name(name) && variables.contains_key(name) => {
+                out.push_str(&variables[name]);
+                rest = tail;
+            }
+            _ => {
+                out.push_str("${");
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
+// The Ctrl-C handler is process-wide, so every engine shares one flag
+static RUNNING: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+fn running_flag() -> Result<Arc<AtomicBool>> {
+    if let Some(running) = RUNNING.get() {
+        return Ok(running.clone());
+    }
+
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+    ctrlc::set_handler(move || {
+        // A second interrupt doesn't wait for the session to wind down
+        if !r.swap(false, Ordering::SeqCst) {
+            supervisor::abort(error::EXIT_INTERRUPTED);
+        }
+        eprintln!("\nInterrupted, stopping playback...");
+    })?;
+
+    Ok(RUNNING.get_or_init(|| running).clone())
+}
+
+/// Whether Ctrl-C has been received
+pub fn interrupted() -> bool {
+    RUNNING
+        .get()
+        .is_some_and(|running| !running.load(Ordering::SeqCst))
+}
 
-pub struct PlaybackEngine {
-    pty: PtyManager,
+/// Sections of a script, each running from a checkpoint to the next one
+#[derive(Debug, Clone)]
+pub enum Sections {
+    Skip(Vec<String>),
+    Only(Vec<String>),
+}
+
+impl Sections {
+    fn names(&self) -> &[String] {
+        match self {
+            Sections::Skip(names) | Sections::Only(names) => names,
+        }
+    }
+}
+
+// Host commands and the like take as long as they take, and the keys after
+// them are timed from when they finish
+fn is_timed(command: &Command) -> bool {
+    matches!(
+        command,
+        Command::Type(_) | Command::Wait(_) | Command::WaitRandom(_, _)
+    )
+}
+
+// Play `playing`, failing with Error::Timeout if it's still going after `limit`
+async fn within(limit: Option<Duration>, playing: impl Future<Output = Result<()>>) -> Result<()> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, playing)
+            .await
+            .unwrap_or_else(|_| Err(Error::Timeout(limit).into())),
+        None => playing.await,
+    }
+}
+
+fn timed_out(played: &Result<()>) -> bool {
+    played
+        .as_ref()
+        .is_err_and(|e| matches!(e.downcast_ref::<Error>(), Some(Error::Timeout(_))))
+}
+
+// Which commands are sent instantly and out of sight rather than played
+fn fast_forwarded(
+    script: &Script,
+    resume_from: Option<&str>,
+    sections: Option<&Sections>,
+) -> Result<Vec<bool>> {
+    let names = sections.map_or(&[][..], Sections::names);
+    for name in resume_from
+        .into_iter()
+        .chain(names.iter().map(String::as_str))
+    {
+        anyhow::ensure!(
+            script.has_checkpoint(name),
+            "No checkpoint named '{}'",
+            name
+        );
+    }
+
+    let mut resuming = resume_from.is_some();
+    let mut section: Option<&str> = None;
+    let mut fast = Vec::with_capacity(script.commands.len());
+    for command in &script.commands {
+        if let Command::Checkpoint(name) = command {
+            section = Some(name);
+            resuming &= resume_from != Some(name.as_str());
+        }
+        let selected = section.is_some_and(|section| names.iter().any(|name| name == section));
+        let skipped = match sections {
+            Some(Sections::Skip(_)) => selected,
+            Some(Sections::Only(_)) => !selected,
+            None => false,
+        };
+        fast.push(resuming || skipped);
+    }
+    Ok(fast)
+}
+
+// How long the shell must be quiet before the next fast-forwarded line, and
+// before the session is shown
+const LINE_SETTLE_TIME: Duration = Duration::from_millis(100);
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+// Longest to wait for it to settle
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How often expects look at the screen
+const EXPECT_POLL_TIME: Duration = Duration::from_millis(20);
+
+// How long output has to stop for before what's at the cursor is taken to be
+// the whole prompt, for `@ waitprompt`
+const PROMPT_SETTLE_TIME: Duration = Duration::from_millis(100);
+
+// How long `@ on-error` and `@ finally` blocks may play for once the script
+// has run past --max-duration or been stopped with Ctrl-C
+const HANDLER_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Ctrl-C as read from a terminal in raw mode
+const CTRL_C: u8 = 0x03;
+
+// How long input can go unread before playback warns, and how often it's looked at
+const INPUT_STALL_TIME: Duration = Duration::from_secs(5);
+const INPUT_CHECK_TIME: Duration = Duration::from_millis(250);
+
+// Input the session has been sent but not read, watched for `check_input`
+#[derive(Default)]
+struct InputWatch {
+    checked: Option<Instant>,
+    // When the unread input last went down, and how much there was since
+    stalled: Option<(Instant, usize)>,
+    warned: bool,
+}
+
+// The session playback starts in, before any `$[NAME]` line names another
+pub const MAIN_SESSION: &str = "main";
+
+// Starts the session of the name given
+type Spawn<S> = Box<dyn FnMut(&str) -> Result<S>>;
+
+/// Plays scripts into a session, a PTY unless given another sink
+pub struct PlaybackEngine<S: KeystrokeSink = PtyManager> {
+    // The session being played into
+    sink: S,
+    // Its name, and the other sessions `$[NAME]` lines have started
+    session: String,
+    sessions: Vec<(String, S)>,
+    // Starts a session for a name not seen before
+    spawn: Option<Spawn<S>>,
     config: PlaybackConfig,
     running: Arc<AtomicBool>,
+    // Whether `@ on-error` and `@ finally` blocks are playing, which Ctrl-C
+    // doesn't stop
+    finishing: bool,
+    // Checkpoint to fast-forward to before playing normally
+    resume_from: Option<String>,
+    // Sections to fast-forward through, as with resume_from
+    sections: Option<Sections>,
+    // Recent keys and the status line they are shown on, with --show-keys
+    keycast: Option<(usize, KeyCast)>,
+    // Status line for captions, if the script has any
+    caption_line: Option<usize>,
+    captions: CaptionTrack,
+    // Whether `#!` notes are shown as captions
+    note_captions: bool,
+    // Whether `@ typeclipboard` may read the clipboard
+    allow_clipboard: bool,
+    // Sounds being played and text being said, when the session is heard
+    sounds: Option<Sounds>,
+    // Played for each key typed, from `@ sound:`
+    key_sound: Option<String>,
+    presenter: Option<Arc<PresenterServer>>,
+    // Wait for a keypress before each `$` line after the first
+    advance: bool,
+    stats: PlaybackStats,
+    webhooks: Webhooks,
+    // Sent every event, with --webhook-url
+    webhook_url: Option<String>,
+    webhook_errors: Vec<String>,
+    // Where to write the screen when an expect fails, with --artifacts
+    artifacts: Option<Artifacts>,
+    // Where they were written for the expect that failed
+    failure_artifacts: Option<PathBuf>,
+    // Longest the script's commands may play for, with --max-duration
+    max_duration: Option<Duration>,
+    events: Events,
+    hooks: Vec<Box<dyn PlaybackHooks>>,
+    // Programs run for plugin directives, by name
+    directives: HashMap<String, String>,
+    // Interpreter for `@ lua` blocks, if not `lua`
+    lua_command: Option<String>,
+    // Values stored by `@ exec:... -> NAME`, typed in place of `${NAME}`
+    variables: HashMap<String, String>,
+    // Drives jitter and `@ choose`, seeded with --seed for repeatable runs
+    rng: StdRng,
+    started: Instant,
+    // Deadlines for keys and waits, so that oversleeping doesn't add up
+    schedule: Schedule,
+    // Whether the next typed text starts a new command line, for `@ no-history:space`
+    line_start: bool,
+    input: InputWatch,
+    input_stall_time: Duration,
+    // When keys were last sent, as only a prompt drawn since counts for `@ waitprompt`
+    keys_sent: Instant,
 }
 
-impl PlaybackEngine {
-    pub fn new(pty: PtyManager) -> Result<Self> {
-        let running = Arc::new(AtomicBool::new(true));
-
-        let r = running.clone();
-        ctrlc::set_handler(move || {
-            eprintln!("\nReceived Ctrl-C, stopping playback...");
-            r.store(false, Ordering::SeqCst);
-        })?;
+impl<S: KeystrokeSink> PlaybackEngine<S> {
+    pub fn new(sink: S, config: PlaybackConfig) -> Result<Self> {
+        let running = running_flag()?;
 
         Ok(Self {
-            pty,
-            config: PlaybackConfig::default(),
+            sink,
+            session: MAIN_SESSION.to_string(),
+            sessions: Vec::new(),
+            spawn: None,
+            config,
             running,
+            finishing: false,
+            resume_from: None,
+            sections: None,
+            keycast: None,
+            caption_line: None,
+            captions: CaptionTrack::default(),
+            note_captions: false,
+            allow_clipboard: false,
+            sounds: None,
+            key_sound: None,
+            presenter: None,
+            advance: false,
+            stats: PlaybackStats::default(),
+            webhooks: Webhooks::new(),
+            webhook_url: None,
+            webhook_errors: Vec::new(),
+            artifacts: None,
+            failure_artifacts: None,
+            max_duration: None,
+            events: Events::default(),
+            hooks: Vec::new(),
+            directives: HashMap::new(),
+            lua_command: None,
+            variables: HashMap::new(),
+            rng: StdRng::from_os_rng(),
+            started: Instant::now(),
+            schedule: Schedule::default(),
+            line_start: true,
+            input: InputWatch::default(),
+            input_stall_time: INPUT_STALL_TIME,
+            keys_sent: Instant::now(),
         })
     }
 
+    /// Start the sessions `$[NAME]` lines name with `spawn`, which should
+    /// start them hidden
+    pub fn start_sessions_with(&mut self, spawn: impl FnMut(&str) -> Result<S> + 'static) {
+        self.spawn = Some(Box::new(spawn));
+    }
+
+    /// Replay everything before checkpoint `name` instantly and out of sight
+    pub fn resume_from(&mut self, name: String) {
+        self.resume_from = Some(name);
+    }
+
+    /// Make random choices and jitter the same on every run with this seed
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Fast-forward through the sections `sections` rules out, playing the rest
+    pub fn select_sections(&mut self, sections: Sections) {
+        self.sections = Some(sections);
+    }
+
+    /// Show each key on status line `line` as it is sent
+    pub fn show_keys(&mut self, line: usize) {
+        self.keycast = Some((line, KeyCast::default()));
+    }
+
+    /// Show captions on status line `line`
+    pub fn show_captions(&mut self, line: usize) {
+        self.caption_line = Some(line);
+    }
+
+    /// Show each `#!` note as a caption, as if it were a `@ caption:`
+    pub fn show_note_captions(&mut self) {
+        self.note_captions = true;
+    }
+
+    /// Let `@ typeclipboard` read the system clipboard
+    pub fn allow_clipboard(&mut self) {
+        self.allow_clipboard = true;
+    }
+
+    /// Play `@ sound:` and `@ audio:` files and speak `@ say:` text on the
+    /// host, piping it to `say_command` if given
+    pub fn play_sounds(&mut self, say_command: Option<String>) {
+        self.sounds = Some(Sounds::new(say_command));
+    }
+
+    /// Captions shown so far, timed from the start of playback
+    pub fn captions(&self) -> &CaptionTrack {
+        &self.captions
+    }
+
+    /// Wait for a keypress before typing each `$` line after the first
+    pub fn advance_on_keypress(&mut self) {
+        self.advance = true;
+    }
+
+    // Returns false if Ctrl-C was pressed instead
+    async fn wait_for_keypress(&self) -> Result<bool> {
+        while self.should_continue() {
+            let keys = input::take_keys();
+            if !keys.is_empty() {
+                return Ok(!keys.contains(&CTRL_C));
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        Ok(false)
+    }
+
+    /// Events from here on, as they happen
+    pub fn events(&mut self) -> EventStream {
+        self.events.subscribe()
+    }
+
+    /// Call `hooks` at each command and key, after any added before
+    pub fn add_hooks(&mut self, hooks: impl PlaybackHooks + 'static) {
+        self.hooks.push(Box::new(hooks));
+    }
+
+    /// Timings for the lines played so far
+    pub fn stats(&self) -> &PlaybackStats {
+        &self.stats
+    }
+
+    /// Report progress to presenter views connected to `server`
+    pub fn present_to(&mut self, server: Arc<PresenterServer>) {
+        self.presenter = Some(server);
+    }
+
+    fn notify(&self, event: Event) {
+        if let Some(presenter) = &self.presenter {
+            presenter.send(&event);
+        }
+    }
+
+    /// Write what the screen showed when an expect fails
+    pub fn save_artifacts(&mut self, artifacts: Artifacts) {
+        self.artifacts = Some(artifacts);
+    }
+
+    /// Stop the script's commands once they've played for `limit`, then play
+    /// its `@ on-error` and `@ finally` blocks
+    pub fn limit_duration(&mut self, limit: Duration) {
+        self.max_duration = Some(limit);
+    }
+
+    /// The directory `--artifacts` were written to when an expect failed
+    pub fn failure_artifacts(&self) -> Option<&Path> {
+        self.failure_artifacts.as_deref()
+    }
+
+    /// Run these programs for the plugin directives named, from the config file
+    pub fn directives(&mut self, directives: HashMap<String, String>) {
+        self.directives = directives;
+    }
+
+    /// Run `@ lua` blocks with `command` rather than `lua`
+    pub fn lua_command(&mut self, command: String) {
+        self.lua_command = Some(command);
+    }
+
+    /// POST every event to `url` as JSON
+    pub fn webhook_url(&mut self, url: String) {
+        self.webhook_url = Some(url);
+    }
+
+    /// What the session has shown so far, if it's being recorded
+    pub fn recording(&self) -> Option<Cast> {
+        self.sink.recording()
+    }
+
+    /// Webhook requests that failed, known once playback has finished
+    pub fn webhook_errors(&self) -> &[String] {
+        &self.webhook_errors
+    }
+
+    fn send_event(&self, event: serde_json::Value) {
+        if let Some(url) = &self.webhook_url {
+            self.webhooks.send(url, &event);
+        }
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    fn set_caption(&mut self, text: &str) -> Result<()> {
+        let text = &self.sink.redact(text);
+        self.captions.set(self.started.elapsed(), text);
+        if let Some(line) = self.caption_line {
+            let (cols, _) = self.sink.screen().size();
+            self.sink
+                .draw_status(line, &captions::styled(text, cols as usize))?;
+        }
+        Ok(())
+    }
+
+    /// End the sessions now instead of waiting for their shells to exit
+    pub fn stop(&mut self) -> Result<()> {
+        for (_, session) in &mut self.sessions {
+            session.signal(Signal::Kill)?;
+        }
+        self.sink.signal(Signal::Kill)
+    }
+
+    // Stop the sessions besides the main one once playback is over, as a
+    // server left running in one would otherwise keep quipu waiting on it
+    fn end_sessions(&mut self) {
+        let current = (self.session != MAIN_SESSION).then_some(&mut self.sink);
+        let others = self
+            .sessions
+            .iter_mut()
+            .filter(|(name, _)| name != MAIN_SESSION)
+            .map(|(_, session)| session);
+        for session in others.chain(current) {
+            if let Err(e) = session.signal(Signal::Kill) {
+                tracing::warn!("{:#}", e);
+            }
+        }
+    }
+
+    // Play into session `name` from here on, starting it if it's new, and show
+    // it in place of the one before unless playback is out of sight
+    fn switch_session(&mut self, name: &str, show: bool) -> Result<()> {
+        if name == self.session {
+            return Ok(());
+        }
+        let index = match self.sessions.iter().position(|(other, _)| other == name) {
+            Some(index) => index,
+            None => {
+                let spawn = self
+                    .spawn
+                    .as_mut()
+                    .with_context(|| format!("Can't start session '{}' here", name))?;
+                tracing::debug!("Starting session {}", name);
+                self.sessions.push((name.to_string(), spawn(name)?));
+                self.sessions.len() - 1
+            }
+        };
+        // Panes stay drawn while another session has the keys
+        if !self.sink.is_split() {
+            self.sink.set_visible(false)?;
+        }
+        let (other, session) = &mut self.sessions[index];
+        std::mem::swap(&mut self.sink, session);
+        std::mem::swap(&mut self.session, other);
+
+        // Status lines belong to whichever session is shown
+        if let (Some(line), Some(text)) = (self.caption_line, self.captions.current()) {
+            let (cols, _) = self.sink.screen().size();
+            self.sink
+                .draw_status(line, &captions::styled(text, cols as usize))?;
+        }
+        if let Some((line, keycast)) = &self.keycast {
+            let (cols, _) = self.sink.screen().size();
+            self.sink
+                .draw_status(*line, &keycast.render(cols as usize))?;
+        }
+        if show {
+            self.sink.set_visible(true)?;
+        }
+        Ok(())
+    }
+
+    // Hide or show playback, which is every session once they're in panes,
+    // the current one last to keep the keys
+    fn set_visible(&self, visible: bool) -> Result<()> {
+        if self.sink.is_split() {
+            for (_, session) in &self.sessions {
+                session.set_visible(visible)?;
+            }
+        }
+        self.sink.set_visible(visible)
+    }
+
+    // Resize every session, as they share the real terminal
+    fn resize(&mut self, cols: u16, rows: u16) -> Result<()> {
+        for (_, session) in &mut self.sessions {
+            session.resize(cols, rows)?;
+        }
+        self.sink.resize(cols, rows)
+    }
+
     fn should_continue(&self) -> bool {
-        self.running.load(Ordering::SeqCst)
+        self.finishing || self.running.load(Ordering::SeqCst)
     }
 
-    fn calculate_delay(&self) -> Duration {
-        let mut rng = rand::rng();
-        let base_ms = (self.config.speed * 1000.0) as u64;
+    // Time to wait after key `index` of the `count` in a line
+    fn calculate_delay(&mut self, index: usize, count: usize) -> Duration {
+        let base = self.config.timing.delay(self.config.speed, index, count);
+        let base_ms = (base * 1000.0) as u64;
         let jitter_ms = (base_ms as f64 * self.config.jitter) as u64;
 
         if jitter_ms > 0 {
-            let variation = rng.random_range(0..=jitter_ms * 2);
+            let variation = self.rng.random_range(0..=jitter_ms * 2);
             let delay = base_ms.saturating_add(variation).saturating_sub(jitter_ms);
             Duration::from_millis(delay)
         } else {
@@ -70,94 +915,1825 @@ impl PlaybackEngine {
         }
     }
 
-    fn escape_sequence_length(&self, bytes: &[u8]) -> usize {
-        if bytes.is_empty() || bytes[0] != 0x1b {
-            return 1;
+    // Apply a command that only changes playback settings
+    fn execute_setting(&mut self, command: &Command) -> Result<()> {
+        match command {
+            Command::SetSpeed(speed) => {
+                self.config.speed = *speed;
+                self.config.timing = Timing::Constant;
+            }
+            Command::SetRamp(start, end) => {
+                self.config.timing = Timing::Ramp(*start, *end);
+            }
+            Command::SetJitter(jitter) => {
+                self.config.jitter = *jitter;
+            }
+            Command::SetWaitJitter(jitter) => {
+                self.config.wait_jitter = *jitter;
+            }
+            Command::SetWordPause(pause) => {
+                self.config.word_pause = *pause;
+            }
+            Command::SetShell(_)
+            | Command::SetShellArgs(_)
+            | Command::SetShellProfile(_)
+            | Command::SetTerm(_) => {
+                // Shell is set before playback starts, ignore during execution
+            }
+            Command::SetSize(_, _) | Command::SetPixelSize(_, _) | Command::SetLayout(_) => {
+                // Size is set before PTY creation, ignore during execution
+            }
+            Command::SetCursorKeys(mode) => {
+                self.config.cursor_keys = *mode;
+            }
+            Command::SetIme(on) => {
+                self.config.ime = *on;
+            }
+            Command::SetSound(path) => {
+                // A missing file only matters when there's something to hear
+                if let (Some(_), Some(path)) = (&self.sounds, path) {
+                    sound::check(path)?;
+                }
+                self.key_sound = path.clone();
+            }
+            Command::SetNewline(newline) => {
+                self.config.newline = *newline;
+            }
+            Command::SetMultiline(mode) => {
+                self.config.multiline = *mode;
+            }
+            Command::SetNoHistory(mode) => {
+                self.config.no_history = Some(*mode);
+            }
+            Command::SetExpectTimeout(timeout) => {
+                self.config.expect_timeout = *timeout;
+            }
+            Command::SetCommandTimeout(timeout) => {
+                self.config.command_timeout = Some(*timeout).filter(|timeout| !timeout.is_zero());
+            }
+            Command::SetPromptPattern(pattern) => {
+                self.config.prompt_pattern = pattern.clone();
+            }
+            Command::SetEncoding(label) => {
+                self.config.encoding =
+                    text_encoding(label).with_context(|| format!("Unknown encoding: {}", label))?;
+            }
+            // Resolved before playback starts
+            Command::Choose(_) => {}
+            // Played like any other command
+            Command::Retry(_, _, _) => {}
+            // Played once the script ends
+            Command::OnError(_) | Command::Finally(_) => {}
+            // Run around the session by whoever starts it
+            Command::Setup(_) | Command::Teardown(_) => {}
+            Command::Exec(_, _)
+            | Command::ExecAsync(_)
+            | Command::Webhook(_)
+            | Command::DumpScrollback(_)
+            | Command::Audio(_)
+            | Command::Say(_, _)
+            | Command::Plugin(_, _)
+            | Command::Lua(_)
+            | Command::Expect(_, _)
+            | Command::ExpectScreen(_)
+            | Command::ExpectCell(_, _, _)
+            | Command::ExpectClipboard(_)
+            | Command::WaitPrompt
+            | Command::WaitFor(_, _)
+            | Command::Clear(_)
+            | Command::Reset
+            | Command::Resize(_, _)
+            | Command::Session(_) => {}
+            Command::Checkpoint(_)
+            | Command::Caption(_)
+            | Command::Note(_)
+            | Command::Meta(_, _)
+            | Command::Requires(_)
+            | Command::Wait(_)
+            | Command::WaitRandom(_, _)
+            | Command::Type(_)
+            | Command::TypeClipboard => {}
         }
+        Ok(())
+    }
 
-        if bytes.len() == 1 {
-            return 1;
+    // What a `$` line types, with variables expanded
+    fn typed_text(&mut self, keys: &Keys) -> Keys {
+        let keys = keys.map_text(|text| expand_variables(text, &self.variables).into_owned());
+        let line_start = std::mem::replace(&mut self.line_start, keys.ends_line());
+        match self.config.no_history {
+            // A leading space keeps the command out of history with HISTCONTROL=ignorespace
+            Some(NoHistory::Space) if line_start && !keys.is_empty() => {
+                let mut spaced = Keys::text(" ");
+                spaced.append(keys);
+                spaced
+            }
+            _ => keys,
         }
+    }
 
-        match bytes[1] {
-            // CSI sequences: ESC [ ... (end with letter or ~)
-            b'[' => {
-                let mut i = 2;
-                while i < bytes.len() && (bytes[i].is_ascii_digit() || bytes[i] == b';') {
-                    i += 1;
-                }
-                if i < bytes.len() { i + 1 } else { bytes.len() }
+    // The commands a plugin directive or `@ lua` block plays in its place,
+    // with any `@ choose` in them resolved
+    async fn generate(&mut self, command: &Command) -> Result<Vec<Command>> {
+        let commands = match command {
+            Command::Plugin(name, args) => {
+                let program = self
+                    .directives
+                    .get(name)
+                    .with_context(|| format!("No plugin for @ {} in the config file", name))?;
+                let (cols, rows) = self.sink.screen().size();
+                let call = plugin::Call {
+                    directive: name,
+                    args,
+                    variables: &self.variables,
+                    screen: self.sink.screen().contents(),
+                    cols,
+                    rows,
+                    elapsed_ms: self.elapsed_ms(),
+                };
+                tracing::debug!("Running '{}' for @ {}", program, name);
+                plugin::run(program, &call).await?
             }
-            // SS3 sequences: ESC O + letter
-            b'O' => {
-                if bytes.len() > 2 {
-                    3
-                } else {
-                    bytes.len()
+            Command::Lua(body) => {
+                let program = lua::program(body, &self.variables, &self.sink.screen().contents());
+                lua::run(self.lua_command.as_deref(), &program).await?
+            }
+            _ => Vec::new(),
+        };
+        Ok(Script { commands }
+            .resolve_choices(&mut |branches| self.rng.random_range(0..branches))
+            .commands)
+    }
+
+    // Run an `@ exec` or `@ exec-async` command on the host
+    async fn execute_host(&mut self, command: &Command) -> Result<()> {
+        match command {
+            Command::Exec(command, name) => {
+                let output = host::run(command).await?;
+                if let Some(name) = name {
+                    self.variables.insert(name.clone(), output);
                 }
             }
-            _ => 2,
+            Command::ExecAsync(command) => host::spawn(command)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Play `command`, failing if it takes longer than `@ timeout` allows.
+    // Waits take as long as they say, and a retry block's commands are
+    // limited one by one. A `$` line is done once it's typed, so what it
+    // runs is only timed by a `@ waitprompt` after it
+    async fn execute_guarded(&mut self, command: &Command) -> Result<()> {
+        let limit = match command {
+            Command::Wait(_) | Command::WaitRandom(_, _) | Command::Retry(_, _, _) => None,
+            _ => self.config.command_timeout,
+        };
+        let Some(limit) = limit else {
+            return self.execute_command(command).await;
+        };
+        match tokio::time::timeout(limit, self.execute_command(command)).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::CommandTimeout(limit, format_command(command)).into()),
         }
     }
 
     async fn execute_command(&mut self, command: &Command) -> Result<()> {
         match command {
-            Command::SetSpeed(speed) => {
-                self.config.speed = *speed;
+            Command::Exec(_, _) | Command::ExecAsync(_) => self.execute_host(command).await?,
+            Command::Clear(scrollback) => self.sink.clear(*scrollback)?,
+            Command::Reset => self.sink.reset()?,
+            Command::Resize(cols, rows) => self.resize(*cols, *rows)?,
+            Command::Session(name) => self.switch_session(name, true)?,
+            Command::Webhook(url) => self.webhooks.send(
+                url,
+                &serde_json::json!({
+                    "event": "webhook",
+                    "elapsed_ms": self.elapsed_ms(),
+                }),
+            ),
+            Command::Wait(duration) => {
+                let jitter = self.config.wait_jitter;
+                let duration = if jitter > 0.0 {
+                    duration.mul_f64(self.rng.random_range(1.0 - jitter..=1.0 + jitter))
+                } else {
+                    *duration
+                };
+                tracing::debug!("Waiting {:?}", duration);
+                self.events.send(PlaybackEvent::WaitStarted { duration });
+                self.pause(duration).await;
             }
-            Command::SetJitter(jitter) => {
-                self.config.jitter = *jitter;
+            Command::WaitRandom(min, max) => {
+                let duration = self.rng.random_range(*min..=*max);
+                tracing::debug!("Waiting {:?}", duration);
+                self.events.send(PlaybackEvent::WaitStarted { duration });
+                self.pause(duration).await;
             }
-            Command::Wait(duration) => {
-                sleep(*duration).await;
+            Command::Caption(text) => self.set_caption(text)?,
+            Command::Note(text) if self.note_captions => self.set_caption(text)?,
+            Command::Checkpoint(name) => self.sink.section(name).await?,
+            Command::DumpScrollback(path) => self.dump_scrollback(Path::new(path)).await?,
+            Command::Audio(path) => {
+                if let Some(sounds) = &mut self.sounds {
+                    sound::check(path)?;
+                    sounds.play(path)?;
+                }
             }
-            Command::SetShell(_) => {
-                // Shell is set before playback starts, ignore during execution
+            Command::Plugin(_, _) | Command::Lua(_) => {
+                for command in self.generate(command).await? {
+                    Box::pin(self.execute_command(&command)).await?;
+                }
             }
-            Command::SetSize(_, _) => {
-                // Size is set before PTY creation, ignore during execution
+            Command::Say(text, wait) => {
+                if let Some(sounds) = &mut self.sounds {
+                    let text = expand_variables(text, &self.variables);
+                    sounds.say(&text, *wait).await?;
+                }
             }
-            Command::Type(text) => {
+            Command::Expect(_, _)
+            | Command::ExpectScreen(_)
+            | Command::ExpectCell(_, _, _)
+            | Command::ExpectClipboard(_)
+            | Command::WaitPrompt => self.expect(command).await?,
+            Command::WaitFor(condition, target) => self.wait_for(*condition, target).await?,
+            Command::Retry(retries, backoff, commands) => {
+                self.retry(*retries, *backoff, commands).await?
+            }
+            Command::TypeClipboard => {
+                let text = self.clipboard()?;
+                Box::pin(self.execute_command(&Command::Type(Keys::text(&text)))).await?
+            }
+            Command::Type(keys) => {
+                let keys = self.typed_text(keys);
+                let (pasted, keys) = self.split_paste(keys);
+                if let Some(pasted) = pasted {
+                    let bytes = paste_bytes(&pasted, &self.config);
+                    self.send_keys(&bytes).await?;
+                    for hooks in &mut self.hooks {
+                        hooks.on_keystroke(&pasted, &bytes);
+                    }
+                    self.events
+                        .send(PlaybackEvent::KeystrokeSent { keystroke: pasted });
+                    self.stats.keystrokes += 1;
+                    let delay = self.calculate_delay(0, 1);
+                    self.schedule.wait(delay).await;
+                }
                 // Escape sequences must be sent atomically without delays between bytes
-                let mut i = 0;
-                let bytes = text.as_bytes();
-
-                while i < bytes.len() {
+                let keystrokes = typed_keystrokes(&keys, &self.config);
+                let count = keystrokes.len();
+                // Keys with no delay between them go out in one write, which
+                // makes `@ speed:0` pastes of long text quick
+                let mut pending = Vec::new();
+                // Logged once the line is typed, so redaction sees secrets whole
+                let mut traced = (String::new(), Vec::new());
+                for (index, keystroke) in keystrokes.into_iter().enumerate() {
                     if !self.should_continue() {
                         return Ok(());
                     }
 
-                    if bytes[i] == 0x1b {
-                        let seq_len = self.escape_sequence_length(&bytes[i..]);
-                        let sequence = &text[i..i + seq_len];
+                    // Pause as if the romanized input were being typed and converted
+                    if let Some(chars) = composed_chars(keystroke, &self.config) {
+                        let keys = chars * KEYS_PER_COMPOSED_CHAR;
+                        let delay = self.calculate_delay(index, count) * keys;
+                        if !delay.is_zero() {
+                            self.send_pending(&mut pending).await?;
+                            self.schedule.wait(delay).await;
+                        }
+                    }
+
+                    // Modes are checked per key, since earlier keys may have changed them
+                    let terminal = self.sink.screen().key_modes();
+                    let bytes = keystroke_bytes(keystroke, &self.config, terminal);
+                    pending.extend_from_slice(&bytes);
+                    let written = keystroke.written();
+                    if tracing::enabled!(tracing::Level::TRACE) {
+                        traced.0.push_str(&written);
+                        traced.1.extend_from_slice(&bytes);
+                    }
+                    for hooks in &mut self.hooks {
+                        hooks.on_keystroke(&written, &bytes);
+                    }
+                    self.events
+                        .send(PlaybackEvent::KeystrokeSent { keystroke: written });
 
-                        self.pty.send_keystroke(sequence)?;
-                        i += seq_len;
+                    self.stats.keystrokes += 1;
 
-                        let delay = self.calculate_delay();
-                        sleep(delay).await;
-                    } else {
-                        let c = text[i..].chars().next().unwrap();
-                        self.pty.send_char(c)?;
-                        i += c.len_utf8();
+                    if let (Some(sounds), Some(path)) = (&mut self.sounds, &self.key_sound) {
+                        sounds.click(path)?;
+                    }
+
+                    if let Some((line, keycast)) = &mut self.keycast {
+                        match keystroke {
+                            Keystroke::Text(text) => keycast.push(text),
+                            Keystroke::Key(event) => keycast.push_key(event),
+                        }
+                        let (cols, _) = self.sink.screen().size();
+                        self.sink
+                            .draw_status(*line, &keycast.render(cols as usize))?;
+                    }
 
-                        let delay = self.calculate_delay();
-                        sleep(delay).await;
+                    let mut delay = self.calculate_delay(index, count);
+                    if keystroke == Keystroke::Text(" ") {
+                        delay += Duration::from_secs_f64(self.config.word_pause);
+                    }
+                    if !delay.is_zero() {
+                        self.send_pending(&mut pending).await?;
+                        self.schedule.wait(delay).await;
                     }
                 }
+                self.send_pending(&mut pending).await?;
+                if tracing::enabled!(tracing::Level::TRACE) {
+                    let (written, bytes) = traced;
+                    tracing::trace!(
+                        "Keys {} as {}",
+                        self.sink.redact(&written),
+                        self.sink
+                            .redact(&explain::caret(&String::from_utf8_lossy(&bytes)))
+                    );
+                }
             }
+            _ => self.execute_setting(command)?,
         }
         Ok(())
     }
 
-    pub async fn execute(&mut self, script: Script) -> Result<()> {
-        for command in script.commands {
+    // What's on the clipboard, for `@ typeclipboard`
+    fn clipboard(&self) -> Result<String> {
+        anyhow::ensure!(
+            self.allow_clipboard,
+            "@ typeclipboard needs --allow-clipboard to read the clipboard"
+        );
+        let text = clipboard::read()?;
+        tracing::debug!(
+            "Typing {} characters from the clipboard",
+            text.chars().count()
+        );
+        Ok(text)
+    }
+
+    // The text up to the last line break, if it's to be pasted, and the
+    // rest, which is typed
+    fn split_paste(&self, keys: Keys) -> (Option<String>, Keys) {
+        if self.config.multiline == Multiline::Paste && self.sink.screen().bracketed_paste() {
+            return keys.split_paste();
+        }
+        (None, keys)
+    }
+
+    async fn send_keys(&mut self, bytes: &[u8]) -> Result<()> {
+        // Taken first, as the reply can arrive before `send` returns
+        self.keys_sent = Instant::now();
+        self.sink.send(bytes).await
+    }
+
+    async fn send_pending(&mut self, pending: &mut Vec<u8>) -> Result<()> {
+        if !pending.is_empty() {
+            self.send_keys(pending).await?;
+            pending.clear();
+        }
+        self.check_input();
+        Ok(())
+    }
+
+    // Wait for `duration`, still watching the session's input meanwhile
+    async fn pause(&mut self, mut duration: Duration) {
+        while duration > INPUT_CHECK_TIME {
+            self.schedule.wait(INPUT_CHECK_TIME).await;
+            self.check_input();
+            duration -= INPUT_CHECK_TIME;
+        }
+        self.schedule.wait(duration).await;
+    }
+
+    // Warn once when input stops being read, as keys typed into a stopped
+    // program or ahead of a prompt that never comes pile up out of sight
+    fn check_input(&mut self) {
+        let now = Instant::now();
+        if self
+            .input
+            .checked
+            .is_some_and(|checked| now - checked < INPUT_CHECK_TIME)
+        {
+            return;
+        }
+        self.input.checked = Some(now);
+
+        let Some(unread) = self.sink.unread_input().filter(|&unread| unread > 0) else {
+            self.input.stalled = None;
+            return;
+        };
+        let since = match self.input.stalled {
+            Some((since, before)) if unread >= before => since,
+            _ => {
+                self.input.warned = false;
+                now
+            }
+        };
+        self.input.stalled = Some((since, unread));
+
+        let duration = now - since;
+        if !self.input.warned && duration >= self.input_stall_time {
+            self.input.warned = true;
+            tracing::warn!(
+                "The session hasn't read {} bytes of its input for {:.1}s; is the program in it stopped, or not reading yet?",
+                unread,
+                duration.as_secs_f64()
+            );
+            self.send_event(serde_json::json!({
+                "event": "input_stalled",
+                "unread": unread,
+                "elapsed_ms": self.elapsed_ms(),
+            }));
+            self.events
+                .send(PlaybackEvent::InputStalled { unread, duration });
+        }
+    }
+
+    // Send a line's keys without delays so the shell ends up in the same state
+    async fn fast_forward_command(&mut self, command: &Command) -> Result<()> {
+        match command {
+            Command::Wait(_) | Command::WaitRandom(_, _) => {}
+            // Kept so the caption in effect at the checkpoint is on screen
+            Command::Caption(text) => self.set_caption(text)?,
+            Command::Note(text) if self.note_captions => self.set_caption(text)?,
+            // The hidden screen is cleared too, so it matches when shown
+            Command::Clear(scrollback) => self.sink.clear(*scrollback)?,
+            Command::Reset => self.sink.reset()?,
+            Command::Resize(cols, rows) => self.resize(*cols, *rows)?,
+            Command::Session(name) => self.switch_session(name, false)?,
+            // Host setup still happens, since later lines may depend on it
+            Command::Exec(_, _) | Command::ExecAsync(_) => self.execute_host(command).await?,
+            Command::WaitFor(condition, target) => self.wait_for(*condition, target).await?,
+            Command::Plugin(_, _) | Command::Lua(_) => {
+                for command in self.generate(command).await? {
+                    Box::pin(self.fast_forward_command(&command)).await?;
+                }
+            }
+            Command::Retry(_, _, commands) => {
+                for command in commands {
+                    Box::pin(self.fast_forward_command(command)).await?;
+                }
+            }
+            Command::TypeClipboard => {
+                let text = self.clipboard()?;
+                Box::pin(self.fast_forward_command(&Command::Type(Keys::text(&text)))).await?
+            }
+            Command::Type(keys) => {
+                let keys = self.typed_text(keys);
+                let (pasted, keys) = self.split_paste(keys);
+                let mut bytes = pasted.map_or_else(Vec::new, |p| paste_bytes(&p, &self.config));
+                let terminal = self.sink.screen().key_modes();
+                bytes.extend(
+                    typed_keystrokes(&keys, &self.config)
+                        .into_iter()
+                        .flat_map(|keystroke| keystroke_bytes(keystroke, &self.config, terminal)),
+                );
+                self.send_keys(&bytes).await?;
+                // Let each line finish so the hidden screen looks like a normal session
+                self.wait_for_quiet(LINE_SETTLE_TIME).await;
+            }
+            // Settings still apply so playback continues with the right ones
+            _ => self.execute_setting(command)?,
+        }
+        Ok(())
+    }
+
+    // Wait for the output or screen to show what an expect looks for,
+    // storing any groups it names and failing playback if it doesn't in time
+    async fn expect(&mut self, command: &Command) -> Result<()> {
+        // `^` and `$` match at each line, as there are many
+        let regex = |pattern: &str| {
+            RegexBuilder::new(pattern)
+                .multi_line(true)
+                .build()
+                .map_err(|e| Error::Script(e.to_string()))
+        };
+        let (regex, wanted, pattern) = match command {
+            Command::Expect(pattern, _) => (
+                Some(regex(pattern)?),
+                format!("output matching '{}'", pattern),
+                pattern.clone(),
+            ),
+            Command::ExpectScreen(pattern) => (
+                Some(regex(pattern)?),
+                format!("the screen to match '{}'", pattern),
+                pattern.clone(),
+            ),
+            Command::ExpectCell(row, col, text) => (
+                None,
+                format!("'{}' at row {}, column {}", text, row, col),
+                text.clone(),
+            ),
+            Command::ExpectClipboard(_) => {
+                (None, "a copy to the clipboard".to_string(), String::new())
+            }
+            // Only at the cursor, where the prompt is left waiting
+            Command::WaitPrompt => (
+                Some(regex(&format!("(?:{})\\z", self.config.prompt_pattern))?),
+                format!("a prompt matching '{}'", self.config.prompt_pattern),
+                self.config.prompt_pattern.clone(),
+            ),
+            _ => return Ok(()),
+        };
+        let keys_sent = self.keys_sent;
+        let found = |screen: &Screen| match (command, &regex) {
+            (Command::Expect(_, _), Some(regex)) => screen.match_output(regex),
+            (Command::ExpectCell(row, col, text), _) => {
+                screen.has_text_at(row - 1, col - 1, text).then(Vec::new)
+            }
+            (Command::ExpectClipboard(_), _) => screen.take_copied().map(|text| vec![text]),
+            // Drawn since the last key, and finished with
+            (Command::WaitPrompt, Some(regex)) => {
+                let idle = screen.idle_time();
+                (idle < keys_sent.elapsed()
+                    && idle >= PROMPT_SETTLE_TIME
+                    && regex.is_match(screen.before_cursor().as_bytes()))
+                .then(Vec::new)
+            }
+            (_, Some(regex)) => regex.is_match(screen.contents().as_bytes()).then(Vec::new),
+            _ => Some(Vec::new()),
+        };
+
+        let start = Instant::now();
+        loop {
+            if let Some(groups) = found(self.sink.screen()) {
+                if let Command::Expect(_, names) = command {
+                    // Without groups, the one variable gets the whole match
+                    let groups = if groups.len() > 1 {
+                        &groups[1..]
+                    } else {
+                        &groups[..]
+                    };
+                    for (name, value) in names.iter().zip(groups) {
+                        self.variables.insert(name.clone(), value.clone());
+                    }
+                }
+                if let Command::ExpectClipboard(Some(name)) = command {
+                    self.variables.insert(name.clone(), groups[0].clone());
+                }
+                self.events.send(PlaybackEvent::ExpectMatched {
+                    pattern,
+                    elapsed: start.elapsed(),
+                });
+                return Ok(());
+            }
+            if !self.should_continue() {
+                return Ok(());
+            }
+            self.check_input();
+            if start.elapsed() >= self.config.expect_timeout {
+                let message = format!(
+                    "Timed out after {}s waiting for {}. The screen showed:\n{}",
+                    self.config.expect_timeout.as_secs_f64(),
+                    wanted,
+                    self.sink.screen().contents().trim_end()
+                );
+                // What's on screen is checked, rather than waited for
+                return Err(match command {
+                    Command::ExpectScreen(_) | Command::ExpectCell(_, _, _) => {
+                        Error::Assertion(message)
+                    }
+                    _ => Error::Expect(message),
+                }
+                .into());
+            }
+            sleep(EXPECT_POLL_TIME).await;
+        }
+    }
+
+    // Wait for a file, port or URL on the host, failing playback like an
+    // expect if it isn't there in time
+    async fn wait_for(&mut self, condition: WaitCondition, target: &str) -> Result<()> {
+        let target = expand_variables(target, &self.variables);
+        condition::check(condition, &target).map_err(anyhow::Error::msg)?;
+        tracing::debug!("Waiting for {} {}", condition.name(), target);
+        let start = Instant::now();
+        while !condition::is_met(condition, &target).await {
+            if !self.should_continue() {
+                return Ok(());
+            }
+            self.check_input();
+            if start.elapsed() >= self.config.expect_timeout {
+                return Err(Error::Expect(format!(
+                    "Timed out after {}s waiting for {}:{}",
+                    self.config.expect_timeout.as_secs_f64(),
+                    condition.name(),
+                    target
+                ))
+                .into());
+            }
+            sleep(EXPECT_POLL_TIME).await;
+        }
+        Ok(())
+    }
+
+    // Play `commands` again while an expect in them fails, up to `retries`
+    // more times, interrupting whatever the failed attempt left running
+    async fn retry(&mut self, retries: u32, backoff: f64, commands: &[Command]) -> Result<()> {
+        let mut delay = self.config.retry_delay;
+        let mut attempt = 0;
+        loop {
+            let result = self.execute_block(commands).await;
+            let failed = match &result {
+                Err(e) => matches!(
+                    e.downcast_ref::<Error>(),
+                    Some(Error::Expect(_) | Error::Assertion(_))
+                ),
+                Ok(()) => false,
+            };
+            if !failed || attempt == retries || !self.should_continue() {
+                return result;
+            }
+            attempt += 1;
+            // Only the last attempt's error shows the screen
+            let reason = result.unwrap_err().to_string();
+            tracing::warn!(
+                "{}; retrying in {:.1}s ({} of {})",
+                reason
+                    .split(". The screen showed")
+                    .next()
+                    .unwrap_or_default(),
+                delay.as_secs_f64(),
+                attempt,
+                retries
+            );
+            self.send_keys(&[CTRL_C]).await?;
+            sleep(delay).await;
+            delay = delay.mul_f64(backoff);
+            self.schedule.restart();
+        }
+    }
+
+    // Play the commands of a block, timing keys as a script's are
+    async fn execute_block(&mut self, commands: &[Command]) -> Result<()> {
+        for command in commands {
             if !self.should_continue() {
                 break;
             }
+            self.check_input();
+            Box::pin(self.execute_guarded(command)).await?;
+            if !is_timed(command) {
+                self.schedule.restart();
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the session's text, scrollback included, to `path` once output
+    /// still on its way has arrived
+    pub async fn dump_scrollback(&mut self, path: &Path) -> Result<()> {
+        self.wait_for_quiet(LINE_SETTLE_TIME).await;
+        std::fs::write(path, self.sink.screen().history())
+            .with_context(|| format!("Failed to write scrollback: {}", path.display()))
+    }
+
+    async fn wait_for_quiet(&self, quiet: Duration) {
+        let start = Instant::now();
+        while self.sink.screen().idle_time() < quiet && start.elapsed() < SETTLE_TIMEOUT {
+            sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Wait for the session's output to settle, as it does once the shell has
+    /// drawn its prompt or the last command has finished
+    pub async fn settle(&self) {
+        self.wait_for_quiet(SETTLE_TIME).await;
+    }
+
+    pub async fn execute(&mut self, script: Script) -> Result<()> {
+        // Choices are resolved one command at a time to know which each played one came from
+        let mut commands = Vec::new();
+        let mut origins = Vec::new();
+        for (origin, command) in script.commands.into_iter().enumerate() {
+            let resolved = Script {
+                commands: vec![command],
+            }
+            .resolve_choices(&mut |branches| self.rng.random_range(0..branches));
+            origins.extend(std::iter::repeat_n(origin, resolved.commands.len()));
+            commands.extend(resolved.commands);
+        }
+        let script = Script { commands };
+        let fast = fast_forwarded(
+            &script,
+            self.resume_from.take().as_deref(),
+            self.sections.take().as_ref(),
+        )?;
+        self.start(&script);
+
+        let mut progress = Progress::default();
+        let limit = self.max_duration;
+        let playing = async {
+            for (index, command) in script.commands.iter().enumerate() {
+                match self
+                    .play_command(index, origins[index], command, fast[index], &mut progress)
+                    .await
+                {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(e) => {
+                        self.save_failure(origins[index], &e);
+                        return Err(e);
+                    }
+                }
+            }
+            Ok(())
+        };
+        let played = within(limit, playing).await;
+        let handled = self
+            .run_handlers(
+                &script.commands,
+                played.is_err(),
+                timed_out(&played),
+                &mut progress,
+            )
+            .await;
+        self.end_sessions();
+        played.and(handled)?;
+        self.finish(progress).await
+    }
+
+    // Write artifacts if `error`, from the script's command `origin`, is an
+    // expect that failed, before anything else changes the screen
+    fn save_failure(&mut self, origin: usize, error: &anyhow::Error) {
+        let Some(artifacts) = &self.artifacts else {
+            return;
+        };
+        if !matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::Expect(_) | Error::Assertion(_))
+        ) {
+            return;
+        }
+        match artifacts.write(origin, self.sink.screen()) {
+            Ok(dir) => {
+                eprintln!("Failure artifacts written to {}", dir.display());
+                self.failure_artifacts = Some(dir);
+            }
+            Err(e) => tracing::warn!("{:#}", e),
+        }
+    }
+
+    // Play the `@ on-error` blocks among `commands` if playback failed, then
+    // the `@ finally` ones. The first of their errors is returned, unless
+    // there's already one to report, when they're only logged. Once playback
+    // has run past --max-duration or been stopped with Ctrl-C, they're
+    // stopped after HANDLER_TIMEOUT, or by a second Ctrl-C
+    async fn run_handlers(
+        &mut self,
+        commands: &[Command],
+        failed: bool,
+        timed_out: bool,
+        progress: &mut Progress,
+    ) -> Result<()> {
+        let on_error = commands.iter().filter_map(|command| match command {
+            Command::OnError(commands) if failed => Some(commands),
+            _ => None,
+        });
+        let finally = commands.iter().filter_map(|command| match command {
+            Command::Finally(commands) => Some(commands),
+            _ => None,
+        });
+        let handlers: Vec<&Vec<Command>> = on_error.chain(finally).collect();
+        if handlers.is_empty() {
+            return Ok(());
+        }
+
+        if progress.hidden {
+            self.wait_for_quiet(SETTLE_TIME).await;
+            self.set_visible(true)?;
+            progress.hidden = false;
+        }
+        self.schedule.restart();
+        // Only a second Ctrl-C stops them, as it stops quipu
+        let interrupted = !self.should_continue();
+        if interrupted {
+            eprintln!("Playing @ finally blocks, press Ctrl-C again to stop them");
+        }
+        self.finishing = true;
+        let playing = async {
+            let mut error = None;
+            for commands in handlers {
+                if let Err(e) = self.execute_block(commands).await {
+                    if failed || error.is_some() {
+                        tracing::warn!("{:#}", e);
+                    } else {
+                        error = Some(e);
+                    }
+                }
+            }
+            error.map_or(Ok(()), Err)
+        };
+        let handled = match timed_out || interrupted {
+            false => playing.await,
+            true => match tokio::time::timeout(HANDLER_TIMEOUT, playing).await {
+                Ok(handled) => handled,
+                Err(_) => {
+                    tracing::warn!(
+                        "Stopped @ on-error and @ finally blocks after {}s",
+                        HANDLER_TIMEOUT.as_secs()
+                    );
+                    Ok(())
+                }
+            },
+        };
+        self.finishing = false;
+        handled
+    }
+
+    /// Play commands as they arrive, for scripts that are still being read
+    ///
+    /// An error from `commands` ends playback once what came before it has
+    /// played. Checkpoints can't be resumed from or skipped, as that needs the
+    /// whole script up front
+    pub async fn execute_stream(
+        &mut self,
+        mut commands: Receiver<Result<Command, String>>,
+    ) -> Result<()> {
+        anyhow::ensure!(
+            self.resume_from.is_none() && self.sections.is_none(),
+            "Checkpoints can't be resumed from or skipped in a script that's still being read"
+        );
+        self.start(&Script { commands: vec![] });
+
+        let mut progress = Progress::default();
+        let mut index = 0;
+        let mut error = None;
+        // Handlers only run if they were read before playback ended
+        let mut handlers = Vec::new();
+        let mut origin = 0;
+        let limit = self.max_duration;
+        let playing = async {
+            'commands: while let Some(command) = commands.recv().await {
+                let command = match command {
+                    // Those before the first `$` line were run before the session started
+                    Ok(Command::Setup(_) | Command::Teardown(_)) if progress.typed => {
+                        error = Some(
+                        "@ setup and @ teardown must come before the first $ line of a script read from stdin"
+                            .to_string(),
+                    );
+                        break;
+                    }
+                    Ok(command) => command,
+                    Err(e) => {
+                        error = Some(e);
+                        break;
+                    }
+                };
+                let script = Script {
+                    commands: vec![command],
+                }
+                .resolve_choices(&mut |branches| self.rng.random_range(0..branches));
+                for command in &script.commands {
+                    if matches!(command, Command::OnError(_) | Command::Finally(_)) {
+                        handlers.push(command.clone());
+                    }
+                    match self
+                        .play_command(index, origin, command, false, &mut progress)
+                        .await
+                    {
+                        Ok(true) => {}
+                        Ok(false) => break 'commands,
+                        Err(e) => {
+                            self.save_failure(origin, &e);
+                            return Err(e);
+                        }
+                    }
+                    index += 1;
+                }
+                origin += 1;
+            }
+            Ok(())
+        };
+        let played = within(limit, playing).await;
+        let failed = played.is_err() || error.is_some();
+        let handled = self
+            .run_handlers(&handlers, failed, timed_out(&played), &mut progress)
+            .await;
+        self.end_sessions();
+        played.and(handled)?;
+        self.finish(progress).await?;
+        match error {
+            Some(e) => Err(Error::Script(format!("Parse error: {}", e)).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Play a single command outside of any script, as `quipu repl` does
+    pub async fn play(&mut self, command: Command) -> Result<()> {
+        let script = Script {
+            commands: vec![command],
+        }
+        .resolve_choices(&mut |branches| self.rng.random_range(0..branches));
+        self.schedule.restart();
+        for command in &script.commands {
+            self.execute_command(command).await?;
+        }
+        Ok(())
+    }
+
+    /// Wake `offset` early for each key and wait, as calibrated by `quipu bench`
+    pub fn timing_offset(&mut self, offset: Duration) {
+        self.schedule.set_offset(offset);
+    }
+
+    /// The sink commands are played into, before any `$[NAME]` line names another
+    pub fn sink(&mut self) -> &mut S {
+        match self
+            .sessions
+            .iter_mut()
+            .find(|(name, _)| name == MAIN_SESSION)
+        {
+            Some((_, session)) => session,
+            None => &mut self.sink,
+        }
+    }
+
+    fn start(&mut self, script: &Script) {
+        self.started = Instant::now();
+        self.schedule.restart();
+        self.notify(Event::Script {
+            commands: script.commands.clone(),
+        });
+        self.send_event(serde_json::json!({ "event": "started", "meta": script.meta() }));
+    }
+
+    // Play one command of a script, returning whether to carry on
+    // `origin` is the script command `index` was picked from, which differs
+    // once `@ choose` has put one of its branches in its place
+    async fn play_command(
+        &mut self,
+        index: usize,
+        origin: usize,
+        command: &Command,
+        fast: bool,
+        progress: &mut Progress,
+    ) -> Result<bool> {
+        // Everything logged while it plays is logged in its span
+        let span = tracing::debug_span!("command", index, origin);
+        self.play_command_in_span(index, origin, command, fast, progress)
+            .instrument(span)
+            .await
+    }
+
+    async fn play_command_in_span(
+        &mut self,
+        index: usize,
+        origin: usize,
+        command: &Command,
+        fast: bool,
+        progress: &mut Progress,
+    ) -> Result<bool> {
+        if !self.should_continue() {
+            return Ok(false);
+        }
+
+        self.notify(Event::Command {
+            index,
+            elapsed_ms: self.elapsed_ms(),
+        });
+
+        if fast {
+            if !progress.hidden {
+                self.set_visible(false)?;
+                progress.hidden = true;
+            }
+            self.fast_forward_command(command).await?;
+            return Ok(true);
+        }
+        if progress.hidden {
+            self.wait_for_quiet(SETTLE_TIME).await;
+            self.set_visible(true)?;
+            progress.hidden = false;
+            self.schedule.restart();
+        }
+
+        if matches!(command, Command::Type(_) | Command::TypeClipboard) {
+            if self.advance && progress.typed {
+                let start = Instant::now();
+                if !self.wait_for_keypress().await? {
+                    return Ok(false);
+                }
+                self.stats.record_keypress_wait(start.elapsed());
+                self.schedule.restart();
+            }
+            progress.typed = true;
+        }
+
+        self.send_event(serde_json::json!({
+            "event": "command",
+            "index": index,
+            "elapsed_ms": self.elapsed_ms(),
+            "command": command,
+        }));
+        tracing::debug!(
+            "Command {}: {}",
+            index,
+            self.sink.redact(&format!("{:?}", command))
+        );
+        self.events.send(PlaybackEvent::CommandStarted {
+            index,
+            command: command.clone(),
+            elapsed: self.started.elapsed(),
+        });
+        for hooks in &mut self.hooks {
+            hooks.before_command(index, command);
+        }
+        self.check_input();
+        let start = Instant::now();
+        if let Err(e) = self.execute_guarded(command).await {
+            self.stats.record_failure(origin, command, start.elapsed());
+            return Err(e);
+        }
+        self.stats.record(origin, command, start.elapsed());
+        if !is_timed(command) {
+            self.schedule.restart();
+        }
+        tracing::debug!("Command {} took {:?}", index, start.elapsed());
+        for hooks in &mut self.hooks {
+            hooks.after_command(index, command, start.elapsed());
+        }
+        Ok(true)
+    }
 
-            self.execute_command(&command).await?;
+    async fn finish(&mut self, progress: Progress) -> Result<()> {
+        if progress.hidden {
+            self.wait_for_quiet(SETTLE_TIME).await;
+            self.set_visible(true)?;
         }
+
+        self.captions.finish(self.started.elapsed());
+        self.events.send(PlaybackEvent::Finished {
+            elapsed: self.started.elapsed(),
+        });
+        self.notify(Event::Finished {
+            elapsed_ms: self.elapsed_ms(),
+        });
+        self.send_event(serde_json::json!({
+            "event": "finished",
+            "elapsed_ms": self.elapsed_ms(),
+        }));
+        self.webhook_errors = self.webhooks.finish().await;
         Ok(())
     }
 }
+
+// How far through a script playback has got
+#[derive(Default)]
+struct Progress {
+    // Whether the session is hidden while fast-forwarding
+    hidden: bool,
+    // Whether anything has been typed yet, for --advance
+    typed: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ScriptBuilder;
+    use crate::key::Key;
+    use crate::sink::RecordingSink;
+
+    #[test]
+    fn test_expand_variables() {
+        let variables = HashMap::from([("PORT".to_string(), "8080".to_string())]);
+        assert_eq!(
+            expand_variables("curl localhost:${PORT}/${HOME}${PORT", &variables),
+            "curl localhost:8080/${HOME}${PORT"
+        );
+    }
+
+    #[test]
+    fn test_estimate_duration() {
+        let commands = vec![
+            Command::SetSpeed(0.1),
+            Command::Type("ls\r".into()),
+            Command::Wait(Duration::from_secs(1)),
+            Command::Choose(vec![
+                vec![Command::Wait(Duration::from_secs(1))],
+                vec![Command::Wait(Duration::from_secs(3))],
+            ]),
+            Command::WaitRandom(Duration::from_secs(1), Duration::from_secs(2)),
+        ];
+        let estimate = estimate_duration(&commands, &mut PlaybackConfig::default());
+        assert_eq!(estimate.as_millis(), 300 + 1000 + 2000 + 1500);
+    }
+
+    #[test]
+    fn test_ramp_timing() {
+        let ramp = Timing::Ramp(0.15, 0.03);
+        assert_eq!(ramp.delay(0.1, 0, 5), 0.15);
+        assert!((ramp.delay(0.1, 2, 5) - 0.09).abs() < 1e-9);
+        assert_eq!(ramp.delay(0.1, 4, 5), 0.03);
+        assert_eq!(ramp.delay(0.1, 0, 1), 0.15);
+        assert_eq!(Timing::Constant.delay(0.1, 3, 5), 0.1);
+    }
+
+    #[test]
+    fn test_fast_forwarded_sections() {
+        let script = Script {
+            commands: vec![
+                Command::Type("cd demo\r".into()),
+                Command::Checkpoint("setup".to_string()),
+                Command::Type("make\r".into()),
+                Command::Checkpoint("run".to_string()),
+                Command::Type("./demo\r".into()),
+            ],
+        };
+        let skip = Sections::Skip(vec!["setup".to_string()]);
+        let only = Sections::Only(vec!["setup".to_string()]);
+
+        assert_eq!(
+            fast_forwarded(&script, None, Some(&skip)).unwrap(),
+            [false, true, true, false, false]
+        );
+        assert_eq!(
+            fast_forwarded(&script, None, Some(&only)).unwrap(),
+            [true, false, false, true, true]
+        );
+        assert_eq!(
+            fast_forwarded(&script, Some("run"), Some(&skip)).unwrap(),
+            [true, true, true, false, false]
+        );
+        assert!(fast_forwarded(&script, Some("deploy"), None).is_err());
+
+        // One inside a block may never be reached
+        let script = Script {
+            commands: vec![
+                Command::Type("cd demo\r".into()),
+                Command::Retry(1, 2.0, vec![Command::Checkpoint("run".to_string())]),
+            ],
+        };
+        assert!(fast_forwarded(&script, Some("run"), None).is_err());
+    }
+
+    #[test]
+    fn test_keystroke_bytes() {
+        let config = PlaybackConfig::default();
+        let normal = KeyModes::default();
+        let application = KeyModes {
+            application_cursor: true,
+            application_keypad: true,
+            kitty_flags: 0,
+        };
+        let key = |spec: &str, modes: KeyModes| {
+            let event = KeyEvent::parse(spec).unwrap();
+            String::from_utf8(keystroke_bytes(Keystroke::Key(event), &config, modes)).unwrap()
+        };
+
+        assert_eq!(key("up", normal), "\x1b[A");
+        assert_eq!(key("up", application), "\x1bOA");
+        assert_eq!(key("home", application), "\x1bOH");
+        // Modified keys are unaffected by DECCKM
+        assert_eq!(key("S-up", application), "\x1b[1;2A");
+
+        assert_eq!(key("kp2", normal), "2");
+        assert_eq!(key("kpenter", normal), "\r");
+        assert_eq!(key("kp2", application), "\x1bOr");
+        // F1 is SS3 in both modes
+        assert_eq!(key("F1", normal), "\x1bOP");
+
+        let kitty = KeyModes {
+            kitty_flags: 1,
+            ..normal
+        };
+        assert_eq!(key("C-c", kitty), "\x1b[99;5u");
+        assert_eq!(key("esc", kitty), "\x1b[27u");
+        // Keys that send the same legacy bytes stay apart
+        assert_eq!(key("C-i", kitty), "\x1b[105;5u");
+        assert_eq!(key("tab", kitty), "\t");
+        assert_eq!(key("C-i", normal), "\t");
+        // Text is sent as it is
+        let text = keystroke_bytes(Keystroke::Text("\x1b[A"), &config, kitty);
+        assert_eq!(text, b"\x1b[A");
+    }
+
+    #[tokio::test]
+    async fn test_types_keys_as_written() {
+        let script = ScriptBuilder::new()
+            .speed(0.0)
+            .ctrl('i')
+            .key(Key::Tab)
+            .ctrl('m')
+            .build()
+            .unwrap();
+        let sink = RecordingSink::new(80, 24);
+        sink.output(b"\x1b[>1u");
+        let mut engine = PlaybackEngine::new(sink, PlaybackConfig::default()).unwrap();
+        engine.execute(script).await.unwrap();
+        assert_eq!(engine.sink.sent, b"\x1b[105;5u\t\x1b[109;5u");
+    }
+
+    #[tokio::test]
+    async fn test_plays_into_sink() {
+        let script = ScriptBuilder::new()
+            .speed(0.0)
+            .type_line("ls")
+            .key(Key::Enter)
+            .command(Command::Resize(100, 30))
+            .key(Key::Escape)
+            .build()
+            .unwrap();
+        let mut engine =
+            PlaybackEngine::new(RecordingSink::new(80, 24), PlaybackConfig::default()).unwrap();
+        engine
+            .execute(Script {
+                commands: script.commands.clone(),
+            })
+            .await
+            .unwrap();
+        engine.stop().unwrap();
+        assert_eq!(engine.sink.sent, b"ls\r\x1b");
+        // With no delay, each line's keys are sent together
+        assert_eq!(engine.sink.writes, 2);
+        assert_eq!(engine.sink.resizes, [(100, 30)]);
+        assert_eq!(engine.sink.signals, [Signal::Kill]);
+
+        // Keys follow the modes the program's output has set
+        let sink = RecordingSink::new(80, 24);
+        sink.output(b"\x1b[>1u");
+        let mut engine = PlaybackEngine::new(sink, PlaybackConfig::default()).unwrap();
+        engine.execute(script).await.unwrap();
+        assert_eq!(engine.sink.sent, b"ls\r\x1b[27u");
+    }
+
+    #[tokio::test]
+    async fn test_stats_count_choose_once() {
+        let script = ScriptBuilder::new()
+            .speed(0.0)
+            .command(Command::Choose(vec![vec![
+                Command::Type("ls".into()),
+                Command::Type("pwd".into()),
+            ]]))
+            .type_line("date")
+            .build()
+            .unwrap();
+        let mut engine =
+            PlaybackEngine::new(RecordingSink::new(80, 24), PlaybackConfig::default()).unwrap();
+        engine.execute(script).await.unwrap();
+        engine.stop().unwrap();
+        let indices: Vec<usize> = engine.stats().commands.iter().map(|c| c.index).collect();
+        // After the `@ speed` the builder starts with
+        assert_eq!(indices, [0, 1, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_sounds_unheard() {
+        // Sound files are only looked for when they'd be heard
+        let script = ScriptBuilder::new()
+            .speed(0.0)
+            .command(Command::SetSound(Some("no-such-click.wav".to_string())))
+            .command(Command::Audio("no-such-intro.mp3".to_string()))
+            .type_line("ls")
+            .build()
+            .unwrap();
+        let mut engine =
+            PlaybackEngine::new(RecordingSink::new(80, 24), PlaybackConfig::default()).unwrap();
+        engine.execute(script).await.unwrap();
+        assert_eq!(engine.sink.sent, b"ls");
+    }
+
+    #[tokio::test]
+    async fn test_lua_block() {
+        // Stands in for Lua, playing the lines the block prints as they are
+        let stub = r#"sed -n 's/^print("\(.*\)")$/\1/p'"#;
+        let script = ScriptBuilder::new()
+            .speed(0.0)
+            .command(Command::Lua("print(\"$ echo hi<ret>\")".to_string()))
+            .build()
+            .unwrap();
+        let mut engine =
+            PlaybackEngine::new(RecordingSink::new(80, 24), PlaybackConfig::default()).unwrap();
+        engine.lua_command(stub.to_string());
+        engine.execute(script).await.unwrap();
+        assert_eq!(engine.sink.sent, b"echo hi\r");
+    }
+
+    #[tokio::test]
+    async fn test_resume_from() {
+        let script = || {
+            ScriptBuilder::new()
+                .speed(0.0)
+                .type_line("cd demo")
+                .key(Key::Enter)
+                .wait(Duration::from_secs(30))
+                .command(Command::Checkpoint("run".to_string()))
+                .type_line("make")
+                .build()
+                .unwrap()
+        };
+        let mut engine =
+            PlaybackEngine::new(RecordingSink::new(80, 24), PlaybackConfig::default()).unwrap();
+        engine.resume_from("run".to_string());
+        engine.execute(script()).await.unwrap();
+        // What comes before is still typed, out of sight and without its waits
+        assert_eq!(engine.sink.sent, b"cd demo\rmake");
+        let played: Vec<&str> = engine
+            .stats()
+            .commands
+            .iter()
+            .map(|command| command.line.as_str())
+            .collect();
+        assert_eq!(played, ["@ checkpoint:run", "$ make"]);
+
+        let mut engine =
+            PlaybackEngine::new(RecordingSink::new(80, 24), PlaybackConfig::default()).unwrap();
+        engine.resume_from("deploy".to_string());
+        let error = engine.execute(script()).await.unwrap_err();
+        assert_eq!(error.to_string(), "No checkpoint named 'deploy'");
+        assert!(engine.sink.sent.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_multiline_text() {
+        let play = |mode, output: &'static [u8]| async move {
+            let script = ScriptBuilder::new()
+                .speed(0.0)
+                .command(Command::SetMultiline(mode))
+                .type_line("a\nb\nc")
+                .build()
+                .unwrap();
+            let sink = RecordingSink::new(80, 24);
+            sink.output(output);
+            let mut engine = PlaybackEngine::new(sink, PlaybackConfig::default()).unwrap();
+            engine.execute(script).await.unwrap();
+            engine.sink.sent.clone()
+        };
+        assert_eq!(play(Multiline::Literal, b"").await, b"a\nb\nc");
+        assert_eq!(play(Multiline::Enter, b"").await, b"a\rb\rc");
+        // Pasted only once the program asks for bracketed paste
+        assert_eq!(play(Multiline::Paste, b"").await, b"a\rb\rc");
+        assert_eq!(
+            play(Multiline::Paste, b"\x1b[?2004h").await,
+            b"\x1b[200~a\rb\r\x1b[201~c"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_expect_screen() {
+        let script = ScriptBuilder::new()
+            .command(Command::SetExpectTimeout(Duration::from_millis(100)))
+            .command(Command::ExpectScreen(r"^\$ ready".to_string()))
+            .command(Command::ExpectCell(2, 3, "next".to_string()))
+            .command(Command::Expect(
+                r"PID (\d+)".to_string(),
+                vec!["PID".to_string()],
+            ))
+            .type_line("kill ${PID}")
+            .build()
+            .unwrap();
+        let sink = RecordingSink::new(80, 24);
+        sink.output(b"$ ready\r\n  next PID 42\r\n");
+        let mut engine = PlaybackEngine::new(sink, PlaybackConfig::default()).unwrap();
+        engine.execute(script).await.unwrap();
+        assert!(engine.sink.sent.starts_with(b"kill 42"));
+
+        let script = ScriptBuilder::new()
+            .command(Command::SetExpectTimeout(Duration::from_millis(100)))
+            .command(Command::ExpectCell(1, 1, "next".to_string()))
+            .build()
+            .unwrap();
+        let mut engine =
+            PlaybackEngine::new(RecordingSink::new(80, 24), PlaybackConfig::default()).unwrap();
+        let error = engine.execute(script).await.unwrap_err();
+        assert_eq!(crate::error::exit_code(&error), 4);
+    }
+
+    #[tokio::test]
+    async fn test_expect_clipboard() {
+        let sink = RecordingSink::new(80, 24);
+        sink.output(b"\x1b]52;c;aGk=\x07");
+        let script = ScriptBuilder::new()
+            .command(Command::ExpectClipboard(Some("COPIED".to_string())))
+            .command(Command::Type("${COPIED}".into()))
+            .build()
+            .unwrap();
+        let mut engine = PlaybackEngine::new(sink, PlaybackConfig::default()).unwrap();
+        engine.execute(script).await.unwrap();
+        assert_eq!(engine.sink.sent, b"hi");
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        let script = ScriptBuilder::new()
+            .speed(0.0)
+            .type_line("one")
+            .command(Command::Session("server".to_string()))
+            .command(Command::Expect(
+                "port (\\d+)".to_string(),
+                vec!["PORT".to_string()],
+            ))
+            .command(Command::Session("client".to_string()))
+            .type_line("curl :${PORT}")
+            .command(Command::Session(MAIN_SESSION.to_string()))
+            .type_line("two")
+            .build()
+            .unwrap();
+        let mut engine =
+            PlaybackEngine::new(RecordingSink::new(80, 24), PlaybackConfig::default()).unwrap();
+        engine.start_sessions_with(|_| {
+            let sink = RecordingSink::new(80, 24);
+            sink.output(b"Serving on port 8080\r\n");
+            Ok(sink)
+        });
+        engine.execute(script).await.unwrap();
+        assert_eq!(engine.sink().sent, b"onetwo");
+        let mut sent: Vec<_> = engine
+            .sessions
+            .iter()
+            .map(|(name, session)| (name.as_str(), session.sent.as_slice()))
+            .collect();
+        sent.sort();
+        assert_eq!(sent, [("client", &b"curl :8080"[..]), ("server", &b""[..])]);
+    }
+
+    #[tokio::test]
+    async fn test_sessions_end_with_playback() {
+        let script = ScriptBuilder::new()
+            .speed(0.0)
+            .command(Command::Session("server".to_string()))
+            .type_line("sleep 300")
+            .command(Command::Session(MAIN_SESSION.to_string()))
+            .type_line("echo hi")
+            .command(Command::Session("client".to_string()))
+            .build()
+            .unwrap();
+        let mut engine =
+            PlaybackEngine::new(RecordingSink::new(80, 24), PlaybackConfig::default()).unwrap();
+        engine.start_sessions_with(|_| Ok(RecordingSink::new(80, 24)));
+        engine.execute(script).await.unwrap();
+        // The main shell is left to exit at the end of its input
+        assert!(engine.sink().signals.is_empty());
+        assert_eq!(engine.sink.signals, [Signal::Kill]);
+        let (_, server) = &engine.sessions[0];
+        assert_eq!(server.sent, b"sleep 300");
+        assert_eq!(server.signals, [Signal::Kill]);
+    }
+
+    #[tokio::test]
+    async fn test_clipboard_needs_allowing() {
+        let mut engine =
+            PlaybackEngine::new(RecordingSink::new(80, 24), PlaybackConfig::default()).unwrap();
+        let error = engine.play(Command::TypeClipboard).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("--allow-clipboard"));
+        assert!(engine.sink.sent.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_wait_prompt() {
+        let script = ScriptBuilder::new()
+            .command(Command::SetExpectTimeout(Duration::from_millis(500)))
+            .command(Command::SetPromptPattern(r"\] ".to_string()))
+            .command(Command::WaitPrompt)
+            .type_line("ls")
+            .command(Command::WaitPrompt)
+            .build()
+            .unwrap();
+        let mut engine =
+            PlaybackEngine::new(RecordingSink::new(80, 24), PlaybackConfig::default()).unwrap();
+        engine.sink.output(b"[~] ");
+        // The prompt from before `ls` doesn't count for the wait after it
+        let error = engine.execute(script).await.unwrap_err();
+        assert_eq!(crate::error::exit_code(&error), 5);
+        assert_eq!(engine.sink.sent, b"ls");
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let script = ScriptBuilder::new()
+            .command(Command::SetExpectTimeout(Duration::from_millis(50)))
+            .command(Command::Retry(
+                1,
+                2.0,
+                vec![
+                    Command::Type("a".into()),
+                    Command::ExpectCell(1, 1, "z".to_string()),
+                ],
+            ))
+            .build()
+            .unwrap();
+        let config = PlaybackConfig {
+            retry_delay: Duration::from_millis(10),
+            ..PlaybackConfig::default()
+        };
+        let mut engine = PlaybackEngine::new(RecordingSink::new(80, 24), config).unwrap();
+        let error = engine.execute(script).await.unwrap_err();
+        assert_eq!(crate::error::exit_code(&error), 4);
+        assert_eq!(engine.sink.sent, b"a\x03a");
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_file() {
+        let path = std::env::temp_dir().join(format!("quipu-waitfile-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let script = ScriptBuilder::new()
+            .command(Command::Exec(
+                format!("echo {}", path.display()),
+                Some("FILE".to_string()),
+            ))
+            .command(Command::WaitFor(WaitCondition::File, "${FILE}".to_string()))
+            .build()
+            .unwrap();
+        let created = path.clone();
+        let creating = tokio::spawn(async move {
+            sleep(Duration::from_millis(300)).await;
+            std::fs::write(created, "").unwrap();
+        });
+        let mut engine =
+            PlaybackEngine::new(RecordingSink::new(80, 24), PlaybackConfig::default()).unwrap();
+        let start = Instant::now();
+        engine.execute(script).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        creating.await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Targets are checked once they're expanded
+        let script = ScriptBuilder::new()
+            .command(Command::Exec(
+                "echo localhost".to_string(),
+                Some("HOST".to_string()),
+            ))
+            .command(Command::WaitFor(WaitCondition::Port, "${HOST}".to_string()))
+            .build()
+            .unwrap();
+        let mut engine =
+            PlaybackEngine::new(RecordingSink::new(80, 24), PlaybackConfig::default()).unwrap();
+        let error = engine.execute(script).await.unwrap_err();
+        assert!(error.to_string().contains("Not a host:port"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_command_timeout() {
+        let script = ScriptBuilder::new()
+            .command(Command::SetCommandTimeout(Duration::from_millis(50)))
+            .command(Command::Wait(Duration::from_millis(100)))
+            .command(Command::Exec("sleep 5".to_string(), None))
+            .build()
+            .unwrap();
+        let mut engine =
+            PlaybackEngine::new(RecordingSink::new(80, 24), PlaybackConfig::default()).unwrap();
+        let start = Instant::now();
+        let error = engine.execute(script).await.unwrap_err();
+        assert_eq!(crate::error::exit_code(&error), 6);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // A line that starts a program that never finishes is only caught by
+        // waiting for the prompt after it
+        let script = ScriptBuilder::new()
+            .speed(0.0)
+            .command(Command::SetCommandTimeout(Duration::from_millis(50)))
+            .type_line("sleep 300\r")
+            .command(Command::WaitPrompt)
+            .build()
+            .unwrap();
+        let mut engine =
+            PlaybackEngine::new(RecordingSink::new(80, 24), PlaybackConfig::default()).unwrap();
+        let error = engine.execute(script).await.unwrap_err();
+        assert_eq!(crate::error::exit_code(&error), 6);
+        assert!(error.to_string().contains("@ waitprompt"), "{}", error);
+        assert_eq!(engine.sink.sent, b"sleep 300\r");
+    }
+
+    #[tokio::test]
+    async fn test_handlers() {
+        let handlers = [
+            Command::OnError(vec![Command::Type("e".into())]),
+            Command::Finally(vec![Command::Type("f".into())]),
+        ];
+        let script = Script {
+            commands: [Command::Type("a".into())]
+                .into_iter()
+                .chain(handlers.clone())
+                .collect(),
+        };
+        let mut engine =
+            PlaybackEngine::new(RecordingSink::new(80, 24), PlaybackConfig::default()).unwrap();
+        engine.execute(script).await.unwrap();
+        assert_eq!(engine.sink.sent, b"af");
+
+        let script = Script {
+            commands: [
+                Command::SetExpectTimeout(Duration::from_millis(50)),
+                Command::ExpectCell(1, 1, "z".to_string()),
+                Command::Type("a".into()),
+            ]
+            .into_iter()
+            .chain(handlers.clone())
+            .collect(),
+        };
+        let mut engine =
+            PlaybackEngine::new(RecordingSink::new(80, 24), PlaybackConfig::default()).unwrap();
+        let error = engine.execute(script).await.unwrap_err();
+        assert_eq!(crate::error::exit_code(&error), 4);
+        assert_eq!(engine.sink.sent, b"ef");
+
+        // Running past --max-duration plays them too
+        let script = Script {
+            commands: [Command::Wait(Duration::from_secs(60))]
+                .into_iter()
+                .chain(handlers)
+                .collect(),
+        };
+        let mut engine =
+            PlaybackEngine::new(RecordingSink::new(80, 24), PlaybackConfig::default()).unwrap();
+        engine.limit_duration(Duration::from_millis(50));
+        let error = engine.execute(script).await.unwrap_err();
+        assert_eq!(crate::error::exit_code(&error), 124);
+        assert_eq!(engine.sink.sent, b"ef");
+
+        // As does Ctrl-C, which `@ finally` blocks play to the end after
+        let script = Script {
+            commands: vec![
+                Command::Type("a".into()),
+                Command::Finally(vec![Command::Type("fg".into())]),
+            ],
+        };
+        let mut engine =
+            PlaybackEngine::new(RecordingSink::new(80, 24), PlaybackConfig::default()).unwrap();
+        engine.running = Arc::new(AtomicBool::new(false));
+        engine.execute(script).await.unwrap();
+        assert_eq!(engine.sink.sent, b"fg");
+    }
+
+    #[tokio::test]
+    async fn test_reports_events() {
+        let script = ScriptBuilder::new()
+            .type_line("a")
+            .wait_secs(0.01)
+            .build()
+            .unwrap();
+        let mut engine =
+            PlaybackEngine::new(RecordingSink::new(80, 24), PlaybackConfig::default()).unwrap();
+        let mut events = engine.events();
+        engine.execute(script).await.unwrap();
+
+        assert!(matches!(
+            events.try_next(),
+            Some(PlaybackEvent::CommandStarted { index: 0, .. })
+        ));
+        assert_eq!(
+            events.try_next(),
+            Some(PlaybackEvent::KeystrokeSent {
+                keystroke: "a".to_string()
+            })
+        );
+        assert!(matches!(
+            events.try_next(),
+            Some(PlaybackEvent::CommandStarted { index: 1, .. })
+        ));
+        assert_eq!(
+            events.try_next(),
+            Some(PlaybackEvent::WaitStarted {
+                duration: Duration::from_millis(10)
+            })
+        );
+        assert!(matches!(
+            events.try_next(),
+            Some(PlaybackEvent::Finished { .. })
+        ));
+        drop(engine);
+        assert_eq!(events.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_reports_expect_matched() {
+        use futures_core::Stream;
+        use std::pin::Pin;
+
+        let script = ScriptBuilder::new()
+            .command(Command::Expect("re(a)dy".to_string(), Vec::new()))
+            .command(Command::ExpectCell(1, 3, "ready".to_string()))
+            .build()
+            .unwrap();
+        let sink = RecordingSink::new(80, 24);
+        sink.output(b"$ ready\r\n");
+        let mut engine = PlaybackEngine::new(sink, PlaybackConfig::default()).unwrap();
+        let mut events = engine.events();
+        engine.execute(script).await.unwrap();
+        drop(engine);
+
+        let mut matched = Vec::new();
+        while let Some(event) = std::future::poll_fn(|cx| Pin::new(&mut events).poll_next(cx)).await
+        {
+            if let PlaybackEvent::ExpectMatched { pattern, .. } = event {
+                matched.push(pattern);
+            }
+        }
+        assert_eq!(matched, ["re(a)dy", "ready"]);
+    }
+
+    #[tokio::test]
+    async fn test_reports_unread_input() {
+        let script = ScriptBuilder::new()
+            .type_line("a")
+            .type_line("b")
+            .build()
+            .unwrap();
+        let mut sink = RecordingSink::new(80, 24);
+        sink.unread = Some(2);
+        let mut engine = PlaybackEngine::new(sink, PlaybackConfig::default()).unwrap();
+        engine.input_stall_time = Duration::ZERO;
+        let mut events = engine.events();
+        engine.execute(script).await.unwrap();
+
+        // Warned once, not on every look while the input stays unread
+        let mut stalls = Vec::new();
+        while let Some(event) = events.try_next() {
+            if let PlaybackEvent::InputStalled { unread, .. } = event {
+                stalls.push(unread);
+            }
+        }
+        assert_eq!(stalls, [2]);
+    }
+
+    #[tokio::test]
+    async fn test_calls_hooks() {
+        #[derive(Clone, Default)]
+        struct Log(Arc<std::sync::Mutex<Vec<String>>>);
+        impl PlaybackHooks for Log {
+            fn before_command(&mut self, index: usize, _command: &Command) {
+                self.0.lock().unwrap().push(format!("before {}", index));
+            }
+            fn after_command(&mut self, index: usize, _command: &Command, _duration: Duration) {
+                self.0.lock().unwrap().push(format!("after {}", index));
+            }
+            fn on_keystroke(&mut self, keystroke: &str, bytes: &[u8]) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("{:?} {:?}", keystroke, bytes));
+            }
+        }
+
+        let script = ScriptBuilder::new()
+            .speed(0.0)
+            .key(Key::Up)
+            .build()
+            .unwrap();
+        let config = PlaybackConfig {
+            cursor_keys: CursorKeys::Application,
+            ..PlaybackConfig::default()
+        };
+        let mut engine = PlaybackEngine::new(RecordingSink::new(80, 24), config).unwrap();
+        let log = Log::default();
+        engine.add_hooks(log.clone());
+        engine.execute(script).await.unwrap();
+        assert_eq!(
+            *log.0.lock().unwrap(),
+            [
+                "before 0",
+                "after 0",
+                "before 1",
+                "\"<up>\" [27, 79, 65]",
+                "after 1"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_keystrokes() {
+        assert_eq!(
+            split_keystrokes("a\x1b[A\x1b[97;5u\x1b[2 q\x1bOPb"),
+            ["a", "\x1b[A", "\x1b[97;5u", "\x1b[2 q", "\x1bOP", "b"]
+        );
+        // OSC 52 ended by BEL or ST, and a DCS query
+        assert_eq!(
+            split_keystrokes("\x1b]52;c;aGk=\x07\x1b]52;c;?\x1b\\\x1bP+q436F\x1b\\"),
+            [
+                "\x1b]52;c;aGk=\x07",
+                "\x1b]52;c;?\x1b\\",
+                "\x1bP+q436F\x1b\\"
+            ]
+        );
+        // Alt with sequences and multibyte characters, and charset designation
+        assert_eq!(
+            split_keystrokes("\x1b\x1b[A\x1bé\x1b(B\x1b\x1bx"),
+            ["\x1b\x1b[A", "\x1bé", "\x1b(B", "\x1b\x1b", "x"]
+        );
+        // A malformed CSI ends at the byte that doesn't belong, and an
+        // unfinished sequence runs to the end
+        assert_eq!(
+            split_keystrokes("\x1b[1\x03x\x1b["),
+            ["\x1b[1", "\x03", "x", "\x1b["]
+        );
+    }
+
+    #[test]
+    fn test_split_composed() {
+        assert_eq!(
+            split_composed("ls 日本語です。ok\x1b[A"),
+            vec!["l", "s", " ", "日本語で", "す", "。", "o", "k", "\x1b[A"]
+        );
+        assert_eq!(split_composed("한국어"), vec!["한국어"]);
+    }
+
+    #[test]
+    fn test_encode_text() {
+        let latin1 = text_encoding("latin1").unwrap();
+        assert_eq!(encode_text("café", latin1).as_ref(), b"caf\xe9");
+        assert_eq!(encode_text("日", latin1).as_ref(), b"?");
+
+        let sjis = text_encoding("shift_jis").unwrap();
+        assert_eq!(encode_text("日本", sjis).as_ref(), b"\x93\xfa\x96\x7b");
+        assert_eq!(encode_text("é", UTF_8).as_ref(), "é".as_bytes());
+    }
+}
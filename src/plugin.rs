@@ -0,0 +1,139 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Directives from plugins, registered in the config file's `[directives]`
+//!
+//! `foo = "/usr/local/bin/quipu-foo"` makes `@ foo:ARGS` a directive that
+//! runs the command with the shell when playback reaches it, for things
+//! that don't belong in quipu itself such as resetting a database or
+//! flipping a feature flag. The command gets JSON describing the call and
+//! the session on stdin, and prints script lines for quipu to play in its
+//! place, such as `@ wait:2` or `$ psql -f seed.sql<ret>`, or nothing
+
+use anyhow::{Context, Result, bail};
+use std::collections::HashMap;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+
+use crate::parser::{ParseOptions, parse_script};
+use crate::types::Command;
+
+/// Check that `name` can be used as a directive, as in `@ name:args`
+pub fn check_name(name: &str) -> Result<(), String> {
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_lowercase())
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if !valid {
+        return Err(format!(
+            "Directive names are lowercase letters, digits and '-': '{}'",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// What a plugin is told about the call and the session it's in
+#[derive(serde::Serialize)]
+pub struct Call<'a> {
+    pub directive: &'a str,
+    pub args: &'a str,
+    pub variables: &'a HashMap<String, String>,
+    // The text on screen
+    pub screen: String,
+    pub cols: u16,
+    pub rows: u16,
+    pub elapsed_ms: u64,
+}
+
+/// Run `program` for `call`, returning the commands it printed
+pub async fn run(program: &str, call: &Call<'_>) -> Result<Vec<Command>> {
+    generate(program, &serde_json::to_string(call)?, call.directive).await
+}
+
+/// Run `program` for `@ directive` with `input` on stdin, returning the
+/// commands in the script lines it prints
+pub async fn generate(program: &str, input: &str, directive: &str) -> Result<Vec<Command>> {
+    let mut child = crate::host::shell_command(program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to run '{}' for @ {}", program, directive))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A program that doesn't read its input may have closed stdin already
+        let _ = stdin.write_all(input.as_bytes()).await;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        bail!(
+            "@ {} failed with {}: {}",
+            directive,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    // Plugins can't call each other or print `@ lua` blocks, so nothing they
+    // print loops back
+    let script = parse_script(
+        &String::from_utf8_lossy(&output.stdout),
+        &ParseOptions::default(),
+    )
+    .map_err(|e| anyhow::anyhow!("@ {} printed an invalid script: {}", directive, e))?;
+    for command in &script.commands {
+        if let Command::Setup(_)
+        | Command::Teardown(_)
+        | Command::Lua(_)
+        | Command::OnError(_)
+        | Command::Finally(_) = command
+        {
+            bail!(
+                "@ {} can't add @ setup, @ teardown, @ lua, @ on-error or @ finally blocks",
+                directive
+            );
+        }
+    }
+    Ok(script.commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run() {
+        let variables = HashMap::from([("DB".to_string(), "shop".to_string())]);
+        let call = Call {
+            directive: "db-reset",
+            args: "fixtures",
+            variables: &variables,
+            screen: String::new(),
+            cols: 80,
+            rows: 24,
+            elapsed_ms: 0,
+        };
+        let program = r#"sed 's/.*"args":"\([a-z]*\)".*"DB":"\([a-z]*\)".*/$ load \1 \2<ret>/'"#;
+        assert_eq!(
+            run(program, &call).await.unwrap(),
+            vec![Command::Type("load fixtures shop\r".into())]
+        );
+        assert!(check_name("db-reset").is_ok());
+        assert!(check_name("Reset").is_err());
+
+        let error = run("echo oops >&2; exit 3", &call).await.unwrap_err();
+        assert!(error.to_string().contains("oops"), "{}", error);
+    }
+}
@@ -0,0 +1,348 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Presenter view for a second terminal
+//!
+//! Playback with `--presenter-socket` listens on a local TCP address and
+//! sends each client the script followed by a line of JSON per command.
+//! `quipu presenter` connects to it and shows the current line, what's
+//! coming up, the elapsed time and the next checkpoint
+
+use anyhow::{Context, Result};
+use crossterm::event::{self, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::{cursor, execute, terminal};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::format::format_command;
+use crate::types::Command;
+
+// A presenter that stops reading shouldn't hold up playback
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Script { commands: Vec<Command> },
+    Command { index: usize, elapsed_ms: u64 },
+    Finished { elapsed_ms: u64 },
+}
+
+#[derive(Default)]
+struct Shared {
+    clients: Vec<TcpStream>,
+    // Replayed to presenters that connect partway through
+    script: Option<String>,
+    latest: Option<String>,
+}
+
+/// Playback's end of the connection, shared by every session in a `--watch` run
+pub struct PresenterServer {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl PresenterServer {
+    pub fn bind(addr: &str) -> Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .with_context(|| format!("Failed to listen for a presenter on {}", addr))?;
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let accept_shared = shared.clone();
+
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+                let mut shared = accept_shared.lock().unwrap();
+                let replay = shared.script.iter().chain(&shared.latest);
+                if replay
+                    .into_iter()
+                    .all(|line| stream.write_all(line.as_bytes()).is_ok())
+                {
+                    shared.clients.push(stream);
+                }
+            }
+        });
+
+        Ok(Self { shared })
+    }
+
+    pub fn send(&self, event: &Event) {
+        let Ok(json) = serde_json::to_string(event) else {
+            return;
+        };
+        let line = json + "\n";
+
+        let mut shared = self.shared.lock().unwrap();
+        shared
+            .clients
+            .retain_mut(|client| client.write_all(line.as_bytes()).is_ok());
+        match event {
+            Event::Script { .. } => {
+                shared.script = Some(line);
+                shared.latest = None;
+            }
+            _ => shared.latest = Some(line),
+        }
+    }
+}
+
+#[derive(Default)]
+struct View {
+    commands: Vec<Command>,
+    current: Option<usize>,
+    elapsed: Duration,
+    // When `elapsed` was last updated, so the clock keeps running between events
+    updated: Option<Instant>,
+    finished: bool,
+}
+
+impl View {
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::Script { commands } => {
+                *self = View {
+                    commands,
+                    ..View::default()
+                };
+            }
+            Event::Command { index, elapsed_ms } => {
+                self.current = Some(index);
+                self.elapsed = Duration::from_millis(elapsed_ms);
+                self.updated = Some(Instant::now());
+            }
+            Event::Finished { elapsed_ms } => {
+                self.elapsed = Duration::from_millis(elapsed_ms);
+                self.updated = None;
+                self.finished = true;
+            }
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.elapsed + self.updated.map_or(Duration::ZERO, |at| at.elapsed())
+    }
+
+    fn upcoming(&self) -> usize {
+        self.current.map_or(0, |index| index + 1)
+    }
+
+    // The next checkpoint after the current line, and how many lines away it is
+    fn next_checkpoint(&self) -> Option<(&str, usize)> {
+        self.commands
+            .iter()
+            .enumerate()
+            .skip(self.upcoming())
+            .find_map(|(index, command)| match command {
+                Command::Checkpoint(name) => Some((name.as_str(), index - self.upcoming() + 1)),
+                _ => None,
+            })
+    }
+
+    // Whether the command at `index` is a note on the `$` line after it
+    fn is_note(&self, index: usize) -> bool {
+        matches!(self.commands[index], Command::Note(_))
+            && matches!(self.commands.get(index + 1), Some(Command::Type(_)))
+    }
+
+    // The command at `index` as written, with a `$` line's note back on the end
+    fn line(&self, index: usize) -> String {
+        let index = if self.is_note(index) {
+            index + 1
+        } else {
+            index
+        };
+        let text = format_command(&self.commands[index]);
+        match index.checked_sub(1) {
+            Some(before) if self.is_note(before) => {
+                format!("{}  {}", text, format_command(&self.commands[before]))
+            }
+            _ => text,
+        }
+    }
+
+    fn render(&self, cols: usize, rows: usize) -> Vec<String> {
+        let secs = self.elapsed().as_secs();
+        let position = self.current.map_or(0, |index| index + 1);
+        let mut lines = vec![
+            format!(
+                "Elapsed {:02}:{:02}    Line {}/{}",
+                secs / 60,
+                secs % 60,
+                position,
+                self.commands.len()
+            ),
+            String::new(),
+            "Now".to_string(),
+        ];
+
+        match (self.finished, self.current) {
+            (true, _) => lines.push("  Playback finished".to_string()),
+            (false, Some(index)) => lines.push(format!("> {}", self.line(index))),
+            (false, None) => lines.push("  Waiting to start".to_string()),
+        }
+
+        lines.push(String::new());
+        lines.push("Next".to_string());
+        let footer = match self.next_checkpoint() {
+            Some((name, 1)) => format!("Next checkpoint: {} (next line)", name),
+            Some((name, distance)) => format!("Next checkpoint: {} (in {} lines)", name, distance),
+            None => "No more checkpoints".to_string(),
+        };
+
+        // Upcoming lines fill whatever is left above the footer
+        let room = rows.saturating_sub(lines.len() + 2);
+        if !self.finished {
+            let upcoming = (self.upcoming()..self.commands.len())
+                .filter(|&index| !self.is_note(index))
+                .take(room);
+            for index in upcoming {
+                lines.push(format!("  {}", self.line(index)));
+            }
+        }
+        lines.resize(rows.saturating_sub(1).max(lines.len()), String::new());
+        lines.push(footer);
+
+        lines
+            .into_iter()
+            .map(|line| line.chars().take(cols).collect())
+            .collect()
+    }
+}
+
+fn connect(addr: &str) -> Result<TcpStream> {
+    let mut waiting = false;
+    loop {
+        match TcpStream::connect(addr) {
+            Ok(stream) => return Ok(stream),
+            Err(_) if !waiting => {
+                eprintln!("Waiting for playback on {}...", addr);
+                waiting = true;
+            }
+            Err(_) => {}
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+fn quit_requested() -> Result<bool> {
+    if !event::poll(REDRAW_INTERVAL)? {
+        return Ok(false);
+    }
+    Ok(matches!(
+        event::read()?,
+        event::Event::Key(KeyEvent {
+            code: KeyCode::Char('q'),
+            ..
+        }) | event::Event::Key(KeyEvent {
+            code: KeyCode::Char('c'),
+            modifiers: KeyModifiers::CONTROL,
+            ..
+        })
+    ))
+}
+
+/// Follow playback on `addr` until it finishes or `q` is pressed
+pub fn run(addr: &str) -> Result<()> {
+    let stream = connect(addr)?;
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines().map_while(Result::ok) {
+            if let Ok(event) = serde_json::from_str::<Event>(&line)
+                && tx.send(event).is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    let mut stdout = std::io::stdout();
+    terminal::enable_raw_mode().context("Failed to enable raw mode")?;
+    execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+
+    let result = (|| -> Result<()> {
+        let mut view = View::default();
+        loop {
+            while let Ok(event) = rx.try_recv() {
+                view.apply(event);
+            }
+
+            let (cols, rows) = terminal::size()?;
+            // Overwrite in place rather than clearing, which flickers
+            let lines = view.render(cols as usize, rows as usize);
+            write!(stdout, "\x1b[H{}\x1b[K\x1b[J", lines.join("\x1b[K\r\n"))?;
+            stdout.flush()?;
+
+            if quit_requested()? {
+                return Ok(());
+            }
+        }
+    })();
+
+    let _ = execute!(stdout, cursor::Show, terminal::LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presenter_view() {
+        let mut view = View::default();
+        view.apply(Event::Script {
+            commands: vec![
+                Command::Type("ls\r".into()),
+                Command::Wait(Duration::from_secs(1)),
+                Command::Checkpoint("deploy".to_string()),
+                Command::Note("build it".to_string()),
+                Command::Type("make\r".into()),
+            ],
+        });
+        assert_eq!(view.next_checkpoint(), Some(("deploy", 3)));
+
+        view.apply(Event::Command {
+            index: 0,
+            elapsed_ms: 61_000,
+        });
+        assert_eq!(view.next_checkpoint(), Some(("deploy", 2)));
+        assert_eq!(
+            view.render(40, 9),
+            vec![
+                "Elapsed 01:01    Line 1/5",
+                "",
+                "Now",
+                "> $ ls<ret>",
+                "",
+                "Next",
+                "  @ wait:1",
+                "",
+                "Next checkpoint: deploy (in 2 lines)",
+            ]
+        );
+
+        // A note shows at the end of its line
+        view.apply(Event::Command {
+            index: 3,
+            elapsed_ms: 62_000,
+        });
+        assert_eq!(view.render(40, 9)[3], "> $ make<ret>  #! build it");
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Built-in shell profiles for `@ shell-profile:`
+//!
+//! Each profile starts a shell without the user's startup files and with a
+//! plain `$ `, `% ` or `> ` prompt, so a demo looks the same on any machine
+//! and `@ waitprompt` knows what to wait for. Where the shell allows it they
+//! also turn off what makes a recording hard to follow: fish's autosuggestions
+//! and syntax highlighting, and bracketed paste, which has shells highlight or
+//! rewrite pasted text. fish and nushell take their settings as flags, while
+//! bash and zsh are given a startup file written for the session
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+#[derive(Debug)]
+pub struct ShellProfile {
+    pub name: &'static str,
+    // The shell it's for, unless `@ shell` names another
+    pub shell: &'static str,
+    pub args: &'static [&'static str],
+    // A file written for the session, and how the shell is told where it is
+    startup: Option<Startup>,
+}
+
+#[derive(Debug)]
+struct Startup {
+    file: &'static str,
+    contents: &'static str,
+    place: Place,
+}
+
+#[derive(Debug)]
+enum Place {
+    // Passed as the argument after this flag
+    Arg(&'static str),
+    // The directory it's in is given in this variable
+    Dir(&'static str),
+}
+
+const FISH_INIT: &str = "\
+set -g fish_greeting ''
+set -g fish_autosuggestion_enabled 0
+for name in normal command keyword quote redirection end error param valid_path option comment operator escape autosuggestion
+    set -g fish_color_$name normal
+end
+function fish_prompt; printf '> '; end
+function fish_right_prompt; end
+function fish_mode_prompt; end";
+
+const NU_INIT: &str = "\
+$env.config.show_banner = false
+$env.config.bracketed_paste = false
+$env.config.use_ansi_coloring = false
+$env.PROMPT_COMMAND = {|| '' }
+$env.PROMPT_COMMAND_RIGHT = {|| '' }
+$env.PROMPT_INDICATOR = {|| '> ' }";
+
+pub const PROFILES: &[ShellProfile] = &[
+    ShellProfile {
+        name: "bash-clean",
+        shell: "bash",
+        args: &["--noprofile"],
+        startup: Some(Startup {
+            file: "bashrc",
+            contents: "PS1='$ '\nPS2='> '\nunset PROMPT_COMMAND\nbind 'set enable-bracketed-paste off'\n",
+            place: Place::Arg("--rcfile"),
+        }),
+    },
+    ShellProfile {
+        name: "zsh-clean",
+        shell: "zsh",
+        // Only the files in the profile's ZDOTDIR, and /etc/zshenv, which can't be skipped
+        args: &["-o", "no_global_rcs"],
+        startup: Some(Startup {
+            file: ".zshrc",
+            contents: "PS1='%# '\nRPS1=''\nunset zle_bracketed_paste\n",
+            place: Place::Dir("ZDOTDIR"),
+        }),
+    },
+    ShellProfile {
+        name: "fish-clean",
+        shell: "fish",
+        args: &["--no-config", "--init-command", FISH_INIT],
+        startup: None,
+    },
+    ShellProfile {
+        name: "nu-clean",
+        shell: "nu",
+        args: &["--no-config-file", "--execute", NU_INIT],
+        startup: None,
+    },
+];
+
+/// The built-in profile called `name`
+pub fn find(name: &str) -> Option<&'static ShellProfile> {
+    PROFILES.iter().find(|profile| profile.name == name)
+}
+
+/// Check that there's a profile called `name`
+pub fn check_name(name: &str) -> Result<(), String> {
+    match find(name) {
+        Some(_) => Ok(()),
+        None => Err(format!(
+            "Unknown shell profile '{}', expected one of {}",
+            name,
+            PROFILES
+                .iter()
+                .map(|profile| profile.name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+// Told apart from other sessions started by the same process
+static COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// The startup file written for a session, removed when dropped, which
+/// shouldn't be until the shell has gone
+#[derive(Default)]
+pub struct StartupFiles {
+    dir: Option<PathBuf>,
+    pub args: Vec<String>,
+    pub env: Vec<(&'static str, String)>,
+}
+
+impl ShellProfile {
+    /// Whether the profile needs a file written before the shell starts
+    pub fn has_startup_file(&self) -> bool {
+        self.startup.is_some()
+    }
+
+    /// Write the profile's startup file, if it has one, returning the
+    /// arguments and environment that point the shell at it
+    pub fn write_startup(&self) -> Result<StartupFiles> {
+        let Some(startup) = &self.startup else {
+            return Ok(StartupFiles::default());
+        };
+        let dir = std::env::temp_dir().join(format!(
+            "quipu-{}-{}-{}",
+            self.name,
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        // Removed on drop from here on, even if writing the file fails
+        let mut files = StartupFiles {
+            dir: Some(dir.clone()),
+            args: Vec::new(),
+            env: Vec::new(),
+        };
+
+        let path = dir.join(startup.file);
+        fs::write(&path, startup.contents)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        match startup.place {
+            Place::Arg(flag) => {
+                files.args = vec![flag.to_string(), path.display().to_string()];
+            }
+            Place::Dir(variable) => files.env = vec![(variable, dir.display().to_string())],
+        }
+        Ok(files)
+    }
+}
+
+impl Drop for StartupFiles {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.dir {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_startup() {
+        let files = find("bash-clean").unwrap().write_startup().unwrap();
+        assert_eq!(files.args[0], "--rcfile");
+        let path = PathBuf::from(&files.args[1]);
+        assert!(
+            fs::read_to_string(&path)
+                .unwrap()
+                .contains("enable-bracketed-paste off")
+        );
+        drop(files);
+        assert!(!path.exists());
+
+        let files = find("zsh-clean").unwrap().write_startup().unwrap();
+        let (variable, dir) = &files.env[0];
+        assert_eq!(*variable, "ZDOTDIR");
+        assert!(PathBuf::from(dir).join(".zshrc").is_file());
+
+        assert!(
+            find("fish-clean")
+                .unwrap()
+                .write_startup()
+                .unwrap()
+                .args
+                .is_empty()
+        );
+        assert!(check_name("fish-dirty").is_err());
+    }
+}
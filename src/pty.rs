@@ -17,67 +17,370 @@
 //!
 //! Handles spawning processes in a PTY and sending keystrokes to them
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
-use portable_pty::{CommandBuilder, PtySize, native_pty_system};
+pub use portable_pty::PtySize;
+use portable_pty::{Child, CommandBuilder, native_pty_system};
+
+use crate::cast::{Cast, Recorder};
+use crate::clipboard::Osc52Filter;
+use crate::input::{self, ReplyQueue};
+use crate::panes::{Panes, SharedMaster};
+use crate::profile::StartupFiles;
+use crate::redact::{MaskState, Redactor};
+use crate::screen::Screen;
+use crate::sink::{KeystrokeSink, Signal};
+use crate::supervisor;
+use crate::transcript::Transcript;
+use crate::width::AmbiguousWidth;
 use std::io::{IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::{SendTimeoutError, TrySendError};
+use tokio::sync::mpsc::{self, Sender};
+
+/// The writing end of a session's PTY, closed by taking it
+type PtyWriter = Arc<Mutex<Option<Box<dyn Write + Send>>>>;
+
+/// Whether a terminfo entry for `term` can be found, for programs in the session
+/// to know how to drive the terminal
+pub fn has_terminfo(term: &str) -> bool {
+    let Some(first) = term.chars().next() else {
+        return false;
+    };
+    let mut dirs: Vec<PathBuf> = std::env::var_os("TERMINFO")
+        .map(PathBuf::from)
+        .into_iter()
+        .collect();
+    if let Some(home) = std::env::var_os("HOME") {
+        dirs.push(PathBuf::from(home).join(".terminfo"));
+    }
+    if let Some(list) = std::env::var_os("TERMINFO_DIRS") {
+        dirs.extend(std::env::split_paths(&list));
+    }
+    dirs.extend(
+        [
+            "/etc/terminfo",
+            "/lib/terminfo",
+            "/usr/share/terminfo",
+            "/usr/lib/terminfo",
+        ]
+        .map(PathBuf::from),
+    );
+
+    // Without a terminfo database at all, as on Windows, there's nothing to check
+    let dirs: Vec<PathBuf> = dirs.into_iter().filter(|dir| dir.is_dir()).collect();
+    if dirs.is_empty() {
+        return true;
+    }
+    // Entries are filed under their first letter, or its hex code on macOS
+    let subdirs = [first.to_string(), format!("{:x}", first as u32)];
+    dirs.iter()
+        .any(|dir| subdirs.iter().any(|sub| dir.join(sub).join(term).exists()))
+}
+
+// Windows consoles only send keys as escape sequences, and only interpret
+// them in output, with their VT modes on
+#[cfg(windows)]
+mod vt_mode {
+    use crossterm_winapi::{ConsoleMode, Handle};
+
+    const ENABLE_VIRTUAL_TERMINAL_INPUT: u32 = 0x0200;
+    const ENABLE_VIRTUAL_TERMINAL_PROCESSING: u32 = 0x0004;
+    // LF moves down without returning, as it does in raw mode on Unix
+    const DISABLE_NEWLINE_AUTO_RETURN: u32 = 0x0008;
+
+    // The modes to put back, for the handles that are consoles
+    pub struct Saved(Vec<(ConsoleMode, u32)>);
+
+    pub fn enable() -> Saved {
+        let handles = [
+            (Handle::current_in_handle(), ENABLE_VIRTUAL_TERMINAL_INPUT),
+            (
+                Handle::current_out_handle(),
+                ENABLE_VIRTUAL_TERMINAL_PROCESSING | DISABLE_NEWLINE_AUTO_RETURN,
+            ),
+        ];
+        let mut saved = Vec::new();
+        for (handle, flags) in handles {
+            let Ok(handle) = handle else { continue };
+            let console = ConsoleMode::from(handle);
+            if let Ok(mode) = console.mode()
+                && console.set_mode(mode | flags).is_ok()
+            {
+                saved.push((console, mode));
+            }
+        }
+        Saved(saved)
+    }
+
+    impl Drop for Saved {
+        fn drop(&mut self) {
+            for (console, mode) in &self.0 {
+                let _ = console.set_mode(*mode);
+            }
+        }
+    }
+}
+
+/// A recording shared by every session of a script, which only those shown add to
+pub type SharedRecorder = Arc<Mutex<Option<Recorder>>>;
+
+/// What becomes of a session's output besides the screen model
+#[derive(Clone)]
+pub struct Output {
+    // The session's name, for its pane's title
+    pub name: String,
+    pub redactor: Redactor,
+    // Whether it's written to stdout at all
+    pub shown: bool,
+    // Whether it starts out hidden, as sessions other than the first do
+    pub hidden: bool,
+    pub recorder: SharedRecorder,
+    // Where the script's sessions are drawn once there's more than one
+    pub panes: Arc<Panes>,
+    // How the screen model lays out ambiguous-width characters
+    pub ambiguous_width: AmbiguousWidth,
+    // Lines of scrollback the screen model keeps
+    pub scrollback: usize,
+    // Whether OSC 52 clipboard writes are passed on, rather than dropped
+    pub forward_clipboard: bool,
+}
 
 // RAII guard for terminal raw mode - only enables if stdout is a TTY
 struct RawModeGuard {
     enabled: bool,
+    #[cfg(windows)]
+    vt_mode: Option<vt_mode::Saved>,
 }
 
 impl RawModeGuard {
-    fn new() -> Result<Self> {
-        let enabled = if std::io::stdout().is_terminal() {
+    fn new(shown: bool) -> Result<Self> {
+        let enabled = if shown && std::io::stdout().is_terminal() {
             enable_raw_mode().context("Failed to enable raw mode")?;
             true
         } else {
             false
         };
-        Ok(RawModeGuard { enabled })
+        Ok(RawModeGuard {
+            enabled,
+            #[cfg(windows)]
+            vt_mode: enabled.then(vt_mode::enable),
+        })
     }
 }
 
 impl Drop for RawModeGuard {
     fn drop(&mut self) {
+        #[cfg(windows)]
+        drop(self.vt_mode.take());
         if self.enabled {
             let _ = disable_raw_mode();
         }
     }
 }
 
+// Rows of the real terminal kept for status lines, below the session's rows
+struct StatusArea {
+    first_row: u16,
+    lines: Vec<String>,
+}
+
+impl StatusArea {
+    fn rows(&self) -> impl Iterator<Item = u16> {
+        self.first_row..self.first_row + self.lines.len() as u16
+    }
+
+    fn write(&self, out: &mut impl Write) -> std::io::Result<()> {
+        // The scroll region is set each time in case the program has reset it
+        write!(out, "\x1b7\x1b[1;{}r", self.first_row - 1)?;
+        for (row, line) in self.rows().zip(&self.lines) {
+            write!(out, "\x1b[{};1H\x1b[2K{}", row, line)?;
+        }
+        write!(out, "\x1b8")
+    }
+}
+
+// Writes queued for the writer thread before more have to wait
+const WRITE_QUEUE: usize = 64;
+
+// How long a full queue is waited on before the session is taken to have
+// stopped reading its input, as a stopped job does
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long the shell is given to exit once its input is closed, before its
+// process group is killed, as a program left running in it won't see EOF
+const EXIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// How long the last of the output is waited for once the shell has exited
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How long output has to stop for before a transcript starts a new group
+const SECTION_SETTLE_TIME: Duration = Duration::from_millis(100);
+
+/// Keys on their way to the PTY, written by a thread of their own so a
+/// session that stops reading can't hang playback
+struct KeyWriter {
+    queue: Option<Sender<Vec<u8>>>,
+    // Why the writer thread stopped, if a write failed
+    error: Arc<Mutex<Option<String>>>,
+    thread: Option<thread::JoinHandle<()>>,
+    timeout: Duration,
+}
+
+impl KeyWriter {
+    fn new(writer: PtyWriter, timeout: Duration) -> Self {
+        let (queue, mut writes) = mpsc::channel::<Vec<u8>>(WRITE_QUEUE);
+        let error = Arc::new(Mutex::new(None));
+        let thread_error = error.clone();
+        let thread = thread::spawn(move || {
+            while let Some(bytes) = writes.blocking_recv() {
+                let mut writer = writer.lock().unwrap();
+                let result = match writer.as_mut() {
+                    Some(writer) => writer.write_all(&bytes).and_then(|_| writer.flush()),
+                    None => Err(std::io::Error::other("PTY writer has been closed")),
+                };
+                if let Err(e) = result {
+                    *thread_error.lock().unwrap() = Some(e.to_string());
+                    break;
+                }
+            }
+        });
+        Self {
+            queue: Some(queue),
+            error,
+            thread: Some(thread),
+            timeout,
+        }
+    }
+
+    async fn send(&self, bytes: &[u8]) -> Result<()> {
+        let queue = self.queue.as_ref().context("PTY writer has been closed")?;
+        match queue.send_timeout(bytes.to_vec(), self.timeout).await {
+            Ok(()) => Ok(()),
+            Err(SendTimeoutError::Timeout(_)) => bail!(
+                "The session hasn't read its input for {}s; is the program in it stopped?",
+                self.timeout.as_secs_f64()
+            ),
+            Err(SendTimeoutError::Closed(_)) => Err(self.failed()),
+        }
+    }
+
+    /// Queue `bytes` without waiting for room, for keys sent outside playback
+    fn try_send(&self, bytes: &[u8]) -> Result<()> {
+        let queue = self.queue.as_ref().context("PTY writer has been closed")?;
+        match queue.try_send(bytes.to_vec()) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => bail!("The session isn't reading its input"),
+            Err(TrySendError::Closed(_)) => Err(self.failed()),
+        }
+    }
+
+    /// Where replies to terminal queries are queued behind the keys, as
+    /// nothing else may write to the PTY while a write there is stuck
+    fn replies(&self) -> Option<ReplyQueue> {
+        self.queue.as_ref().map(Sender::downgrade)
+    }
+
+    fn failed(&self) -> anyhow::Error {
+        let error = self.error.lock().unwrap().clone().unwrap_or_default();
+        anyhow::anyhow!("Failed to write to PTY: {}", error)
+    }
+
+    /// Stop taking keys, returning whether those queued were all written in time
+    fn finish(&mut self) -> bool {
+        drop(self.queue.take());
+        let Some(thread) = self.thread.take() else {
+            return true;
+        };
+        let deadline = Instant::now() + self.timeout;
+        while !thread.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        // A thread stuck in a write is left behind rather than waited for
+        let finished = thread.is_finished();
+        if finished {
+            let _ = thread.join();
+        }
+        finished
+    }
+}
+
 pub struct PtyManager {
-    writer: Option<Box<dyn Write + Send>>,
+    master: SharedMaster,
+    size: PtySize,
+    // Closed by taking it once the keys have all been written
+    writer: PtyWriter,
+    keys: KeyWriter,
+    screen: Arc<Screen>,
+    // Whether output is written to stdout as is, to a terminal
+    mirrored: bool,
+    // Output as plain lines, when it's shown but not to a terminal
+    transcript: Option<Arc<Mutex<Transcript>>>,
+    // Whether output is shown or hidden for now, held while each chunk is handled
+    visible: Arc<Mutex<bool>>,
+    status: Option<StatusArea>,
+    redactor: Redactor,
+    // Output while visible, shared with the reader thread
+    recorder: SharedRecorder,
+    panes: Arc<Panes>,
+    // Which of them is this session's
+    pane: usize,
+    child: Box<dyn Child + Send + Sync>,
     _reader_thread: Option<thread::JoinHandle<()>>,
+    // Startup files the shell may still be reading, removed with the session
+    startup_files: Option<StartupFiles>,
     _raw_mode_guard: RawModeGuard,
 }
 
 impl PtyManager {
-    pub fn new(shell: &str, cols: u16, rows: u16) -> Result<Self> {
+    pub fn new(
+        shell: &str,
+        args: &[String],
+        size: PtySize,
+        env: &[(&str, &str)],
+        output: Output,
+    ) -> Result<Self> {
+        let Output {
+            name,
+            redactor,
+            shown,
+            hidden,
+            recorder,
+            panes,
+            ambiguous_width,
+            scrollback,
+            forward_clipboard,
+        } = output;
         // Enable raw mode before PTY creation for proper escape sequence handling
-        let raw_mode_guard = RawModeGuard::new()?;
+        let raw_mode_guard = RawModeGuard::new(shown)?;
 
         let pty_system = native_pty_system();
 
-        let pair = pty_system
-            .openpty(PtySize {
-                rows,
-                cols,
-                pixel_width: 0,
-                pixel_height: 0,
-            })
-            .context("Failed to create PTY")?;
+        let pair = pty_system.openpty(size).context("Failed to create PTY")?;
 
         let mut cmd = CommandBuilder::new(shell);
-        cmd.env("TERM", "xterm-256color");
+        cmd.args(args);
+        for (name, value) in env {
+            cmd.env(name, value);
+        }
 
-        let _child = pair
+        let child = pair
             .slave
             .spawn_command(cmd)
             .context("Failed to spawn shell in PTY")?;
+        if let Some(pid) = child.process_id() {
+            supervisor::register(pid);
+        }
+        tracing::debug!(
+            "Started {} {:?} in a {}x{} PTY",
+            shell,
+            args,
+            size.cols,
+            size.rows
+        );
 
         let reader = pair
             .master
@@ -88,72 +391,495 @@ impl PtyManager {
             .master
             .take_writer()
             .context("Failed to get PTY writer")?;
+        let writer: PtyWriter = Arc::new(Mutex::new(Some(writer)));
+        let master: SharedMaster = Arc::new(Mutex::new(pair.master));
+
+        let keys = KeyWriter::new(writer.clone(), WRITE_TIMEOUT);
+        let reader_replies = keys.replies();
+
+        // The real terminal answers queries in mirrored output, on our stdin
+        let mirrored = shown && std::io::stdout().is_terminal();
+        if mirrored && !hidden {
+            input::route_replies(keys.replies());
+        }
+
+        let screen = Arc::new(Screen::new(
+            size.cols,
+            size.rows,
+            ambiguous_width,
+            scrollback,
+        ));
+        let reader_screen = screen.clone();
+        let visible = Arc::new(Mutex::new(!hidden));
+        let reader_visible = visible.clone();
+        let reader_redactor = redactor.clone();
+        let reader_recorder = recorder.clone();
+        let transcript = (shown && !mirrored).then(|| Arc::new(Mutex::new(Transcript::new())));
+        let reader_transcript = transcript.clone();
+        let pane = panes.add(&name, screen.clone(), master.clone(), !hidden)?;
+        let reader_panes = panes.clone();
 
         let reader_thread = thread::spawn(move || {
             let mut reader = reader;
             let mut stdout = std::io::stdout();
             let mut buffer = [0u8; 8192];
+            let mut masking = MaskState::default();
+            // The screen model still sees copies, for `@ expect-clipboard`
+            let mut copies = (!forward_clipboard).then(Osc52Filter::default);
 
-            loop {
-                match reader.read(&mut buffer) {
-                    Ok(0) => break,
-                    Ok(n) => {
-                        if stdout.write_all(&buffer[..n]).is_err() {
-                            break;
-                        }
-                        if stdout.flush().is_err() {
-                            break;
-                        }
+            let mut ended = false;
+            while !ended {
+                // The screen model sees the masked output too, so redraws stay masked
+                let output = match reader.read(&mut buffer) {
+                    Ok(n) if n > 0 => reader_redactor.mask(&mut masking, &buffer[..n]),
+                    // Text held back as the start of a match is let go at the end
+                    _ => {
+                        ended = true;
+                        reader_redactor.finish(&mut masking)
                     }
-                    Err(_) => break,
+                };
+                if output.is_empty() {
+                    continue;
+                }
+                let visible = reader_visible.lock().unwrap();
+                let replies = reader_screen.process(&output);
+
+                // Queries are answered here when no real terminal will see them
+                let split = reader_panes.is_split();
+                let headless = !*visible || !mirrored || split;
+                if headless
+                    && !replies.is_empty()
+                    && let Some(queue) = &reader_replies
+                {
+                    input::queue_reply(queue, replies);
+                }
+
+                if split {
+                    if reader_panes.draw(pane).is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                if !*visible {
+                    continue;
+                }
+                let output = match &mut copies {
+                    Some(filter) => filter.strip(&output),
+                    None => output,
+                };
+                if let Some(recorder) = reader_recorder.lock().unwrap().as_mut() {
+                    recorder.output(&output);
+                }
+                if !shown {
+                    continue;
+                }
+                let written = match &reader_transcript {
+                    Some(transcript) => transcript.lock().unwrap().write(&output, &mut stdout),
+                    None => stdout.write_all(&output),
+                };
+                if written.is_err() {
+                    break;
+                }
+                if stdout.flush().is_err() {
+                    break;
                 }
             }
         });
 
         Ok(Self {
-            writer: Some(writer),
+            master,
+            size,
+            keys,
+            writer,
+            screen,
+            mirrored,
+            transcript,
+            visible,
+            status: None,
+            redactor,
+            recorder,
+            panes,
+            pane,
+            child,
             _reader_thread: Some(reader_thread),
+            startup_files: None,
             _raw_mode_guard: raw_mode_guard,
         })
     }
 
-    pub fn send_keystroke(&mut self, data: &str) -> Result<()> {
-        let writer = self.writer.as_mut().context("PTY writer has been closed")?;
-        writer
-            .write_all(data.as_bytes())
-            .context("Failed to write to PTY")?;
-        writer.flush().context("Failed to flush PTY")?;
+    // Record and show bytes that are written as if the session had, followed by
+    // the status rows. Those are only redraws, which a transcript does without
+    fn write_output(&self, bytes: &[u8]) -> Result<()> {
+        if self.panes.is_split() {
+            return self.panes.draw(self.pane);
+        }
+        if let Some(recorder) = self.recorder.lock().unwrap().as_mut() {
+            recorder.output(bytes);
+        }
+        if self.mirrored {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(bytes)?;
+            if let Some(status) = &self.status {
+                status.write(&mut stdout)?;
+            }
+            stdout.flush()?;
+        }
+        Ok(())
+    }
+
+    /// The shell's exit status, if it has exited
+    pub fn exit_code(&mut self) -> Option<u32> {
+        let status = self.child.try_wait().ok()??;
+        Some(status.exit_code())
+    }
+
+    /// Close the shell's input and give it a moment to exit, returning its
+    /// exit status if it did
+    ///
+    /// The shell only sees the end of its input once the keys queued for it
+    /// have gone out; one that won't take them is stopped instead
+    pub fn hang_up(&mut self) -> Option<u32> {
+        if !self.keys.finish() {
+            let _ = self.child.kill();
+        }
+        if let Ok(mut writer) = self.writer.try_lock() {
+            drop(writer.take());
+        }
+        let deadline = Instant::now() + EXIT_TIMEOUT;
+        while matches!(self.child.try_wait(), Ok(None)) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.exit_code()
+    }
+
+    /// Keep the shell's startup files until the session ends
+    pub fn keep_startup_files(&mut self, files: StartupFiles) {
+        self.startup_files = Some(files);
+    }
+
+    /// Keep `count` rows from `first_row` of the real terminal for status lines,
+    /// scrolling only the rows above them
+    pub fn reserve_status_rows(&mut self, first_row: u16, count: u16) -> Result<()> {
+        if !self.mirrored {
+            return Ok(());
+        }
+        // Scroll so the cursor isn't left in the reserved rows, which a hidden
+        // session's redraw takes care of when it's shown
+        if *self.visible.lock().unwrap() {
+            let mut stdout = std::io::stdout();
+            stdout.write_all(&b"\n".repeat(count as usize))?;
+            write!(stdout, "\x1b[{}A", count)?;
+        }
+
+        self.status = Some(StatusArea {
+            first_row,
+            lines: vec![String::new(); count as usize],
+        });
+        self.draw_status(0, "")
+    }
+
+    // Send `sequence` to the terminal as if the session had, then put back the
+    // cursor's line so the shell's prompt isn't lost
+    fn redraw_after(&self, sequence: &[u8]) -> Result<()> {
+        // Held so no output from the session lands in between
+        let visible = self.visible.lock().unwrap();
+        let mut bytes = sequence.to_vec();
+        bytes.extend(self.screen.cursor_line());
+        self.screen.process(&bytes);
+
+        if *visible {
+            self.write_output(&bytes)?;
+        }
+        Ok(())
+    }
+}
+
+impl KeystrokeSink for PtyManager {
+    async fn send(&mut self, data: &[u8]) -> Result<()> {
+        self.keys.send(data).await?;
+        // Keys sent out of sight go unrecorded, as their output does
+        if *self.visible.lock().unwrap()
+            && let Some(recorder) = self.recorder.lock().unwrap().as_mut()
+        {
+            recorder.input(data);
+        }
+        Ok(())
+    }
+
+    /// Resize the session to `cols` by `rows`, less any status rows, asking the
+    /// real terminal to resize its window to match
+    fn resize(&mut self, cols: u16, rows: u16) -> Result<()> {
+        let status_rows = self.status.as_ref().map_or(0, |s| s.lines.len() as u16);
+        anyhow::ensure!(
+            rows > status_rows,
+            "Terminal is too small for captions and --show-keys"
+        );
+        // Cells keep their size in pixels
+        let scale = |pixels: u16, new: u16, old: u16| {
+            (u32::from(pixels) * u32::from(new) / u32::from(old)) as u16
+        };
+        let size = PtySize {
+            cols,
+            rows: rows - status_rows,
+            pixel_width: scale(self.size.pixel_width, cols, self.size.cols),
+            pixel_height: scale(self.size.pixel_height, rows - status_rows, self.size.rows),
+        };
+
+        // Held so no output from the session lands in between
+        let visible = self.visible.lock().unwrap();
+        if self.mirrored {
+            // XTWINOPS, which terminals that allow it answer by resizing, so a
+            // recording of the real terminal gets a resize event too
+            let mut stdout = std::io::stdout();
+            write!(stdout, "\x1b[8;{};{}t", rows, cols)?;
+            stdout.flush()?;
+        }
+        // Split panes share the terminal out between them
+        if !self.panes.resize(size)? {
+            self.master
+                .lock()
+                .unwrap()
+                .resize(size)
+                .context("Failed to resize PTY")?;
+            self.screen.set_size(size.cols, size.rows);
+            if let Some(recorder) = self.recorder.lock().unwrap().as_mut() {
+                recorder.resize(size.cols, size.rows);
+            }
+        }
+        self.size = size;
+
+        if let Some(status) = &mut self.status {
+            status.first_row = size.rows + 1;
+            if *visible {
+                let mut stdout = std::io::stdout();
+                status.write(&mut stdout)?;
+                stdout.flush()?;
+            }
+        }
         Ok(())
     }
 
-    pub fn send_char(&mut self, c: char) -> Result<()> {
-        let mut buf = [0u8; 4];
-        let s = c.encode_utf8(&mut buf);
-        self.send_keystroke(s)
+    fn signal(&mut self, signal: Signal) -> Result<()> {
+        // The terminal driver turns control characters into signals for the
+        // foreground program, as it would for keys typed at a real terminal
+        match signal.control_char() {
+            Some(byte) => self.keys.try_send(&[byte]),
+            None => self.child.kill().context("Failed to stop the shell"),
+        }
+    }
+
+    /// Terminal state as seen through the PTY output so far
+    fn screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    /// Bytes waiting in the PTY's input queue. In canonical mode only whole
+    /// lines are counted, as a line still being edited is never unread
+    #[cfg(unix)]
+    fn unread_input(&self) -> Option<usize> {
+        use std::os::fd::AsRawFd;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        // Opened for each look, since a slave held open would keep the
+        // reader thread from seeing the shell exit
+        let slave = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open(self.master.lock().unwrap().tty_name()?)
+            .ok()?;
+        let mut count: libc::c_int = 0;
+        let result = unsafe { libc::ioctl(slave.as_raw_fd(), libc::FIONREAD, &mut count) };
+        (result == 0).then_some(count as usize)
+    }
+
+    /// Hide or show the session's output; showing it again redraws the screen
+    /// and has the real terminal's replies sent to it
+    fn set_visible(&self, visible: bool) -> Result<()> {
+        let mut current = self.visible.lock().unwrap();
+        if self.panes.is_split() {
+            *current = visible;
+            self.panes.show(self.pane, visible)?;
+            if visible
+                && self.mirrored
+                && let Some(status) = &self.status
+            {
+                let mut stdout = std::io::stdout().lock();
+                status.write(&mut stdout)?;
+                stdout.flush()?;
+            }
+            return Ok(());
+        }
+        if visible && !*current {
+            if self.mirrored {
+                input::route_replies(self.keys.replies());
+            }
+            let mut redraw = b"\x1b[H\x1b[2J".to_vec();
+            redraw.extend(self.screen.redraw());
+            self.write_output(&redraw)?;
+        }
+        *current = visible;
+        Ok(())
+    }
+
+    /// Replace status line `line`, if status rows are reserved
+    fn draw_status(&mut self, line: usize, text: &str) -> Result<()> {
+        let Some(status) = &mut self.status else {
+            return Ok(());
+        };
+        if let Some(current) = status.lines.get_mut(line) {
+            *current = self.redactor.mask_str(text);
+        }
+
+        // Hidden status lines are drawn when the output is shown again
+        let visible = self.visible.lock().unwrap();
+        if *visible {
+            let mut stdout = std::io::stdout();
+            status.write(&mut stdout)?;
+            stdout.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Clear the screen and home the cursor, keeping the line the cursor is on
+    fn clear(&self, scrollback: bool) -> Result<()> {
+        let sequence: &[u8] = if scrollback {
+            b"\x1b[H\x1b[2J\x1b[3J"
+        } else {
+            b"\x1b[H\x1b[2J"
+        };
+        self.redraw_after(sequence)
+    }
+
+    /// Reset the terminal with RIS, keeping the line the cursor is on
+    fn reset(&self) -> Result<()> {
+        self.redraw_after(b"\x1bc")
+    }
+
+    /// Start a foldable group in the transcript, if output is one
+    async fn section(&mut self, name: &str) -> Result<()> {
+        if self.transcript.is_none() {
+            return Ok(());
+        }
+        // Output from the section before, still on its way, belongs in its group
+        let start = Instant::now();
+        while self.screen.idle_time() < SECTION_SETTLE_TIME && start.elapsed() < WRITE_TIMEOUT {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let visible = self.visible.lock().unwrap();
+        if *visible && let Some(transcript) = &self.transcript {
+            transcript
+                .lock()
+                .unwrap()
+                .section(name, &mut std::io::stdout())?;
+        }
+        Ok(())
+    }
+
+    fn is_split(&self) -> bool {
+        self.panes.is_split()
+    }
+
+    /// `text` with anything matching the redact patterns masked
+    fn redact(&self, text: &str) -> String {
+        self.redactor.mask_str(text)
+    }
+
+    /// The recording of everything shown so far, if the session is being recorded
+    fn recording(&self) -> Option<Cast> {
+        self.recorder
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(Recorder::snapshot)
     }
 }
 
 impl Drop for PtyManager {
     fn drop(&mut self) {
-        // Close writer to signal EOF
-        drop(self.writer.take());
+        // Once the shell has gone, anything it left running in the background
+        // would keep the PTY open, so the rest of its process group goes too.
+        // A shell still busy with a program that won't exit goes with them
+        self.hang_up();
+        input::route_replies(None);
+        self.panes.finish();
+        if let Some(pid) = self.child.process_id() {
+            supervisor::release(pid);
+        }
+        if matches!(self.child.try_wait(), Ok(None)) {
+            let _ = self.child.kill();
+        }
+        let _ = self.child.wait();
 
-        // Wait for reader thread to ensure all output is flushed before raw mode is disabled
+        // Wait for reader thread to ensure all output is flushed before raw mode is
+        // disabled. Jobs the shell put in groups of their own can still hold the
+        // PTY open, and a reader left waiting on them is left behind
         if let Some(handle) = self._reader_thread.take() {
-            let _ = handle.join();
+            let deadline = Instant::now() + READ_TIMEOUT;
+            while !handle.is_finished() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+            if handle.is_finished() {
+                let _ = handle.join();
+            }
+        }
+        if let Some(transcript) = &self.transcript {
+            let _ = transcript.lock().unwrap().finish(&mut std::io::stdout());
         }
 
-        // Allow time for parent terminal to respond to any terminal queries
-        thread::sleep(Duration::from_millis(100));
-
-        // Drain stdin to prevent terminal query responses from appearing as garbage after exit
-        if std::io::stdin().is_terminal() {
-            use crossterm::event::{poll, read};
-            while poll(Duration::from_millis(0)).unwrap_or(false) {
-                let _ = read();
+        // Give the status rows back to the terminal
+        if let Some(status) = &self.status {
+            let mut stdout = std::io::stdout();
+            let _ = write!(stdout, "\x1b7\x1b[r");
+            for row in status.rows() {
+                let _ = write!(stdout, "\x1b[{};1H\x1b[2K", row);
             }
+            let _ = write!(stdout, "\x1b8");
+            let _ = stdout.flush();
         }
 
+        // Allow time for the real terminal to answer any last queries, which the
+        // input thread then drops rather than leaving them for the shell after exit
+        thread::sleep(Duration::from_millis(100));
+
         // _raw_mode_guard drops here, restoring terminal state
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{Receiver, channel};
+
+    // Blocks on every write until dropped, like a session that isn't reading
+    struct Stuck(Receiver<()>);
+
+    impl Write for Stuck {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let _ = self.0.recv();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_key_writer_times_out() {
+        let (unblock, blocked) = channel();
+        let writer: PtyWriter = Arc::new(Mutex::new(Some(Box::new(Stuck(blocked)))));
+        let mut keys = KeyWriter::new(writer, Duration::from_millis(100));
+
+        let mut result = Ok(());
+        for _ in 0..=WRITE_QUEUE + 1 {
+            result = keys.send(b"x").await;
+            if result.is_err() {
+                break;
+            }
+        }
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("hasn't read its input"), "{}", error);
+        assert!(!keys.finish());
+        drop(unblock);
+    }
+}
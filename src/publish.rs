@@ -0,0 +1,170 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Uploading recordings to an asciinema server
+//!
+//! Uploads work as `asciinema upload` does: the recording is POSTed to the
+//! server with the asciinema install ID as the password. The ID is shared
+//! with the asciinema CLI if it's installed, so recordings land in the same
+//! account, and one is made the first time otherwise. The server needs
+//! HTTPS, so the upload itself is left to curl
+
+use anyhow::{Context, Result, bail};
+use rand::Rng;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::process::Command;
+
+pub const DEFAULT_SERVER: &str = "https://asciinema.org";
+
+/// The server given, or the one the asciinema CLI would use
+pub fn server_url(server: Option<&str>) -> String {
+    server
+        .map(str::to_string)
+        .or_else(|| std::env::var("ASCIINEMA_API_URL").ok())
+        .unwrap_or_else(|| DEFAULT_SERVER.to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+// Where the asciinema CLI keeps its install ID
+fn install_id_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("ASCIINEMA_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| crate::config::config_home().map(|home| home.join("asciinema")))?;
+    Some(dir.join("install-id"))
+}
+
+// A random UUID, as the asciinema CLI makes
+fn new_install_id(rng: &mut impl Rng) -> String {
+    let mut bytes: [u8; 16] = rng.random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+// The saved install ID, and whether it was only just made
+fn install_id() -> Result<(String, bool)> {
+    let path = install_id_path().context("Can't find a config directory for the install ID")?;
+    if let Ok(id) = std::fs::read_to_string(&path)
+        && !id.trim().is_empty()
+    {
+        return Ok((id.trim().to_string(), false));
+    }
+
+    let id = new_install_id(&mut rand::rng());
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    std::fs::write(&path, format!("{}\n", id))
+        .with_context(|| format!("Failed to save install ID: {}", path.display()))?;
+    Ok((id, true))
+}
+
+// The recording's URL and any message from the server. Newer servers answer
+// with JSON, older ones with the URL alone
+fn parse_response(body: &str) -> Option<(String, Option<String>)> {
+    match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(json) => {
+            let url = json.get("url")?.as_str()?.to_string();
+            let message = json.get("message").and_then(|m| m.as_str());
+            Some((url, message.map(str::to_string)))
+        }
+        Err(_) => {
+            let url = body.trim();
+            url.starts_with("http").then(|| (url.to_string(), None))
+        }
+    }
+}
+
+/// Upload the recording at `path` to `server`, returning its URL
+pub async fn publish(path: &Path, server: &str) -> Result<String> {
+    let (id, new) = install_id()?;
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "quipu".to_string());
+
+    let output = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail-with-body"])
+        .args(["--user", &format!("{}:{}", user, id)])
+        .args(["--header", "Accept: application/json"])
+        .args(["--user-agent", concat!("quipu/", env!("CARGO_PKG_VERSION"))])
+        .arg("--form")
+        .arg(format!("asciicast=@{};filename=ascii.cast", path.display()))
+        .arg(format!("{}/api/asciicasts", server))
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .context("Failed to run curl, which is needed to upload recordings")?;
+
+    let body = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "Upload to {} failed: {} {}",
+            server,
+            stderr.trim(),
+            body.trim()
+        );
+    }
+    let (url, message) = parse_response(&body)
+        .with_context(|| format!("Unexpected response from {}: {}", server, body.trim()))?;
+
+    if let Some(message) = message {
+        eprintln!("{}", message.trim());
+    } else if new {
+        eprintln!(
+            "To keep this recording in your account, open {}/connect/{}",
+            server, id
+        );
+    }
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_install_id_and_response() {
+        let id = new_install_id(&mut rand::rngs::StdRng::seed_from_u64(1));
+        assert_eq!(id.len(), 36);
+        assert_eq!(id.as_bytes()[14], b'4');
+        assert!(matches!(id.as_bytes()[19], b'8' | b'9' | b'a' | b'b'));
+
+        assert_eq!(
+            parse_response(r#"{"url": "https://asciinema.org/a/1", "message": "Saved"}"#),
+            Some((
+                "https://asciinema.org/a/1".to_string(),
+                Some("Saved".to_string())
+            ))
+        );
+        assert_eq!(
+            parse_response("https://example.com/a/2\n"),
+            Some(("https://example.com/a/2".to_string(), None))
+        );
+        assert_eq!(parse_response("<html>"), None);
+    }
+}
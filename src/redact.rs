@@ -0,0 +1,259 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Redaction for `--redact`
+//!
+//! Text matching any of the patterns is masked with `*` wherever quipu shows
+//! or writes the session's output, one `*` per character so the layout of the
+//! screen is kept. Escape sequences are passed through untouched. Output
+//! is shown as it's read, except for text at the end of a read that a match
+//! could still be starting in, which is held back until the next read or the
+//! end of the output, so a secret split across reads is masked whole
+
+use regex::bytes::Regex;
+use regex_automata::hybrid::dfa::{Cache, DFA};
+use regex_automata::{Anchored, Input};
+
+use crate::screen::Scan;
+
+// Text from earlier reads that a match can start in, and the most that's
+// held back
+const MAX_CARRY: usize = 256;
+
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    // Each pattern, with an automaton to tell whether a match could start
+    // in text that's been cut short
+    patterns: Vec<(Regex, DFA)>,
+}
+
+/// Where masking is up to in a stream of output, kept from one read to the next
+#[derive(Default)]
+pub struct MaskState {
+    // Any escape sequence left unfinished
+    scan: Scan,
+    // The end of the run of text the last read finished in, already shown
+    tail: Vec<u8>,
+    // Text after it that a match could start in, not shown yet
+    held: Vec<u8>,
+    // One for each pattern's automaton
+    caches: Vec<Cache>,
+}
+
+fn compile(pattern: &str) -> Result<(Regex, DFA), String> {
+    let error = |e: &dyn std::fmt::Display| format!("Invalid redact pattern '{}': {}", pattern, e);
+    let regex = Regex::new(pattern).map_err(|e| error(&e))?;
+    let dfa = DFA::builder()
+        .configure(DFA::config().unicode_word_boundary(true))
+        .syntax(regex_automata::util::syntax::Config::new().utf8(false))
+        .build(pattern)
+        .map_err(|e| error(&e))?;
+    Ok((regex, dfa))
+}
+
+/// Check that `pattern` is a regex that can be used for redaction
+pub fn check_pattern(pattern: &str) -> Result<(), String> {
+    compile(pattern).map(|_| ())
+}
+
+// Whether a match of `dfa` could start at `start` in `run` and go on past
+// its end. Automata that give up are taken to mean it could
+fn could_match(dfa: &DFA, cache: &mut Cache, run: &[u8], start: usize) -> bool {
+    let input = Input::new(run).range(start..).anchored(Anchored::Yes);
+    let Ok(mut state) = dfa.start_state_forward(cache, &input) else {
+        return true;
+    };
+    for &byte in &run[start..] {
+        match dfa.next_state(cache, state, byte) {
+            Ok(next) if next.is_dead() => return false,
+            Ok(next) if !next.is_quit() => state = next,
+            _ => return true,
+        }
+    }
+    true
+}
+
+impl Redactor {
+    pub fn new(patterns: &[String]) -> Result<Self, String> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| compile(pattern))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { patterns })
+    }
+
+    /// Mask output read from a program, with `state` carried from one
+    /// read to the next
+    pub fn mask(&self, state: &mut MaskState, bytes: &[u8]) -> Vec<u8> {
+        if self.patterns.is_empty() {
+            return bytes.to_vec();
+        }
+        let mut out = Vec::with_capacity(bytes.len());
+        for &byte in bytes {
+            if state.scan.is_ground() && byte != 0x1b {
+                state.held.push(byte);
+            } else {
+                // Matches don't run on past an escape sequence
+                out.extend(self.release(state, true));
+                out.push(byte);
+            }
+            state.scan.next(byte);
+        }
+        out.extend(self.release(state, false));
+        out
+    }
+
+    /// Mask what's held back at the end of the output
+    pub fn finish(&self, state: &mut MaskState) -> Vec<u8> {
+        self.release(state, true)
+    }
+
+    /// Mask a line quipu draws itself, such as a caption
+    pub fn mask_str(&self, text: &str) -> String {
+        let mut state = MaskState::default();
+        let mut masked = self.mask(&mut state, text.as_bytes());
+        masked.extend(self.finish(&mut state));
+        String::from_utf8_lossy(&masked).into_owned()
+    }
+
+    // Mask and let go of the text held back in `state`, all of it when its
+    // run has `ended`, or else up to where a match could still be starting
+    fn release(&self, state: &mut MaskState, ended: bool) -> Vec<u8> {
+        if ended && state.held.is_empty() {
+            state.tail.clear();
+            return Vec::new();
+        }
+        let run = [&state.tail[..], &state.held[..]].concat();
+        let shown = state.tail.len();
+        let end = if ended {
+            run.len()
+        } else {
+            self.match_start(&mut state.caches, &run, shown)
+        };
+        let out = self.mask_text(&run, shown, end);
+
+        state.held.drain(..end - shown);
+        state.tail.clear();
+        if !ended {
+            state
+                .tail
+                .extend_from_slice(&run[end.saturating_sub(MAX_CARRY)..end]);
+        }
+        out
+    }
+
+    // The first place after `shown` in `run` that a match could be starting
+    // in, or its end
+    fn match_start(&self, caches: &mut Vec<Cache>, run: &[u8], shown: usize) -> usize {
+        if caches.len() != self.patterns.len() {
+            *caches = self
+                .patterns
+                .iter()
+                .map(|(_, dfa)| dfa.create_cache())
+                .collect();
+        }
+        let first = shown.max(run.len().saturating_sub(MAX_CARRY));
+        (first..run.len())
+            .find(|&start| {
+                self.patterns
+                    .iter()
+                    .zip(caches.iter_mut())
+                    .any(|((_, dfa), cache)| could_match(dfa, cache, run, start))
+            })
+            .unwrap_or(run.len())
+    }
+
+    // Mask the matches in a run of text with no escape sequences in it,
+    // giving the masked bytes from `shown`, where what's already been shown
+    // ends, up to `end`
+    fn mask_text(&self, run: &[u8], shown: usize, end: usize) -> Vec<u8> {
+        let mut masked = vec![false; run.len()];
+        for (pattern, _) in &self.patterns {
+            for found in pattern.find_iter(run) {
+                masked[found.range()].fill(true);
+            }
+        }
+
+        let mut out = Vec::with_capacity(end - shown);
+        for (i, (&byte, masked)) in run[shown..end].iter().zip(&masked[shown..end]).enumerate() {
+            match byte {
+                // Line breaks and other controls still move the cursor
+                _ if !masked || byte < 0x20 || byte == 0x7f => out.push(byte),
+                // The rest of a UTF-8 character already masked, unless it
+                // started in a read already shown
+                0x80..=0xbf if i > 0 => {}
+                0x80..=0xbf => out.push(byte),
+                _ => out.push(b'*'),
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask_keeps_escapes_and_layout() {
+        let redactor =
+            Redactor::new(&["/home/[a-z]+".to_string(), "sk-[0-9a-f]+".to_string()]).unwrap();
+        let mut state = MaskState::default();
+        assert_eq!(
+            redactor.mask(&mut state, b"\x1b[1m/home/tom\x1b[0m\r\nkey=sk-12ab\r\n"),
+            b"\x1b[1m*********\x1b[0m\r\nkey=*******\r\n"
+        );
+        // An escape sequence split across reads is still left alone
+        assert_eq!(redactor.mask(&mut state, b"\x1b]0;"), b"\x1b]0;");
+        assert_eq!(redactor.mask(&mut state, b"sk-1\x07sk-1"), b"sk-1\x07");
+        assert_eq!(redactor.finish(&mut state), b"****");
+        assert_eq!(
+            Redactor::new(&["é+".to_string()])
+                .unwrap()
+                .mask_str("café!"),
+            "caf*!"
+        );
+    }
+
+    #[test]
+    fn test_mask_across_reads() {
+        let redactor = Redactor::new(&["sk-[0-9a-f]+".to_string()]).unwrap();
+        let mut state = MaskState::default();
+        // A secret split across reads is held back until it's whole
+        assert_eq!(redactor.mask(&mut state, b"key=s"), b"key=");
+        assert_eq!(redactor.mask(&mut state, b"k-12"), b"");
+        assert_eq!(redactor.mask(&mut state, b"ab\r\n"), b"*******\r\n");
+        // Text that turns out not to be one is let go as it is
+        assert_eq!(redactor.mask(&mut state, b"$ s"), b"$ ");
+        assert_eq!(redactor.mask(&mut state, b"ky\r\n"), b"sky\r\n");
+        // Nor does a match run on past an escape sequence
+        assert_eq!(redactor.mask(&mut state, b"sk-1\x1b[m"), b"****\x1b[m");
+        assert_eq!(redactor.mask(&mut state, b"2"), b"2");
+        // What's held back when the output ends is masked then
+        assert_eq!(redactor.mask(&mut state, b" sk-9"), b" ");
+        assert_eq!(redactor.finish(&mut state), b"****");
+        assert_eq!(redactor.finish(&mut state), b"");
+    }
+
+    #[test]
+    fn test_mask_secret_split_across_chunks() {
+        let redactor = Redactor::new(&["ghp_[A-Za-z0-9]{8}".to_string()]).unwrap();
+        let mut state = MaskState::default();
+        let mut shown = redactor.mask(&mut state, b"export TOKEN=ghp_Ab3");
+        assert_eq!(shown, b"export TOKEN=");
+        shown.extend(redactor.mask(&mut state, b"dE5fG\r\n$ "));
+        assert_eq!(shown, b"export TOKEN=************\r\n$ ");
+    }
+}
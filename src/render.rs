@@ -0,0 +1,178 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Drawing the screen model for exports
+//!
+//! Turns each row of a screen into runs of text sharing a colour and style,
+//! for renderers such as the HTML player to lay out
+
+pub type Rgb = (u8, u8, u8);
+
+/// Colours for the default foreground and background and the 16 ANSI colours;
+/// the rest of the 256 are the standard xterm cube and greys
+#[derive(Debug, Clone, PartialEq)]
+pub struct Palette {
+    pub foreground: Rgb,
+    pub background: Rgb,
+    pub ansi: [Rgb; 16],
+}
+
+impl Default for Palette {
+    // White on black, as the screen model tells programs that ask
+    fn default() -> Self {
+        Self {
+            foreground: (255, 255, 255),
+            background: (0, 0, 0),
+            ansi: [
+                (0, 0, 0),
+                (205, 0, 0),
+                (0, 205, 0),
+                (205, 205, 0),
+                (0, 0, 238),
+                (205, 0, 205),
+                (0, 205, 205),
+                (229, 229, 229),
+                (127, 127, 127),
+                (255, 0, 0),
+                (0, 255, 0),
+                (255, 255, 0),
+                (92, 92, 255),
+                (255, 0, 255),
+                (0, 255, 255),
+                (255, 255, 255),
+            ],
+        }
+    }
+}
+
+impl Palette {
+    fn resolve(&self, color: vt100::Color, default: Rgb) -> Rgb {
+        match color {
+            vt100::Color::Default => default,
+            vt100::Color::Idx(index) => self.indexed(index),
+            vt100::Color::Rgb(r, g, b) => (r, g, b),
+        }
+    }
+
+    fn indexed(&self, index: u8) -> Rgb {
+        let level = |n: u8| if n == 0 { 0 } else { 55 + n * 40 };
+        match index {
+            0..=15 => self.ansi[index as usize],
+            16..=231 => {
+                let n = index - 16;
+                (level(n / 36), level(n / 6 % 6), level(n % 6))
+            }
+            _ => {
+                let grey = 8 + (index - 232) * 10;
+                (grey, grey, grey)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Style {
+    pub foreground: Rgb,
+    pub background: Rgb,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Run {
+    pub text: String,
+    pub style: Style,
+}
+
+fn blend(a: Rgb, b: Rgb) -> Rgb {
+    let mid = |x: u8, y: u8| ((u16::from(x) + u16::from(y)) / 2) as u8;
+    (mid(a.0, b.0), mid(a.1, b.1), mid(a.2, b.2))
+}
+
+/// How `cell` is drawn, inverted as a block if the cursor is on it
+pub fn cell_style(cell: &vt100::Cell, palette: &Palette, cursor: bool) -> Style {
+    let mut foreground = palette.resolve(cell.fgcolor(), palette.foreground);
+    let mut background = palette.resolve(cell.bgcolor(), palette.background);
+    if cell.dim() {
+        foreground = blend(foreground, background);
+    }
+    // The cursor is drawn as a block, inverting the cell under it
+    if cell.inverse() != cursor {
+        std::mem::swap(&mut foreground, &mut background);
+    }
+    Style {
+        foreground,
+        background,
+        bold: cell.bold(),
+        italic: cell.italic(),
+        underline: cell.underline(),
+    }
+}
+
+/// Row `row` of `screen` as runs of text, with the cursor drawn if it's on this row
+pub fn row_runs(screen: &vt100::Screen, row: u16, palette: &Palette) -> Vec<Run> {
+    let (_, cols) = screen.size();
+    let (cursor_row, cursor_col) = screen.cursor_position();
+    let cursor_col = (row == cursor_row && !screen.hide_cursor()).then_some(cursor_col);
+
+    let mut runs: Vec<Run> = Vec::new();
+    for col in 0..cols {
+        let Some(cell) = screen.cell(row, col) else {
+            continue;
+        };
+        // Wide characters take the next cell too
+        if cell.is_wide_continuation() {
+            continue;
+        }
+        let style = cell_style(cell, palette, cursor_col == Some(col));
+        let text = if cell.has_contents() {
+            cell.contents()
+        } else {
+            " "
+        };
+        match runs.last_mut() {
+            Some(run) if run.style == style => run.text.push_str(text),
+            _ => runs.push(Run {
+                text: text.to_string(),
+                style,
+            }),
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_row_runs() {
+        let mut parser = vt100::Parser::new(2, 6, 0);
+        parser.process(b"a\x1b[31mbc\x1b[0m\r\n\x1b[38;5;196mx");
+        let palette = Palette::default();
+
+        let runs = row_runs(parser.screen(), 0, &palette);
+        let texts: Vec<&str> = runs.iter().map(|run| run.text.as_str()).collect();
+        assert_eq!(texts, ["a", "bc", "   "]);
+        assert_eq!(runs[1].style.foreground, (205, 0, 0));
+
+        // The cursor inverts the cell after the `x`
+        let runs = row_runs(parser.screen(), 1, &palette);
+        assert_eq!(runs[0].style.foreground, (255, 0, 0));
+        assert_eq!(runs[1].text, " ");
+        assert_eq!(runs[1].style.background, palette.foreground);
+    }
+}
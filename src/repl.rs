@@ -0,0 +1,187 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! `quipu repl`, for writing scripts a line at a time
+//!
+//! Lines are typed on a status line below the session and each one is played
+//! as soon as Enter is pressed, so a script can be tried out as it's written.
+//! Lines that parse and play can be appended to a script file as they go
+
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use tokio::time::sleep;
+
+use crate::input;
+use crate::parser::{ParseOptions, ScriptParser};
+use crate::playback::{self, PlaybackEngine};
+use crate::pty::PtyManager;
+use crate::sink::KeystrokeSink;
+use crate::types::Command;
+
+const PROMPT: &str = "quipu> ";
+
+// How often to check for keypresses
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// What a keypress did to the line being typed
+#[derive(Debug, PartialEq)]
+pub enum Edit {
+    Changed,
+    Submitted(String),
+    Quit,
+}
+
+/// The line being typed at the prompt
+#[derive(Debug, Default)]
+pub struct LineEditor {
+    line: String,
+}
+
+impl LineEditor {
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    /// Apply a keystroke, as split by [`playback::split_keystrokes`]
+    pub fn key(&mut self, keystroke: &str) -> Edit {
+        match keystroke {
+            "\r" | "\n" => return Edit::Submitted(std::mem::take(&mut self.line)),
+            // Ctrl-D quits at an empty prompt, as it would a shell
+            "\x04" if self.line.is_empty() => return Edit::Quit,
+            "\x7f" | "\x08" => {
+                self.line.pop();
+            }
+            // Ctrl-C and Ctrl-U throw the line away
+            "\x03" | "\x15" => self.line.clear(),
+            // Ctrl-W deletes the last word
+            "\x17" => {
+                let kept = self.line.trim_end().rfind(' ').map_or(0, |space| space + 1);
+                self.line.truncate(kept);
+            }
+            // Tab is typed as a space so the status line stays one line
+            "\t" => self.line.push(' '),
+            // Other control characters and escape sequences aren't text
+            _ if keystroke.starts_with(|c: char| c.is_control()) => {}
+            _ => self.line.push_str(keystroke),
+        }
+        Edit::Changed
+    }
+}
+
+/// Read lines at the prompt on status line `prompt_line` and play each in the
+/// session until Ctrl-D, appending those that play to `append` if given
+pub async fn run(
+    engine: &mut PlaybackEngine<PtyManager>,
+    prompt_line: usize,
+    options: &ParseOptions,
+    append: Option<&Path>,
+) -> Result<()> {
+    let mut script = append
+        .map(|path| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open {}", path.display()))
+        })
+        .transpose()?;
+    let mut parser = ScriptParser::new(options).map_err(anyhow::Error::msg)?;
+    let mut editor = LineEditor::default();
+    // An error from the last line, shown until the next key
+    let mut message: Option<String> = None;
+    draw_prompt(engine, prompt_line, &editor, None)?;
+
+    while !playback::interrupted() {
+        let keys = input::take_keys();
+        if keys.is_empty() {
+            sleep(POLL_INTERVAL).await;
+            continue;
+        }
+        let text = String::from_utf8_lossy(&keys);
+        for keystroke in playback::split_keystrokes(&text) {
+            message = None;
+            match editor.key(keystroke) {
+                Edit::Changed => {}
+                Edit::Quit => return Ok(()),
+                Edit::Submitted(line) => {
+                    if let Err(e) = submit(engine, &mut parser, &line, script.as_mut()).await {
+                        message = Some(format!("{:#}", e));
+                    }
+                }
+            }
+        }
+        draw_prompt(engine, prompt_line, &editor, message.as_deref())?;
+    }
+    Ok(())
+}
+
+// Parse and play `line`, then keep it in the script if it worked
+async fn submit(
+    engine: &mut PlaybackEngine<PtyManager>,
+    parser: &mut ScriptParser,
+    line: &str,
+    script: Option<&mut File>,
+) -> Result<()> {
+    for command in parser.next_line(line).map_err(anyhow::Error::msg)? {
+        anyhow::ensure!(
+            !matches!(command, Command::Setup(_) | Command::Teardown(_)),
+            "@ setup and @ teardown only run around a script's session"
+        );
+        engine.play(command).await?;
+    }
+    if let Some(script) = script {
+        writeln!(script, "{}", line).context("Failed to append to the script")?;
+    }
+    Ok(())
+}
+
+fn draw_prompt(
+    engine: &mut PlaybackEngine<PtyManager>,
+    line: usize,
+    editor: &LineEditor,
+    message: Option<&str>,
+) -> Result<()> {
+    let text = match message {
+        Some(message) => format!("{}{}  \x1b[31m{}\x1b[0m", PROMPT, editor.line(), message),
+        None => format!("{}{}", PROMPT, editor.line()),
+    };
+    engine.sink().draw_status(line, &text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_editor() {
+        let mut editor = LineEditor::default();
+        for keystroke in playback::split_keystrokes("$ ls -la\x1b[D\x7f\x7f") {
+            assert_eq!(editor.key(keystroke), Edit::Changed);
+        }
+        assert_eq!(editor.line(), "$ ls -");
+        editor.key("\x17");
+        assert_eq!(editor.line(), "$ ls ");
+        assert_eq!(editor.key("\r"), Edit::Submitted("$ ls ".to_string()));
+        assert_eq!(editor.line(), "");
+
+        editor.key("x");
+        assert_eq!(editor.key("\x04"), Edit::Changed);
+        editor.key("\x03");
+        assert_eq!(editor.key("\x04"), Edit::Quit);
+    }
+}
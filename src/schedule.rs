@@ -0,0 +1,92 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Keystroke timing
+//!
+//! `tokio::time::sleep` only promises to sleep for at least as long as it's
+//! asked, so sleeping for each key in turn runs a little slow on every key
+//! and a long script falls behind its estimate. [`Schedule`] keeps a
+//! deadline instead, moved on by each delay, so time overslept on one key
+//! comes off the next. The time it takes to wake is measured by `quipu
+//! bench`, and can be taken off each sleep so keys land on their deadlines
+
+use std::time::Duration;
+use tokio::time::{Instant, sleep_until};
+
+pub struct Schedule {
+    deadline: Instant,
+    // How much earlier than each deadline to wake
+    offset: Duration,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self {
+            deadline: Instant::now(),
+            offset: Duration::ZERO,
+        }
+    }
+}
+
+impl Schedule {
+    /// Count from now, after something that isn't timed such as a host command
+    pub fn restart(&mut self) {
+        self.deadline = Instant::now();
+    }
+
+    /// Wake `offset` before each deadline, to make up for waking late
+    pub fn set_offset(&mut self, offset: Duration) {
+        self.offset = offset;
+    }
+
+    /// Wait until `duration` after the last deadline
+    pub async fn wait(&mut self, duration: Duration) {
+        self.deadline += duration;
+        sleep_until(
+            self.deadline
+                .checked_sub(self.offset)
+                .unwrap_or(self.deadline),
+        )
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The clock is paused, so it only moves on while every task sleeps, or
+    // when moved on by hand for the work between keys
+    #[tokio::test(start_paused = true)]
+    async fn test_oversleeping_does_not_add_up() {
+        let start = Instant::now();
+        let mut schedule = Schedule::default();
+        for _ in 0..50 {
+            // Work before a key comes out of its delay
+            tokio::time::advance(Duration::from_millis(1)).await;
+            schedule.wait(Duration::from_millis(2)).await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+
+        // Keys after a late one catch up rather than each being as late
+        schedule.restart();
+        let start = Instant::now();
+        schedule.wait(Duration::from_millis(2)).await;
+        tokio::time::advance(Duration::from_millis(3)).await;
+        schedule.wait(Duration::from_millis(2)).await;
+        schedule.wait(Duration::from_millis(2)).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(6));
+    }
+}
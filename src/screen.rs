@@ -0,0 +1,739 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Terminal screen model
+//!
+//! Feeds PTY output through a VT100 emulator so playback can see the
+//! terminal state the running program has set up, and answers the queries
+//! programs send when there's no real terminal to do it
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use unicode_width::UnicodeWidthStr;
+
+use crate::clipboard;
+use crate::frames::{Image, draw_screen};
+use crate::key::KeyProtocol;
+use crate::theme::Theme;
+use crate::width::{AmbiguousWidth, Layout};
+
+/// Input modes that change how keys are encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct KeyModes {
+    // DECCKM: arrows, Home and End are sent as SS3 sequences
+    pub application_cursor: bool,
+    // DECKPAM: keypad keys are sent as SS3 sequences
+    pub application_keypad: bool,
+    // Kitty keyboard protocol flags the program has pushed, 0 if none
+    pub kitty_flags: u8,
+}
+
+impl KeyModes {
+    pub fn protocol(self) -> KeyProtocol {
+        match self.kitty_flags {
+            0 => KeyProtocol::Legacy,
+            flags => KeyProtocol::Kitty(flags),
+        }
+    }
+}
+
+// Longest query worth reading; anything longer is ordinary output
+const MAX_QUERY_LEN: usize = 256;
+
+/// Longest OSC 52 clipboard write kept in full, as copies can be far longer than queries
+pub const MAX_COPY_LEN: usize = 1 << 20;
+
+// Output kept for `@ expect`, of which the oldest is dropped
+const MAX_UNMATCHED: usize = 1 << 20;
+
+/// Raw output kept for failure artifacts, the last 64 KiB
+pub const RECENT_OUTPUT: usize = 64 << 10;
+
+/// Where a scanner is in an escape sequence, which may be split across reads
+#[derive(Default)]
+pub enum Scan {
+    #[default]
+    Ground,
+    Escape,
+    Csi(Vec<u8>),
+    // OSC, DCS or APC string, by its introducer, and whether ESC was just seen
+    Str(u8, Vec<u8>, bool),
+}
+
+/// A complete escape sequence, without its introducer
+pub enum Sequence {
+    Csi(Vec<u8>),
+    // With the terminator it ended with, BEL or ST
+    Osc(Vec<u8>, &'static [u8]),
+    Dcs(Vec<u8>),
+    // Only ever replies, e.g. to kitty graphics, so the contents aren't kept
+    Apc,
+}
+
+impl Scan {
+    pub fn is_ground(&self) -> bool {
+        matches!(self, Scan::Ground)
+    }
+
+    pub fn is_escape(&self) -> bool {
+        matches!(self, Scan::Escape)
+    }
+
+    /// Read one byte, returning the sequence it completes if any
+    pub fn next(&mut self, byte: u8) -> Option<Sequence> {
+        match std::mem::take(self) {
+            Scan::Ground | Scan::Escape if byte == 0x1b => *self = Scan::Escape,
+            Scan::Ground => {}
+            Scan::Escape => {
+                *self = match byte {
+                    b'[' => Scan::Csi(Vec::new()),
+                    b']' | b'P' | b'_' => Scan::Str(byte, Vec::new(), false),
+                    _ => Scan::Ground,
+                }
+            }
+            Scan::Csi(mut body) => match byte {
+                0x40..=0x7e => {
+                    body.push(byte);
+                    return Some(Sequence::Csi(body));
+                }
+                0x20..=0x3f if body.len() < MAX_QUERY_LEN => {
+                    body.push(byte);
+                    *self = Scan::Csi(body);
+                }
+                0x1b => *self = Scan::Escape,
+                _ => {}
+            },
+            Scan::Str(kind, body, true) if byte == b'\\' => {
+                return Some(match kind {
+                    b']' => Sequence::Osc(body, b"\x1b\\"),
+                    b'P' => Sequence::Dcs(body),
+                    _ => Sequence::Apc,
+                });
+            }
+            // Any other ESC starts a new sequence
+            Scan::Str(_, _, true) => {
+                *self = Scan::Escape;
+                return self.next(byte);
+            }
+            Scan::Str(kind, mut body, false) => match byte {
+                0x07 if kind == b']' => return Some(Sequence::Osc(body, b"\x07")),
+                0x1b => *self = Scan::Str(kind, body, true),
+                _ if body.len() < MAX_QUERY_LEN
+                    || kind == b']' && body.starts_with(b"52;") && body.len() < MAX_COPY_LEN =>
+                {
+                    body.push(byte);
+                    *self = Scan::Str(kind, body, false);
+                }
+                _ => {}
+            },
+        }
+        None
+    }
+}
+
+// Keep the last `limit` bytes of `buffer`, letting it grow to twice that
+// between trims so each read doesn't move everything kept
+fn trim(buffer: &mut Vec<u8>, limit: usize) {
+    if buffer.len() > 2 * limit {
+        buffer.drain(..buffer.len() - limit);
+    }
+}
+
+// The last `limit` bytes of a buffer kept with [`trim`]
+fn last(buffer: &[u8], limit: usize) -> &[u8] {
+    &buffer[buffer.len().saturating_sub(limit)..]
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+fn hex_decode(hex: &[u8]) -> Option<String> {
+    let hex = std::str::from_utf8(hex).ok()?;
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+// What a terminal would send back for the query `sequence`, answering as a
+// 256 colour xterm with white text on black
+fn reply(sequence: &Sequence, screen: &vt100::Screen) -> Option<Vec<u8>> {
+    match sequence {
+        Sequence::Csi(body) => match body.as_slice() {
+            // Primary device attributes: a VT220 with ANSI colour
+            b"c" | b"0c" => Some(b"\x1b[?62;22c".to_vec()),
+            // Secondary device attributes
+            b">c" | b">0c" => Some(b"\x1b[>1;10;0c".to_vec()),
+            // Status report: OK
+            b"5n" => Some(b"\x1b[0n".to_vec()),
+            // Cursor position report
+            b"6n" | b"?6n" => {
+                let (row, col) = screen.cursor_position();
+                let private = if body[0] == b'?' { "?" } else { "" };
+                Some(format!("\x1b[{}{};{}R", private, row + 1, col + 1).into_bytes())
+            }
+            _ => None,
+        },
+        // Foreground and background colours
+        Sequence::Osc(body, terminator) => {
+            let (code, colour) = match body.as_slice() {
+                b"10;?" => (10, "ffff/ffff/ffff"),
+                b"11;?" => (11, "0000/0000/0000"),
+                _ => return None,
+            };
+            let mut out = format!("\x1b]{};rgb:{}", code, colour).into_bytes();
+            out.extend_from_slice(terminator);
+            Some(out)
+        }
+        // XTGETTCAP, answered for each capability in turn
+        Sequence::Dcs(body) => {
+            let names = body.strip_prefix(b"+q")?;
+            let mut out = Vec::new();
+            for name in names.split(|&byte| byte == b';') {
+                let value = match hex_decode(name).as_deref() {
+                    Some("Co" | "colors") => Some("256"),
+                    _ => None,
+                };
+                let reply = match value {
+                    Some(value) => format!(
+                        "\x1bP1+r{}={}\x1b\\",
+                        String::from_utf8_lossy(name),
+                        hex_encode(value.as_bytes())
+                    ),
+                    None => format!("\x1bP0+r{}\x1b\\", String::from_utf8_lossy(name)),
+                };
+                out.extend(reply.into_bytes());
+            }
+            Some(out)
+        }
+        Sequence::Apc => None,
+    }
+}
+
+// Kitty keyboard flags pushed and not yet popped, which kitty limits
+const MAX_KITTY_FLAGS: usize = 16;
+
+// Track the kitty keyboard protocol's `CSI > flags u` push, `CSI < n u`
+// pop and `CSI = flags ; mode u` set, answering the `CSI ? u` query
+fn kitty_keyboard(body: &[u8], stack: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let params = std::str::from_utf8(body.strip_suffix(b"u")?).ok()?;
+    let number = |text: &str, default: u8| text.parse().unwrap_or(default);
+    if let Some(flags) = params.strip_prefix('>') {
+        if stack.len() == MAX_KITTY_FLAGS {
+            stack.remove(0);
+        }
+        stack.push(number(flags, 0));
+    } else if let Some(count) = params.strip_prefix('<') {
+        let count = usize::from(number(count, 1));
+        stack.truncate(stack.len().saturating_sub(count));
+    } else if let Some(set) = params.strip_prefix('=') {
+        let (flags, mode) = set.split_once(';').unwrap_or((set, "1"));
+        let flags = number(flags, 0);
+        let current = stack.last().copied().unwrap_or(0);
+        let flags = match number(mode, 1) {
+            2 => current | flags,
+            3 => current & !flags,
+            _ => flags,
+        };
+        match stack.last_mut() {
+            Some(top) => *top = flags,
+            None => stack.push(flags),
+        }
+    } else if params == "?" {
+        let flags = stack.last().copied().unwrap_or(0);
+        return Some(format!("\x1b[?{}u", flags).into_bytes());
+    }
+    None
+}
+
+pub struct Screen {
+    parser: Mutex<vt100::Parser>,
+    layout: Mutex<Layout>,
+    // Lines kept after they scroll off the top
+    scrollback: usize,
+    scan: Mutex<Scan>,
+    kitty_flags: Mutex<Vec<u8>>,
+    unmatched: Mutex<Unmatched>,
+    // The latest output as the program wrote it
+    recent: Mutex<Vec<u8>>,
+    last_output: Mutex<Instant>,
+    // The last text copied with OSC 52, and whether `@ expect-clipboard` has taken it
+    copied: Mutex<(Option<String>, bool)>,
+}
+
+/// Text the program has written since an `@ expect` last matched, without
+/// escape sequences or carriage returns
+#[derive(Default)]
+struct Unmatched {
+    scan: Scan,
+    // After an ESC and an intermediate byte, as in `ESC ( B`, whose final byte isn't text
+    designator: bool,
+    text: Vec<u8>,
+}
+
+impl Unmatched {
+    fn extend(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            let escape = self.scan.is_escape();
+            let ground = self.scan.is_ground();
+            self.scan.next(byte);
+            if std::mem::take(&mut self.designator) {
+                continue;
+            }
+            if escape && (0x20..0x30).contains(&byte) {
+                self.designator = true;
+            } else if ground
+                && self.scan.is_ground()
+                && (byte >= 0x20 || byte == b'\n' || byte == b'\t')
+                && byte != 0x7f
+            {
+                self.text.push(byte);
+            }
+        }
+        trim(&mut self.text, MAX_UNMATCHED);
+    }
+}
+
+impl Screen {
+    pub fn new(cols: u16, rows: u16, ambiguous: AmbiguousWidth, scrollback: usize) -> Self {
+        Self {
+            parser: Mutex::new(vt100::Parser::new(rows, cols, scrollback)),
+            scrollback,
+            layout: Mutex::new(Layout::new(ambiguous)),
+            scan: Mutex::new(Scan::default()),
+            kitty_flags: Mutex::new(Vec::new()),
+            unmatched: Mutex::new(Unmatched::default()),
+            recent: Mutex::new(Vec::new()),
+            last_output: Mutex::new(Instant::now()),
+            copied: Mutex::new((None, false)),
+        }
+    }
+
+    /// Feed the program's output through, returning what a terminal would
+    /// have replied to any queries in it
+    pub fn process(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut parser = self.parser.lock().unwrap();
+        let mut scan = self.scan.lock().unwrap();
+        let mut kitty_flags = self.kitty_flags.lock().unwrap();
+        let mut replies = Vec::new();
+        self.unmatched.lock().unwrap().extend(bytes);
+        let mut recent = self.recent.lock().unwrap();
+        recent.extend_from_slice(bytes);
+        trim(&mut recent, RECENT_OUTPUT);
+        drop(recent);
+        let bytes = self.layout.lock().unwrap().rewrite(bytes);
+
+        // Output up to each query is processed first, so replies see the state at that point
+        let mut start = 0;
+        for (i, &byte) in bytes.iter().enumerate() {
+            if let Some(sequence) = scan.next(byte) {
+                parser.process(&bytes[start..=i]);
+                start = i + 1;
+                replies.extend(reply(&sequence, parser.screen()).unwrap_or_default());
+                if let Sequence::Osc(body, _) = &sequence
+                    && let Some(text) = clipboard::osc52_text(body)
+                {
+                    *self.copied.lock().unwrap() = (Some(text), false);
+                }
+                if let Sequence::Csi(body) = &sequence {
+                    replies.extend(kitty_keyboard(body, &mut kitty_flags).unwrap_or_default());
+                    // vt100 doesn't clear its scrollback for ED 3, so it starts afresh
+                    if body == b"3J" {
+                        let state = parser.screen().state_formatted();
+                        let (rows, cols) = parser.screen().size();
+                        *parser = vt100::Parser::new(rows, cols, self.scrollback);
+                        parser.process(&state);
+                    }
+                }
+            }
+        }
+        parser.process(&bytes[start..]);
+
+        *self.last_output.lock().unwrap() = Instant::now();
+        replies
+    }
+
+    /// The last text the program copied with OSC 52
+    pub fn copied(&self) -> Option<String> {
+        self.copied.lock().unwrap().0.clone()
+    }
+
+    /// Text copied with OSC 52 since this was last called, if any
+    pub fn take_copied(&self) -> Option<String> {
+        let mut copied = self.copied.lock().unwrap();
+        let (text, taken) = &mut *copied;
+        if std::mem::replace(taken, true) {
+            return None;
+        }
+        text.clone()
+    }
+
+    /// Time since the program last wrote anything
+    pub fn idle_time(&self) -> Duration {
+        self.last_output.lock().unwrap().elapsed()
+    }
+
+    /// The text on screen, with lines that wrapped joined up
+    pub fn contents(&self) -> String {
+        self.parser.lock().unwrap().screen().contents()
+    }
+
+    /// The groups of the first match of `regex` in output no earlier
+    /// match has used, the whole match first, leaving the output up to the
+    /// end of it used
+    pub fn match_output(&self, regex: &regex::bytes::Regex) -> Option<Vec<String>> {
+        let mut unmatched = self.unmatched.lock().unwrap();
+        let text = last(&unmatched.text, MAX_UNMATCHED);
+        let skipped = unmatched.text.len() - text.len();
+        let captures = regex.captures(text)?;
+        let groups = captures
+            .iter()
+            .map(|group| {
+                group.map_or_else(String::new, |group| {
+                    String::from_utf8_lossy(group.as_bytes()).into_owned()
+                })
+            })
+            .collect();
+        let end = captures.get(0).map_or(0, |whole| whole.end());
+        unmatched.text.drain(..skipped + end);
+        Some(groups)
+    }
+
+    /// Whether `text` is on screen starting at `row` and `col`, counted from 0
+    pub fn has_text_at(&self, row: u16, col: u16, text: &str) -> bool {
+        let parser = self.parser.lock().unwrap();
+        let screen = parser.screen();
+        let (_, cols) = screen.size();
+        col < cols
+            && screen
+                .rows(col, cols - col)
+                .nth(usize::from(row))
+                .is_some_and(|line| line.starts_with(text))
+    }
+
+    /// The text of the scrollback and the screen, oldest line first, with
+    /// trailing blank lines left off
+    pub fn history(&self) -> String {
+        let mut parser = self.parser.lock().unwrap();
+        let screen = parser.screen_mut();
+        let (rows, cols) = screen.size();
+        let mut lines = Vec::new();
+
+        // Each view of the scrollback starts with up to a screen's worth of it
+        screen.set_scrollback(usize::MAX);
+        let mut offset = screen.scrollback();
+        while offset > 0 {
+            screen.set_scrollback(offset);
+            let count = offset.min(usize::from(rows));
+            lines.extend(screen.rows(0, cols).take(count));
+            offset -= count;
+        }
+        screen.set_scrollback(0);
+        lines.extend(screen.rows(0, cols));
+
+        let end = lines.iter().rposition(|line| !line.trim_end().is_empty());
+        lines[..end.map_or(0, |end| end + 1)]
+            .iter()
+            .map(|line| line.trim_end().to_string() + "\n")
+            .collect()
+    }
+
+    /// Up to the last [`RECENT_OUTPUT`] bytes the program wrote, escape
+    /// sequences and all
+    pub fn recent_output(&self) -> Vec<u8> {
+        last(&self.recent.lock().unwrap(), RECENT_OUTPUT).to_vec()
+    }
+
+    /// The screen as a PNG export would draw it
+    pub fn draw(&self, theme: &Theme, title: Option<&str>) -> Image {
+        draw_screen(self.parser.lock().unwrap().screen(), theme, title)
+    }
+
+    /// Escape sequences that redraw the current screen and input modes from scratch
+    pub fn redraw(&self) -> Vec<u8> {
+        self.parser.lock().unwrap().screen().state_formatted()
+    }
+
+    /// The text of the cursor's row up to the cursor, spaces included
+    pub fn before_cursor(&self) -> String {
+        let parser = self.parser.lock().unwrap();
+        let screen = parser.screen();
+        let (row, col) = screen.cursor_position();
+        let mut text = screen
+            .rows(0, col)
+            .nth(usize::from(row))
+            .unwrap_or_default();
+        // Blanks at the end of a row are left off, such as the space after a prompt
+        let width = UnicodeWidthStr::width(text.as_str());
+        text.extend(std::iter::repeat_n(
+            ' ',
+            usize::from(col).saturating_sub(width),
+        ));
+        text
+    }
+
+    /// Escape sequences that draw the cursor's row on the top row, with the
+    /// cursor where it was, for a prompt to survive clearing the screen
+    pub fn cursor_line(&self) -> Vec<u8> {
+        let parser = self.parser.lock().unwrap();
+        let screen = parser.screen();
+        let (row, col) = screen.cursor_position();
+        let (_, cols) = screen.size();
+        let mut out = b"\x1b[H".to_vec();
+        out.extend(
+            screen
+                .rows_formatted(0, cols)
+                .nth(row as usize)
+                .unwrap_or_default(),
+        );
+        out.extend(screen.attributes_formatted());
+        out.extend(format!("\x1b[1;{}H", col + 1).into_bytes());
+        out
+    }
+
+    /// Each row on its own, styled from a reset and ending with one, for
+    /// drawing the screen in a pane at any position, and the cursor position
+    /// unless it's hidden
+    pub fn pane_rows(&self) -> (Vec<Vec<u8>>, Option<(u16, u16)>) {
+        let parser = self.parser.lock().unwrap();
+        let screen = parser.screen();
+        let (rows, cols) = screen.size();
+        let lines = (0..rows)
+            .map(|row| {
+                let mut line = Vec::new();
+                let mut style = String::new();
+                for col in 0..cols {
+                    let Some(cell) = screen.cell(row, col) else {
+                        break;
+                    };
+                    if cell.is_wide_continuation() {
+                        continue;
+                    }
+                    let cell_style = cell_sgr(cell);
+                    if cell_style != style {
+                        line.extend(format!("\x1b[{}m", cell_style).into_bytes());
+                        style = cell_style;
+                    }
+                    match cell.contents() {
+                        "" => line.push(b' '),
+                        text => line.extend(text.as_bytes()),
+                    }
+                }
+                line.extend(b"\x1b[m");
+                line
+            })
+            .collect();
+        let cursor = (!screen.hide_cursor()).then(|| screen.cursor_position());
+        (lines, cursor)
+    }
+
+    /// Change the size of the screen, as the PTY's changes
+    pub fn set_size(&self, cols: u16, rows: u16) {
+        self.parser
+            .lock()
+            .unwrap()
+            .screen_mut()
+            .set_size(rows, cols);
+    }
+
+    /// Size in columns and rows
+    pub fn size(&self) -> (u16, u16) {
+        let (rows, cols) = self.parser.lock().unwrap().screen().size();
+        (cols, rows)
+    }
+
+    /// Whether the program has turned on bracketed paste
+    pub fn bracketed_paste(&self) -> bool {
+        self.parser.lock().unwrap().screen().bracketed_paste()
+    }
+
+    pub fn key_modes(&self) -> KeyModes {
+        let parser = self.parser.lock().unwrap();
+        let screen = parser.screen();
+        KeyModes {
+            application_cursor: screen.application_cursor(),
+            application_keypad: screen.application_keypad(),
+            kitty_flags: self
+                .kitty_flags
+                .lock()
+                .unwrap()
+                .last()
+                .copied()
+                .unwrap_or(0),
+        }
+    }
+}
+
+// The SGR parameters that set a cell's style from a reset
+fn cell_sgr(cell: &vt100::Cell) -> String {
+    let mut params = vec!["0".to_string()];
+    for (on, param) in [
+        (cell.bold(), "1"),
+        (cell.dim(), "2"),
+        (cell.italic(), "3"),
+        (cell.underline(), "4"),
+        (cell.inverse(), "7"),
+    ] {
+        if on {
+            params.push(param.to_string());
+        }
+    }
+    for (color, base) in [(cell.fgcolor(), 30), (cell.bgcolor(), 40)] {
+        match color {
+            vt100::Color::Default => {}
+            vt100::Color::Idx(i) if i < 8 => params.push((base + u16::from(i)).to_string()),
+            vt100::Color::Idx(i) if i < 16 => {
+                params.push((base + 60 + u16::from(i) - 8).to_string())
+            }
+            vt100::Color::Idx(i) => params.push(format!("{};5;{}", base + 8, i)),
+            vt100::Color::Rgb(r, g, b) => params.push(format!("{};2;{};{};{}", base + 8, r, g, b)),
+        }
+    }
+    params.join(";")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracks_key_modes() {
+        let screen = Screen::new(80, 24, AmbiguousWidth::Narrow, 0);
+        assert_eq!(screen.key_modes(), KeyModes::default());
+
+        screen.process(b"\x1b[?1h\x1b=");
+        assert_eq!(
+            screen.key_modes(),
+            KeyModes {
+                application_cursor: true,
+                application_keypad: true,
+                kitty_flags: 0,
+            }
+        );
+
+        screen.process(b"\x1b[?1l\x1b>");
+        assert_eq!(screen.key_modes(), KeyModes::default());
+    }
+
+    #[test]
+    fn test_tracks_kitty_keyboard() {
+        let screen = Screen::new(80, 24, AmbiguousWidth::Narrow, 0);
+        assert_eq!(screen.process(b"\x1b[?u"), b"\x1b[?0u");
+        screen.process(b"\x1b[>1u\x1b[>3u");
+        assert_eq!(screen.key_modes().kitty_flags, 3);
+        screen.process(b"\x1b[=8;2u");
+        assert_eq!(screen.key_modes().protocol(), KeyProtocol::Kitty(11));
+        screen.process(b"\x1b[<u");
+        assert_eq!(screen.key_modes().kitty_flags, 1);
+        screen.process(b"\x1b[<5u");
+        assert_eq!(screen.key_modes().protocol(), KeyProtocol::Legacy);
+    }
+
+    #[test]
+    fn test_answers_queries() {
+        let screen = Screen::new(80, 24, AmbiguousWidth::Narrow, 0);
+        assert_eq!(
+            screen.process(b"ab\x1b[cc\x1b[6n"),
+            b"\x1b[?62;22c\x1b[1;4R"
+        );
+
+        // Split across reads, and ended with BEL
+        assert!(screen.process(b"\x1b]11").is_empty());
+        assert_eq!(screen.process(b";?\x07"), b"\x1b]11;rgb:0000/0000/0000\x07");
+
+        assert_eq!(
+            screen.process(b"\x1bP+q436F;544E\x1b\\"),
+            b"\x1bP1+r436F=323536\x1b\\\x1bP0+r544E\x1b\\"
+        );
+        assert!(screen.process(b"\x1b[2J\x1b]0;title\x07").is_empty());
+    }
+
+    #[test]
+    fn test_history() {
+        let screen = Screen::new(10, 3, AmbiguousWidth::Narrow, 100);
+        let lines: String = (1..=6).map(|i| format!("line {}\r\n", i)).collect();
+        screen.process(lines.as_bytes());
+        assert_eq!(
+            screen.history(),
+            "line 1\nline 2\nline 3\nline 4\nline 5\nline 6\n"
+        );
+
+        assert!(screen.has_text_at(1, 2, "ne 6"));
+        assert!(!screen.has_text_at(0, 2, "ne 6"));
+
+        // ED 3 clears the scrollback but not the screen
+        screen.process(b"\x1b[3J");
+        assert_eq!(screen.history(), "line 5\nline 6\n");
+    }
+
+    #[test]
+    fn test_match_output() {
+        let screen = Screen::new(80, 24, AmbiguousWidth::Narrow, 0);
+        let pid = regex::bytes::Regex::new(r"PID (\d+)").unwrap();
+        screen.process(b"\x1b[1mPID\x1b(B");
+        assert_eq!(screen.match_output(&pid), None);
+        screen.process(b" 34\r\n");
+        assert_eq!(
+            screen.match_output(&pid),
+            Some(vec!["PID 34".to_string(), "34".to_string()])
+        );
+        // Each match uses up the output it was found in
+        assert_eq!(screen.match_output(&pid), None);
+    }
+
+    #[test]
+    fn test_output_limits() {
+        let screen = Screen::new(80, 24, AmbiguousWidth::Narrow, 0);
+        screen.process(b"PID 1\n");
+        let chunk = vec![b'x'; RECENT_OUTPUT / 2];
+        for _ in 0..2 * MAX_UNMATCHED / chunk.len() {
+            screen.process(&chunk);
+        }
+        screen.process(b"end");
+        let recent = screen.recent_output();
+        assert_eq!(recent.len(), RECENT_OUTPUT);
+        assert!(recent.ends_with(b"xend"));
+        // Output from before the last MAX_UNMATCHED bytes is out of reach
+        let pid = regex::bytes::Regex::new(r"PID \d").unwrap();
+        assert_eq!(screen.match_output(&pid), None);
+        let end = regex::bytes::Regex::new(r"x+end").unwrap();
+        let groups = screen.match_output(&end).unwrap();
+        assert_eq!(groups[0].len(), MAX_UNMATCHED);
+    }
+
+    #[test]
+    fn test_cursor_line() {
+        let screen = Screen::new(80, 24, AmbiguousWidth::Narrow, 0);
+        screen.process(b"one\r\ntwo\r\n$ ls");
+        assert_eq!(screen.cursor_line(), b"\x1b[H$ ls\x1b[m\x1b[1;5H");
+    }
+
+    #[test]
+    fn test_pane_rows() {
+        let screen = Screen::new(4, 2, AmbiguousWidth::Narrow, 0);
+        screen.process(b"a\x1b[1;31mb\x1b[m\r\n\x1b[?25l");
+        let (rows, cursor) = screen.pane_rows();
+        assert_eq!(
+            rows,
+            vec![
+                b"\x1b[0ma\x1b[0;1;31mb\x1b[0m  \x1b[m".to_vec(),
+                b"\x1b[0m    \x1b[m".to_vec(),
+            ]
+        );
+        assert_eq!(cursor, None);
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Where playback sends keys
+//!
+//! [`PlaybackEngine`](crate::playback::PlaybackEngine) types into anything
+//! that implements [`KeystrokeSink`]: a local PTY for the `quipu` command,
+//! or [`RecordingSink`] to check what a script sends without starting a shell
+
+use anyhow::Result;
+
+use crate::cast::Cast;
+use crate::screen::Screen;
+use crate::width::AmbiguousWidth;
+
+/// Ways to stop or pause what's running in the session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// SIGINT to the foreground program, as Ctrl-C
+    Interrupt,
+    /// SIGQUIT, as Ctrl-\
+    Quit,
+    /// SIGTSTP, as Ctrl-Z
+    Suspend,
+    /// End the session's shell outright
+    Kill,
+}
+
+impl Signal {
+    /// The control character a terminal sends for the signal, if it has one
+    pub fn control_char(self) -> Option<u8> {
+        match self {
+            Signal::Interrupt => Some(0x03),
+            Signal::Quit => Some(0x1c),
+            Signal::Suspend => Some(0x1a),
+            Signal::Kill => None,
+        }
+    }
+}
+
+/// A session keys are typed into
+///
+/// Only sending, resizing and signals are needed; the rest are for sessions
+/// quipu shows as they play, and do nothing by default
+pub trait KeystrokeSink {
+    /// Write `bytes` to the session as if typed, waiting while the
+    /// session is behind on reading its input
+    fn send(&mut self, bytes: &[u8]) -> impl Future<Output = Result<()>>;
+
+    /// Resize the session to `cols` by `rows`
+    fn resize(&mut self, cols: u16, rows: u16) -> Result<()>;
+
+    fn signal(&mut self, signal: Signal) -> Result<()>;
+
+    /// Terminal state as seen through the session's output so far
+    fn screen(&self) -> &Screen;
+
+    /// Hide or show the session's output
+    fn set_visible(&self, _visible: bool) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether the session shares the terminal with others in panes, so
+    /// showing one doesn't hide the rest
+    fn is_split(&self) -> bool {
+        false
+    }
+
+    /// Replace status line `line`, if status rows are reserved
+    fn draw_status(&mut self, _line: usize, _text: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Clear the screen, and the scrollback too if `scrollback` is set
+    fn clear(&self, _scrollback: bool) -> Result<()> {
+        Ok(())
+    }
+
+    /// Reset the terminal
+    fn reset(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Start the section from checkpoint `name`
+    fn section(&mut self, _name: &str) -> impl Future<Output = Result<()>> {
+        async { Ok(()) }
+    }
+
+    /// `text` as it may be shown, with anything secret masked
+    fn redact(&self, text: &str) -> String {
+        text.to_string()
+    }
+
+    /// The recording of everything shown so far, if the session is being recorded
+    fn recording(&self) -> Option<Cast> {
+        None
+    }
+
+    /// Bytes of input sent but not yet read by the program in the session,
+    /// if that can be told
+    fn unread_input(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// A sink that keeps everything sent to it, for tests
+pub struct RecordingSink {
+    pub sent: Vec<u8>,
+    // How many writes `sent` arrived in
+    pub writes: usize,
+    pub resizes: Vec<(u16, u16)>,
+    pub signals: Vec<Signal>,
+    // Reported as input the session hasn't read
+    pub unread: Option<usize>,
+    screen: Screen,
+}
+
+impl RecordingSink {
+    pub fn new(cols: u16, rows: u16) -> Self {
+        Self {
+            sent: Vec::new(),
+            writes: 0,
+            resizes: Vec::new(),
+            signals: Vec::new(),
+            unread: None,
+            screen: Screen::new(cols, rows, AmbiguousWidth::Narrow, 1000),
+        }
+    }
+
+    /// Output for the screen model, as if the session had written it
+    pub fn output(&self, bytes: &[u8]) {
+        self.screen.process(bytes);
+    }
+}
+
+impl KeystrokeSink for RecordingSink {
+    async fn send(&mut self, bytes: &[u8]) -> Result<()> {
+        self.sent.extend_from_slice(bytes);
+        self.writes += 1;
+        Ok(())
+    }
+
+    fn resize(&mut self, cols: u16, rows: u16) -> Result<()> {
+        self.screen.set_size(cols, rows);
+        self.resizes.push((cols, rows));
+        Ok(())
+    }
+
+    fn signal(&mut self, signal: Signal) -> Result<()> {
+        self.signals.push(signal);
+        Ok(())
+    }
+
+    fn screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    fn unread_input(&self) -> Option<usize> {
+        self.unread
+    }
+}
@@ -0,0 +1,297 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Sounds and speech for `@ sound:`, `@ audio:` and `@ say:`
+//!
+//! Files are decoded and played in-process with rodio when built with the
+//! `rodio` feature. Otherwise they're played by whichever player the host
+//! has: `afplay` on macOS, PowerShell's `SoundPlayer` on Windows, and
+//! `ffplay`, `paplay` or `aplay` elsewhere. Speech is piped to the config
+//! file's `say_command`, or else to `say` on macOS, PowerShell's speech
+//! synthesizer on Windows, and `espeak-ng` or `espeak` elsewhere. Each is started in the background so
+//! playback only waits for `@ say-wait:`, and any still going are stopped
+//! when playback ends
+//!
+//! Only the host hears them: recordings and exports are of the terminal
+//! alone and have no audio track
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::OnceLock;
+use tokio::io::AsyncWriteExt;
+use tokio::process::{Child, Command};
+
+use crate::host;
+
+type Program = (&'static str, &'static [&'static str]);
+
+// A sound file being played, stopped when dropped
+#[cfg(feature = "rodio")]
+type Playback = rodio::Player;
+#[cfg(not(feature = "rodio"))]
+type Playback = Child;
+
+// Players to look for on PATH, best first, with the arguments before the file
+#[cfg(not(feature = "rodio"))]
+const PLAYERS: &[Program] = &[
+    ("afplay", &[]),
+    // Plays MP3 and the rest, where the others only manage WAV
+    ("ffplay", &["-nodisp", "-autoexit", "-loglevel", "quiet"]),
+    ("paplay", &[]),
+    ("aplay", &["-q"]),
+];
+
+// Key sounds let play at once; keys typed while this many are still going
+// aren't heard, rather than starting a player for every one
+const MAX_CLICKS: usize = 4;
+
+// Speech synthesizers to look for on PATH, which read the text on stdin
+const SPEAKERS: &[Program] = &[
+    ("say", &[]),
+    ("espeak-ng", &["--stdin"]),
+    ("espeak", &["--stdin"]),
+];
+
+/// Check that the sound in `path` can be played
+pub fn check(path: &str) -> Result<()> {
+    anyhow::ensure!(Path::new(path).is_file(), "No sound file '{}'", path);
+    Ok(())
+}
+
+// The first of `programs` on PATH
+fn find_program(programs: &'static [Program]) -> Option<&'static Program> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let dirs: Vec<PathBuf> = std::env::split_paths(&path).collect();
+    programs
+        .iter()
+        .find(|(program, _)| dirs.iter().any(|dir| dir.join(program).is_file()))
+}
+
+#[cfg(not(feature = "rodio"))]
+fn player_command(path: &Path) -> Option<Command> {
+    static PLAYER: OnceLock<Option<&Program>> = OnceLock::new();
+    if cfg!(windows) {
+        let path = path.display().to_string().replace('\'', "''");
+        let mut command = Command::new("powershell");
+        command.args([
+            "-NoProfile",
+            "-Command",
+            &format!("(New-Object Media.SoundPlayer '{}').PlaySync()", path),
+        ]);
+        return Some(command);
+    }
+    let (program, args) = (*PLAYER.get_or_init(|| find_program(PLAYERS)))?;
+    let mut command = Command::new(program);
+    command.args(*args).arg(path);
+    Some(command)
+}
+
+// The default output device's mixer, or None without one
+#[cfg(feature = "rodio")]
+fn mixer() -> Option<&'static rodio::mixer::Mixer> {
+    static MIXER: OnceLock<Option<rodio::mixer::Mixer>> = OnceLock::new();
+    MIXER
+        .get_or_init(|| {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            // The device's stream can't move between threads on every host,
+            // so it's opened on one of its own and kept open until quipu exits
+            std::thread::spawn(
+                move || match rodio::DeviceSinkBuilder::open_default_sink() {
+                    Ok(mut sink) => {
+                        sink.log_on_drop(false);
+                        let _ = sender.send(Some(sink.mixer().clone()));
+                        loop {
+                            std::thread::park();
+                        }
+                    }
+                    Err(error) => {
+                        tracing::debug!("No audio device: {}", error);
+                        let _ = sender.send(None);
+                    }
+                },
+            );
+            receiver.recv().ok().flatten()
+        })
+        .as_ref()
+}
+
+#[cfg(feature = "rodio")]
+fn is_playing(player: &mut Playback) -> bool {
+    !player.empty()
+}
+
+#[cfg(not(feature = "rodio"))]
+fn is_playing(child: &mut Playback) -> bool {
+    is_running(child)
+}
+
+fn speaker_command(say_command: Option<&str>) -> Option<Command> {
+    static SPEAKER: OnceLock<Option<&Program>> = OnceLock::new();
+    if let Some(say_command) = say_command {
+        return Some(host::shell_command(say_command));
+    }
+    if cfg!(windows) {
+        let mut command = Command::new("powershell");
+        command.args([
+            "-NoProfile",
+            "-Command",
+            "Add-Type -AssemblyName System.Speech; \
+             (New-Object System.Speech.Synthesis.SpeechSynthesizer).Speak([Console]::In.ReadToEnd())",
+        ]);
+        return Some(command);
+    }
+    let (program, args) = (*SPEAKER.get_or_init(|| find_program(SPEAKERS)))?;
+    let mut command = Command::new(program);
+    command.args(*args);
+    Some(command)
+}
+
+/// Sounds and speech started by a session, stopped when dropped
+#[derive(Default)]
+pub struct Sounds {
+    playing: Vec<Playback>,
+    // Those from `@ sound:`, kept apart to be counted against MAX_CLICKS
+    clicks: Vec<Playback>,
+    speaking: Vec<Child>,
+    // Run with the text to say on stdin, from the config file
+    say_command: Option<String>,
+    // Whether the lack of a player or synthesizer has been reported yet
+    warned_player: bool,
+    warned_speaker: bool,
+}
+
+impl Sounds {
+    pub fn new(say_command: Option<String>) -> Self {
+        Self {
+            say_command,
+            ..Self::default()
+        }
+    }
+
+    fn start(command: &mut Command, input: Stdio) -> Result<Child> {
+        Ok(command
+            .stdin(input)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?)
+    }
+
+    /// Start playing the file at `path`
+    pub fn play(&mut self, path: &str) -> Result<()> {
+        self.playing.retain_mut(is_playing);
+        if let Some(playback) = self.start_player(path)? {
+            self.playing.push(playback);
+        }
+        Ok(())
+    }
+
+    /// Start playing the key sound at `path`, unless enough are playing already
+    pub fn click(&mut self, path: &str) -> Result<()> {
+        // Finished ones are reaped first, as keystroke sounds come thick and fast
+        self.clicks.retain_mut(is_playing);
+        if self.clicks.len() >= MAX_CLICKS {
+            return Ok(());
+        }
+        if let Some(playback) = self.start_player(path)? {
+            self.clicks.push(playback);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "rodio")]
+    fn start_player(&mut self, path: &str) -> Result<Option<Playback>> {
+        let Some(mixer) = mixer() else {
+            if !std::mem::replace(&mut self.warned_player, true) {
+                tracing::warn!("No audio device found for sounds");
+            }
+            return Ok(None);
+        };
+        let source = std::fs::File::open(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|file| Ok(rodio::Decoder::try_from(file)?))
+            .with_context(|| format!("Failed to play '{}'", path))?;
+        let player = rodio::Player::connect_new(mixer);
+        player.append(source);
+        Ok(Some(player))
+    }
+
+    #[cfg(not(feature = "rodio"))]
+    fn start_player(&mut self, path: &str) -> Result<Option<Playback>> {
+        let Some(mut command) = player_command(Path::new(path)) else {
+            if !std::mem::replace(&mut self.warned_player, true) {
+                tracing::warn!("No audio player found for sounds, install ffplay, paplay or aplay");
+            }
+            return Ok(None);
+        };
+        let child = Self::start(&mut command, Stdio::null())
+            .with_context(|| format!("Failed to play '{}'", path))?;
+        Ok(Some(child))
+    }
+
+    /// Start saying `text`, and with `wait`, wait until it's been said
+    pub async fn say(&mut self, text: &str, wait: bool) -> Result<()> {
+        let Some(mut command) = speaker_command(self.say_command.as_deref()) else {
+            if !std::mem::replace(&mut self.warned_speaker, true) {
+                tracing::warn!(
+                    "No speech synthesizer found for @ say, install espeak-ng or set say_command"
+                );
+            }
+            return Ok(());
+        };
+        self.speaking.retain_mut(is_running);
+        let mut child =
+            Self::start(&mut command, Stdio::piped()).context("Failed to start speech")?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes()).await?;
+        }
+        if wait {
+            let status = child.wait().await?;
+            anyhow::ensure!(status.success(), "Speech failed with {}", status);
+        } else {
+            self.speaking.push(child);
+        }
+        Ok(())
+    }
+}
+
+fn is_running(child: &mut Child) -> bool {
+    matches!(child.try_wait(), Ok(None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        assert!(check("Cargo.toml").is_ok());
+        let error = check("no-such-click.wav").unwrap_err();
+        assert_eq!(error.to_string(), "No sound file 'no-such-click.wav'");
+    }
+
+    #[tokio::test]
+    async fn test_say_command() {
+        let said = std::env::temp_dir().join(format!("quipu-said-{}.txt", std::process::id()));
+        let mut sounds = Sounds::new(Some(format!("cat > '{}'", said.display())));
+        sounds.say("Now we run the tests", true).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&said).unwrap(),
+            "Now we run the tests"
+        );
+        std::fs::remove_file(&said).unwrap();
+    }
+}
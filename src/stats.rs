@@ -0,0 +1,156 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Playback statistics for `--stats`
+//!
+//! How long each line took and where the time went, for fitting a demo
+//! into a time slot
+
+use serde::Serialize;
+use std::time::Duration;
+
+use crate::format::format_command;
+use crate::types::Command;
+
+// Words are counted as five keystrokes, as typing tests do
+const KEYS_PER_WORD: f64 = 5.0;
+
+#[derive(Debug, Serialize)]
+pub struct CommandTiming {
+    // Position among the script's commands, counting an `@ choose` as one
+    pub index: usize,
+    pub line: String,
+    pub seconds: f64,
+    // Whether it was an expect, for --summary
+    #[serde(skip)]
+    pub expect: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct PlaybackStats {
+    pub commands: Vec<CommandTiming>,
+    pub keystrokes: usize,
+    pub total_seconds: f64,
+    // Time spent typing `$` lines, including the pauses between keys
+    pub typing_seconds: f64,
+    // Time spent in `@ wait`, `@ wait-random`, expects, `@ waitprompt`, `@ say-wait` and
+    // waiting for --advance keypresses
+    pub waiting_seconds: f64,
+    // The command playback failed at, left out of the rest
+    #[serde(skip)]
+    pub failed: Option<CommandTiming>,
+}
+
+// Commands that check the session, failing playback if it doesn't show what they want
+fn is_expect(command: &Command) -> bool {
+    matches!(
+        command,
+        Command::Expect(_, _)
+            | Command::ExpectScreen(_)
+            | Command::ExpectCell(_, _, _)
+            | Command::ExpectClipboard(_)
+            | Command::WaitPrompt
+    )
+}
+
+fn timing(index: usize, command: &Command, time: Duration) -> CommandTiming {
+    CommandTiming {
+        index,
+        line: format_command(command),
+        seconds: time.as_secs_f64(),
+        expect: is_expect(command),
+    }
+}
+
+impl PlaybackStats {
+    pub fn record(&mut self, index: usize, command: &Command, time: Duration) {
+        match command {
+            Command::Type(_) | Command::TypeClipboard => self.typing_seconds += time.as_secs_f64(),
+            Command::Wait(_)
+            | Command::WaitRandom(_, _)
+            | Command::Expect(_, _)
+            | Command::ExpectScreen(_)
+            | Command::ExpectCell(_, _, _)
+            | Command::ExpectClipboard(_)
+            | Command::Say(_, true)
+            | Command::WaitPrompt
+            | Command::WaitFor(_, _) => self.waiting_seconds += time.as_secs_f64(),
+            _ => {}
+        }
+        self.total_seconds += time.as_secs_f64();
+        self.commands.push(timing(index, command, time));
+    }
+
+    pub fn record_failure(&mut self, index: usize, command: &Command, time: Duration) {
+        self.failed = Some(timing(index, command, time));
+    }
+
+    pub fn record_keypress_wait(&mut self, time: Duration) {
+        self.waiting_seconds += time.as_secs_f64();
+        self.total_seconds += time.as_secs_f64();
+    }
+
+    pub fn words_per_minute(&self) -> f64 {
+        if self.typing_seconds == 0.0 {
+            return 0.0;
+        }
+        self.keystrokes as f64 / KEYS_PER_WORD / (self.typing_seconds / 60.0)
+    }
+
+    pub fn summary(&self) -> String {
+        let mut out = String::from("Playback statistics\n\n");
+        // Settings take no time, so only lines that do are listed
+        for timing in self.commands.iter().filter(|t| t.seconds >= 0.001) {
+            out.push_str(&format!("{:>9.2}s  {}\n", timing.seconds, timing.line));
+        }
+        out.push_str(&format!(
+            "\nTotal      {:.2}s\nKeystrokes {}\nTyping     {:.2}s at {:.0} WPM\nWaiting    {:.2}s\n",
+            self.total_seconds,
+            self.keystrokes,
+            self.typing_seconds,
+            self.words_per_minute(),
+            self.waiting_seconds
+        ));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_summary() {
+        let mut stats = PlaybackStats::default();
+        stats.record(0, &Command::SetSpeed(0.1), Duration::ZERO);
+        stats.record(1, &Command::Type("ls -la\r".into()), Duration::from_secs(1));
+        stats.record(
+            2,
+            &Command::Wait(Duration::from_secs(2)),
+            Duration::from_secs(2),
+        );
+        stats.record_keypress_wait(Duration::from_millis(500));
+        stats.keystrokes = 10;
+
+        assert_eq!(stats.words_per_minute(), 120.0);
+        assert_eq!(
+            stats.summary(),
+            "Playback statistics\n\n\
+             \x20    1.00s  $ ls -la<ret>\n\
+             \x20    2.00s  @ wait:2\n\
+             \nTotal      3.50s\nKeystrokes 10\nTyping     1.00s at 120 WPM\nWaiting    2.50s\n"
+        );
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Running many scripts at once, for `quipu test`
+//!
+//! Each script is played by a quipu process of its own, so every session
+//! has its own headless PTY and Ctrl-C handling as when run alone. Their
+//! output is printed as it arrives, each line prefixed with the script it
+//! came from, and can be kept in a log file per script as well
+
+use anyhow::{Context, Result};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+use tokio::sync::{Semaphore, mpsc};
+
+// How long past its --timeout a script is given to stop on its own before it's killed
+const KILL_GRACE: Duration = Duration::from_secs(30);
+
+// How long to wait for the last of a script's output once it has exited
+const DRAIN_TIME: Duration = Duration::from_millis(200);
+
+pub struct SuiteOptions {
+    // Scripts played at once
+    pub jobs: usize,
+    // Longest each script may play for, passed on as --max-duration
+    pub timeout: Option<Duration>,
+    // Where each script's output is written as well, as NAME.log
+    pub log_dir: Option<PathBuf>,
+    // quipu options each script is played with
+    pub args: Vec<OsString>,
+}
+
+/// How a script's run went
+pub struct Outcome {
+    pub script: PathBuf,
+    // The exit status; None if it was killed
+    pub status: Option<i32>,
+    pub elapsed: Duration,
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        self.status == Some(0)
+    }
+}
+
+// Arguments to play `script` with
+fn child_args(options: &SuiteOptions, script: &Path) -> Vec<OsString> {
+    let mut args = options.args.clone();
+    if let Some(timeout) = options.timeout {
+        args.push("--max-duration".into());
+        args.push(format!("{}s", timeout.as_secs_f64()).into());
+    }
+    args.push(script.into());
+    args
+}
+
+// The log file for `script`, named after its path so scripts in different
+// directories don't share one
+fn log_name(script: &Path) -> String {
+    let name = script.to_string_lossy().replace(['/', '\\'], "-");
+    format!("{}.log", name.trim_start_matches(['.', '-']))
+}
+
+// Send each line `output` writes to `lines`
+async fn forward_lines(output: impl AsyncRead + Unpin, lines: mpsc::UnboundedSender<String>) {
+    let mut reader = BufReader::new(output).lines();
+    while let Ok(Some(line)) = reader.next_line().await {
+        if lines.send(line).is_err() {
+            break;
+        }
+    }
+}
+
+async fn run_one(script: PathBuf, options: Arc<SuiteOptions>) -> Result<Outcome> {
+    let start = Instant::now();
+    let exe = std::env::current_exe().context("Failed to find the quipu executable")?;
+    let mut child = Command::new(exe)
+        .args(child_args(&options, &script))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to start {}", script.display()))?;
+
+    let (sender, mut lines) = mpsc::unbounded_channel();
+    tokio::spawn(forward_lines(child.stdout.take().unwrap(), sender.clone()));
+    tokio::spawn(forward_lines(child.stderr.take().unwrap(), sender));
+
+    let prefix = script.display().to_string();
+    let mut log = Vec::new();
+    let mut print = |line: String| {
+        println!("[{}] {}", prefix, line);
+        log.push(line);
+    };
+    let limit = options
+        .timeout
+        .map_or(Duration::MAX, |timeout| timeout + KILL_GRACE);
+    let waited = tokio::time::timeout(limit, async {
+        loop {
+            tokio::select! {
+                Some(line) = lines.recv() => print(line),
+                status = child.wait() => break status,
+            }
+        }
+    })
+    .await;
+    // Anything it started in the background may still have the pipes open
+    while let Ok(Some(line)) = tokio::time::timeout(DRAIN_TIME, lines.recv()).await {
+        print(line);
+    }
+    let status = match waited {
+        Ok(status) => status?.code(),
+        Err(_) => {
+            child.kill().await?;
+            print(format!("Killed after {}s", limit.as_secs_f64()));
+            None
+        }
+    };
+
+    if let Some(dir) = &options.log_dir {
+        let path = dir.join(log_name(&script));
+        let text: String = log.iter().map(|line| format!("{}\n", line)).collect();
+        std::fs::write(&path, text)
+            .with_context(|| format!("Failed to write log: {}", path.display()))?;
+    }
+    Ok(Outcome {
+        script,
+        status,
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Play `scripts`, up to `options.jobs` at a time, returning how each went
+/// in the order given
+pub async fn run(scripts: &[PathBuf], options: SuiteOptions) -> Result<Vec<Outcome>> {
+    if let Some(dir) = &options.log_dir {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create directory: {}", dir.display()))?;
+    }
+    let slots = Arc::new(Semaphore::new(options.jobs.max(1)));
+    let options = Arc::new(options);
+    let tasks: Vec<_> = scripts
+        .iter()
+        .map(|script| {
+            let slots = slots.clone();
+            let options = options.clone();
+            let script = script.clone();
+            tokio::spawn(async move {
+                let _slot = slots.acquire_owned().await?;
+                run_one(script, options).await
+            })
+        })
+        .collect();
+
+    let mut outcomes = Vec::new();
+    for task in tasks {
+        outcomes.push(task.await??);
+    }
+    Ok(outcomes)
+}
+
+/// A line for each script saying how it went, then the totals
+pub fn summary(outcomes: &[Outcome]) -> String {
+    let mut out = String::new();
+    for outcome in outcomes {
+        let status = match outcome.status {
+            Some(0) => "ok".to_string(),
+            Some(code) => format!("FAILED (exit {})", code),
+            None => "FAILED (killed)".to_string(),
+        };
+        out.push_str(&format!(
+            "{:<40} {:>8.1}s  {}\n",
+            outcome.script.display(),
+            outcome.elapsed.as_secs_f64(),
+            status
+        ));
+    }
+    let passed = outcomes.iter().filter(|outcome| outcome.passed()).count();
+    out.push_str(&format!(
+        "\n{} passed, {} failed\n",
+        passed,
+        outcomes.len() - passed
+    ));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_child_args() {
+        let options = SuiteOptions {
+            jobs: 2,
+            timeout: Some(Duration::from_secs(90)),
+            log_dir: None,
+            args: vec!["--no-rc".into()],
+        };
+        assert_eq!(
+            child_args(&options, Path::new("demos/git.qp")),
+            ["--no-rc", "--max-duration", "90s", "demos/git.qp"].map(OsString::from)
+        );
+        assert_eq!(log_name(Path::new("./demos/git.qp")), "demos-git.qp.log");
+    }
+}
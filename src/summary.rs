@@ -0,0 +1,188 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The run summary written with `--summary`
+//!
+//! A JSON report for pipelines deciding what to do with a run: whether it
+//! passed and the exit status quipu gave, each command played with how long
+//! it took, how the expects went, the shell's own exit status and the files
+//! the run wrote. Fields are only ever added; `version` goes up if one is
+//! removed or changes meaning
+
+use serde::Serialize;
+use std::path::PathBuf;
+
+use crate::stats::{CommandTiming, PlaybackStats};
+
+const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Passed,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CommandSummary {
+    // Position among the script's commands
+    pub index: usize,
+    // Where it starts in the script file, if it was read from one; for an
+    // included command, the line that included it
+    pub line: Option<usize>,
+    pub command: String,
+    pub status: Status,
+    pub seconds: f64,
+    // Whether it's an expect or `@ waitprompt`, which check the session
+    pub expect: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ExpectCounts {
+    pub passed: usize,
+    pub failed: usize,
+}
+
+/// Files written by the run, each only if it was
+#[derive(Debug, Default, Serialize)]
+pub struct Files {
+    pub recording: Option<PathBuf>,
+    pub captions: Option<PathBuf>,
+    pub final_dump: Option<PathBuf>,
+    // The --artifacts directory for the expect that failed
+    pub failure: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub version: u32,
+    pub script: String,
+    pub status: Status,
+    pub exit_code: u8,
+    pub error: Option<String>,
+    // The shell's exit status, if it had exited by the end of playback
+    pub shell_exit_code: Option<u32>,
+    pub seconds: f64,
+    pub commands: Vec<CommandSummary>,
+    pub expects: ExpectCounts,
+    pub artifacts: Files,
+}
+
+fn command_summary(timing: &CommandTiming, lines: &[usize], status: Status) -> CommandSummary {
+    CommandSummary {
+        index: timing.index,
+        line: lines.get(timing.index).copied(),
+        command: timing.line.clone(),
+        status,
+        seconds: timing.seconds,
+        expect: timing.expect,
+    }
+}
+
+impl Summary {
+    /// How a run of `script` went, from its statistics and the error it
+    /// ended with, if any; `lines` has the line each command starts on
+    pub fn new(
+        script: String,
+        stats: &PlaybackStats,
+        lines: &[usize],
+        error: Option<&anyhow::Error>,
+    ) -> Self {
+        let mut commands: Vec<CommandSummary> = stats
+            .commands
+            .iter()
+            .map(|timing| command_summary(timing, lines, Status::Passed))
+            .collect();
+        commands.extend(
+            stats
+                .failed
+                .iter()
+                .map(|timing| command_summary(timing, lines, Status::Failed)),
+        );
+
+        let mut expects = ExpectCounts::default();
+        for command in commands.iter().filter(|command| command.expect) {
+            match command.status {
+                Status::Passed => expects.passed += 1,
+                Status::Failed => expects.failed += 1,
+            }
+        }
+
+        let exit_code = match error {
+            Some(error) => crate::error::exit_code(error),
+            None if crate::playback::interrupted() => crate::error::EXIT_INTERRUPTED,
+            None => 0,
+        };
+        Self {
+            version: VERSION,
+            script,
+            status: if exit_code == 0 {
+                Status::Passed
+            } else {
+                Status::Failed
+            },
+            exit_code,
+            error: error.map(|error| format!("{:#}", error)),
+            shell_exit_code: None,
+            seconds: stats.total_seconds + stats.failed.as_ref().map_or(0.0, |t| t.seconds),
+            commands,
+            expects,
+            artifacts: Files::default(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default() + "\n"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::types::Command;
+    use std::time::Duration;
+
+    #[test]
+    fn test_summary() {
+        let mut stats = PlaybackStats::default();
+        let typed = Command::Type("make test\r".into());
+        let expect = Command::Expect("passed".to_string(), vec![]);
+        stats.record(0, &typed, Duration::from_secs(1));
+        stats.record_failure(1, &expect, Duration::from_secs(10));
+        let error = anyhow::Error::from(Error::Expect("Timed out".to_string()));
+
+        let summary = Summary::new("demo.qp".to_string(), &stats, &[3, 4], Some(&error));
+        let json: serde_json::Value = serde_json::from_str(&summary.to_json()).unwrap();
+        assert_eq!(json["status"], "failed");
+        assert_eq!(json["exit_code"], 5);
+        assert_eq!(
+            json["expects"],
+            serde_json::json!({ "passed": 0, "failed": 1 })
+        );
+        assert_eq!(
+            json["commands"][1],
+            serde_json::json!({
+                "index": 1,
+                "line": 4,
+                "command": "@ expect:passed",
+                "status": "failed",
+                "seconds": 10.0,
+                "expect": true,
+            })
+        );
+        assert_eq!(json["artifacts"]["recording"], serde_json::Value::Null);
+    }
+}
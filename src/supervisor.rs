@@ -0,0 +1,108 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Making sure sessions don't outlive quipu
+//!
+//! Each session's shell leads a process group of its own, in a new session.
+//! Running sessions are kept here so their processes can be killed however
+//! quipu exits: when the session is dropped, from a panic hook that also puts
+//! the terminal back before the panic message prints, and on a second Ctrl-C.
+//! SIGTERM and SIGHUP stop playback as Ctrl-C does, and if quipu is killed
+//! outright the PTY closes with it, which hangs up on the session
+
+use std::io::{IsTerminal, Write};
+use std::sync::{Mutex, Once};
+
+static GROUPS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+static HOOK: Once = Once::new();
+
+/// Watch over the process group led by `pid`, a session's shell
+pub fn register(pid: u32) {
+    HOOK.call_once(install_panic_hook);
+    GROUPS.lock().unwrap().push(pid);
+}
+
+/// Kill whatever is left of the session led by `pid`, such as background
+/// jobs, and stop watching it
+pub fn release(pid: u32) {
+    GROUPS.lock().unwrap().retain(|&group| group != pid);
+    kill_group(pid);
+}
+
+#[cfg(unix)]
+fn kill_group(pid: u32) {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return;
+    };
+    // SAFETY: kill and getsid take no pointers; a negative pid names a process group
+    unsafe {
+        libc::kill(-pid, libc::SIGKILL);
+    }
+    // Jobs started with job control have groups of their own in the shell's
+    // session, which are found through /proc where there is one
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if let Some(process) = entry.file_name().to_str().and_then(|n| n.parse().ok())
+            && unsafe { libc::getsid(process) } == pid
+        {
+            unsafe {
+                libc::kill(process, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+// Windows has no process groups, and closing the console ends its processes
+#[cfg(not(unix))]
+fn kill_group(_pid: u32) {}
+
+/// Exit with `code` at once, putting the terminal back and killing every
+/// session first
+pub fn abort(code: u8) -> ! {
+    restore_terminal();
+    if let Ok(groups) = GROUPS.try_lock() {
+        groups.iter().copied().for_each(kill_group);
+    }
+    std::process::exit(code.into())
+}
+
+fn install_panic_hook() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        // A panic while the list is locked mustn't deadlock here
+        if let Ok(groups) = GROUPS.try_lock() {
+            groups.iter().copied().for_each(kill_group);
+        }
+        previous(info);
+    }));
+}
+
+// Leave raw mode, the status rows' scroll region and any alternate screen the
+// session was showing, so the panic message is readable
+fn restore_terminal() {
+    if !crossterm::terminal::is_raw_mode_enabled().unwrap_or(false) {
+        return;
+    }
+    let _ = crossterm::terminal::disable_raw_mode();
+    // stdout may be locked by the thread that panicked
+    let mut stderr = std::io::stderr();
+    if stderr.is_terminal() {
+        let _ = stderr.write_all(b"\x1b[r\x1b[?1049l\x1b[?25h\r\n");
+    }
+}
@@ -0,0 +1,510 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! VHS tape support
+//!
+//! Maps charmbracelet VHS `Type`, `Sleep`, `Set` and key commands to and
+//! from quipu commands
+
+use anyhow::{Context, Result, bail};
+use std::time::Duration;
+
+use crate::format::{KeyToken, cursor_keys_name, format_command, typed_tokens};
+use crate::key::{Key, KeyEvent, Keys};
+use crate::types::{Command, Script};
+
+// VHS types at 50ms per key unless told otherwise
+const VHS_TYPING_SPEED: f64 = 0.05;
+
+const QUOTES: [char; 3] = ['"', '\'', '`'];
+
+// Commands that only affect VHS rendering or have no playback equivalent
+const IGNORED_COMMANDS: &[&str] = &[
+    "Output",
+    "Require",
+    "Hide",
+    "Show",
+    "Screenshot",
+    "Copy",
+    "Paste",
+    "Env",
+    "Source",
+    "Wait",
+];
+
+// VHS key name to quipu key name
+const KEYS: &[(&str, &str)] = &[
+    ("Enter", "ret"),
+    ("Escape", "esc"),
+    ("Backspace", "bs"),
+    ("Tab", "tab"),
+    ("Space", "space"),
+    ("Up", "up"),
+    ("Down", "down"),
+    ("Left", "left"),
+    ("Right", "right"),
+    ("PageUp", "pageup"),
+    ("PageDown", "pagedown"),
+    ("Home", "home"),
+    ("End", "end"),
+    ("Insert", "insert"),
+    ("Delete", "delete"),
+];
+
+fn parse_duration(input: &str) -> Result<Duration> {
+    let input = input.trim();
+    let (number, scale) = if let Some(ms) = input.strip_suffix("ms") {
+        (ms, 0.001)
+    } else if let Some(s) = input.strip_suffix('s') {
+        (s, 1.0)
+    } else if let Some(m) = input.strip_suffix('m') {
+        (m, 60.0)
+    } else {
+        (input, 1.0)
+    };
+
+    let value: f64 = number
+        .parse()
+        .with_context(|| format!("Invalid duration: {}", input))?;
+    Duration::try_from_secs_f64(value * scale)
+        .with_context(|| format!("Invalid duration: {}", input))
+}
+
+fn unquote(input: &str) -> Option<&str> {
+    let input = input.trim();
+    QUOTES
+        .iter()
+        .find_map(|q| input.strip_prefix(*q)?.strip_suffix(*q))
+}
+
+fn key_spec(name: &str) -> Option<KeyEvent> {
+    let parts: Vec<&str> = name.split('+').collect();
+    let (key, modifiers) = parts.split_last()?;
+
+    let key = match KEYS.iter().find(|(vhs, _)| vhs == key) {
+        Some((_, quipu)) => *quipu,
+        None if key.chars().count() == 1 => key,
+        None => return None,
+    };
+
+    let mut spec = String::new();
+    for modifier in modifiers {
+        match *modifier {
+            "Ctrl" => spec.push_str("C-"),
+            "Alt" => spec.push_str("A-"),
+            "Shift" => spec.push_str("S-"),
+            _ => return None,
+        }
+    }
+    spec.push_str(key);
+
+    // Only keys terminals can send
+    let mut event = KeyEvent::parse(&spec).filter(|event| event.legacy().is_some())?;
+    // VHS writes Ctrl-c as Ctrl+C
+    if let (true, Key::Char(c)) = (event.mods.ctrl, event.key) {
+        event.key = Key::Char(c.to_ascii_lowercase());
+    }
+    Some(event)
+}
+
+// Append to the current typing line unless it has already been submitted
+fn push_type(commands: &mut Vec<Command>, keys: Keys) {
+    if let Some(Command::Type(line)) = commands.last_mut()
+        && !line.ends_line()
+    {
+        line.append(keys);
+        return;
+    }
+    commands.push(Command::Type(keys));
+}
+
+pub fn parse_tape(input: &str) -> Result<Script> {
+    let mut commands = vec![Command::SetSpeed(VHS_TYPING_SPEED)];
+    let mut speed = VHS_TYPING_SPEED;
+
+    for (line_num, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (word, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let args = args.trim();
+        let (name, override_speed) = match word.split_once('@') {
+            Some((name, time)) => (name, Some(parse_duration(time)?.as_secs_f64())),
+            None => (word, None),
+        };
+
+        let keys = match name {
+            "Type" => Keys::text(
+                unquote(args)
+                    .with_context(|| format!("Line {}: Expected a quoted string", line_num + 1))?,
+            ),
+            "Sleep" => {
+                commands.push(Command::Wait(parse_duration(args)?));
+                continue;
+            }
+            "Set" => {
+                let (setting, value) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+                match setting {
+                    "Shell" => {
+                        let shell = unquote(value).unwrap_or(value.trim());
+                        commands.push(Command::SetShell(shell.to_string()));
+                    }
+                    "TypingSpeed" => {
+                        speed = parse_duration(value)?.as_secs_f64();
+                        if let Some(Command::SetSpeed(current)) = commands.last_mut() {
+                            *current = speed;
+                        } else {
+                            commands.push(Command::SetSpeed(speed));
+                        }
+                    }
+                    // Fonts, themes, pixel sizes and so on only matter to VHS
+                    _ => {}
+                }
+                continue;
+            }
+            _ if IGNORED_COMMANDS.contains(&name) => {
+                tracing::warn!(
+                    "Line {}: Skipping unsupported tape command: {}",
+                    line_num + 1,
+                    name
+                );
+                continue;
+            }
+            _ => {
+                let event = key_spec(name).with_context(|| {
+                    format!("Line {}: Unknown tape command: {}", line_num + 1, name)
+                })?;
+                let count = if args.is_empty() {
+                    1
+                } else {
+                    args.parse::<usize>()
+                        .with_context(|| format!("Line {}: Invalid repeat count", line_num + 1))?
+                };
+                let mut keys = Keys::new();
+                for _ in 0..count {
+                    keys.push_key(event);
+                }
+                keys
+            }
+        };
+
+        match override_speed {
+            Some(typing_speed) => {
+                commands.push(Command::SetSpeed(typing_speed));
+                commands.push(Command::Type(keys));
+                commands.push(Command::SetSpeed(speed));
+            }
+            None => push_type(&mut commands, keys),
+        }
+    }
+
+    Ok(Script { commands })
+}
+
+fn vhs_key(token: KeyToken) -> Result<String> {
+    let name = match token {
+        KeyToken::Named(name) => match KEYS.iter().find(|(_, quipu)| *quipu == name) {
+            Some((vhs, _)) => vhs.to_string(),
+            None => bail!("VHS has no equivalent for <{}>", name),
+        },
+        KeyToken::Shift(name) => match KEYS.iter().find(|(_, quipu)| *quipu == name) {
+            Some((vhs, _)) => format!("Shift+{}", vhs),
+            None => bail!("VHS has no equivalent for <S-{}>", name),
+        },
+        KeyToken::Ctrl(' ') => "Ctrl+Space".to_string(),
+        KeyToken::Ctrl(c) => format!("Ctrl+{}", c.to_ascii_uppercase()),
+        KeyToken::Alt(c) => format!("Alt+{}", c),
+        KeyToken::Key(event) => bail!("VHS has no equivalent for <{}>", event),
+        KeyToken::Char(c) => bail!("{:?} is not a key", c),
+    };
+    Ok(name)
+}
+
+// Emit a Type command, switching quote style (or splitting) so no escaping is needed
+fn push_type_lines(out: &mut Vec<String>, text: &str) {
+    let mut chunk = String::new();
+    for c in text.chars() {
+        let has_quote = QUOTES.iter().any(|q| *q != c && !chunk.contains(*q));
+        if !has_quote {
+            push_quoted(out, &chunk);
+            chunk.clear();
+        }
+        chunk.push(c);
+    }
+    push_quoted(out, &chunk);
+}
+
+fn push_quoted(out: &mut Vec<String>, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    let quote = QUOTES.iter().find(|q| !text.contains(**q)).unwrap_or(&'"');
+    out.push(format!("Type {}{}{}", quote, text, quote));
+}
+
+fn format_type(out: &mut Vec<String>, keys: &Keys) -> Result<()> {
+    let mut chars = String::new();
+    let mut last_key: Option<(String, usize)> = None;
+
+    let flush_key = |out: &mut Vec<String>, key: &mut Option<(String, usize)>| {
+        if let Some((name, count)) = key.take() {
+            if count > 1 {
+                out.push(format!("{} {}", name, count));
+            } else {
+                out.push(name);
+            }
+        }
+    };
+
+    for token in typed_tokens(keys) {
+        if let KeyToken::Char(c) = token {
+            flush_key(out, &mut last_key);
+            chars.push(c);
+            continue;
+        }
+
+        push_type_lines(out, &chars);
+        chars.clear();
+
+        let name = vhs_key(token)?;
+        match &mut last_key {
+            Some((last, count)) if *last == name => *count += 1,
+            _ => {
+                flush_key(out, &mut last_key);
+                last_key = Some((name, 1));
+            }
+        }
+    }
+
+    push_type_lines(out, &chars);
+    flush_key(out, &mut last_key);
+    Ok(())
+}
+
+pub fn format_tape(script: &Script) -> Result<String> {
+    let mut out = Vec::new();
+
+    // VHS plays the same every time, so the first branch of each choice stands in
+    if script.has_choices() {
+        out.push("# Only the first branch of each @ choose block is exported".to_string());
+    }
+    let script = script.resolve_choices(&mut |_| 0);
+    // Nor can it go back, so retry blocks play once
+    if script.has_retries() {
+        out.push("# @ retry blocks are exported to run once".to_string());
+    }
+    let script = script.without_retries();
+
+    for command in &script.commands {
+        match command {
+            Command::SetSpeed(speed) => {
+                out.push(format!("Set TypingSpeed {}ms", (speed * 1000.0).round()))
+            }
+            Command::SetRamp(start, end) => {
+                out.push(format!(
+                    "# ramp:{}..{} has no VHS equivalent, typing at the average speed",
+                    start, end
+                ));
+                out.push(format!(
+                    "Set TypingSpeed {}ms",
+                    ((start + end) * 500.0).round()
+                ));
+            }
+            Command::SetJitter(jitter) => {
+                out.push(format!("# jitter:{} has no VHS equivalent", jitter))
+            }
+            Command::SetWaitJitter(jitter) => {
+                out.push(format!("# waitjitter:{} has no VHS equivalent", jitter))
+            }
+            Command::SetWordPause(pause) => {
+                out.push(format!("# word-pause:{} has no VHS equivalent", pause))
+            }
+            Command::Wait(duration) => out.push(format!("Sleep {}ms", duration.as_millis())),
+            Command::WaitRandom(min, max) => {
+                out.push(format!(
+                    "# wait-random:{}:{} has no VHS equivalent, sleeping for the midpoint",
+                    min.as_secs_f64(),
+                    max.as_secs_f64()
+                ));
+                out.push(format!("Sleep {}ms", ((*min + *max) / 2).as_millis()));
+            }
+            Command::SetShell(shell) => out.push(format!("Set Shell \"{}\"", shell)),
+            Command::SetTerm(term) => out.push(format!("# term:{} has no VHS equivalent", term)),
+            Command::SetShellProfile(name) => {
+                out.push(format!("# shell-profile:{} has no VHS equivalent", name))
+            }
+            Command::SetShellArgs(args) => out.push(format!(
+                "# shellargs:{} has no VHS equivalent",
+                args.join(" ")
+            )),
+            Command::SetSize(cols, rows) => out.push(format!(
+                "# size:{}:{} has no VHS equivalent, set Width and Height in pixels",
+                cols, rows
+            )),
+            Command::SetPixelSize(width, height) => out.push(format!(
+                "# pixelsize:{}x{} has no VHS equivalent",
+                width, height
+            )),
+            Command::SetCursorKeys(mode) => out.push(format!(
+                "# cursor-keys:{} has no VHS equivalent",
+                cursor_keys_name(*mode)
+            )),
+            // VHS types each character on its own
+            Command::SetIme(_) => {}
+            Command::Checkpoint(name) => out.push(format!("# checkpoint:{}", name)),
+            Command::Caption(text) => out.push(format!("# caption:{}", text)),
+            Command::Note(text) => out.push(format!("# {}", text)),
+            Command::Meta(field, text) => out.push(format!("# {}:{}", field.name(), text)),
+            Command::Requires(requirement) => out.push(format!("# quipu:{}", requirement)),
+            // VHS can wait for a regex on screen, but not for text in a given cell
+            Command::ExpectScreen(pattern) => {
+                out.push(format!("Wait+Screen /{}/", pattern.replace('/', "\\/")))
+            }
+            // VHS's Wait looks for its WaitPattern at the end of the last line,
+            // with the space a prompt ends in trimmed off
+            Command::SetPromptPattern(pattern) => {
+                let pattern = match pattern.strip_suffix(" $") {
+                    Some(start) => format!("{}$", start),
+                    None => pattern.clone(),
+                };
+                out.push(format!("Set WaitPattern /{}/", pattern.replace('/', "\\/")))
+            }
+            Command::WaitPrompt => out.push("Wait".to_string()),
+            Command::SetExpectTimeout(_)
+            | Command::SetCommandTimeout(_)
+            | Command::Expect(_, _)
+            | Command::ExpectCell(_, _, _)
+            | Command::SetSound(_)
+            | Command::Audio(_)
+            | Command::Say(_, _)
+            | Command::Clear(_)
+            | Command::Reset
+            | Command::Resize(_, _)
+            | Command::Session(_)
+            | Command::SetLayout(_) => out.push(format!(
+                "# {} has no VHS equivalent",
+                format_command(command).trim_start_matches("@ ")
+            )),
+            Command::Exec(_, _)
+            | Command::ExecAsync(_)
+            | Command::Webhook(_)
+            | Command::DumpScrollback(_)
+            | Command::ExpectClipboard(_)
+            | Command::Plugin(_, _)
+            | Command::WaitFor(_, _)
+            | Command::TypeClipboard => out.push(format!(
+                "# {} is not run by VHS",
+                format_command(command).trim_start_matches("@ ")
+            )),
+            Command::Setup(_) => out.push("# setup is not run by VHS".to_string()),
+            Command::Lua(_) => out.push("# lua is not run by VHS".to_string()),
+            Command::Teardown(_) => out.push("# teardown is not run by VHS".to_string()),
+            Command::OnError(_) => out.push("# on-error is not run by VHS".to_string()),
+            Command::Finally(_) => out.push("# finally is not run by VHS".to_string()),
+            // Resolved above
+            Command::Choose(_) | Command::Retry(_, _, _) => {}
+            Command::SetEncoding(label) => {
+                out.push(format!("# encoding:{} has no VHS equivalent", label))
+            }
+            Command::SetNewline(newline) => out.push(format!(
+                "# newline:{} has no VHS equivalent",
+                newline.name()
+            )),
+            Command::SetMultiline(mode) => {
+                out.push(format!("# multiline:{} has no VHS equivalent", mode.name()))
+            }
+            Command::SetNoHistory(mode) => out.push(format!(
+                "# no-history:{} has no VHS equivalent",
+                mode.name()
+            )),
+            Command::Type(text) => format_type(&mut out, text)?,
+        }
+    }
+
+    let mut tape = out.join("\n");
+    tape.push('\n');
+    Ok(tape)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tape() {
+        let tape = r#"Output demo.gif
+Set FontSize 32
+Set TypingSpeed 100ms
+Set Shell "bash"
+Type "echo 'hi'"
+Enter
+Sleep 1.5s
+Type@20ms `fast`
+Backspace 2
+Ctrl+C
+"#;
+        let script = parse_tape(tape).unwrap();
+        assert_eq!(
+            script.commands,
+            vec![
+                Command::SetSpeed(0.1),
+                Command::SetShell("bash".to_string()),
+                Command::Type("echo 'hi'\r".into()),
+                Command::Wait(Duration::from_millis(1500)),
+                Command::SetSpeed(0.02),
+                Command::Type("fast".into()),
+                Command::SetSpeed(0.1),
+                Command::Type("\x7f\x7f\x03".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_tape() {
+        let script = Script {
+            commands: vec![
+                Command::SetSpeed(0.08),
+                Command::Type("say \"hi\"\r\r".into()),
+                Command::Wait(Duration::from_millis(500)),
+                Command::Type("\x1bf\x12".into()),
+            ],
+        };
+        assert_eq!(
+            format_tape(&script).unwrap(),
+            "Set TypingSpeed 80ms\nType 'say \"hi\"'\nEnter 2\nSleep 500ms\nAlt+f\nCtrl+R\n"
+        );
+    }
+
+    #[test]
+    fn test_format_tape_rejects_unmapped_keys() {
+        let script = Script {
+            commands: vec![Command::Type("\x1b[15~".into())],
+        };
+        assert!(format_tape(&script).is_err());
+    }
+
+    #[test]
+    fn test_tape_round_trip() {
+        let script = crate::parser::parse_script(
+            "@ speed:0.05\n$ ls -la<ret>\n@ wait:2.0\n$ <C-d>\n",
+            &crate::parser::ParseOptions::default(),
+        )
+        .unwrap();
+        let reparsed = parse_tape(&format_tape(&script).unwrap()).unwrap();
+        assert_eq!(script.commands, reparsed.commands);
+    }
+}
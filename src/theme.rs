@@ -0,0 +1,233 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Themes for the HTML and PNG exports
+//!
+//! A theme is a palette plus how the terminal is framed: the padding around
+//! it, an optional window title bar and, for HTML, the font. A few themes
+//! are built in, and any other can be written as a TOML file, optionally
+//! starting from a built-in one with `base = "dracula"`
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::render::{Palette, Rgb};
+
+pub const BUILT_IN: &[&str] = &[
+    "default",
+    "dracula",
+    "gruvbox",
+    "nord",
+    "solarized-dark",
+    "solarized-light",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Theme {
+    pub palette: Palette,
+    // CSS font-family and size in pixels, for HTML; frames use the built-in font
+    pub font: String,
+    pub font_size: u16,
+    // Pixels between the terminal and the edge
+    pub padding: u16,
+    // A window title bar above the terminal, showing the recording's title
+    pub title_bar: bool,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            palette: Palette::default(),
+            font: "ui-monospace, Menlo, Consolas, monospace".to_string(),
+            font_size: 14,
+            padding: 8,
+            title_bar: false,
+        }
+    }
+}
+
+// A palette from `#rrggbb` colours: foreground, background, then the 16 ANSI colours
+fn palette(foreground: &str, background: &str, ansi: [&str; 16]) -> Palette {
+    // Only ever given the literals below
+    let rgb = |color: &str| parse_color(color).unwrap();
+    Palette {
+        foreground: rgb(foreground),
+        background: rgb(background),
+        ansi: ansi.map(rgb),
+    }
+}
+
+const SOLARIZED: [&str; 16] = [
+    "#073642", "#dc322f", "#859900", "#b58900", "#268bd2", "#d33682", "#2aa198", "#eee8d5",
+    "#002b36", "#cb4b16", "#586e75", "#657b83", "#839496", "#6c71c4", "#93a1a1", "#fdf6e3",
+];
+
+fn built_in(name: &str) -> Option<Theme> {
+    let palette = match name {
+        "default" => Palette::default(),
+        "dracula" => palette(
+            "#f8f8f2",
+            "#282a36",
+            [
+                "#21222c", "#ff5555", "#50fa7b", "#f1fa8c", "#bd93f9", "#ff79c6", "#8be9fd",
+                "#f8f8f2", "#6272a4", "#ff6e6e", "#69ff94", "#ffffa5", "#d6acff", "#ff92df",
+                "#a4ffff", "#ffffff",
+            ],
+        ),
+        "gruvbox" => palette(
+            "#ebdbb2",
+            "#282828",
+            [
+                "#282828", "#cc241d", "#98971a", "#d79921", "#458588", "#b16286", "#689d6a",
+                "#a89984", "#928374", "#fb4934", "#b8bb26", "#fabd2f", "#83a598", "#d3869b",
+                "#8ec07c", "#ebdbb2",
+            ],
+        ),
+        "nord" => palette(
+            "#d8dee9",
+            "#2e3440",
+            [
+                "#3b4252", "#bf616a", "#a3be8c", "#ebcb8b", "#81a1c1", "#b48ead", "#88c0d0",
+                "#e5e9f0", "#4c566a", "#bf616a", "#a3be8c", "#ebcb8b", "#81a1c1", "#b48ead",
+                "#8fbcbb", "#eceff4",
+            ],
+        ),
+        "solarized-dark" => palette("#839496", "#002b36", SOLARIZED),
+        "solarized-light" => palette("#657b83", "#fdf6e3", SOLARIZED),
+        _ => return None,
+    };
+    Some(Theme {
+        palette,
+        ..Theme::default()
+    })
+}
+
+fn parse_color(color: &str) -> Option<Rgb> {
+    let hex = color.strip_prefix('#').filter(|hex| hex.len() == 6)?;
+    let channel = |at: usize| u8::from_str_radix(hex.get(at..at + 2)?, 16).ok();
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
+
+// A theme file; anything left out comes from the base theme
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ThemeFile {
+    base: Option<String>,
+    foreground: Option<String>,
+    background: Option<String>,
+    // All 16, black to bright white
+    ansi: Option<Vec<String>>,
+    font: Option<String>,
+    font_size: Option<u16>,
+    padding: Option<u16>,
+    title_bar: Option<bool>,
+}
+
+impl ThemeFile {
+    fn resolve(self) -> Result<Theme, String> {
+        let base = self.base.as_deref().unwrap_or("default");
+        let mut theme = built_in(base).ok_or_else(|| format!("Unknown base theme '{}'", base))?;
+
+        let color = |color: &str| {
+            parse_color(color)
+                .ok_or_else(|| format!("Colours must be written as #rrggbb, not '{}'", color))
+        };
+        if let Some(foreground) = &self.foreground {
+            theme.palette.foreground = color(foreground)?;
+        }
+        if let Some(background) = &self.background {
+            theme.palette.background = color(background)?;
+        }
+        if let Some(ansi) = &self.ansi {
+            if ansi.len() != 16 {
+                return Err(format!("ansi needs all 16 colours, not {}", ansi.len()));
+            }
+            for (slot, value) in theme.palette.ansi.iter_mut().zip(ansi) {
+                *slot = color(value)?;
+            }
+        }
+        if let Some(font) = self.font {
+            theme.font = font;
+        }
+        if let Some(font_size) = self.font_size {
+            if font_size == 0 {
+                return Err("font_size must be more than zero".to_string());
+            }
+            theme.font_size = font_size;
+        }
+        theme.padding = self.padding.unwrap_or(theme.padding);
+        theme.title_bar = self.title_bar.unwrap_or(theme.title_bar);
+        Ok(theme)
+    }
+}
+
+impl Theme {
+    /// A built-in theme by name, or a theme file
+    pub fn load(spec: &str) -> Result<Self> {
+        if let Some(theme) = built_in(spec) {
+            return Ok(theme);
+        }
+        let path = Path::new(spec);
+        if path.extension().is_none_or(|ext| ext != "toml") && !path.exists() {
+            anyhow::bail!(
+                "Unknown theme '{}', expected one of {} or a .toml file",
+                spec,
+                BUILT_IN.join(", ")
+            );
+        }
+
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read theme file: {}", path.display()))?;
+        let file: ThemeFile = toml::from_str(&content)
+            .with_context(|| format!("Invalid theme file: {}", path.display()))?;
+        file.resolve()
+            .map_err(|e| anyhow::anyhow!("Invalid theme file: {}: {}", path.display(), e))
+    }
+
+    /// The title bar's colour, a little towards the foreground from the background
+    pub fn bar_color(&self) -> Rgb {
+        let (fg, bg) = (self.palette.foreground, self.palette.background);
+        let mix = |f: u8, b: u8| ((u16::from(f) + u16::from(b) * 7) / 8) as u8;
+        (mix(fg.0, bg.0), mix(fg.1, bg.1), mix(fg.2, bg.2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_theme_file() {
+        for name in BUILT_IN {
+            assert!(built_in(name).is_some(), "{}", name);
+        }
+
+        let file: ThemeFile =
+            toml::from_str("base = \"nord\"\nbackground = \"#101010\"\ntitle_bar = true\n")
+                .unwrap();
+        let theme = file.resolve().unwrap();
+        assert_eq!(theme.palette.background, (16, 16, 16));
+        assert_eq!(theme.palette.foreground, (0xd8, 0xde, 0xe9));
+        assert!(theme.title_bar);
+        assert_eq!(theme.padding, Theme::default().padding);
+
+        let file: ThemeFile = toml::from_str("foreground = \"red\"\n").unwrap();
+        assert!(file.resolve().is_err());
+        let file: ThemeFile = toml::from_str("ansi = [\"#000000\"]\n").unwrap();
+        assert!(file.resolve().is_err());
+        assert!(toml::from_str::<ThemeFile>("colour = \"#000000\"\n").is_err());
+    }
+}
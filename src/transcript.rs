@@ -0,0 +1,177 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Session output for a stdout that isn't a terminal
+//!
+//! Raw terminal output piped into a CI log is unreadable, so it's turned into
+//! plain lines instead: escape sequences are dropped, carriage returns and
+//! backspaces are applied to the line they're on, and each line is stamped
+//! with the time since the session started. Checkpoints start a foldable
+//! `::group::` in the log, as GitHub Actions understands
+
+use std::io::Write;
+use std::time::Instant;
+
+use crate::screen::Scan;
+
+pub struct Transcript {
+    started: Instant,
+    scan: Scan,
+    // After ESC and an intermediate byte, as in the `ESC ( B` charset
+    // selection, the byte that ends the sequence
+    intermediate: bool,
+    line: Vec<u8>,
+    // A CR not yet followed by LF, which starts the line over if more text comes
+    returned: bool,
+    in_group: bool,
+}
+
+impl Default for Transcript {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transcript {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            scan: Scan::default(),
+            intermediate: false,
+            line: Vec::new(),
+            returned: false,
+            in_group: false,
+        }
+    }
+
+    /// Add a chunk of session output, writing out any lines it completes
+    pub fn write(&mut self, bytes: &[u8], out: &mut impl Write) -> std::io::Result<()> {
+        for &byte in bytes {
+            if std::mem::take(&mut self.intermediate) {
+                continue;
+            }
+            let (ground, escape) = (self.scan.is_ground(), self.scan.is_escape());
+            if self.scan.next(byte).is_some() || !ground || byte == 0x1b {
+                self.intermediate = escape && (0x20..=0x2f).contains(&byte);
+                continue;
+            }
+            match byte {
+                b'\n' => {
+                    self.returned = false;
+                    self.end_line(out)?;
+                }
+                b'\r' => self.returned = true,
+                0x08 => {
+                    // Back over a whole character, not just its last byte
+                    while self.line.pop().is_some_and(|last| last & 0xc0 == 0x80) {}
+                }
+                b'\t' => self.line.push(byte),
+                0x00..=0x1f | 0x7f => {}
+                _ => {
+                    if std::mem::take(&mut self.returned) {
+                        self.line.clear();
+                    }
+                    self.line.push(byte);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn end_line(&mut self, out: &mut impl Write) -> std::io::Result<()> {
+        let line = String::from_utf8_lossy(&self.line);
+        writeln!(
+            out,
+            "[{:>8.3}s] {}",
+            self.started.elapsed().as_secs_f64(),
+            line.trim_end()
+        )?;
+        self.line.clear();
+        Ok(())
+    }
+
+    // Write out a line still being typed, such as the shell's prompt
+    fn flush_line(&mut self, out: &mut impl Write) -> std::io::Result<()> {
+        self.returned = false;
+        if self.line.iter().all(u8::is_ascii_whitespace) {
+            self.line.clear();
+            return Ok(());
+        }
+        self.end_line(out)
+    }
+
+    /// Start a group for the section from checkpoint `name`, ending the last one
+    pub fn section(&mut self, name: &str, out: &mut impl Write) -> std::io::Result<()> {
+        self.flush_line(out)?;
+        if self.in_group {
+            writeln!(out, "::endgroup::")?;
+        }
+        writeln!(out, "::group::{}", name)?;
+        self.in_group = true;
+        out.flush()
+    }
+
+    /// Write out what's left once the session has ended
+    pub fn finish(&mut self, out: &mut impl Write) -> std::io::Result<()> {
+        self.flush_line(out)?;
+        if std::mem::take(&mut self.in_group) {
+            writeln!(out, "::endgroup::")?;
+        }
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The lines written, without their timestamps
+    fn lines(out: &[u8]) -> Vec<&str> {
+        std::str::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| line.split_once("s] ").map_or(line, |(_, text)| text))
+            .collect()
+    }
+
+    #[test]
+    fn test_transcript() {
+        let mut transcript = Transcript::new();
+        let mut out = Vec::new();
+        transcript
+            .write(b"\x1b[1;32m$\x1b(B\x1b[m ls\x08\x08ls\r\n", &mut out)
+            .unwrap();
+        transcript.section("build", &mut out).unwrap();
+        transcript
+            .write(
+                "10%\r50%\r100%\r\ncafé\x08e\x1b]0;title\x07\r\n$ ".as_bytes(),
+                &mut out,
+            )
+            .unwrap();
+        transcript.finish(&mut out).unwrap();
+        assert_eq!(
+            lines(&out),
+            [
+                "$ ls",
+                "::group::build",
+                "100%",
+                "cafe",
+                "$",
+                "::endgroup::"
+            ]
+        );
+        assert!(out.starts_with(b"[   0.000s] $ ls\n"));
+    }
+}
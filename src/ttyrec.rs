@@ -0,0 +1,101 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! ttyrec recordings
+//!
+//! A ttyrec file is a series of frames, each a header of seconds,
+//! microseconds and length as little-endian 32-bit numbers followed by the
+//! output written at that time. Only output is kept, and not the terminal
+//! size, so recordings become casts rather than scripts
+
+use anyhow::{Context, Result, bail};
+
+use crate::cast::{Cast, CastEvent, take_utf8};
+
+const HEADER_LEN: usize = 12;
+
+/// Read a ttyrec recording made in a `cols` by `rows` terminal
+pub fn parse_ttyrec(data: &[u8], cols: u16, rows: u16) -> Result<Cast> {
+    let mut events = Vec::new();
+    let mut partial = Vec::new();
+    let mut start = None;
+    let mut rest = data;
+
+    while !rest.is_empty() {
+        if rest.len() < HEADER_LEN {
+            bail!("ttyrec file ends partway through a frame header");
+        }
+        let word =
+            |at: usize| u32::from_le_bytes([rest[at], rest[at + 1], rest[at + 2], rest[at + 3]]);
+        let (secs, micros, len) = (word(0), word(4), word(8) as usize);
+        let frame = rest
+            .get(HEADER_LEN..HEADER_LEN + len)
+            .context("ttyrec file ends partway through a frame")?;
+        rest = &rest[HEADER_LEN + len..];
+
+        // Frames carry the wall-clock time they were written
+        let time = f64::from(secs) + f64::from(micros) / 1_000_000.0;
+        let start = *start.get_or_insert(time);
+        partial.extend_from_slice(frame);
+        let data = take_utf8(&mut partial);
+        if !data.is_empty() {
+            events.push(CastEvent {
+                time: (time - start).max(0.0),
+                kind: "o".to_string(),
+                data,
+            });
+        }
+    }
+
+    Ok(Cast {
+        cols,
+        rows,
+        title: None,
+        events,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(secs: u32, micros: u32, data: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend(secs.to_le_bytes());
+        frame.extend(micros.to_le_bytes());
+        frame.extend((data.len() as u32).to_le_bytes());
+        frame.extend(data);
+        frame
+    }
+
+    #[test]
+    fn test_parse_ttyrec() {
+        let mut data = frame(1_700_000_000, 500_000, b"$ ");
+        // A character split between frames goes with the one that finishes it
+        data.extend(frame(1_700_000_001, 0, b"caf\xc3"));
+        data.extend(frame(1_700_000_001, 250_000, b"\xa9\r\n"));
+
+        let cast = parse_ttyrec(&data, 80, 24).unwrap();
+        let events: Vec<(f64, &str)> = cast
+            .events
+            .iter()
+            .map(|e| (e.time, e.data.as_str()))
+            .collect();
+        assert_eq!(events, [(0.0, "$ "), (0.5, "caf"), (0.75, "é\r\n")]);
+
+        data.extend(&frame(1_700_000_002, 0, b"more")[..14]);
+        assert!(parse_ttyrec(&data, 80, 24).is_err());
+    }
+}
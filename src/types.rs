@@ -15,38 +15,699 @@
 
 //! Core types for quipu script execution
 
+use crate::key::Keys;
+use encoding_rs::{Encoding, UTF_8};
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Command {
     SetSpeed(f64),
+    // Speed at the first and last keys of each line, changing steadily in between
+    SetRamp(f64, f64),
     SetJitter(f64),
-    Wait(Duration),
+    // Variation in `@ wait` pauses, as a fraction of each
+    SetWaitJitter(f64),
+    // Extra seconds to pause after each space typed
+    SetWordPause(f64),
+    Wait(#[serde(with = "duration_secs")] Duration),
+    // Pause for a random time between the two bounds
+    WaitRandom(
+        #[serde(with = "duration_secs")] Duration,
+        #[serde(with = "duration_secs")] Duration,
+    ),
     // Must come before any Type commands
     SetShell(String),
+    // Arguments the shell is started with, e.g. `-l -i`
+    SetShellArgs(Vec<String>),
+    // Built-in shell setup from `profile`, e.g. `fish-clean`
+    SetShellProfile(String),
+    // TERM for the session, e.g. `tmux-256color`
+    SetTerm(String),
     // Must come before PTY creation
     SetSize(u16, u16),
-    Type(String),
+    // Size of the whole terminal in pixels, for programs that draw images
+    SetPixelSize(u16, u16),
+    // Change the terminal size mid-script
+    Resize(u16, u16),
+    SetCursorKeys(CursorKeys),
+    // Type CJK text in composed chunks, as through an input method
+    SetIme(bool),
+    // Character encoding label for typed text, e.g. `latin1` or `shift_jis`
+    SetEncoding(String),
+    SetNewline(Newline),
+    // What line breaks within typed text do
+    SetMultiline(Multiline),
+    // Keep typed commands out of the shell's history
+    SetNoHistory(NoHistory),
+    // A point playback can be resumed from with --resume-from
+    Checkpoint(String),
+    // Caption shown until the next one; empty clears it
+    Caption(String),
+    // A `#!` note at the end of the `$` line that follows, not typed
+    Note(String),
+    // `@ title:`, `@ author:` or `@ description:`, which don't affect playback
+    Meta(MetaField, String),
+    // Versions of quipu the script is written for, e.g. `>=0.4`
+    Requires(String),
+    // `@ choose` block, of which one branch is played per run
+    Choose(Vec<Vec<Command>>),
+    // `@ retry` block, played again up to a number of times when an expect in
+    // it fails, with the wait before each retry growing by a factor
+    Retry(u32, f64, Vec<Command>),
+    // `@ on-error` block, played when a command fails
+    OnError(Vec<Command>),
+    // `@ finally` block, played when the script ends, whether or not it failed
+    Finally(Vec<Command>),
+    // Host command, with the variable its output is stored in
+    Exec(String, Option<String>),
+    // Host command that is left running in the background
+    ExecAsync(String),
+    // Shell script run on the host before the session starts
+    Setup(String),
+    // Shell script run on the host after the session ends, however it ended
+    Teardown(String),
+    // Lua program run on the host, whose printed script lines are played in its place
+    Lua(String),
+    // URL that is sent a POST when playback gets here
+    Webhook(String),
+    // File the session's text, scrollback included, is written to
+    DumpScrollback(String),
+    // Sound played on the host for each key typed; None turns it off
+    SetSound(Option<String>),
+    // Sound file started on the host, playing on while playback continues
+    Audio(String),
+    // Text spoken on the host, waiting until it's been said when set
+    Say(String, bool),
+    // A directive from the config file's `[directives]`, with its arguments
+    Plugin(String, String),
+    // How long expects wait before failing playback
+    SetExpectTimeout(#[serde(with = "duration_secs")] Duration),
+    // How long each command after it may take before failing playback; zero for no limit
+    SetCommandTimeout(#[serde(with = "duration_secs")] Duration),
+    // Wait for output matching a regex, with the variables its groups are stored in
+    Expect(String, Vec<String>),
+    // Wait until the text on screen matches a regex
+    ExpectScreen(String),
+    // Wait until text is on screen at a row and column, counted from 1
+    ExpectCell(u16, u16, String),
+    // Wait for the session to copy something with OSC 52, storing it in a variable if named
+    ExpectClipboard(Option<String>),
+    // Regex the end of the shell's prompt matches, for `@ waitprompt`
+    SetPromptPattern(String),
+    // Wait until the shell has drawn its prompt after the last key typed
+    WaitPrompt,
+    // Wait until a file exists, a port accepts connections or a URL answers on the host
+    WaitFor(WaitCondition, String),
+    // Session the commands after it are played into, started on first use
+    Session(String),
+    // How sessions share the real terminal once there's more than one
+    SetLayout(PaneLayout),
+    // Clear the screen, and the scrollback too when set
+    Clear(bool),
+    // Reset the terminal with RIS
+    Reset,
+    Type(Keys),
+    // Type what's on the system clipboard, with --allow-clipboard
+    TypeClipboard,
+}
+
+// The groups in an expect's pattern. It's parsed rather than compiled, which
+// takes far longer for scripts with thousands of expects, so a pattern too big
+// to compile is only caught when it's played
+fn expect_groups(pattern: &str) -> Result<usize, String> {
+    regex_syntax::Parser::new()
+        .parse(pattern)
+        .map(|hir| hir.properties().explicit_captures_len())
+        .map_err(|e| format!("Invalid expect pattern '{}': {}", pattern, e))
+}
+
+impl Command {
+    /// Reject values that would make playback misbehave
+    pub fn check(&self) -> Result<(), String> {
+        match self {
+            Command::SetSpeed(speed) if !(speed.is_finite() && *speed >= 0.0) => {
+                Err(format!("Speed can't be negative: {}", speed))
+            }
+            Command::SetWordPause(pause) if !(pause.is_finite() && *pause >= 0.0) => {
+                Err(format!("Word pause can't be negative: {}", pause))
+            }
+            Command::SetRamp(start, end)
+                if !(start.is_finite() && *start >= 0.0 && end.is_finite() && *end >= 0.0) =>
+            {
+                Err(format!("Ramp speeds can't be negative: {}..{}", start, end))
+            }
+            Command::SetJitter(jitter) | Command::SetWaitJitter(jitter)
+                if !(0.0..=1.0).contains(jitter) =>
+            {
+                Err(format!("Jitter must be between 0.0 and 1.0: {}", jitter))
+            }
+            Command::SetSize(cols, rows) | Command::Resize(cols, rows)
+                if *cols == 0 || *rows == 0 =>
+            {
+                Err(format!(
+                    "Terminal size must be at least 1x1: {}x{}",
+                    cols, rows
+                ))
+            }
+            Command::SetPixelSize(width, height) if *width == 0 || *height == 0 => Err(format!(
+                "Pixel size must be at least 1x1: {}x{}",
+                width, height
+            )),
+            Command::WaitRandom(min, max) if min > max => {
+                Err("wait-random minimum is longer than its maximum".to_string())
+            }
+            Command::SetTerm(term) if term.is_empty() || term.contains(char::is_whitespace) => {
+                Err(format!("Not a terminal type: '{}'", term))
+            }
+            Command::SetEncoding(label) if text_encoding(label).is_none() => {
+                Err(format!("Unknown encoding: '{}'", label))
+            }
+            Command::Exec(command, _) | Command::ExecAsync(command) if command.is_empty() => {
+                Err("exec needs a command to run".to_string())
+            }
+            Command::Say(text, _) if text.is_empty() => Err("say needs text to speak".to_string()),
+            Command::Webhook(url) => crate::webhook::check_url(url),
+            Command::Requires(requirement) => crate::version::check(requirement),
+            Command::WaitFor(condition, target) => crate::condition::check(*condition, target),
+            Command::SetShellProfile(name) => crate::profile::check_name(name),
+            Command::ExpectScreen(pattern) => expect_groups(pattern).map(|_| ()),
+            Command::Expect(pattern, names) => {
+                // Without groups, the one variable gets the whole match
+                let groups = expect_groups(pattern)?.max(1);
+                if names.len() > groups {
+                    return Err(format!(
+                        "'{}' has {} groups to store, not {}",
+                        pattern,
+                        groups,
+                        names.len()
+                    ));
+                }
+                Ok(())
+            }
+            Command::ExpectClipboard(Some(name)) if !crate::parser::is_variable_name(name) => {
+                Err(format!("Invalid variable name '{}'", name))
+            }
+            Command::Session(name) if !crate::parser::is_variable_name(name) => {
+                Err(format!("Invalid session name '{}'", name))
+            }
+            Command::ExpectCell(row, col, _) if *row == 0 || *col == 0 => {
+                Err(format!("Rows and columns count from 1: {}:{}", row, col))
+            }
+            Command::SetPromptPattern(pattern) => regex::Regex::new(pattern)
+                .map(|_| ())
+                .map_err(|e| format!("Invalid prompt pattern '{}': {}", pattern, e)),
+            Command::ExpectCell(_, _, text) if text.is_empty() => {
+                Err("expect-cell needs text to look for".to_string())
+            }
+            Command::Choose(branches) => {
+                branches
+                    .iter()
+                    .flatten()
+                    .try_for_each(|command| match command {
+                        Command::Setup(_)
+                        | Command::Teardown(_)
+                        | Command::Checkpoint(_)
+                        | Command::OnError(_)
+                        | Command::Finally(_) => Err(
+                            "@ setup, @ teardown, @ checkpoint, @ on-error and @ finally can't be inside @ choose"
+                                .to_string(),
+                        ),
+                        command => command.check(),
+                    })
+            }
+            Command::Retry(0, _, _) => Err("@ retry needs at least 1 retry".to_string()),
+            Command::Retry(_, backoff, _) if !(backoff.is_finite() && *backoff >= 1.0) => Err(
+                format!("Backoff must be a factor of at least 1.0: {}", backoff),
+            ),
+            Command::Retry(_, _, commands) => {
+                commands.iter().try_for_each(|command| match command {
+                    Command::Setup(_)
+                    | Command::Teardown(_)
+                    | Command::Checkpoint(_)
+                    | Command::OnError(_)
+                    | Command::Finally(_) => Err(
+                        "@ setup, @ teardown, @ checkpoint, @ on-error and @ finally can't be inside @ retry"
+                            .to_string(),
+                    ),
+                    command => command.check(),
+                })
+            }
+            Command::OnError(commands) | Command::Finally(commands) => {
+                commands.iter().try_for_each(|command| match command {
+                    Command::Setup(_)
+                    | Command::Teardown(_)
+                    | Command::Checkpoint(_)
+                    | Command::OnError(_)
+                    | Command::Finally(_) => Err(
+                        "@ setup, @ teardown, @ checkpoint, @ on-error and @ finally can't be inside @ on-error or @ finally"
+                            .to_string(),
+                    ),
+                    command => command.check(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// How arrow keys are encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CursorKeys {
+    // Follow the mode the running program has set (DECCKM)
+    #[default]
+    Auto,
+    Normal,
+    Application,
+}
+
+// What `<ret>` sends
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Newline {
+    #[default]
+    Cr,
+    Crlf,
+    Lf,
+}
+
+impl Newline {
+    pub fn sequence(self) -> &'static str {
+        match self {
+            Newline::Cr => "\r",
+            Newline::Crlf => "\r\n",
+            Newline::Lf => "\n",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Newline::Cr => "cr",
+            Newline::Crlf => "crlf",
+            Newline::Lf => "lf",
+        }
+    }
+}
+
+// How line breaks within typed text, as from a JSON or YAML script, are sent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Multiline {
+    // As a line feed byte
+    #[default]
+    Literal,
+    // As Enter, following `@ newline`
+    Enter,
+    // As a bracketed paste when the program has turned that on, or as Enter
+    Paste,
+}
+
+impl Multiline {
+    pub fn name(self) -> &'static str {
+        match self {
+            Multiline::Literal => "literal",
+            Multiline::Enter => "enter",
+            Multiline::Paste => "paste",
+        }
+    }
+}
+
+// Descriptive details a script can declare about itself
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetaField {
+    Title,
+    Author,
+    Description,
+}
+
+impl MetaField {
+    pub fn name(self) -> &'static str {
+        match self {
+            MetaField::Title => "title",
+            MetaField::Author => "author",
+            MetaField::Description => "description",
+        }
+    }
+}
+
+// How several sessions are shown: one at a time, side by side, or one above another
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaneLayout {
+    Single,
+    #[default]
+    Horizontal,
+    Vertical,
+}
+
+impl PaneLayout {
+    pub fn name(self) -> &'static str {
+        match self {
+            PaneLayout::Single => "single",
+            PaneLayout::Horizontal => "horizontal",
+            PaneLayout::Vertical => "vertical",
+        }
+    }
+}
+
+// What `@ waitfile:`, `@ waitport:` and `@ waiturl:` wait for on the host
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WaitCondition {
+    File,
+    Port,
+    Url,
+}
+
+impl WaitCondition {
+    pub fn name(self) -> &'static str {
+        match self {
+            WaitCondition::File => "waitfile",
+            WaitCondition::Port => "waitport",
+            WaitCondition::Url => "waiturl",
+        }
+    }
+}
+
+// What a script says about itself, for cast headers, page titles and `quipu info`
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ScriptMeta {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub description: Option<String>,
+}
+
+// How `@ no-history` keeps typed commands out of the shell's history
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoHistory {
+    // Start the shell with HISTFILE=/dev/null
+    Histfile,
+    // Type a space before each command, for HISTCONTROL=ignorespace
+    Space,
+}
+
+impl NoHistory {
+    pub fn name(self) -> &'static str {
+        match self {
+            NoHistory::Histfile => "histfile",
+            NoHistory::Space => "space",
+        }
+    }
+}
+
+// How the time between keystrokes is chosen
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Timing {
+    // Every key takes `speed`
+    #[default]
+    Constant,
+    // Each line starts at the first time and ends at the second
+    Ramp(f64, f64),
+}
+
+impl Timing {
+    /// Seconds to wait after key `index` of the `count` in a line
+    pub fn delay(self, speed: f64, index: usize, count: usize) -> f64 {
+        match self {
+            Timing::Constant => speed,
+            Timing::Ramp(start, end) => {
+                let progress = if count > 1 {
+                    index as f64 / (count - 1) as f64
+                } else {
+                    0.0
+                };
+                start + (end - start) * progress
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct PlaybackConfig {
     // Base time between keystrokes in seconds
     pub speed: f64,
+    pub timing: Timing,
     // Jitter as a fraction (0.0 to 1.0) of speed
     pub jitter: f64,
+    // Jitter as a fraction of each `@ wait`
+    pub wait_jitter: f64,
+    // Extra seconds after each space typed
+    pub word_pause: f64,
+    pub cursor_keys: CursorKeys,
+    pub ime: bool,
+    pub encoding: &'static Encoding,
+    pub newline: Newline,
+    pub multiline: Multiline,
+    pub no_history: Option<NoHistory>,
+    // How long `@ expect-screen` and `@ expect-cell` wait
+    pub expect_timeout: Duration,
+    // How long any other command may take, from `@ timeout`
+    pub command_timeout: Option<Duration>,
+    // The pause before a `@ retry` block's first retry, multiplied by its
+    // backoff for each one after
+    pub retry_delay: Duration,
+    // What the end of the shell's prompt looks like, for `@ waitprompt`
+    pub prompt_pattern: String,
 }
 
+/// The ends of the default bash, zsh and fish prompts, and root's
+pub const DEFAULT_PROMPT_PATTERN: &str = r"[$%>#] $";
+
 impl Default for PlaybackConfig {
     fn default() -> Self {
         Self {
-            speed: 0.1,  // 100ms per keystroke
+            speed: 0.1, // 100ms per keystroke
+            timing: Timing::Constant,
             jitter: 0.0, // No jitter
+            wait_jitter: 0.0,
+            word_pause: 0.0,
+            cursor_keys: CursorKeys::Auto,
+            ime: false,
+            encoding: UTF_8,
+            newline: Newline::Cr,
+            multiline: Multiline::Literal,
+            no_history: None,
+            expect_timeout: Duration::from_secs(10),
+            command_timeout: None,
+            retry_delay: Duration::from_secs(1),
+            prompt_pattern: DEFAULT_PROMPT_PATTERN.to_string(),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Script {
     pub commands: Vec<Command>,
 }
+
+/// Look up an encoding that typed text can be converted to
+pub fn text_encoding(label: &str) -> Option<&'static Encoding> {
+    let encoding = Encoding::for_label(label.as_bytes())?;
+    // UTF-16 and the replacement encoding can only be decoded
+    (encoding.output_encoding() == encoding).then_some(encoding)
+}
+
+// Session settings declared before the first Type command
+#[derive(Debug, Default, PartialEq)]
+pub struct Preamble {
+    pub shell: Option<String>,
+    pub shell_args: Option<Vec<String>>,
+    pub shell_profile: Option<String>,
+    pub term: Option<String>,
+    pub size: Option<(u16, u16)>,
+    pub pixel_size: Option<(u16, u16)>,
+    pub no_history: Option<NoHistory>,
+    pub layout: Option<PaneLayout>,
+}
+
+// Whether `test` holds for any command, including blocks and the commands inside them
+fn any_command(commands: &[Command], test: &impl Fn(&Command) -> bool) -> bool {
+    commands.iter().any(|command| {
+        test(command)
+            || match command {
+                Command::Choose(branches) => {
+                    branches.iter().any(|branch| any_command(branch, test))
+                }
+                Command::Retry(_, _, commands)
+                | Command::OnError(commands)
+                | Command::Finally(commands) => any_command(commands, test),
+                _ => false,
+            }
+    })
+}
+
+impl Script {
+    pub fn has_captions(&self) -> bool {
+        any_command(&self.commands, &|command| {
+            matches!(command, Command::Caption(_))
+        })
+    }
+
+    pub fn has_notes(&self) -> bool {
+        any_command(&self.commands, &|command| {
+            matches!(command, Command::Note(_))
+        })
+    }
+
+    /// Whether there's a checkpoint `name` outside any block, where playback
+    /// can be resumed from it
+    pub fn has_checkpoint(&self, name: &str) -> bool {
+        self.commands
+            .iter()
+            .any(|command| matches!(command, Command::Checkpoint(n) if n == name))
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        self.commands.iter().try_for_each(Command::check)
+    }
+
+    pub fn has_choices(&self) -> bool {
+        self.commands
+            .iter()
+            .any(|command| matches!(command, Command::Choose(_)))
+    }
+
+    /// The script with each `@ choose` block replaced by the branch `pick` returns,
+    /// given the number of branches
+    pub fn resolve_choices(&self, pick: &mut impl FnMut(usize) -> usize) -> Script {
+        fn resolve(commands: &[Command], pick: &mut impl FnMut(usize) -> usize) -> Vec<Command> {
+            let mut out = Vec::new();
+            for command in commands {
+                match command {
+                    Command::Choose(branches) if branches.is_empty() => {}
+                    Command::Choose(branches) => {
+                        let branch = &branches[pick(branches.len())];
+                        out.extend(resolve(branch, pick));
+                    }
+                    Command::Retry(retries, backoff, commands) => {
+                        out.push(Command::Retry(*retries, *backoff, resolve(commands, pick)));
+                    }
+                    Command::OnError(commands) => {
+                        out.push(Command::OnError(resolve(commands, pick)))
+                    }
+                    Command::Finally(commands) => {
+                        out.push(Command::Finally(resolve(commands, pick)))
+                    }
+                    command => out.push(command.clone()),
+                }
+            }
+            out
+        }
+
+        Script {
+            commands: resolve(&self.commands, pick),
+        }
+    }
+
+    pub fn has_retries(&self) -> bool {
+        any_command(&self.commands, &|command| {
+            matches!(command, Command::Retry(_, _, _))
+        })
+    }
+
+    /// The script with each `@ retry` block replaced by the commands in it,
+    /// for exports that play them once
+    pub fn without_retries(&self) -> Script {
+        fn unroll(commands: &[Command]) -> Vec<Command> {
+            let mut out = Vec::new();
+            for command in commands {
+                match command {
+                    Command::Retry(_, _, commands) => out.extend(unroll(commands)),
+                    Command::Choose(branches) => out.push(Command::Choose(
+                        branches.iter().map(|branch| unroll(branch)).collect(),
+                    )),
+                    Command::OnError(commands) => out.push(Command::OnError(unroll(commands))),
+                    Command::Finally(commands) => out.push(Command::Finally(unroll(commands))),
+                    command => out.push(command.clone()),
+                }
+            }
+            out
+        }
+
+        Script {
+            commands: unroll(&self.commands),
+        }
+    }
+
+    /// Metadata from anywhere in the script, the last of each field winning
+    pub fn meta(&self) -> ScriptMeta {
+        let mut meta = ScriptMeta::default();
+        for command in &self.commands {
+            if let Command::Meta(field, text) = command {
+                let value = match field {
+                    MetaField::Title => &mut meta.title,
+                    MetaField::Author => &mut meta.author,
+                    MetaField::Description => &mut meta.description,
+                };
+                *value = Some(text.clone());
+            }
+        }
+        meta
+    }
+
+    pub fn preamble(&self) -> Preamble {
+        let mut preamble = Preamble::default();
+        for command in &self.commands {
+            match command {
+                Command::SetShell(s) => preamble.shell = Some(s.clone()),
+                Command::SetShellArgs(args) => preamble.shell_args = Some(args.clone()),
+                Command::SetShellProfile(name) => preamble.shell_profile = Some(name.clone()),
+                Command::SetTerm(term) => preamble.term = Some(term.clone()),
+                Command::SetSize(c, r) => preamble.size = Some((*c, *r)),
+                Command::SetPixelSize(w, h) => preamble.pixel_size = Some((*w, *h)),
+                Command::SetNoHistory(mode) => preamble.no_history = Some(*mode),
+                Command::SetLayout(layout) => preamble.layout = Some(*layout),
+                // Stop looking once we hit a Type command
+                Command::Type(_) => break,
+                _ => {}
+            }
+        }
+        preamble
+    }
+}
+
+// Durations are written as fractional seconds to match the text format
+mod duration_secs {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(duration.as_secs_f64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let secs = f64::deserialize(deserializer)?;
+        Duration::try_from_secs_f64(secs).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_without_retries() {
+        let script = Script {
+            commands: vec![
+                Command::Type("make\r".into()),
+                Command::OnError(vec![Command::Retry(
+                    2,
+                    2.0,
+                    vec![Command::Type("make clean\r".into())],
+                )]),
+            ],
+        };
+        assert!(script.has_retries());
+        assert_eq!(
+            script.without_retries().commands,
+            [
+                Command::Type("make\r".into()),
+                Command::OnError(vec![Command::Type("make clean\r".into())]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! script(1) recordings
+//!
+//! `script --timing` writes a typescript of the session's output and a
+//! timing file saying how long after the previous write each piece of it
+//! came. The older timing format has a delay and a byte count on each line;
+//! util-linux's newer one starts each line with its stream, `O` for output,
+//! `I` for input, `S` for signals such as resizes and `H` for headers. When
+//! input is logged too, with `script --log-io`, both streams are in the one
+//! typescript, in the order the timing file gives
+
+use anyhow::{Context, Result, bail};
+
+use crate::cast::{Cast, CastEvent, take_utf8};
+
+const DEFAULT_SIZE: (u16, u16) = (80, 24);
+
+// The size script(1) noted in the typescript's first line, e.g.
+// `Script started on ... [TERM="xterm" COLUMNS="80" LINES="24"]`
+fn header_size(header: &str) -> (Option<u16>, Option<u16>) {
+    let value = |name: &str| -> Option<u16> {
+        let start = header.find(&format!("{}=\"", name))? + name.len() + 2;
+        let len = header[start..].find('"')?;
+        header[start..start + len].parse().ok()
+    };
+    (value("COLUMNS"), value("LINES"))
+}
+
+// `ROWS=24 COLS=80` from a SIGWINCH line
+fn resize_size(rest: &str) -> Option<(u16, u16)> {
+    let field = |name: &str| -> Option<u16> {
+        rest.split([' ', ';'])
+            .find_map(|part| part.strip_prefix(name)?.strip_prefix('='))?
+            .parse()
+            .ok()
+    };
+    Some((field("COLS")?, field("ROWS")?))
+}
+
+/// Read a typescript and its timing file
+pub fn parse_typescript(typescript: &[u8], timing: &str) -> Result<Cast> {
+    // The "Script started" line isn't covered by the timing file
+    let mut data = typescript;
+    let (mut cols, mut rows) = (None, None);
+    if data.starts_with(b"Script started") {
+        let end = data
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(data.len(), |i| i + 1);
+        (cols, rows) = header_size(&String::from_utf8_lossy(&data[..end]));
+        data = &data[end..];
+    }
+
+    let mut events = Vec::new();
+    let (mut output, mut input) = (Vec::new(), Vec::new());
+    let mut time = 0.0;
+    for (line_num, line) in timing.lines().enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+        let invalid = || format!("Invalid timing on line {}: '{}'", line_num + 1, line);
+
+        // Older timing files have no stream
+        let (stream, fields) = match fields[0].parse::<f64>() {
+            Ok(_) => ("O", &fields[..]),
+            Err(_) => (fields[0], &fields[1..]),
+        };
+        let delay: f64 = fields
+            .first()
+            .and_then(|delay| delay.parse().ok())
+            .with_context(invalid)?;
+        time += delay;
+
+        match stream {
+            "O" | "I" => {
+                let count: usize = fields
+                    .get(1)
+                    .and_then(|count| count.parse().ok())
+                    .with_context(invalid)?;
+                let Some(bytes) = data.get(..count) else {
+                    bail!("Typescript ends before the timing file does");
+                };
+                data = &data[count..];
+                let (kind, partial) = match stream {
+                    "O" => ("o", &mut output),
+                    _ => ("i", &mut input),
+                };
+                partial.extend_from_slice(bytes);
+                let text = take_utf8(partial);
+                if !text.is_empty() {
+                    events.push(CastEvent {
+                        time,
+                        kind: kind.to_string(),
+                        data: text,
+                    });
+                }
+            }
+            "S" => {
+                if let Some((cols, rows)) = resize_size(&fields[1..].join(" ")) {
+                    events.push(CastEvent {
+                        time,
+                        kind: "r".to_string(),
+                        data: format!("{}x{}", cols, rows),
+                    });
+                }
+            }
+            "H" => match (fields.get(1), fields.get(2).and_then(|n| n.parse().ok())) {
+                (Some(&"COLUMNS"), Some(n)) => cols = Some(n),
+                (Some(&"LINES"), Some(n)) => rows = Some(n),
+                _ => {}
+            },
+            _ => bail!(invalid()),
+        }
+    }
+
+    Ok(Cast {
+        cols: cols.unwrap_or(DEFAULT_SIZE.0),
+        rows: rows.unwrap_or(DEFAULT_SIZE.1),
+        title: None,
+        events,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_typescript() {
+        // The older format, output only
+        let typescript = b"Script started on 2024-01-01 [TERM=\"xterm\" COLUMNS=\"100\" LINES=\"30\"]\n$ ls\r\nfile\r\n";
+        let cast = parse_typescript(typescript, "0.5 2\n1.25 4\n0.1 6\n").unwrap();
+        assert_eq!((cast.cols, cast.rows), (100, 30));
+        let events: Vec<(f64, &str, &str)> = cast
+            .events
+            .iter()
+            .map(|e| (e.time, e.kind.as_str(), e.data.as_str()))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (0.5, "o", "$ "),
+                (1.75, "o", "ls\r\n"),
+                (1.85, "o", "file\r\n")
+            ]
+        );
+
+        // The newer format, with input logged too
+        let timing = "H 0.000000 COLUMNS 90\nH 0.000000 LINES 20\nO 0.1 2\nI 1.0 1\nO 0.01 1\nS 0.5 SIGWINCH ROWS=25 COLS=81\n";
+        let cast = parse_typescript(b"$ ll", timing).unwrap();
+        assert_eq!((cast.cols, cast.rows), (90, 20));
+        let kinds: Vec<(&str, &str)> = cast
+            .events
+            .iter()
+            .map(|e| (e.kind.as_str(), e.data.as_str()))
+            .collect();
+        assert_eq!(kinds, [("o", "$ "), ("i", "l"), ("o", "l"), ("r", "81x25")]);
+
+        assert!(parse_typescript(b"$ ", "0.1 5\n").is_err());
+        assert!(parse_typescript(b"$ ", "X 0.1 2\n").is_err());
+    }
+}
@@ -0,0 +1,84 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Typing profiles for `@ profile:`
+//!
+//! A profile is a set of typing settings to switch to in one line, such as
+//! quick and even for code and slower with pauses between words for prose.
+//! `code` and `prose` are built in, and the config file's `[profiles]` table
+//! can add more or replace them. A profile's line is read as the directives
+//! it stands for, so exports and estimates treat it like any other
+
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::types::Command;
+
+/// Typing settings switched to together; any left out stay as they are
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TypingProfile {
+    pub speed: Option<f64>,
+    // Speeds at the first and last keys of each line, as for `@ ramp`
+    pub ramp: Option<(f64, f64)>,
+    pub jitter: Option<f64>,
+    // Extra pause after each space, as for `@ word-pause`
+    pub word_pause: Option<f64>,
+}
+
+/// The built-in profiles, by name
+pub const BUILT_IN: &[(&str, TypingProfile)] = &[
+    (
+        "code",
+        TypingProfile {
+            speed: Some(0.05),
+            ramp: None,
+            jitter: Some(0.2),
+            word_pause: Some(0.0),
+        },
+    ),
+    (
+        "prose",
+        TypingProfile {
+            speed: Some(0.08),
+            ramp: None,
+            jitter: Some(0.4),
+            word_pause: Some(0.12),
+        },
+    ),
+];
+
+/// The profile called `name`, looking in `custom` before the built-in ones
+pub fn find(name: &str, custom: &HashMap<String, TypingProfile>) -> Option<TypingProfile> {
+    custom.get(name).cloned().or_else(|| {
+        BUILT_IN
+            .iter()
+            .find(|(built_in, _)| *built_in == name)
+            .map(|(_, profile)| profile.clone())
+    })
+}
+
+impl TypingProfile {
+    /// The directives the profile stands for
+    pub fn commands(&self) -> Vec<Command> {
+        // A speed goes first, since it turns off any earlier ramp
+        let mut commands = Vec::new();
+        commands.extend(self.speed.map(Command::SetSpeed));
+        commands.extend(self.ramp.map(|(start, end)| Command::SetRamp(start, end)));
+        commands.extend(self.jitter.map(Command::SetJitter));
+        commands.extend(self.word_pause.map(Command::SetWordPause));
+        commands
+    }
+}
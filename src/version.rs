@@ -0,0 +1,97 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Version requirements given with `@ quipu:`
+//!
+//! A script that says which versions of quipu it was written for fails
+//! before anything runs on one it wasn't, rather than halfway through on a
+//! directive an older quipu doesn't know. A requirement is a comma-separated
+//! list of comparisons such as `>=0.4` or `>=0.4, <0.6`, and a version on its
+//! own is the oldest that will do
+
+/// The version of quipu running
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Major, minor and patch, with any missing left at 0
+type Version = (u64, u64, u64);
+
+fn parse_version(text: &str) -> Option<Version> {
+    // Pre-release and build suffixes don't change what a script can use
+    let core = text.split(['-', '+']).next().unwrap_or_default();
+    let mut parts = core.split('.').map(|part| part.parse::<u64>().ok());
+    let major = parts.next()??;
+    let minor = parts.next().unwrap_or(Some(0))?;
+    let patch = parts.next().unwrap_or(Some(0))?;
+    parts.next().is_none().then_some((major, minor, patch))
+}
+
+// Whether `version` meets every comparison in `requirement`
+fn satisfies(requirement: &str, version: Version) -> Result<bool, String> {
+    let mut met = true;
+    for comparison in requirement.split(',') {
+        let comparison = comparison.trim();
+        let op_len = comparison
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(comparison.len());
+        let (op, wanted) = comparison.split_at(op_len);
+        let wanted = parse_version(wanted)
+            .ok_or_else(|| format!("Invalid quipu version requirement: '{}'", requirement))?;
+        met &= match op.trim() {
+            ">=" | "" => version >= wanted,
+            ">" => version > wanted,
+            "<=" => version <= wanted,
+            "<" => version < wanted,
+            "=" => version == wanted,
+            _ => {
+                return Err(format!(
+                    "Invalid quipu version requirement: '{}'",
+                    requirement
+                ));
+            }
+        };
+    }
+    Ok(met)
+}
+
+/// Check that this quipu meets `requirement`
+pub fn check(requirement: &str) -> Result<(), String> {
+    let version = parse_version(VERSION).expect("package version");
+    match satisfies(requirement, version)? {
+        true => Ok(()),
+        false => Err(format!(
+            "This script needs quipu {}, but this is quipu {}",
+            requirement, VERSION
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_satisfies() {
+        assert_eq!(satisfies(">=0.4", (0, 4, 0)), Ok(true));
+        assert_eq!(satisfies("0.4", (0, 3, 9)), Ok(false));
+        assert_eq!(satisfies(">=0.4, <0.6", (0, 5, 2)), Ok(true));
+        assert_eq!(satisfies(">=0.4, <0.6", (0, 6, 0)), Ok(false));
+        assert_eq!(satisfies("=1.2.3", (1, 2, 3)), Ok(true));
+        assert!(satisfies("~0.4", (0, 4, 0)).is_err());
+        assert!(satisfies(">=0.4.x", (0, 4, 0)).is_err());
+        assert!(satisfies("", (0, 4, 0)).is_err());
+        assert!(check(&format!(">={}", VERSION)).is_ok());
+        assert!(check(">=9999").is_err());
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Script file watching for `--watch`
+//!
+//! Polls the file's modification time, which works the same on every
+//! platform and for editors that replace the file rather than writing it
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::time::sleep;
+
+use crate::playback;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Editors often write a file in several steps, so let them finish
+const SETTLE_TIME: Duration = Duration::from_millis(100);
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+pub struct Watcher {
+    path: PathBuf,
+    last_modified: Option<SystemTime>,
+}
+
+impl Watcher {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            last_modified: modified(path),
+        }
+    }
+
+    // Whether the file has been written since it was last seen; one that's
+    // gone, perhaps while an editor replaces it, hasn't changed yet
+    fn is_modified(&self) -> bool {
+        let current = modified(&self.path);
+        current.is_some() && current != self.last_modified
+    }
+
+    /// Wait for the file to change, returning false if playback is interrupted first
+    pub async fn changed(&mut self) -> bool {
+        loop {
+            if playback::interrupted() {
+                return false;
+            }
+
+            if self.is_modified() {
+                sleep(SETTLE_TIME).await;
+                self.last_modified = modified(&self.path);
+                return true;
+            }
+
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_changed() {
+        let path = std::env::temp_dir().join(format!("quipu-watch-{}.qp", std::process::id()));
+        std::fs::write(&path, "$ ls<ret>\n").unwrap();
+        let mut watcher = Watcher::new(&path);
+        assert!(!watcher.is_modified());
+
+        // Set ahead rather than left to the clock, which may not have moved on
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        let later = modified(&path).unwrap() + Duration::from_secs(10);
+        file.set_modified(later).unwrap();
+        assert!(watcher.is_modified());
+        assert!(watcher.changed().await);
+        assert!(!watcher.is_modified());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(!watcher.is_modified());
+    }
+}
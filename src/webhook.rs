@@ -0,0 +1,210 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Webhooks for `@ webhook:` and `--webhook-url`
+//!
+//! Events are POSTed as JSON from a background task, one at a time and in
+//! order, so a slow receiver never holds up playback. Plain `http://`
+//! requests, the usual case for local tools such as OBS bridges and the
+//! health checks `@ waiturl:` polls, are sent directly. `https://` ones are
+//! left to curl, as uploads are
+
+use anyhow::{Context, Result, bail};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::sync::mpsc::{UnboundedSender, unbounded_channel};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Check that `url` is one we can send to
+pub fn check_url(url: &str) -> Result<(), String> {
+    split_url(url).map(|_| ())
+}
+
+// Host and port to connect to, the Host header, and the request path
+fn split_url(url: &str) -> Result<(String, &str, &str), String> {
+    let (rest, port) = match url.strip_prefix("http://") {
+        Some(rest) => (rest, 80),
+        None => url
+            .strip_prefix("https://")
+            .map(|rest| (rest, 443))
+            .ok_or_else(|| format!("Only http:// and https:// URLs are supported: '{}'", url))?,
+    };
+    let (host, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(format!("URL has no host: '{}'", url));
+    }
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, port)
+    };
+    Ok((address, host, path))
+}
+
+/// POST `body` to `url` as JSON, failing unless the response is a 2xx
+pub async fn post(url: &str, body: &str) -> Result<()> {
+    if url.starts_with("https://") {
+        return curl(url, Some(body)).await;
+    }
+    send(url, |host, path| {
+        format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            path,
+            host,
+            body.len(),
+            body
+        )
+    })
+    .await
+}
+
+/// GET `url`, failing unless the response is a 2xx
+pub async fn get(url: &str) -> Result<()> {
+    if url.starts_with("https://") {
+        return curl(url, None).await;
+    }
+    send(url, |host, path| {
+        format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, host
+        )
+    })
+    .await
+}
+
+// Send the request `request` makes from the Host header and path
+async fn send(url: &str, request: impl FnOnce(&str, &str) -> String) -> Result<()> {
+    let (address, host, path) = split_url(url).map_err(anyhow::Error::msg)?;
+    let mut stream = TcpStream::connect(&address)
+        .await
+        .with_context(|| format!("Failed to connect to {}", address))?;
+    stream.write_all(request(host, path).as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => bail!("unexpected response '{}'", status),
+    }
+}
+
+// Send an HTTPS request with curl, POSTing `body` if there is one
+async fn curl(url: &str, body: Option<&str>) -> Result<()> {
+    split_url(url).map_err(anyhow::Error::msg)?;
+    let mut command = Command::new("curl");
+    // The status goes on a line of its own after the body
+    command.args(["--silent", "--show-error", "--write-out", "\n%{http_code}"]);
+    if let Some(body) = body {
+        command
+            .args(["--header", "Content-Type: application/json"])
+            .args(["--data-binary", body]);
+    }
+    let output = command
+        .arg(url)
+        .stdin(Stdio::null())
+        // Timeouts drop the request, which has to stop curl too
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("Failed to run curl, which is needed for https:// URLs")?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match stdout.lines().last() {
+        Some(code) if code.starts_with('2') => Ok(()),
+        code => bail!("unexpected response {}", code.unwrap_or_default()),
+    }
+}
+
+pub struct Webhooks {
+    queue: Option<UnboundedSender<(String, String)>>,
+    worker: Option<JoinHandle<Vec<String>>>,
+}
+
+impl Webhooks {
+    // Not Default, as it starts a task on the runtime
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        let (queue, mut requests) = unbounded_channel::<(String, String)>();
+        let worker = tokio::spawn(async move {
+            let mut errors = Vec::new();
+            while let Some((url, body)) = requests.recv().await {
+                let result = match timeout(REQUEST_TIMEOUT, post(&url, &body)).await {
+                    Ok(result) => result,
+                    Err(_) => Err(anyhow::anyhow!("timed out")),
+                };
+                if let Err(e) = result {
+                    errors.push(format!("{}: {:#}", url, e));
+                }
+            }
+            errors
+        });
+
+        Self {
+            queue: Some(queue),
+            worker: Some(worker),
+        }
+    }
+
+    pub fn send(&self, url: &str, body: &serde_json::Value) {
+        if let Some(queue) = &self.queue {
+            let _ = queue.send((url.to_string(), body.to_string()));
+        }
+    }
+
+    /// Wait for queued requests to go out, returning any that failed
+    pub async fn finish(&mut self) -> Vec<String> {
+        drop(self.queue.take());
+        match self.worker.take() {
+            Some(worker) => worker.await.unwrap_or_default(),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_url() {
+        assert_eq!(
+            split_url("http://localhost:8000/scene/2"),
+            Ok(("localhost:8000".to_string(), "localhost:8000", "/scene/2"))
+        );
+        assert_eq!(
+            split_url("http://example.com"),
+            Ok(("example.com:80".to_string(), "example.com", "/"))
+        );
+        assert_eq!(
+            split_url("https://example.com/hooks"),
+            Ok(("example.com:443".to_string(), "example.com", "/hooks"))
+        );
+        assert!(split_url("ftp://example.com/").is_err());
+        assert!(split_url("https:///hooks").is_err());
+    }
+}
@@ -0,0 +1,166 @@
+// Copyright (C) 2025  Tom Waddington
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Character widths, as a terminal lays them out
+//!
+//! vt100 gives each character its own cells, which isn't how terminals draw
+//! everything. Output is rewritten on its way into a screen model so that
+//! columns line up: an emoji sequence joined with ZWJ or a skin tone takes
+//! one wide cell, holding its first emoji, and with `ambiguous_width = "wide"`
+//! East Asian ambiguous characters like `①` and `→` take two, the second
+//! left blank as a CJK terminal would
+
+use serde::Deserialize;
+use unicode_width::UnicodeWidthChar;
+
+use crate::screen::Scan;
+
+const ZWJ: char = '\u{200d}';
+
+/// How wide East Asian ambiguous-width characters are drawn
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AmbiguousWidth {
+    #[default]
+    Narrow,
+    Wide,
+}
+
+/// Columns `c` takes, 0 for combining and other zero-width characters
+pub fn char_width(c: char, ambiguous: AmbiguousWidth) -> usize {
+    let width = match ambiguous {
+        AmbiguousWidth::Narrow => c.width(),
+        AmbiguousWidth::Wide => c.width_cjk(),
+    };
+    width.unwrap_or(0)
+}
+
+fn is_skin_tone(c: char) -> bool {
+    ('\u{1f3fb}'..='\u{1f3ff}').contains(&c)
+}
+
+/// Rewrites output for a screen model, keeping track of characters and
+/// escape sequences split across reads
+#[derive(Default)]
+pub struct Layout {
+    ambiguous: AmbiguousWidth,
+    scan: Scan,
+    // The start of a character whose other bytes haven't arrived
+    partial: Vec<u8>,
+    // After a wide character, which a ZWJ or skin tone would join the next to
+    joinable: bool,
+    joining: bool,
+}
+
+impl Layout {
+    pub fn new(ambiguous: AmbiguousWidth) -> Self {
+        Self {
+            ambiguous,
+            ..Self::default()
+        }
+    }
+
+    pub fn rewrite(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(bytes.len());
+        for &byte in bytes {
+            let ground = self.scan.is_ground();
+            self.scan.next(byte);
+            if !ground || byte < 0x80 {
+                out.append(&mut self.partial);
+                if ground {
+                    self.joinable = false;
+                    self.joining = false;
+                }
+                out.push(byte);
+                continue;
+            }
+
+            // A new character, or a stray continuation byte, ends any partial one
+            if self.partial.is_empty() || !(0x80..0xc0).contains(&byte) {
+                out.append(&mut self.partial);
+            }
+            self.partial.push(byte);
+            let len = match self.partial[0] {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+            if self.partial.len() < len {
+                continue;
+            }
+            match std::str::from_utf8(&self.partial) {
+                Ok(text) => {
+                    let c = text.chars().next().unwrap_or_default();
+                    self.partial.clear();
+                    self.character(c, &mut out);
+                }
+                Err(_) => out.append(&mut self.partial),
+            }
+        }
+        out
+    }
+
+    fn character(&mut self, c: char, out: &mut Vec<u8>) {
+        let mut buffer = [0; 4];
+        let encoded = c.encode_utf8(&mut buffer).as_bytes();
+        let width = char_width(c, self.ambiguous);
+
+        if c == ZWJ && self.joinable {
+            self.joining = true;
+            return;
+        } else if width > 0 && (self.joining || (self.joinable && is_skin_tone(c))) {
+            // Part of the emoji before it, which already has the cells
+            self.joining = false;
+            return;
+        } else if width > 0 {
+            self.joinable = width == 2;
+            self.joining = false;
+        }
+        out.extend_from_slice(encoded);
+        // vt100 only knows the narrow width, so the second cell is filled in
+        if width == 2 && c.width() == Some(1) {
+            out.push(b' ');
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        let mut parser = vt100::Parser::new(2, 20, 0);
+        let mut layout = Layout::new(AmbiguousWidth::Narrow);
+        // A family, then a waving hand split across reads, then a skin tone
+        let family = "👨\u{200d}👩\u{200d}👧|".as_bytes();
+        parser.process(&layout.rewrite(&family[..6]));
+        parser.process(&layout.rewrite(&family[6..]));
+        parser.process(&layout.rewrite("日本|👋🏽|".as_bytes()));
+        let screen = parser.screen();
+        assert_eq!(screen.cell(0, 0).unwrap().contents(), "👨");
+        assert_eq!(screen.cell(0, 2).unwrap().contents(), "|");
+        assert_eq!(screen.cell(0, 7).unwrap().contents(), "|");
+        assert_eq!(screen.cell(0, 10).unwrap().contents(), "|");
+
+        // Ambiguous characters, and escape sequences left alone
+        let mut parser = vt100::Parser::new(2, 20, 0);
+        let mut layout = Layout::new(AmbiguousWidth::Wide);
+        parser.process(&layout.rewrite("①\x1b[31m→|a".as_bytes()));
+        assert_eq!(parser.screen().contents(), "① → |a");
+        assert_eq!(parser.screen().cell(0, 4).unwrap().contents(), "|");
+    }
+}